@@ -0,0 +1,346 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{ Float, BBox, Point, Ray, Union };
+use rbrtcore::intersection::Intersection;
+use rbrtcore::light::AreaLight;
+use rbrtcore::primitive::{ Primitive, fully_refine };
+use rbrtcore::reflection::{ Bsdf, Bssrdf };
+use rbrtcore::transform::Transform;
+
+/// Buckets the centroids are binned into when looking for a split
+const N_BUCKETS: usize = 12;
+
+/// Up to this many primitives are split at their median rather than by
+/// the surface area heuristic
+const MEDIAN_SPLIT_PRIMITIVES: usize = 4;
+
+/// Cost of visiting a node, relative to intersecting a primitive
+const TRAVERSAL_COST: Float = 0.125;
+
+struct PrimitiveInfo {
+  primitive_number: usize,
+  centroid: Point,
+  bounds: BBox
+}
+
+enum BuildNode {
+  Leaf { bounds: BBox, first: usize, count: usize },
+  Interior { bounds: BBox, axis: usize, children: [Box<BuildNode>; 2] }
+}
+
+/// Node of the flattened tree. The first child of an interior node
+/// directly follows it, so only the second one is recorded.
+#[derive(Clone, Copy, Debug)]
+struct LinearNode {
+  bounds: BBox,
+  /// First primitive of a leaf, or second child of an interior node
+  offset: usize,
+  /// Zero for interior nodes
+  n_primitives: usize,
+  axis: usize
+}
+
+/// Bounding volume hierarchy over primitives, built with binned surface
+/// area heuristic splits and stored depth first in a single array
+pub struct BvhAccel {
+  pub max_prims_in_node: usize,
+  primitives: Vec<Rc<dyn Primitive>>,
+  nodes: Vec<LinearNode>
+}
+
+impl BvhAccel {
+  /// Build over the fully refined `primitives`, making leaves of at most
+  /// `max_prims_in_node` primitives unless they cannot be told apart
+  pub fn new(primitives: Vec<Rc<dyn Primitive>>, max_prims_in_node: usize) -> Result<BvhAccel, RenderError> {
+    let mut refined = Vec::with_capacity(primitives.len());
+    for p in primitives {
+      refined.extend(fully_refine(p)?);
+    }
+    let primitives = refined;
+    let max_prims_in_node = max_prims_in_node.clamp(1, 255);
+
+    let mut info: Vec<PrimitiveInfo> = primitives.iter().enumerate().map(|(i, p)| {
+      let bounds = p.world_bound();
+      PrimitiveInfo { primitive_number: i, centroid: (bounds.p_min + bounds.p_max) * 0.5, bounds }
+    }).collect();
+
+    let mut bvh = BvhAccel { max_prims_in_node, primitives: Vec::new(), nodes: Vec::new() };
+    if info.is_empty() {
+      return Ok(bvh);
+    }
+
+    let mut ordered = Vec::with_capacity(primitives.len());
+    let mut total_nodes = 0;
+    let root = bvh.recursive_build(&mut info, &primitives, &mut ordered, &mut total_nodes);
+
+    bvh.primitives = ordered;
+    bvh.nodes.reserve_exact(total_nodes);
+    bvh.flatten(&root);
+
+    Ok(bvh)
+  }
+
+  fn recursive_build(&self, info: &mut [PrimitiveInfo], primitives: &[Rc<dyn Primitive>],
+      ordered: &mut Vec<Rc<dyn Primitive>>, total_nodes: &mut usize) -> BuildNode {
+    *total_nodes += 1;
+
+    let n = info.len();
+    let bounds = info[1..].iter().fold(info[0].bounds, |b, i| b.union(&i.bounds));
+    let leaf = |info: &[PrimitiveInfo], ordered: &mut Vec<Rc<dyn Primitive>>| {
+      let first = ordered.len();
+      ordered.extend(info.iter().map(|i| primitives[i.primitive_number].clone()));
+      BuildNode::Leaf { bounds, first, count: info.len() }
+    };
+
+    if n == 1 {
+      return leaf(info, ordered);
+    }
+
+    let centroid_bounds = info[1..].iter()
+      .fold(BBox::from_point(&info[0].centroid), |b, i| b.union(&i.centroid));
+    let axis = centroid_bounds.maximum_extent();
+    let (c_min, c_max) = (centroid_bounds.p_min[axis], centroid_bounds.p_max[axis]);
+
+    // Coincident centroids leave nothing to split by
+    if c_max == c_min {
+      return leaf(info, ordered);
+    }
+
+    let mid = if n <= MEDIAN_SPLIT_PRIMITIVES {
+      info.select_nth_unstable_by(n / 2, |a, b|
+        a.centroid[axis].partial_cmp(&b.centroid[axis]).unwrap_or(Ordering::Equal));
+      n / 2
+    } else {
+      let bucket = |i: &PrimitiveInfo|
+        (((i.centroid[axis] - c_min) / (c_max - c_min) * N_BUCKETS as Float) as usize).min(N_BUCKETS - 1);
+
+      let mut counts = [0usize; N_BUCKETS];
+      let mut bucket_bounds: [Option<BBox>; N_BUCKETS] = [None; N_BUCKETS];
+      for i in info.iter() {
+        let b = bucket(i);
+        counts[b] += 1;
+        bucket_bounds[b] = Some(bucket_bounds[b].map_or(i.bounds, |bb| bb.union(&i.bounds)));
+      }
+
+      // Cost of splitting after each bucket; the first and last buckets
+      // always hold a centroid, so neither side is ever empty
+      let side = |buckets: std::ops::Range<usize>| {
+        let count: usize = counts[buckets.clone()].iter().sum();
+        let area = bucket_bounds[buckets].iter().flatten()
+          .fold(None, |b: Option<BBox>, bb| Some(b.map_or(*bb, |b| b.union(bb))))
+          .map_or(0.0, |b| b.surface_area());
+        count as Float * area
+      };
+      let (min_bucket, min_cost) = (0..N_BUCKETS - 1)
+        .map(|i| (i, TRAVERSAL_COST + (side(0..i + 1) + side(i + 1..N_BUCKETS)) / bounds.surface_area()))
+        .fold((0, Float::INFINITY), |best, c| if c.1 < best.1 { c } else { best });
+
+      if n <= self.max_prims_in_node && min_cost >= n as Float {
+        return leaf(info, ordered);
+      }
+
+      let mut mid = 0;
+      for i in 0..n {
+        if bucket(&info[i]) <= min_bucket {
+          info.swap(i, mid);
+          mid += 1;
+        }
+      }
+      mid
+    };
+
+    let (left, right) = info.split_at_mut(mid);
+    BuildNode::Interior {
+      bounds,
+      axis,
+      children: [
+        Box::new(self.recursive_build(left, primitives, ordered, total_nodes)),
+        Box::new(self.recursive_build(right, primitives, ordered, total_nodes))
+      ]
+    }
+  }
+
+  /// Append `node` and its subtree depth first, returning its offset
+  fn flatten(&mut self, node: &BuildNode) -> usize {
+    let offset = self.nodes.len();
+
+    match *node {
+      BuildNode::Leaf { bounds, first, count } => {
+        self.nodes.push(LinearNode { bounds, offset: first, n_primitives: count, axis: 0 });
+      },
+      BuildNode::Interior { bounds, axis, ref children } => {
+        self.nodes.push(LinearNode { bounds, offset: 0, n_primitives: 0, axis });
+        self.flatten(&children[0]);
+        self.nodes[offset].offset = self.flatten(&children[1]);
+      }
+    }
+
+    offset
+  }
+
+  /// Visit the leaves whose bounds `ray` passes through, nearer children
+  /// first, until `visit` returns true. Bounds are tested against `ray` as
+  /// `visit` leaves it, so a ray shortened by a hit skips farther nodes.
+  fn traverse<F>(&self, ray: &mut Ray, mut visit: F)
+      where F: FnMut(&[Rc<dyn Primitive>], &mut Ray) -> bool {
+    if self.nodes.is_empty() {
+      return;
+    }
+
+    let dir_is_neg = [ray.d.x < 0.0, ray.d.y < 0.0, ray.d.z < 0.0];
+    let mut todo = Vec::with_capacity(64);
+    let mut current = 0;
+
+    loop {
+      let node = &self.nodes[current];
+
+      if node.bounds.intersect_p(ray).is_some() {
+        if node.n_primitives > 0 {
+          if visit(&self.primitives[node.offset..node.offset + node.n_primitives], ray) {
+            return;
+          }
+        } else {
+          if dir_is_neg[node.axis] {
+            todo.push(current + 1);
+            current = node.offset;
+          } else {
+            todo.push(node.offset);
+            current += 1;
+          }
+          continue;
+        }
+      }
+
+      match todo.pop() {
+        Some(next) => current = next,
+        None => return
+      }
+    }
+  }
+}
+
+impl Primitive for BvhAccel {
+  fn intersect(&self, ray: &mut Ray) -> Option<Intersection> {
+    let mut hit = None;
+
+    self.traverse(ray, |primitives, ray| {
+      for p in primitives {
+        if let Some(mut isect) = p.intersect(ray) {
+          if isect.primitive.is_none() {
+            isect.primitive = Some(p.clone());
+          }
+          hit = Some(isect);
+        }
+      }
+      false
+    });
+
+    hit
+  }
+
+  fn intersect_p(&self, ray: &Ray) -> bool {
+    let mut hit = false;
+    self.traverse(&mut ray.clone(), |primitives, ray| {
+      hit = primitives.iter().any(|p| p.intersect_p(ray));
+      hit
+    });
+    hit
+  }
+
+  fn world_bound(&self) -> BBox {
+    self.nodes.first().map_or_else(
+      || BBox::new(&Point::new(Float::INFINITY, Float::INFINITY, Float::INFINITY),
+        &Point::new(-Float::INFINITY, -Float::INFINITY, -Float::INFINITY)),
+      |root| root.bounds)
+  }
+
+  fn get_bsdf(&self, _dg: &DifferentialGeometry, _object_to_world: &Transform) -> Option<Bsdf> {
+    None
+  }
+
+  fn get_bssrdf(&self, _dg: &DifferentialGeometry, _object_to_world: &Transform) -> Option<Bssrdf> {
+    None
+  }
+
+  fn get_area_light(&self) -> Option<Rc<dyn AreaLight>> {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rbrtcore::geometry::Vector;
+  use rbrtcore::testing::ball;
+
+  /// Balls on a jittered grid
+  fn grid(n: usize) -> Vec<Rc<dyn Primitive>> {
+    let mut balls = Vec::new();
+    for i in 0..n * n * n {
+      let (x, y, z) = ((i % n) as Float, (i / n % n) as Float, (i / (n * n)) as Float);
+      let jitter = ((i * 7919) % 13) as Float / 40.0;
+      balls.push(ball(Point::new(x + jitter, y, z - jitter), 0.2 + jitter / 2.0, 0));
+    }
+    balls
+  }
+
+  /// Rays from outside the grid through it in every direction
+  fn rays() -> Vec<Ray> {
+    (0..200).map(|i| {
+      let f = i as Float;
+      let o = Point::new(2.0 + 8.0 * (f * 0.37).sin(), 2.0 + 8.0 * (f * 0.61).cos(), 2.0 + 8.0 * (f * 0.23).sin());
+      let target = Point::new((f * 0.11) % 4.0, (f * 0.29) % 4.0, (f * 0.47) % 4.0);
+      Ray::new(&o, &(target - o), 0.0, Float::INFINITY, 0.0)
+    }).collect()
+  }
+
+  #[test]
+  fn matches_brute_force() {
+    let balls = grid(5);
+    let bvh = BvhAccel::new(balls.clone(), 4).unwrap();
+    assert!(bvh.nodes.iter().all(|n| n.n_primitives <= 4));
+
+    let mut hits = 0;
+    for ray in rays() {
+      let mut expected_ray = ray;
+      let expected = balls.iter().filter_map(|b| b.intersect(&mut expected_ray)).last();
+
+      let mut r = ray;
+      let isect = bvh.intersect(&mut r);
+      assert_eq!(isect.as_ref().map(|i| i.primitive_id), expected.map(|i| i.primitive_id));
+      assert_eq!(r.maxt, expected_ray.maxt);
+      assert_eq!(bvh.intersect_p(&ray), isect.is_some());
+
+      if let Some(isect) = isect {
+        assert!(isect.primitive.is_some());
+        hits += 1;
+      }
+    }
+    assert!(hits > 50);
+  }
+
+  #[test]
+  fn refines_and_handles_degenerate_input() {
+    let row = ball(Point::new(0.0, 0.0, 0.0), 0.25, 9);
+    let bvh = BvhAccel::new(vec![row], 1).unwrap();
+    assert_eq!(bvh.primitives.len(), 10);
+    assert_eq!(bvh.world_bound().p_max.x, 9.25);
+
+    let mut ray = Ray::new(&Point::new(5.0, 0.0, 5.0), &Vector::new(0.0, 0.0, -1.0), 0.0, Float::INFINITY, 0.0);
+    assert!(bvh.intersect(&mut ray).is_some());
+    assert!((ray.maxt - 4.75).abs() < 1e-4);
+
+    // Primitives sharing a centroid end up in one leaf, whatever the limit
+    let stacked = (0..6).map(|_| ball(Point::new(0.0, 0.0, 0.0), 1.0, 0)).collect();
+    let bvh = BvhAccel::new(stacked, 2).unwrap();
+    assert_eq!(bvh.nodes.len(), 1);
+    assert_eq!(bvh.nodes[0].n_primitives, 6);
+
+    let empty = BvhAccel::new(Vec::new(), 4).unwrap();
+    assert!(!empty.intersect_p(&ray));
+    assert!(empty.world_bound().p_min.x > empty.world_bound().p_max.x);
+  }
+}
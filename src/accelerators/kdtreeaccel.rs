@@ -0,0 +1,403 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{ Float, BBox, Point, Ray, Union };
+use rbrtcore::intersection::Intersection;
+use rbrtcore::light::AreaLight;
+use rbrtcore::primitive::{ Primitive, fully_refine };
+use rbrtcore::reflection::{ Bsdf, Bssrdf };
+use rbrtcore::transform::Transform;
+
+pub const DEFAULT_ISECT_COST: Float = 80.0;
+pub const DEFAULT_TRAVERSAL_COST: Float = 1.0;
+pub const DEFAULT_EMPTY_BONUS: Float = 0.5;
+pub const DEFAULT_MAX_PRIMS: usize = 1;
+
+/// Splits costing more than the primitives they separate tolerated along
+/// one path, before it is made a leaf
+const MAX_BAD_REFINES: usize = 3;
+
+#[derive(Clone, Copy, Debug)]
+enum KdAccelNode {
+  /// Range of `primitive_indices`
+  Leaf { first: usize, count: usize },
+  /// The child below the split directly follows its parent
+  Interior { axis: usize, split: Float, above_child: usize }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum EdgeType {
+  Start,
+  End
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BoundEdge {
+  t: Float,
+  primitive: usize,
+  edge_type: EdgeType
+}
+
+/// kd-tree over primitives, split by the surface area heuristic with a
+/// bonus for cutting off empty space
+pub struct KdTreeAccel {
+  isect_cost: Float,
+  traversal_cost: Float,
+  empty_bonus: Float,
+  max_prims: usize,
+  max_depth: usize,
+  primitives: Vec<Rc<dyn Primitive>>,
+  primitive_indices: Vec<usize>,
+  nodes: Vec<KdAccelNode>,
+  bounds: BBox
+}
+
+impl KdTreeAccel {
+  /// Build over the fully refined `primitives`. Costs are relative to one
+  /// another, `empty_bonus` is the fraction taken off the cost of splits
+  /// with an empty side. Without a `max_depth` the tree grows to about
+  /// `8 + 1.3 log2(n)` levels for `n` primitives.
+  pub fn new(primitives: Vec<Rc<dyn Primitive>>, isect_cost: Float, traversal_cost: Float,
+      empty_bonus: Float, max_prims: usize, max_depth: Option<usize>) -> Result<KdTreeAccel, RenderError> {
+    let mut refined = Vec::with_capacity(primitives.len());
+    for p in primitives {
+      refined.extend(fully_refine(p)?);
+    }
+    let primitives = refined;
+    let n = primitives.len();
+    let max_depth = max_depth
+      .unwrap_or_else(|| (8.0 + 1.3 * (n.max(1) as Float).log2()).round() as usize);
+
+    let prim_bounds: Vec<BBox> = primitives.iter().map(|p| p.world_bound()).collect();
+    let bounds = match prim_bounds.split_first() {
+      Some((first, rest)) => rest.iter().fold(*first, |b, pb| b.union(pb)),
+      None => BBox::new(&Point::new(Float::INFINITY, Float::INFINITY, Float::INFINITY),
+        &Point::new(-Float::INFINITY, -Float::INFINITY, -Float::INFINITY))
+    };
+
+    let mut tree = KdTreeAccel {
+      isect_cost,
+      traversal_cost,
+      empty_bonus,
+      max_prims,
+      max_depth,
+      primitives,
+      primitive_indices: Vec::new(),
+      nodes: Vec::new(),
+      bounds
+    };
+
+    if n > 0 {
+      tree.build_tree(&bounds, &prim_bounds, (0..n).collect(), max_depth, 0);
+    }
+
+    Ok(tree)
+  }
+
+  /// Depth the tree was allowed to grow to
+  pub fn max_depth(&self) -> usize {
+    self.max_depth
+  }
+
+  fn build_tree(&mut self, node_bounds: &BBox, prim_bounds: &[BBox], prims: Vec<usize>,
+      depth: usize, mut bad_refines: usize) {
+    let n = prims.len();
+    if n <= self.max_prims || depth == 0 {
+      self.make_leaf(&prims);
+      return;
+    }
+
+    let (best, best_cost, edges) = self.find_split(node_bounds, prim_bounds, &prims);
+    let old_cost = self.isect_cost * n as Float;
+    if best_cost > old_cost {
+      bad_refines += 1;
+    }
+
+    let (axis, offset) = match best {
+      Some(b) if !((best_cost > 4.0 * old_cost && n < 16) || bad_refines == MAX_BAD_REFINES) => b,
+      _ => {
+        self.make_leaf(&prims);
+        return;
+      }
+    };
+
+    // Primitives starting below the split go below it, those ending above
+    // it go above, straddling ones go to both sides
+    let edges = &edges[axis];
+    let below: Vec<usize> = edges[..offset].iter()
+      .filter(|e| e.edge_type == EdgeType::Start).map(|e| e.primitive).collect();
+    let above: Vec<usize> = edges[offset + 1..].iter()
+      .filter(|e| e.edge_type == EdgeType::End).map(|e| e.primitive).collect();
+
+    let split = edges[offset].t;
+    let (mut bounds_below, mut bounds_above) = (*node_bounds, *node_bounds);
+    set_axis(&mut bounds_below.p_max, axis, split);
+    set_axis(&mut bounds_above.p_min, axis, split);
+
+    let node = self.nodes.len();
+    self.nodes.push(KdAccelNode::Interior { axis, split, above_child: 0 });
+    self.build_tree(&bounds_below, prim_bounds, below, depth - 1, bad_refines);
+
+    let above_child = self.nodes.len();
+    self.nodes[node] = KdAccelNode::Interior { axis, split, above_child };
+    self.build_tree(&bounds_above, prim_bounds, above, depth - 1, bad_refines);
+  }
+
+  /// Cheapest split of `node_bounds` as an axis and an offset into that
+  /// axis's sorted edges, trying the longest axis first and the others
+  /// only when it has no split inside the node
+  fn find_split(&self, node_bounds: &BBox, prim_bounds: &[BBox], prims: &[usize])
+      -> (Option<(usize, usize)>, Float, [Vec<BoundEdge>; 3]) {
+    let n = prims.len();
+    let d = node_bounds.p_max - node_bounds.p_min;
+    let inv_total_sa = 1.0 / node_bounds.surface_area();
+
+    let mut edges: [Vec<BoundEdge>; 3] = Default::default();
+    let mut best = None;
+    let mut best_cost = Float::INFINITY;
+    let mut axis = node_bounds.maximum_extent();
+
+    for _ in 0..3 {
+      let axis_edges = &mut edges[axis];
+      for &p in prims {
+        axis_edges.push(BoundEdge { t: prim_bounds[p].p_min[axis], primitive: p, edge_type: EdgeType::Start });
+        axis_edges.push(BoundEdge { t: prim_bounds[p].p_max[axis], primitive: p, edge_type: EdgeType::End });
+      }
+      axis_edges.sort_by(|a, b|
+        a.t.partial_cmp(&b.t).unwrap_or(Ordering::Equal).then(a.edge_type.cmp(&b.edge_type)));
+
+      let (other0, other1) = ((axis + 1) % 3, (axis + 2) % 3);
+      let (t_min, t_max) = (node_bounds.p_min[axis], node_bounds.p_max[axis]);
+      let (mut n_below, mut n_above) = (0, n);
+
+      for (i, edge) in axis_edges.iter().enumerate() {
+        if edge.edge_type == EdgeType::End {
+          n_above -= 1;
+        }
+
+        if edge.t > t_min && edge.t < t_max {
+          let face = d[other0] * d[other1];
+          let side = d[other0] + d[other1];
+          let p_below = 2.0 * (face + (edge.t - t_min) * side) * inv_total_sa;
+          let p_above = 2.0 * (face + (t_max - edge.t) * side) * inv_total_sa;
+          let bonus = if n_below == 0 || n_above == 0 { self.empty_bonus } else { 0.0 };
+          let cost = self.traversal_cost +
+            self.isect_cost * (1.0 - bonus) * (p_below * n_below as Float + p_above * n_above as Float);
+
+          if cost < best_cost {
+            best_cost = cost;
+            best = Some((axis, i));
+          }
+        }
+
+        if edge.edge_type == EdgeType::Start {
+          n_below += 1;
+        }
+      }
+
+      if best.is_some() {
+        break;
+      }
+      axis = (axis + 1) % 3;
+    }
+
+    (best, best_cost, edges)
+  }
+
+  fn make_leaf(&mut self, prims: &[usize]) {
+    self.nodes.push(KdAccelNode::Leaf { first: self.primitive_indices.len(), count: prims.len() });
+    self.primitive_indices.extend_from_slice(prims);
+  }
+
+  /// Visit the leaves `ray` passes through, front to back, until `visit`
+  /// returns true. Leaves starting beyond `ray.maxt`, as `visit` leaves
+  /// it, are not visited.
+  fn traverse<F>(&self, ray: &mut Ray, mut visit: F)
+      where F: FnMut(&[usize], &mut Ray) -> bool {
+    if self.nodes.is_empty() {
+      return;
+    }
+
+    let (mut t_min, mut t_max) = match self.bounds.intersect_p(ray) {
+      Some(t) => t,
+      None => return
+    };
+
+    let mut todo: Vec<(usize, Float, Float)> = Vec::with_capacity(64);
+    let mut current = 0;
+
+    while ray.maxt >= t_min {
+      match self.nodes[current] {
+        KdAccelNode::Interior { axis, split, above_child } => {
+          let (o, d) = (ray.o[axis], ray.d[axis]);
+          let t_plane = (split - o) / d;
+
+          // The child on the ray origin's side of the split comes first
+          let below_first = o < split || (o == split && d <= 0.0);
+          let (first, second) = if below_first {
+            (current + 1, above_child)
+          } else {
+            (above_child, current + 1)
+          };
+
+          // A ray lying in the split plane has no crossing, its `t_plane`
+          // is not a number
+          if t_plane > t_max || t_plane <= 0.0 || t_plane.is_nan() {
+            current = first;
+          } else if t_plane < t_min {
+            current = second;
+          } else {
+            todo.push((second, t_plane, t_max));
+            current = first;
+            t_max = t_plane;
+          }
+        },
+        KdAccelNode::Leaf { first, count } => {
+          if visit(&self.primitive_indices[first..first + count], ray) {
+            return;
+          }
+
+          match todo.pop() {
+            Some(next) => (current, t_min, t_max) = next,
+            None => return
+          }
+        }
+      }
+    }
+  }
+}
+
+fn set_axis(p: &mut Point, axis: usize, value: Float) {
+  match axis {
+    0 => p.x = value,
+    1 => p.y = value,
+    _ => p.z = value
+  }
+}
+
+impl Primitive for KdTreeAccel {
+  fn intersect(&self, ray: &mut Ray) -> Option<Intersection> {
+    let mut hit = None;
+
+    self.traverse(ray, |indices, ray| {
+      for &i in indices {
+        let p = &self.primitives[i];
+        if let Some(mut isect) = p.intersect(ray) {
+          if isect.primitive.is_none() {
+            isect.primitive = Some(p.clone());
+          }
+          hit = Some(isect);
+        }
+      }
+      false
+    });
+
+    hit
+  }
+
+  fn intersect_p(&self, ray: &Ray) -> bool {
+    let mut hit = false;
+    self.traverse(&mut ray.clone(), |indices, ray| {
+      hit = indices.iter().any(|&i| self.primitives[i].intersect_p(ray));
+      hit
+    });
+    hit
+  }
+
+  fn world_bound(&self) -> BBox {
+    self.bounds
+  }
+
+  fn get_bsdf(&self, _dg: &DifferentialGeometry, _object_to_world: &Transform) -> Option<Bsdf> {
+    None
+  }
+
+  fn get_bssrdf(&self, _dg: &DifferentialGeometry, _object_to_world: &Transform) -> Option<Bssrdf> {
+    None
+  }
+
+  fn get_area_light(&self) -> Option<Rc<dyn AreaLight>> {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rbrtcore::geometry::Vector;
+  use rbrtcore::testing::ball;
+
+  fn kdtree(primitives: Vec<Rc<dyn Primitive>>, max_prims: usize, max_depth: Option<usize>) -> KdTreeAccel {
+    KdTreeAccel::new(primitives, DEFAULT_ISECT_COST, DEFAULT_TRAVERSAL_COST, DEFAULT_EMPTY_BONUS,
+      max_prims, max_depth).unwrap()
+  }
+
+  fn down(x: Float) -> Ray {
+    Ray::new(&Point::new(x, 0.0, 5.0), &Vector::new(0.0, 0.0, -1.0), 0.0, Float::INFINITY, 0.0)
+  }
+
+  #[test]
+  fn matches_brute_force() {
+    let n = 5;
+    let balls: Vec<Rc<dyn Primitive>> = (0..n * n * n).map(|i| {
+      let (x, y, z) = ((i % n) as Float, (i / n % n) as Float, (i / (n * n)) as Float);
+      let jitter = ((i * 7919) % 13) as Float / 40.0;
+      ball(Point::new(x + jitter, y, z - jitter), 0.2 + jitter / 2.0, 0)
+    }).collect();
+    let tree = kdtree(balls.clone(), DEFAULT_MAX_PRIMS, None);
+    assert_eq!(tree.max_depth(), 17);
+    assert!(tree.nodes.len() > 100);
+
+    let mut hits = 0;
+    for i in 0..200 {
+      let f = i as Float;
+      let o = Point::new(2.0 + 8.0 * (f * 0.37).sin(), 2.0 + 8.0 * (f * 0.61).cos(), 2.0 + 8.0 * (f * 0.23).sin());
+      let target = Point::new((f * 0.11) % 4.0, (f * 0.29) % 4.0, (f * 0.47) % 4.0);
+      let ray = Ray::new(&o, &(target - o), 0.0, Float::INFINITY, 0.0);
+
+      let mut expected_ray = ray;
+      let expected = balls.iter().filter_map(|b| b.intersect(&mut expected_ray)).last();
+
+      let mut r = ray;
+      let isect = tree.intersect(&mut r);
+      assert_eq!(isect.as_ref().map(|i| i.primitive_id), expected.map(|i| i.primitive_id));
+      assert_eq!(r.maxt, expected_ray.maxt);
+      assert_eq!(tree.intersect_p(&ray), isect.is_some());
+
+      if let Some(isect) = isect {
+        assert!(isect.primitive.is_some());
+        hits += 1;
+      }
+    }
+    assert!(hits > 50);
+  }
+
+  #[test]
+  fn refines_and_respects_limits() {
+    let row = ball(Point::new(0.0, 0.0, 0.0), 0.25, 9);
+    let tree = kdtree(vec![row.clone()], 1, None);
+    assert_eq!(tree.primitives.len(), 10);
+    assert_eq!(tree.world_bound().p_max.x, 9.25);
+
+    let mut ray = down(5.0);
+    assert!(tree.intersect(&mut ray).is_some());
+    assert!((ray.maxt - 4.75).abs() < 1e-4);
+    assert!(!tree.intersect_p(&down(5.5)));
+
+    // Leaves may hold any number of primitives when the tree is cut short
+    for tree in [kdtree(vec![row.clone()], 10, None), kdtree(vec![row], 1, Some(0))] {
+      assert_eq!(tree.nodes.len(), 1);
+      assert!(tree.intersect_p(&down(5.0)));
+    }
+
+    // Coincident primitives cannot be split apart
+    let stacked = (0..6).map(|_| ball(Point::new(0.0, 0.0, 0.0), 1.0, 0)).collect();
+    assert_eq!(kdtree(stacked, 1, None).nodes.len(), 1);
+
+    let empty = kdtree(Vec::new(), 1, None);
+    assert!(!empty.intersect_p(&down(0.0)));
+    assert!(empty.world_bound().p_min.x > empty.world_bound().p_max.x);
+  }
+}
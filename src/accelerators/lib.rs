@@ -0,0 +1,2 @@
+pub mod bvh;
+pub mod kdtreeaccel;
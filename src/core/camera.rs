@@ -1,15 +1,101 @@
-use film::Film;
-use geometry::{ Ray, RayDifferential };
+use crate::film::Film;
+use crate::geometry::{ Float, Ray, RayDifferential, lerp };
+use crate::sampler::CameraSampleBase;
+use crate::transform::AnimatedTransform;
 
 pub struct CameraBase {
-  shutter_open:  f32,
-  shutter_close: f32,
-  film:      Box<Film>
+  pub camera_to_world: AnimatedTransform,
+  pub shutter_open:  Float,
+  pub shutter_close: Float,
+  pub film:      Box<dyn Film>
 }
 
-pub trait Camera<'a> {
-  fn get_base(&'a self) -> &'a CameraBase;
-  fn get_base_mut(&'a mut self) -> &'a mut CameraBase;
-  fn generate_ray(&'a self, ray: &Ray);
-  fn generate_ray_differential(&'a self, rd: &RayDifferential);
+pub trait Camera {
+  fn get_base(&self) -> &CameraBase;
+  fn get_base_mut(&mut self) -> &mut CameraBase;
+
+  /// Camera space ray for `sample`, with the weight its radiance is
+  /// given on the film. Its time is set by `generate_ray`.
+  fn generate_camera_ray(&self, sample: &CameraSampleBase) -> (Float, Ray);
+
+  /// World space ray for `sample`, leaving the camera where it is at the
+  /// sample's time within the shutter interval
+  fn generate_ray(&self, sample: &CameraSampleBase) -> (Float, Ray) {
+    let base = self.get_base();
+    let (weight, mut ray) = self.generate_camera_ray(sample);
+    ray.time = lerp(sample.time, base.shutter_open, base.shutter_close);
+
+    (weight, base.camera_to_world.apply_ray(&ray))
+  }
+
+  /// `generate_ray` with differentials towards the rays one pixel over in
+  /// x and in y
+  fn generate_ray_differential(&self, sample: &CameraSampleBase) -> (Float, RayDifferential) {
+    let (weight, ray) = self.generate_ray(sample);
+    let mut rd = RayDifferential::new(&ray);
+
+    let (_, rx) = self.generate_ray(&CameraSampleBase { image_x: sample.image_x + 1.0, ..*sample });
+    let (_, ry) = self.generate_ray(&CameraSampleBase { image_y: sample.image_y + 1.0, ..*sample });
+    rd.rx_origin = rx.o;
+    rd.rx_direction = rx.d;
+    rd.ry_origin = ry.o;
+    rd.ry_direction = ry.d;
+    rd.has_differentials = true;
+
+    (weight, rd)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::RenderError;
+  use crate::geometry::{ Point, Vector };
+  use crate::spectrum::Spectrum;
+  use crate::transform::Transform;
+
+  struct NoFilm;
+
+  impl Film for NoFilm {
+    fn add_sample(&mut self, _sample: &CameraSampleBase, _l: &Spectrum) {}
+    fn splat(&mut self, _sample: &CameraSampleBase, _l: &Spectrum) {}
+    fn get_sample_extent(&self) -> (usize, usize, usize, usize) { (0, 0, 0, 0) }
+    fn get_pixel_extent(&self) -> (usize, usize, usize, usize) { (0, 0, 0, 0) }
+    fn update_display(&mut self, _x0: usize, _y0: usize, _x1: usize, _y1: usize, _splat_scale: Option<Float>) {}
+    fn write_image(&mut self, _splat_scale: Option<Float>) -> Result<(), RenderError> { Ok(()) }
+  }
+
+  /// Parallel rays along z from the image plane
+  struct Ortho(CameraBase);
+
+  impl Camera for Ortho {
+    fn get_base(&self) -> &CameraBase { &self.0 }
+    fn get_base_mut(&mut self) -> &mut CameraBase { &mut self.0 }
+
+    fn generate_camera_ray(&self, sample: &CameraSampleBase) -> (Float, Ray) {
+      let o = Point::new(sample.image_x, sample.image_y, 0.0);
+      (1.0, Ray::new(&o, &Vector::new(0.0, 0.0, 1.0), 0.0, Float::INFINITY, 0.0))
+    }
+  }
+
+  #[test]
+  fn rays_follow_the_moving_camera() {
+    let end = Transform::translate(&Vector::new(10.0, 0.0, 0.0));
+    let camera = Ortho(CameraBase {
+      camera_to_world: AnimatedTransform::new(&Transform::translate(&Vector::zero()), 1.0, &end, 3.0).unwrap(),
+      shutter_open: 1.0,
+      shutter_close: 3.0,
+      film: Box::new(NoFilm)
+    });
+
+    let sample = CameraSampleBase { image_x: 2.0, image_y: 1.0, time: 0.25, ..Default::default() };
+    let (weight, ray) = camera.generate_ray(&sample);
+    assert_eq!(weight, 1.0);
+    assert_eq!(ray.time, 1.5);
+    assert!((ray.o.x - 4.5).abs() < 1e-4 && (ray.o.y - 1.0).abs() < 1e-4);
+
+    let (_, rd) = camera.generate_ray_differential(&sample);
+    assert!(rd.has_differentials);
+    assert!((rd.rx_origin.x - 5.5).abs() < 1e-4 && (rd.ry_origin.y - 2.0).abs() < 1e-4);
+  }
 }
@@ -0,0 +1,241 @@
+use std::rc::Rc;
+
+use crate::diffgeom::DifferentialGeometry;
+use crate::geometry::{ Float, BBox, Point, Ray, Union, dot, offset_ray_origin };
+use crate::intersection::Intersection;
+use crate::light::AreaLight;
+use crate::primitive::Primitive;
+use crate::reflection::{ Bsdf, Bssrdf };
+use crate::transform::Transform;
+
+/// Crossings collected per child along a ray, so that children which are
+/// not closed cannot keep a ray going forever
+const MAX_CROSSINGS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsgOperation {
+  Union,
+  Intersection,
+  /// Everything in `a` that is not in `b`
+  Difference
+}
+
+impl CsgOperation {
+  fn inside(self, in_a: bool, in_b: bool) -> bool {
+    match self {
+      CsgOperation::Union => in_a || in_b,
+      CsgOperation::Intersection => in_a && in_b,
+      CsgOperation::Difference => in_a && !in_b
+    }
+  }
+}
+
+/// A ray entering or leaving one of the children
+struct Crossing {
+  t: Float,
+  entering: bool,
+  isect: Intersection
+}
+
+/// All crossings of `child` from `ray.mint` on, up to and including the
+/// first one past `ray.maxt`
+fn crossings(child: &Rc<dyn Primitive>, ray: &Ray) -> Vec<Crossing> {
+  let mut crossings = Vec::new();
+  let mut r = Ray { maxt: Float::INFINITY, ..*ray };
+  // Parameter along `ray` of the origin of `r`
+  let mut t_origin = 0.0;
+
+  while crossings.len() < MAX_CROSSINGS {
+    let mut isect = match child.intersect(&mut r) {
+      Some(isect) => isect,
+      None => break
+    };
+
+    if isect.primitive.is_none() {
+      isect.primitive = Some(child.clone());
+    }
+
+    let t = t_origin + r.maxt;
+    let entering = dot(isect.dg.nn, ray.d) < 0.0;
+
+    // Go on past the crossing like a spawned ray, from an origin offset
+    // beyond the error bounds of the hit
+    let o = offset_ray_origin(&isect.dg.p, &isect.p_error, &isect.dg.nn, &ray.d);
+    crossings.push(Crossing { t, entering, isect });
+
+    // Beyond the ray only the first crossing is needed, to tell whether
+    // the ray runs inside the child
+    if t > ray.maxt {
+      break;
+    }
+
+    t_origin = dot(o - ray.o, ray.d) / dot(ray.d, ray.d);
+    r = Ray { o, mint: 0.0, maxt: Float::INFINITY, ..*ray };
+  }
+
+  crossings
+}
+
+/// Boolean combination of two closed children whose normals point
+/// outwards. Hits are reported on whichever child bounds the result,
+/// with that child as their primitive so that it supplies the material.
+pub struct CsgPrimitive {
+  pub a: Rc<dyn Primitive>,
+  pub b: Rc<dyn Primitive>,
+  pub operation: CsgOperation
+}
+
+impl CsgPrimitive {
+  pub fn new(a: Rc<dyn Primitive>, b: Rc<dyn Primitive>, operation: CsgOperation) -> CsgPrimitive {
+    CsgPrimitive { a, b, operation }
+  }
+}
+
+impl Primitive for CsgPrimitive {
+  fn intersect(&self, ray: &mut Ray) -> Option<Intersection> {
+    let mut a = crossings(&self.a, ray).into_iter().peekable();
+    let mut b = crossings(&self.b, ray).into_iter().peekable();
+
+    // A child the ray first leaves contains its origin
+    let mut in_a = a.peek().is_some_and(|c| !c.entering);
+    let mut in_b = b.peek().is_some_and(|c| !c.entering);
+    let inside = self.operation.inside(in_a, in_b);
+
+    loop {
+      let from_a = match (a.peek(), b.peek()) {
+        (Some(ca), Some(cb)) => ca.t <= cb.t,
+        (Some(_), None) => true,
+        (None, Some(_)) => false,
+        (None, None) => return None
+      };
+
+      let c = if from_a { a.next() } else { b.next() }?;
+      if c.t > ray.maxt {
+        return None;
+      }
+
+      if from_a {
+        in_a = c.entering;
+      } else {
+        in_b = c.entering;
+      }
+
+      if self.operation.inside(in_a, in_b) != inside {
+        let mut isect = c.isect;
+
+        // The result lies on the other side of `b` in a difference
+        if !from_a && self.operation == CsgOperation::Difference {
+          isect.dg.flip();
+          isect.flip_normals = !isect.flip_normals;
+        }
+
+        ray.maxt = c.t;
+        return Some(isect);
+      }
+    }
+  }
+
+  fn intersect_p(&self, ray: &Ray) -> bool {
+    self.intersect(&mut ray.clone()).is_some()
+  }
+
+  fn world_bound(&self) -> BBox {
+    let (a, b) = (self.a.world_bound(), self.b.world_bound());
+
+    match self.operation {
+      CsgOperation::Union => a.union(&b),
+      CsgOperation::Intersection => BBox::new(
+        &Point::new(a.p_min.x.max(b.p_min.x), a.p_min.y.max(b.p_min.y), a.p_min.z.max(b.p_min.z)),
+        &Point::new(a.p_max.x.min(b.p_max.x), a.p_max.y.min(b.p_max.y), a.p_max.z.min(b.p_max.z))),
+      CsgOperation::Difference => a
+    }
+  }
+
+  fn get_bsdf(&self, _dg: &DifferentialGeometry, _object_to_world: &Transform) -> Option<Bsdf> {
+    None
+  }
+
+  fn get_bssrdf(&self, _dg: &DifferentialGeometry, _object_to_world: &Transform) -> Option<Bssrdf> {
+    None
+  }
+
+  fn get_area_light(&self) -> Option<Rc<dyn AreaLight>> {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::{ RayDifferential, Vector };
+  use crate::testing;
+
+  fn ball(x: Float, radius: Float) -> Rc<dyn Primitive> {
+    testing::ball(Point::new(x, 0.0, 0.0), radius, 0)
+  }
+
+  fn along_x(x: Float) -> Ray {
+    Ray::new(&Point::new(x, 0.0, 0.0), &Vector::new(1.0, 0.0, 0.0), 0.0, Float::INFINITY, 0.0)
+  }
+
+  #[test]
+  fn union_skips_inner_surfaces() {
+    let (left, right) = (ball(-0.5, 1.0), ball(0.5, 1.0));
+    let union = CsgPrimitive::new(left.clone(), right.clone(), CsgOperation::Union);
+
+    let mut ray = along_x(-5.0);
+    let isect = union.intersect(&mut ray).unwrap();
+    assert!((ray.maxt - 3.5).abs() < 1e-4);
+    assert!(isect.dg.nn.x < -0.99);
+    assert!(Rc::ptr_eq(isect.primitive.as_ref().unwrap(), &left));
+
+    // From inside the overlap the ray leaves through the far side of `right`
+    let mut ray = along_x(0.0);
+    let isect = union.intersect(&mut ray).unwrap();
+    assert!((ray.maxt - 1.5).abs() < 1e-4);
+    assert!(Rc::ptr_eq(isect.primitive.as_ref().unwrap(), &right));
+  }
+
+  #[test]
+  fn difference_flips_the_cutting_surface() {
+    let (body, drill) = (ball(0.0, 1.0), ball(-1.0, 0.5));
+    let bitten = CsgPrimitive::new(body, drill.clone(), CsgOperation::Difference);
+
+    // The body is entered inside the drill, the hit is where the drill ends
+    let mut ray = along_x(-5.0);
+    let isect = bitten.intersect(&mut ray).unwrap();
+    assert!((ray.maxt - 4.5).abs() < 1e-4);
+    assert!(isect.dg.nn.x < -0.99);
+    assert!(Rc::ptr_eq(isect.primitive.as_ref().unwrap(), &drill));
+
+    // The drill shades its inside, facing back along the ray
+    let mut isect = isect;
+    assert!(isect.flip_normals);
+    let bsdf = isect.get_bsdf(&RayDifferential::new(&ray)).unwrap();
+    assert!(bsdf.nn.x < -0.99 && bsdf.ng.x < -0.99);
+    assert!(bsdf.dg_shading.nn.x < -0.99);
+
+    let hollow = CsgPrimitive::new(ball(0.0, 1.0), ball(0.0, 2.0), CsgOperation::Difference);
+    assert!(!hollow.intersect_p(&along_x(-5.0)));
+  }
+
+  #[test]
+  fn intersection_keeps_the_lens_and_respects_maxt() {
+    let lens = CsgPrimitive::new(ball(-0.5, 1.0), ball(0.5, 1.0), CsgOperation::Intersection);
+
+    let mut ray = along_x(-5.0);
+    let isect = lens.intersect(&mut ray).unwrap();
+    assert!((ray.maxt - 4.5).abs() < 1e-4);
+    assert!(isect.dg.nn.x < -0.99);
+
+    let mut short = Ray { maxt: 4.0, ..along_x(-5.0) };
+    assert!(lens.intersect(&mut short).is_none());
+    assert_eq!(short.maxt, 4.0);
+
+    let bound = lens.world_bound();
+    assert!((bound.p_min.x + 0.5).abs() < 1e-6 && (bound.p_max.x - 0.5).abs() < 1e-6);
+
+    let apart = CsgPrimitive::new(ball(-2.0, 1.0), ball(2.0, 1.0), CsgOperation::Intersection);
+    assert!(!apart.intersect_p(&along_x(-5.0)));
+  }
+}
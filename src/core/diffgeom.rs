@@ -1,46 +1,55 @@
 use std::rc::Rc;
-use std::cell::RefCell;
 
-use geometry::{ Normal, Point, RayDifferential, Vector, normalize, cross, dot, solve_linear_system };
-use shape::Shape;
+use crate::geometry::{ Float, Normal, Point, RayDifferential, Vector, normalize, cross, dot, solve_linear_system };
+use crate::shape::Shape;
 
-#[deriving(Clone)]
+#[derive(Clone)]
 pub struct DifferentialGeometry {
   pub p:     Point,
   pub nn:    Normal,
-  pub u:     f32,
-  pub v:     f32,
-  pub shape: Option<Rc<RefCell<Box<Shape>>>>,
+  pub u:     Float,
+  pub v:     Float,
+  pub shape: Option<Rc<dyn Shape>>,
   pub dpdu:  Vector,
   pub dpdv:  Vector,
   pub dndu:  Normal,
   pub dndv:  Normal,
   pub dpdx:  Vector,
   pub dpdy:  Vector,
-  pub dudx:  f32,
-  pub dvdx:  f32,
-  pub dudy:  f32,
-  pub dvdy:  f32
+  pub dudx:  Float,
+  pub dvdx:  Float,
+  pub dudy:  Float,
+  pub dvdy:  Float
 }
 
 impl DifferentialGeometry {
+  #[allow(clippy::too_many_arguments)]
   pub fn new(p: Point, dpdu: Vector, dpdv: Vector,
-      dndu: Normal, dndv: Normal, u: f32, v: f32,
-      sh: Option<Rc<RefCell<Box<Shape>>>>) -> DifferentialGeometry {
+      dndu: Normal, dndv: Normal, u: Float, v: Float,
+      sh: Option<Rc<dyn Shape>>) -> DifferentialGeometry {
+    let mut dg = DifferentialGeometry::from_shape(p, dpdu, dpdv, dndu, dndv, u, v,
+      sh.as_deref());
+    dg.shape = sh;
+    dg
+  }
+
+  /// Geometry for a hit on `sh`, oriented by its base but without a
+  /// reference to it, for shapes that do not own an `Rc` to themselves
+  #[allow(clippy::too_many_arguments)]
+  pub fn from_shape(p: Point, dpdu: Vector, dpdv: Vector,
+      dndu: Normal, dndv: Normal, u: Float, v: Float,
+      sh: Option<&dyn Shape>) -> DifferentialGeometry {
     let mut n = Normal::from_vector(&normalize(cross(dpdu, dpdv)));
 
-    match sh {
-      Some(ref x) => {
-        if x.borrow().get_base().reverse_orientation ^ x.borrow().get_base().transform_swaps_handedness {
-          n = -n;
-        }
-      },
-      None => ()
+    if let Some(x) = sh {
+      if x.get_base().reverse_orientation ^ x.get_base().transform_swaps_handedness {
+        n = -n;
+      }
     }
 
     DifferentialGeometry {
-      p: p, nn: n, u: u, v: v, shape: sh,
-      dpdu: dpdu, dpdv: dpdv, dndu: dndu, dndv: dndv,
+      p, nn: n, u, v, shape: None,
+      dpdu, dpdv, dndu, dndv,
       dpdx: Vector::zero(),
       dpdy: Vector::zero(),
       dudx: 0.0,
@@ -78,31 +87,19 @@ impl DifferentialGeometry {
     self.dpdx = px - self.p;
     self.dpdy = py - self.p;
 
-    let mut a = [[0.0, ..2], ..2];
-    let mut bx = [0.0, ..2];
-    let mut by = [0.0, ..2];
-    let mut axes = [0, ..2];
-
-    if self.nn.x.abs() > self.nn.y.abs() && self.nn.x.abs() > self.nn.z.abs() {
-      axes[0] = 1;
-      axes[1] = 2;
+    let axes = if self.nn.x.abs() > self.nn.y.abs() && self.nn.x.abs() > self.nn.z.abs() {
+      [1, 2]
     } else if self.nn.y.abs() > self.nn.z.abs() {
-      axes[0] = 0;
-      axes[1] = 2;
+      [0, 2]
     } else {
-      axes[0] = 0;
-      axes[1] = 1;
-    }
+      [0, 1]
+    };
 
-    a[0][0] = self.dpdu[axes[0]];
-    a[0][1] = self.dpdu[axes[0]];
-    a[1][0] = self.dpdu[axes[1]];
-    a[1][1] = self.dpdu[axes[1]];
+    let a = [[self.dpdu[axes[0]], self.dpdv[axes[0]]],
+             [self.dpdu[axes[1]], self.dpdv[axes[1]]]];
 
-    bx[0] = px[axes[0]] - self.p[axes[0]];
-    bx[1] = px[axes[1]] - self.p[axes[1]];
-    by[0] = py[axes[0]] - self.p[axes[0]];
-    by[1] = py[axes[1]] - self.p[axes[1]];
+    let bx = [px[axes[0]] - self.p[axes[0]], px[axes[1]] - self.p[axes[1]]];
+    let by = [py[axes[0]] - self.p[axes[0]], py[axes[1]] - self.p[axes[1]]];
 
     if !solve_linear_system(a, bx, &mut self.dudx, &mut self.dvdx) {
       self.dudx = 0.0;
@@ -123,17 +120,51 @@ impl DifferentialGeometry {
     self.dpdy = Vector::zero();
   }
 
+  /// Turn the surface over, negating its normal and how it varies
+  pub fn flip(&mut self) {
+    self.nn = -self.nn;
+    self.dndu = -self.dndu;
+    self.dndv = -self.dndv;
+  }
+
   pub fn reverse_orientation(&self) -> bool {
     match self.shape {
       None => false,
-      Some(ref x) => x.borrow().get_base().reverse_orientation
+      Some(ref x) => x.get_base().reverse_orientation
     }
   }
 
   pub fn transform_swaps_handedness(&self) -> bool {
     match self.shape {
       None => false,
-      Some(ref x) => x.borrow().get_base().transform_swaps_handedness
+      Some(ref x) => x.get_base().transform_swaps_handedness
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::Ray;
+
+  #[test]
+  fn compute_differentials_on_a_plane() {
+    let mut dg = DifferentialGeometry::new(Point::new(0.0, 0.0, 0.0),
+      Vector::new(2.0, 0.0, 0.0), Vector::new(0.0, 4.0, 0.0),
+      Normal::new(0.0, 0.0, 0.0), Normal::new(0.0, 0.0, 0.0), 0.5, 0.5, None);
+
+    let d = Vector::new(0.0, 0.0, -1.0);
+    let mut ray = RayDifferential::new(&Ray::new(&Point::new(0.0, 0.0, 1.0), &d,
+      0.0, Float::INFINITY, 0.0));
+    ray.has_differentials = true;
+    ray.rx_origin = Point::new(0.2, 0.0, 1.0);
+    ray.ry_origin = Point::new(0.0, 0.4, 1.0);
+    ray.rx_direction = d;
+    ray.ry_direction = d;
+
+    dg.compute_differentials(&ray);
+
+    assert!((dg.dudx - 0.1).abs() < 1e-6 && dg.dvdx.abs() < 1e-6);
+    assert!(dg.dudy.abs() < 1e-6 && (dg.dvdy - 0.1).abs() < 1e-6);
+  }
+}
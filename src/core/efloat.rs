@@ -0,0 +1,217 @@
+use std::ops::{ Add, Sub, Mul, Div, Neg };
+
+use crate::geometry::Float;
+
+/// Half the distance between 1.0 and the next representable float, the
+/// bound on the relative error of a single correctly rounded operation
+pub const MACHINE_EPSILON: Float = Float::EPSILON * 0.5;
+
+/// Conservative bound on the relative error accumulated by `n` operations
+pub fn gamma(n: i32) -> Float {
+  (n as Float * MACHINE_EPSILON) / (1.0 - n as Float * MACHINE_EPSILON)
+}
+
+pub fn next_float_up(v: Float) -> Float {
+  if v.is_infinite() && v > 0.0 {
+    return v;
+  }
+
+  let v = if v == -0.0 { 0.0 } else { v };
+  let bits = v.to_bits();
+
+  Float::from_bits(if v >= 0.0 { bits + 1 } else { bits - 1 })
+}
+
+pub fn next_float_down(v: Float) -> Float {
+  if v.is_infinite() && v < 0.0 {
+    return v;
+  }
+
+  let v = if v == 0.0 { -0.0 } else { v };
+  let bits = v.to_bits();
+
+  Float::from_bits(if v > 0.0 { bits - 1 } else { bits + 1 })
+}
+
+/// Float that carries an interval guaranteed to contain the exact result
+/// of the computation that produced it
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EFloat {
+  pub v: Float,
+  pub low: Float,
+  pub high: Float
+}
+
+impl EFloat {
+  pub fn new(v: Float, err: Float) -> EFloat {
+    if err == 0.0 {
+      EFloat { v, low: v, high: v }
+    } else {
+      EFloat { v, low: next_float_down(v - err), high: next_float_up(v + err) }
+    }
+  }
+
+  pub fn lower_bound(&self) -> Float {
+    self.low
+  }
+
+  pub fn upper_bound(&self) -> Float {
+    self.high
+  }
+
+  pub fn absolute_error(&self) -> Float {
+    next_float_up((self.high - self.v).abs().max((self.v - self.low).abs()))
+  }
+
+  pub fn sqrt(&self) -> EFloat {
+    EFloat {
+      v: self.v.sqrt(),
+      low: next_float_down(self.low.sqrt()),
+      high: next_float_up(self.high.sqrt())
+    }
+  }
+
+  pub fn abs(&self) -> EFloat {
+    if self.low >= 0.0 {
+      *self
+    } else if self.high <= 0.0 {
+      -*self
+    } else {
+      EFloat { v: self.v.abs(), low: 0.0, high: (-self.low).max(self.high) }
+    }
+  }
+}
+
+impl From<Float> for EFloat {
+  fn from(v: Float) -> EFloat {
+    EFloat::new(v, 0.0)
+  }
+}
+
+impl Add<EFloat> for EFloat {
+  type Output = EFloat;
+
+  fn add(self, e: EFloat) -> EFloat {
+    EFloat {
+      v: self.v + e.v,
+      low: next_float_down(self.low + e.low),
+      high: next_float_up(self.high + e.high)
+    }
+  }
+}
+
+impl Sub<EFloat> for EFloat {
+  type Output = EFloat;
+
+  fn sub(self, e: EFloat) -> EFloat {
+    EFloat {
+      v: self.v - e.v,
+      low: next_float_down(self.low - e.high),
+      high: next_float_up(self.high - e.low)
+    }
+  }
+}
+
+impl Mul<EFloat> for EFloat {
+  type Output = EFloat;
+
+  fn mul(self, e: EFloat) -> EFloat {
+    let prod = [self.low * e.low, self.high * e.low, self.low * e.high, self.high * e.high];
+
+    EFloat {
+      v: self.v * e.v,
+      low: next_float_down(prod.iter().cloned().fold(Float::INFINITY, Float::min)),
+      high: next_float_up(prod.iter().cloned().fold(Float::NEG_INFINITY, Float::max))
+    }
+  }
+}
+
+impl Div<EFloat> for EFloat {
+  type Output = EFloat;
+
+  fn div(self, e: EFloat) -> EFloat {
+    // Dividing by an interval that contains zero is unbounded
+    if e.low < 0.0 && e.high > 0.0 {
+      return EFloat { v: self.v / e.v, low: Float::NEG_INFINITY, high: Float::INFINITY };
+    }
+
+    let div = [self.low / e.low, self.high / e.low, self.low / e.high, self.high / e.high];
+
+    EFloat {
+      v: self.v / e.v,
+      low: next_float_down(div.iter().cloned().fold(Float::INFINITY, Float::min)),
+      high: next_float_up(div.iter().cloned().fold(Float::NEG_INFINITY, Float::max))
+    }
+  }
+}
+
+impl Neg for EFloat {
+  type Output = EFloat;
+
+  fn neg(self) -> EFloat {
+    EFloat { v: -self.v, low: -self.high, high: -self.low }
+  }
+}
+
+/// Solve `a t^2 + b t + c = 0`, keeping track of the error in both roots
+// The discriminant is always computed in f64, a no-op cast with `double`
+#[allow(clippy::unnecessary_cast)]
+pub fn quadratic(a: EFloat, b: EFloat, c: EFloat) -> Option<(EFloat, EFloat)> {
+  let discrim = b.v as f64 * b.v as f64 - 4.0 * a.v as f64 * c.v as f64;
+  if discrim < 0.0 {
+    return None;
+  }
+
+  let root_discrim = discrim.sqrt() as Float;
+  let float_root_discrim = EFloat::new(root_discrim, MACHINE_EPSILON * root_discrim);
+
+  let q = if b.v < 0.0 {
+    EFloat::from(-0.5) * (b - float_root_discrim)
+  } else {
+    EFloat::from(-0.5) * (b + float_root_discrim)
+  };
+
+  let t0 = q / a;
+  let t1 = c / q;
+
+  if t0.v > t1.v {
+    Some((t1, t0))
+  } else {
+    Some((t0, t1))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn next_float_steps_by_one_ulp() {
+    assert!(next_float_up(1.0) > 1.0);
+    assert!(next_float_down(1.0) < 1.0);
+    assert_eq!(next_float_down(next_float_up(1.0)), 1.0);
+    assert!(next_float_up(-0.0) > 0.0);
+  }
+
+  #[test]
+  fn intervals_contain_the_exact_result() {
+    let a = EFloat::new(0.1, 0.0);
+    let b = EFloat::new(0.2, 0.0);
+    let sum = a + b;
+
+    assert!(sum.lower_bound() <= 0.3 && sum.upper_bound() >= 0.3);
+    assert!(sum.absolute_error() > 0.0);
+
+    let p = (a - b) * b / a;
+    assert!(p.lower_bound() <= -0.2 && p.upper_bound() >= -0.2);
+  }
+
+  #[test]
+  fn quadratic_bounds_its_roots() {
+    let (t0, t1) = quadratic(EFloat::from(1.0), EFloat::from(-3.0), EFloat::from(2.0)).unwrap();
+
+    assert!(t0.lower_bound() <= 1.0 && t0.upper_bound() >= 1.0);
+    assert!(t1.lower_bound() <= 2.0 && t1.upper_bound() >= 2.0);
+    assert!(quadratic(EFloat::from(1.0), EFloat::from(0.0), EFloat::from(1.0)).is_none());
+  }
+}
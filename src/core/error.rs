@@ -0,0 +1,47 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Errors reported by the fallible entry points of rbrtcore, such as
+/// parameter lookup, texture construction and image output.
+#[derive(Debug)]
+pub enum RenderError {
+  /// A parameter was given in a form that cannot be used
+  InvalidParameter { name: String, message: String },
+  /// A matrix that had to be inverted is singular
+  SingularMatrix,
+  /// Texture data could not be turned into a texture
+  InvalidTexture(String),
+  /// The scene description is malformed
+  InvalidScene(String),
+  /// Reading or writing a file failed
+  Io(io::Error)
+}
+
+impl fmt::Display for RenderError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      RenderError::InvalidParameter { ref name, ref message } =>
+        write!(f, "invalid parameter \"{}\": {}", name, message),
+      RenderError::SingularMatrix => write!(f, "singular matrix"),
+      RenderError::InvalidTexture(ref message) => write!(f, "invalid texture: {}", message),
+      RenderError::InvalidScene(ref message) => write!(f, "invalid scene: {}", message),
+      RenderError::Io(ref err) => write!(f, "i/o error: {}", err)
+    }
+  }
+}
+
+impl Error for RenderError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match *self {
+      RenderError::Io(ref err) => Some(err),
+      _ => None
+    }
+  }
+}
+
+impl From<io::Error> for RenderError {
+  fn from(err: io::Error) -> RenderError {
+    RenderError::Io(err)
+  }
+}
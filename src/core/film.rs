@@ -1,16 +1,18 @@
-use sampler::CameraSampleBase;
-use spectrum::Spectrum;
+use crate::error::RenderError;
+use crate::geometry::Float;
+use crate::sampler::CameraSampleBase;
+use crate::spectrum::Spectrum;
 
 pub struct FilmBase {
-  x_resolution: uint,
-  y_resolution: uint
+  pub x_resolution: usize,
+  pub y_resolution: usize
 }
 
 pub trait Film {
-  fn add_sample(sample: &CameraSampleBase, L: &Spectrum);
-  fn splat(sample: &CameraSampleBase, L: &Spectrum);
-  fn get_sample_extent() -> (uint, uint, uint, uint);
-  fn get_pixel_extent() -> (uint, uint, uint, uint);
-  fn update_display(x0: uint, y0: uint, x1: uint, y1: uint, splat_scale: Option<f32>);
-  fn write_image(splat_scale: Option<f32>);
+  fn add_sample(&mut self, sample: &CameraSampleBase, l: &Spectrum);
+  fn splat(&mut self, sample: &CameraSampleBase, l: &Spectrum);
+  fn get_sample_extent(&self) -> (usize, usize, usize, usize);
+  fn get_pixel_extent(&self) -> (usize, usize, usize, usize);
+  fn update_display(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, splat_scale: Option<Float>);
+  fn write_image(&mut self, splat_scale: Option<Float>) -> Result<(), RenderError>;
 }
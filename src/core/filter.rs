@@ -1,12 +1,14 @@
+use crate::geometry::Float;
+
 pub struct FilterBase {
-  pub x_width: f32,
-  pub y_width: f32,
-  pub inv_x_width: f32,
-  pub inv_y_width: f32,
+  pub x_width: Float,
+  pub y_width: Float,
+  pub inv_x_width: Float,
+  pub inv_y_width: Float,
 }
 
 impl FilterBase {
-  pub fn new(x: f32, y: f32) -> FilterBase {
+  pub fn new(x: Float, y: Float) -> FilterBase {
     FilterBase {
       x_width: x,
       y_width: y,
@@ -17,5 +19,5 @@ impl FilterBase {
 }
 
 pub trait Filter {
-  fn evaluate(x: f32, y: f32) -> f32;
+  fn evaluate(&self, x: Float, y: Float) -> Float;
 }
@@ -1,18 +1,33 @@
-use std::f32;
+use std::ops::{ Add, Sub, Mul, Div, Neg, Index };
+
+use crate::efloat::{ next_float_up, next_float_down };
+use crate::transform::{ Matrix, Transform };
+
+/// Floating point type used for geometry and shading, `f64` when the
+/// `double` feature is enabled
+#[cfg(not(feature = "double"))]
+pub type Float = f32;
+#[cfg(feature = "double")]
+pub type Float = f64;
+
+pub const PI: Float = std::f64::consts::PI as Float;
+
+/// Fraction of a shadow ray's length left untested near its end point
+pub const SHADOW_EPSILON: Float = 0.0001;
 
 pub trait Length {
-  fn length_squared(&self) -> f32;
+  fn length_squared(&self) -> Float;
 
-  fn length(&self) -> f32 {
+  fn length(&self) -> Float {
     self.length_squared().sqrt()
   }
 }
 
-#[deriving(Show, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Vector {
-  pub x: f32,
-  pub y: f32,
-  pub z: f32
+  pub x: Float,
+  pub y: Float,
+  pub z: Float
 }
 
 impl Vector {
@@ -20,8 +35,8 @@ impl Vector {
     Vector { x: 0.0, y: 0.0, z: 0.0 }
   }
 
-  pub fn new(x: f32, y: f32, z: f32) -> Vector {
-    Vector { x: x, y: y, z: z }
+  pub fn new(x: Float, y: Float, z: Float) -> Vector {
+    Vector { x, y, z }
   }
 
   pub fn from_normal(n: &Normal) -> Vector {
@@ -30,57 +45,69 @@ impl Vector {
 }
 
 impl Length for Vector {
-  fn length_squared(&self) -> f32 {
+  fn length_squared(&self) -> Float {
     self.x * self.x + self.y * self.y + self.z * self.z
   }
 }
 
-impl Add<Vector, Vector> for Vector {
-  fn add(&self, rhs: &Vector) -> Vector {
+impl Add<Vector> for Vector {
+  type Output = Vector;
+
+  fn add(self, rhs: Vector) -> Vector {
     Vector::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
   }
 }
 
-impl Sub<Vector, Vector> for Vector {
-  fn sub(&self, rhs: &Vector) -> Vector {
+impl Sub<Vector> for Vector {
+  type Output = Vector;
+
+  fn sub(self, rhs: Vector) -> Vector {
     Vector::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
   }
 }
 
-impl Mul<f32, Vector> for Vector {
-  fn mul(&self, rhs: &f32) -> Vector {
-    Vector::new(self.x * *rhs, self.y * *rhs, self.z * *rhs)
+impl Mul<Float> for Vector {
+  type Output = Vector;
+
+  fn mul(self, rhs: Float) -> Vector {
+    Vector::new(self.x * rhs, self.y * rhs, self.z * rhs)
   }
 }
 
-impl Div<f32, Vector> for Vector {
-  fn div(&self, rhs: &f32) -> Vector {
-    Vector::new(self.x / *rhs, self.y / *rhs, self.z / *rhs)
+impl Div<Float> for Vector {
+  type Output = Vector;
+
+  fn div(self, rhs: Float) -> Vector {
+    Vector::new(self.x / rhs, self.y / rhs, self.z / rhs)
   }
 }
 
-impl Neg<Vector> for Vector {
-  fn neg(&self) -> Vector {
+impl Neg for Vector {
+  type Output = Vector;
+
+  fn neg(self) -> Vector {
     Vector::new(-self.x, -self.y, -self.z)
   }
 }
 
-impl Index<uint, f32> for Vector {
-  fn index(&self, index: &uint) -> f32 {
+impl Index<usize> for Vector {
+  type Output = Float;
+
+  fn index(&self, index: usize) -> &Float {
     match index {
-      &0 => self.x,
-      &1 => self.y,
-      &2 => self.z,
-      _  => fail!("Unknown vector index")
+      0 => &self.x,
+      1 => &self.y,
+      2 => &self.z,
+      _ => panic!("Unknown vector index")
     }
   }
 }
 
-#[deriving(Show, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Point {
-  pub x: f32,
-  pub y: f32,
-  pub z: f32
+  pub x: Float,
+  pub y: Float,
+  pub z: Float
 }
 
 impl Point {
@@ -88,80 +115,77 @@ impl Point {
     Point { x: 0.0, y: 0.0, z: 0.0 }
   }
 
-  pub fn new(x: f32, y: f32, z: f32) -> Point {
-    Point { x: x, y: y, z: z }
+  pub fn new(x: Float, y: Float, z: Float) -> Point {
+    Point { x, y, z }
   }
 }
 
-pub trait PointRhsAdd<S> {
-  fn add_to_point(&self, lhs: &Point) -> S;
-}
-pub trait PointRhsSub<S> {
-  fn sub_from_point(&self, lhs: &Point) -> S;
-}
+impl Add<Point> for Point {
+  type Output = Point;
 
-impl<S, R: PointRhsAdd<S>> Add<R, S> for Point {
-  fn add(&self, rhs: &R) -> S {
-    rhs.add_to_point(self)
+  fn add(self, rhs: Point) -> Point {
+    Point::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
   }
 }
 
-impl<S, R: PointRhsSub<S>> Sub<R, S> for Point {
-  fn sub(&self, rhs: &R) -> S {
-    rhs.sub_from_point(self)
-  }
-}
+impl Sub<Point> for Point {
+  type Output = Vector;
 
-impl PointRhsAdd<Point> for Point {
-  fn add_to_point(&self, lhs: &Point) -> Point {
-    Point::new(lhs.x + self.x, lhs.y + self.y, lhs.z + self.z)
-  }
-}
-impl PointRhsSub<Vector> for Point {
-  fn sub_from_point(&self, lhs: &Point) -> Vector {
-    Vector::new(lhs.x - self.x, lhs.y - self.y, lhs.z - self.z)
+  fn sub(self, rhs: Point) -> Vector {
+    Vector::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
   }
 }
 
-impl PointRhsAdd<Point> for Vector {
-  fn add_to_point(&self, lhs: &Point) -> Point {
-    Point::new(lhs.x + self.x, lhs.y + self.y, lhs.z + self.z)
+impl Add<Vector> for Point {
+  type Output = Point;
+
+  fn add(self, rhs: Vector) -> Point {
+    Point::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
   }
 }
-impl PointRhsSub<Point> for Vector {
-  fn sub_from_point(&self, lhs: &Point) -> Point {
-    Point::new(lhs.x - self.x, lhs.y - self.y, lhs.z - self.z)
+
+impl Sub<Vector> for Point {
+  type Output = Point;
+
+  fn sub(self, rhs: Vector) -> Point {
+    Point::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
   }
 }
 
-impl Mul<f32, Point> for Point {
-  fn mul(&self, rhs: &f32) -> Point {
-    Point::new(self.x * *rhs, self.y * *rhs, self.z * *rhs)
+impl Mul<Float> for Point {
+  type Output = Point;
+
+  fn mul(self, rhs: Float) -> Point {
+    Point::new(self.x * rhs, self.y * rhs, self.z * rhs)
   }
 }
 
-impl Div<f32, Point> for Point {
-  fn div(&self, rhs: &f32) -> Point {
-    Point::new(self.x / *rhs, self.y / *rhs, self.z / *rhs)
+impl Div<Float> for Point {
+  type Output = Point;
+
+  fn div(self, rhs: Float) -> Point {
+    Point::new(self.x / rhs, self.y / rhs, self.z / rhs)
   }
 }
 
-impl Index<uint, f32> for Point {
-  fn index(&self, index: &uint) -> f32 {
+impl Index<usize> for Point {
+  type Output = Float;
+
+  fn index(&self, index: usize) -> &Float {
     match index {
-      &0 => self.x,
-      &1 => self.y,
-      &2 => self.z,
-      _  => fail!("Unknown point index")
+      0 => &self.x,
+      1 => &self.y,
+      2 => &self.z,
+      _ => panic!("Unknown point index")
     }
   }
 }
 
-#[deriving(Show, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Normal {
-  pub x: f32,
-  pub y: f32,
-  pub z: f32
+  pub x: Float,
+  pub y: Float,
+  pub z: Float
 }
 
 impl Normal {
@@ -169,8 +193,8 @@ impl Normal {
     Normal { x: 0.0, y: 0.0, z: 0.0 }
   }
 
-  pub fn new(x: f32, y: f32, z: f32) -> Normal {
-    Normal { x: x, y: y, z: z }
+  pub fn new(x: Float, y: Float, z: Float) -> Normal {
+    Normal { x, y, z }
   }
 
   pub fn from_vector(v: &Vector) -> Normal {
@@ -179,60 +203,72 @@ impl Normal {
 }
 
 impl Length for Normal {
-  fn length_squared(&self) -> f32 {
+  fn length_squared(&self) -> Float {
     self.x * self.x + self.y * self.y + self.z * self.z
   }
 }
 
-impl Add<Normal, Normal> for Normal {
-  fn add(&self, rhs: &Normal) -> Normal {
+impl Add<Normal> for Normal {
+  type Output = Normal;
+
+  fn add(self, rhs: Normal) -> Normal {
     Normal::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
   }
 }
 
-impl Sub<Normal, Normal> for Normal {
-  fn sub(&self, rhs: &Normal) -> Normal {
+impl Sub<Normal> for Normal {
+  type Output = Normal;
+
+  fn sub(self, rhs: Normal) -> Normal {
     Normal::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
   }
 }
 
-impl Mul<f32, Normal> for Normal {
-  fn mul(&self, rhs: &f32) -> Normal {
-    Normal::new(self.x * *rhs, self.y * *rhs, self.z * *rhs)
+impl Mul<Float> for Normal {
+  type Output = Normal;
+
+  fn mul(self, rhs: Float) -> Normal {
+    Normal::new(self.x * rhs, self.y * rhs, self.z * rhs)
   }
 }
 
-impl Div<f32, Normal> for Normal {
-  fn div(&self, rhs: &f32) -> Normal {
-    Normal::new(self.x / *rhs, self.y / *rhs, self.z / *rhs)
+impl Div<Float> for Normal {
+  type Output = Normal;
+
+  fn div(self, rhs: Float) -> Normal {
+    Normal::new(self.x / rhs, self.y / rhs, self.z / rhs)
   }
 }
 
-impl Neg<Normal> for Normal {
-  fn neg(&self) -> Normal {
+impl Neg for Normal {
+  type Output = Normal;
+
+  fn neg(self) -> Normal {
     Normal::new(-self.x, -self.y, -self.z)
   }
 }
 
-impl Index<uint, f32> for Normal {
-  fn index(&self, index: &uint) -> f32 {
+impl Index<usize> for Normal {
+  type Output = Float;
+
+  fn index(&self, index: usize) -> &Float {
     match index {
-      &0 => self.x,
-      &1 => self.y,
-      &2 => self.z,
-      _  => fail!("Unknown normal index")
+      0 => &self.x,
+      1 => &self.y,
+      2 => &self.z,
+      _ => panic!("Unknown normal index")
     }
   }
 }
 
-#[deriving(Eq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Ray {
   pub o: Point,
   pub d: Vector,
-  pub mint: f32,
-  pub maxt: f32,
-  pub time: f32,
-  pub depth: uint
+  pub mint: Float,
+  pub maxt: Float,
+  pub time: Float,
+  pub depth: usize
 }
 
 impl Ray {
@@ -241,21 +277,22 @@ impl Ray {
       o:   Point::zero(),
       d:   Vector::zero(),
       mint:  0.0,
-      maxt:  f32::INFINITY,
+      maxt:  Float::INFINITY,
       time:  0.0,
       depth: 0
     }
   }
 
-  pub fn new(o: &Point, d: &Vector, mint: f32, maxt: f32, time: f32) -> Ray {
-    Ray { o: *o, d: *d, mint: mint, maxt: maxt, time: time, depth: 1 }
+  pub fn new(o: &Point, d: &Vector, mint: Float, maxt: Float, time: Float) -> Ray {
+    Ray { o: *o, d: *d, mint, maxt, time, depth: 1 }
   }
 
-  pub fn apply(&self, t: f32) -> Point {
+  pub fn apply(&self, t: Float) -> Point {
     self.o + self.d * t
   }
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct RayDifferential {
   pub ray: Ray,
   pub has_differentials: bool,
@@ -267,14 +304,30 @@ pub struct RayDifferential {
 
 impl RayDifferential {
   pub fn new(r: &Ray) -> RayDifferential {
-    fail!("not implemented");
+    RayDifferential {
+      ray: *r,
+      has_differentials: false,
+      rx_origin: Point::zero(),
+      ry_origin: Point::zero(),
+      rx_direction: Vector::zero(),
+      ry_direction: Vector::zero()
+    }
+  }
+
+  /// Spawn a ray without differentials from a point on a surface hit by
+  /// `parent`, one bounce deeper than the parent ray
+  pub fn from_parent(o: &Point, d: &Vector, parent: &Ray) -> RayDifferential {
+    let mut ray = Ray::new(o, d, 0.0, Float::INFINITY, parent.time);
+    ray.depth = parent.depth + 1;
+
+    RayDifferential::new(&ray)
   }
 
-  pub fn apply(&self, t: f32) -> Point {
+  pub fn apply(&self, t: Float) -> Point {
     self.ray.apply(t)
   }
 
-  pub fn scale_differentials(&mut self, s: f32) {
+  pub fn scale_differentials(&mut self, s: Float) {
     self.rx_origin  = self.ray.o + (self.rx_origin  - self.ray.o) * s;
     self.ry_origin  = self.ray.o + (self.ry_origin  - self.ray.o) * s;
     self.rx_direction = self.ray.d + (self.rx_direction - self.ray.d) * s;
@@ -282,7 +335,7 @@ impl RayDifferential {
   }
 }
 
-#[deriving(Show, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct BBox {
   pub p_min: Point,
   pub p_max: Point
@@ -304,7 +357,7 @@ impl<S, R: BBoxRhs<S>> Union<R, S> for BBox {
 
 impl BBoxRhs<BBox> for Point {
   fn box_union(&self, lhs: &BBox) -> BBox {
-    let mut b = lhs.clone();
+    let mut b = *lhs;
 
     b.p_min.x = b.p_min.x.min(self.x);
     b.p_min.y = b.p_min.y.min(self.y);
@@ -313,13 +366,13 @@ impl BBoxRhs<BBox> for Point {
     b.p_max.y = b.p_max.y.max(self.y);
     b.p_max.z = b.p_max.z.max(self.z);
 
-    return b;
+    b
   }
 }
 
 impl BBoxRhs<BBox> for BBox {
   fn box_union(&self, lhs: &BBox) -> BBox {
-    let mut b = lhs.clone();
+    let mut b = *lhs;
 
     b.p_min.x = b.p_min.x.min(self.p_min.x);
     b.p_min.y = b.p_min.y.min(self.p_min.y);
@@ -328,7 +381,7 @@ impl BBoxRhs<BBox> for BBox {
     b.p_max.y = b.p_max.y.max(self.p_max.y);
     b.p_max.z = b.p_max.z.max(self.p_max.z);
 
-    return b;
+    b
   }
 }
 
@@ -347,26 +400,26 @@ impl BBox {
     pt.z >= self.p_min.z && pt.z <= self.p_max.z
   }
 
-  pub fn expand(&mut self, delta: f32) {
+  pub fn expand(&mut self, delta: Float) {
     self.p_min = self.p_min - Vector::new(delta, delta, delta);
     self.p_max = self.p_max + Vector::new(delta, delta, delta);
   }
 
-  pub fn surface_area(&self) -> f32 {
+  pub fn surface_area(&self) -> Float {
     let d = self.p_max - self.p_min;
     2.0 * (d.x * d.y + d.x * d.z + d.y * d.z)
   }
 
-  pub fn volume(&self) -> f32 {
+  pub fn volume(&self) -> Float {
     let d = self.p_max - self.p_min;
     d.x * d.y * d.z
   }
 
-  pub fn intersect_p(&self, ray: &Ray) -> Option<(f32, f32)> {
+  pub fn intersect_p(&self, ray: &Ray) -> Option<(Float, Float)> {
     let mut t0 = ray.mint;
     let mut t1 = ray.maxt;
 
-    for i in range(0u, 3) {
+    for i in 0..3 {
       let inverted_ray_dir = 1.0 / ray.d[i];
       let tnear = (self.p_min[i] - ray.o[i]) * inverted_ray_dir;
       let tfar  = (self.p_max[i] - ray.o[i]) * inverted_ray_dir;
@@ -388,7 +441,7 @@ impl BBox {
     Some((t0, t1))
   }
 
-  pub fn maximum_extent(&self) -> uint {
+  pub fn maximum_extent(&self) -> usize {
     let diag = self.p_max - self.p_min;
 
     match (diag.x > diag.y, diag.x > diag.z, diag.y > diag.z) {
@@ -399,9 +452,174 @@ impl BBox {
   }
 }
 
-/// Utility methods
+/// Quaternion, used to represent and interpolate rotations
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Quaternion {
+  pub v: Vector,
+  pub w: Float
+}
+
+impl Quaternion {
+  pub fn new(v: Vector, w: Float) -> Quaternion {
+    Quaternion { v, w }
+  }
+
+  pub fn identity() -> Quaternion {
+    Quaternion::new(Vector::zero(), 1.0)
+  }
+
+  /// Extract the rotation from the upper 3x3 part of `t`
+  pub fn from_transform(t: &Transform) -> Quaternion {
+    let m = t.get_matrix();
+    let trace = m[0][0] + m[1][1] + m[2][2];
+
+    if trace > 0.0 {
+      let s = (trace + 1.0).sqrt();
+      let w = s / 2.0;
+      let s = 0.5 / s;
+
+      Quaternion::new(Vector::new(
+        (m[2][1] - m[1][2]) * s,
+        (m[0][2] - m[2][0]) * s,
+        (m[1][0] - m[0][1]) * s), w)
+    } else {
+      let next = [1, 2, 0];
+      let mut q = [0.0; 3];
+      let mut i = 0;
+
+      if m[1][1] > m[0][0] {
+        i = 1;
+      }
+      if m[2][2] > m[i][i] {
+        i = 2;
+      }
+
+      let j = next[i];
+      let k = next[j];
+      let mut s = ((m[i][i] - (m[j][j] + m[k][k])) + 1.0).sqrt();
+      q[i] = s * 0.5;
+      if s != 0.0 {
+        s = 0.5 / s;
+      }
+
+      let w = (m[k][j] - m[j][k]) * s;
+      q[j] = (m[j][i] + m[i][j]) * s;
+      q[k] = (m[k][i] + m[i][k]) * s;
+
+      Quaternion::new(Vector::new(q[0], q[1], q[2]), w)
+    }
+  }
+
+  pub fn to_transform(&self) -> Transform {
+    let (x, y, z, w) = (self.v.x, self.v.y, self.v.z, self.w);
+    let (xx, yy, zz) = (x * x, y * y, z * z);
+    let (xy, xz, yz) = (x * y, x * z, y * z);
+    let (wx, wy, wz) = (x * w, y * w, z * w);
+
+    let m = Matrix::new(
+      1.0 - 2.0 * (yy + zz),       2.0 * (xy + wz),       2.0 * (xz - wy), 0.0,
+            2.0 * (xy - wz), 1.0 - 2.0 * (xx + zz),       2.0 * (yz + wx), 0.0,
+            2.0 * (xz + wy),       2.0 * (yz - wx), 1.0 - 2.0 * (xx + yy), 0.0,
+                        0.0,                   0.0,                   0.0, 1.0);
+
+    // m is the inverse rotation, so the forward matrix is its transpose
+    Transform::new(Matrix::transpose(&m), m)
+  }
+
+  pub fn dot(&self, q: &Quaternion) -> Float {
+    dot(self.v, q.v) + self.w * q.w
+  }
+
+  pub fn conjugate(&self) -> Quaternion {
+    Quaternion::new(-self.v, self.w)
+  }
+
+  /// Rotation by `angle` degrees about `axis`
+  pub fn from_axis_angle(angle: Float, axis: &Vector) -> Quaternion {
+    let half = radians(angle) / 2.0;
+    Quaternion::new(normalize(*axis) * half.sin(), half.cos())
+  }
+
+  /// Rotate `v` by this (unit) quaternion
+  pub fn rotate(&self, v: &Vector) -> Vector {
+    (*self * Quaternion::new(*v, 0.0) * self.conjugate()).v
+  }
+
+  /// Spherical linear interpolation between `q1` and `q2`
+  pub fn slerp(t: Float, q1: &Quaternion, q2: &Quaternion) -> Quaternion {
+    let cos_theta = q1.dot(q2);
+
+    if cos_theta > 0.9995 {
+      return normalize(*q1 * (1.0 - t) + *q2 * t);
+    }
+
+    let theta = cos_theta.clamp(-1.0, 1.0).acos();
+    let thetap = theta * t;
+    let qperp = normalize(*q2 - *q1 * cos_theta);
+
+    *q1 * thetap.cos() + qperp * thetap.sin()
+  }
+}
 
-pub fn mod_t(a: uint, b: uint) -> uint {
+impl Length for Quaternion {
+  fn length_squared(&self) -> Float {
+    self.dot(self)
+  }
+}
+
+impl Add<Quaternion> for Quaternion {
+  type Output = Quaternion;
+
+  fn add(self, q: Quaternion) -> Quaternion {
+    Quaternion::new(self.v + q.v, self.w + q.w)
+  }
+}
+
+impl Sub<Quaternion> for Quaternion {
+  type Output = Quaternion;
+
+  fn sub(self, q: Quaternion) -> Quaternion {
+    Quaternion::new(self.v - q.v, self.w - q.w)
+  }
+}
+
+impl Mul<Float> for Quaternion {
+  type Output = Quaternion;
+
+  fn mul(self, f: Float) -> Quaternion {
+    Quaternion::new(self.v * f, self.w * f)
+  }
+}
+
+/// Hamilton product, composing the rotation `q` followed by `self`
+impl Mul<Quaternion> for Quaternion {
+  type Output = Quaternion;
+
+  fn mul(self, q: Quaternion) -> Quaternion {
+    Quaternion::new(cross(self.v, q.v) + q.v * self.w + self.v * q.w,
+      self.w * q.w - dot(self.v, q.v))
+  }
+}
+
+impl Div<Float> for Quaternion {
+  type Output = Quaternion;
+
+  fn div(self, f: Float) -> Quaternion {
+    Quaternion::new(self.v / f, self.w / f)
+  }
+}
+
+impl Neg for Quaternion {
+  type Output = Quaternion;
+
+  fn neg(self) -> Quaternion {
+    Quaternion::new(-self.v, -self.w)
+  }
+}
+
+// Utility methods
+
+pub fn mod_t(a: i32, b: i32) -> i32 {
   let n = a / b;
   let x = a - n * b;
 
@@ -412,7 +630,7 @@ pub fn mod_t(a: uint, b: uint) -> uint {
   }
 }
 
-pub fn clamp<T: Ord>(val: T, low: T, high: T) -> T {
+pub fn clamp<T: PartialOrd>(val: T, low: T, high: T) -> T {
   if val < low {
     low
   } else if val > high {
@@ -422,23 +640,23 @@ pub fn clamp<T: Ord>(val: T, low: T, high: T) -> T {
   }
 }
 
-pub fn lerp(t: f32, v1: f32, v2: f32) -> f32 {
+pub fn lerp(t: Float, v1: Float, v2: Float) -> Float {
   (1.0 - t) * v1 + t * v2
 }
 
-pub fn radians(deg: f32) -> f32 {
-  (f32::consts::PI / 180.0) * deg
+pub fn radians(deg: Float) -> Float {
+  (PI / 180.0) * deg
 }
 
-pub fn degrees(rad: f32) -> f32 {
-  (180.0 / f32::consts::PI) * rad
+pub fn degrees(rad: Float) -> Float {
+  (180.0 / PI) * rad
 }
 
-pub fn is_power_of_2(v: uint) -> bool {
+pub fn is_power_of_2(v: usize) -> bool {
   v != 0 && (v & (v - 1)) == 0
 }
 
-pub fn round_up_pow_2(v: uint) -> uint {
+pub fn round_up_pow_2(v: usize) -> usize {
   let mut x = v - 1;
   x |= x >>  1; x |= x >> 2;
   x |= x >>  4; x |= x >> 8;
@@ -446,7 +664,7 @@ pub fn round_up_pow_2(v: uint) -> uint {
   x + 1
 }
 
-pub fn quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+pub fn quadratic(a: Float, b: Float, c: Float) -> Option<(Float, Float)> {
   let discrim = b * b - 4.0 * a * c;
   if discrim < 0.0 {
     return None;
@@ -471,33 +689,34 @@ pub fn quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
 }
 
 /// Normalize the object (vector, normal, ...) to unit length 1.0
-pub fn normalize<T: Length + Div<f32, T>>(x: T) -> T {
-  x / x.length()
+pub fn normalize<T: Length + Div<Float, Output = T>>(x: T) -> T {
+  let len = x.length();
+  x / len
 }
 
 /// Compute the distance between two points
-pub fn distance(a: &Point, b: &Point) -> f32 {
-  (a - *b).length()
+pub fn distance(a: &Point, b: &Point) -> Float {
+  (*a - *b).length()
 }
 
 /// Compute the squared distance between two points
-pub fn distance_squared(a: &Point, b: &Point) -> f32 {
-  (a - *b).length_squared()
+pub fn distance_squared(a: &Point, b: &Point) -> Float {
+  (*a - *b).length_squared()
 }
 
 /// Compute the dot product between two vectors or normals
-pub fn dot<T: Index<uint, f32> + Length, S: Index<uint, f32> + Length>(a: T, b: S) -> f32 {
+pub fn dot<T: Index<usize, Output = Float> + Length, S: Index<usize, Output = Float> + Length>(a: T, b: S) -> Float {
   a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
 }
 
 /// Compute the absolute dot product between
 /// two vectors or normals
-pub fn abs_dot<T: Index<uint, f32> + Length, S: Index<uint, f32> + Length>(a: T, b: S) -> f32 {
+pub fn abs_dot<T: Index<usize, Output = Float> + Length, S: Index<usize, Output = Float> + Length>(a: T, b: S) -> Float {
   (a[0] * b[0] + a[1] * b[1] + a[2] * b[2]).abs()
 }
 
 /// Compute the cross product between two vector or normals
-pub fn cross<T: Index<uint, f32> + Length, S: Index<uint, f32> + Length>(a: T, b: S) -> Vector {
+pub fn cross<T: Index<usize, Output = Float> + Length, S: Index<usize, Output = Float> + Length>(a: T, b: S) -> Vector {
   let (v1x, v1y, v1z) = (a[0], a[1], a[2]);
   let (v2x, v2y, v2z) = (b[0], b[1], b[2]);
   Vector::new((v1y * v2z) - (v1z * v2y),
@@ -506,7 +725,7 @@ pub fn cross<T: Index<uint, f32> + Length, S: Index<uint, f32> + Length>(a: T, b
 }
 
 /// Compute the cross product between two vector or normals
-pub fn cross_n<T: Index<uint, f32> + Length, S: Index<uint, f32> + Length>(a: T, b: S) -> Normal {
+pub fn cross_n<T: Index<usize, Output = Float> + Length, S: Index<usize, Output = Float> + Length>(a: T, b: S) -> Normal {
   let (v1x, v1y, v1z) = (a[0], a[1], a[2]);
   let (v2x, v2y, v2z) = (b[0], b[1], b[2]);
   Normal::new((v1y * v2z) - (v1z * v2y),
@@ -514,26 +733,37 @@ pub fn cross_n<T: Index<uint, f32> + Length, S: Index<uint, f32> + Length>(a: T,
     (v1x * v2y) - (v1y * v2x))
 }
 
-pub fn spherical_direction(sin_theta: f32, cos_theta: f32, phi: f32) -> Vector {
+/// Two unit vectors that form an orthonormal basis together with `v1`
+pub fn coordinate_system(v1: &Vector) -> (Vector, Vector) {
+  let v2 = if v1.x.abs() > v1.y.abs() {
+    Vector::new(-v1.z, 0.0, v1.x) / (v1.x * v1.x + v1.z * v1.z).sqrt()
+  } else {
+    Vector::new(0.0, v1.z, -v1.y) / (v1.y * v1.y + v1.z * v1.z).sqrt()
+  };
+
+  (v2, cross(*v1, v2))
+}
+
+pub fn spherical_direction(sin_theta: Float, cos_theta: Float, phi: Float) -> Vector {
   Vector::new(sin_theta * phi.cos(),
     sin_theta * phi.sin(),
     cos_theta)
 }
 
-pub fn spherical_theta(v: &Vector) -> f32 {
+pub fn spherical_theta(v: &Vector) -> Float {
   clamp(v.z, -1.0, 1.0).acos()
 }
 
-pub fn spherical_phi(v: &Vector) -> f32 {
+pub fn spherical_phi(v: &Vector) -> Float {
   let p = v.y.atan2(v.x);
   if p < 0.0 {
-    p + 2.0 * 3.142
+    p + 2.0 * PI
   } else {
     p
   }
 }
 
-pub fn solve_linear_system(a: [[f32, ..2], ..2], b: [f32, ..2], x0: &mut f32, x1: &mut f32) -> bool {
+pub fn solve_linear_system(a: [[Float; 2]; 2], b: [Float; 2], x0: &mut Float, x1: &mut Float) -> bool {
   let det = a[0][0] * a[1][1] - a[0][1] * a[1][0];
   if det.abs() < 1e-10 {
     return false;
@@ -545,9 +775,33 @@ pub fn solve_linear_system(a: [[f32, ..2], ..2], b: [f32, ..2], x0: &mut f32, x1
   !x0.is_nan() && !x1.is_nan()
 }
 
+/// Move `p` off the surface with normal `n` along the side `w` leaves
+/// from, far enough that the error box `p_error` around it is cleared
+pub fn offset_ray_origin(p: &Point, p_error: &Vector, n: &Normal, w: &Vector) -> Point {
+  let d = n.x.abs() * p_error.x + n.y.abs() * p_error.y + n.z.abs() * p_error.z;
+  let mut offset = Vector::from_normal(n) * d;
+
+  if dot(*w, *n) < 0.0 {
+    offset = -offset;
+  }
+
+  let po = *p + offset;
+  let round = |x: Float, o: Float| {
+    if o > 0.0 {
+      next_float_up(x)
+    } else if o < 0.0 {
+      next_float_down(x)
+    } else {
+      x
+    }
+  };
+
+  Point::new(round(po.x, offset.x), round(po.y, offset.y), round(po.z, offset.z))
+}
+
 pub fn face_forward
-    <T: Index<uint, f32> + Length + Neg<T> + Clone,
-     S: Index<uint, f32> + Length>(n: T, v: S) -> T {
+    <T: Index<usize, Output = Float> + Length + Neg<Output = T> + Clone,
+     S: Index<usize, Output = Float> + Length>(n: T, v: S) -> T {
   let r = n.clone();
   if dot(n, v) < 0.0 {
     -r
@@ -555,3 +809,94 @@ pub fn face_forward
     r
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::transform::Applicable;
+
+  #[test]
+  fn point_arithmetic() {
+    let p = Point::new(1.0, 2.0, 3.0);
+    let q = Point::new(4.0, 6.0, 3.0);
+
+    assert_eq!(q - p, Vector::new(3.0, 4.0, 0.0));
+    assert_eq!(p + (q - p), q);
+    assert_eq!(distance(&p, &q), 5.0);
+    assert_eq!(distance_squared(&p, &q), 25.0);
+  }
+
+  #[test]
+  fn cross_and_dot() {
+    let x = Vector::new(1.0, 0.0, 0.0);
+    let y = Vector::new(0.0, 1.0, 0.0);
+
+    assert_eq!(cross(x, y), Vector::new(0.0, 0.0, 1.0));
+    assert_eq!(dot(x, y), 0.0);
+    assert_eq!(normalize(Vector::new(0.0, 3.0, 4.0)).length(), 1.0);
+  }
+
+  #[test]
+  fn bbox_union_and_intersect() {
+    let b = BBox::from_point(&Point::new(-1.0, -1.0, -1.0)).union(&Point::new(1.0, 1.0, 1.0));
+    let ray = Ray::new(&Point::new(0.0, 0.0, -5.0), &Vector::new(0.0, 0.0, 1.0),
+      0.0, Float::INFINITY, 0.0);
+
+    assert_eq!(b.intersect_p(&ray), Some((4.0, 6.0)));
+    assert_eq!(b.surface_area(), 24.0);
+    assert_eq!(b.maximum_extent(), 2);
+  }
+
+  #[test]
+  fn quaternion_rotates_vectors() {
+    let q = Quaternion::from_axis_angle(90.0, &Vector::new(0.0, 0.0, 1.0));
+    let v = q.rotate(&Vector::new(1.0, 0.0, 0.0));
+
+    assert!(v.x.abs() < 1e-6 && (v.y - 1.0).abs() < 1e-6);
+    assert!((q.length() - 1.0).abs() < 1e-6);
+
+    let r = Transform::rotate_z(90.0);
+    let rv: Vector = q.to_transform().apply(Vector::new(1.0, 0.0, 0.0));
+    assert!((rv.y - 1.0).abs() < 1e-6);
+    assert!((Quaternion::from_transform(&r).dot(&q) - 1.0).abs() < 1e-6);
+  }
+
+  #[test]
+  fn quaternion_product_composes_rotations() {
+    let a = Quaternion::from_axis_angle(30.0, &Vector::new(0.0, 1.0, 0.0));
+    let b = Quaternion::from_axis_angle(60.0, &Vector::new(0.0, 1.0, 0.0));
+    let c = Quaternion::from_axis_angle(90.0, &Vector::new(0.0, 1.0, 0.0));
+
+    assert!(((a * b).dot(&c) - 1.0).abs() < 1e-6);
+    assert!((((a * 2.0) / 2.0) - a).length() < 1e-6);
+  }
+
+  #[test]
+  fn quaternion_slerp_is_halfway() {
+    let a = Quaternion::identity();
+    let b = Quaternion::from_axis_angle(90.0, &Vector::new(1.0, 0.0, 0.0));
+    let mid = Quaternion::slerp(0.5, &a, &b);
+
+    assert!((mid.dot(&Quaternion::from_axis_angle(45.0, &Vector::new(1.0, 0.0, 0.0))) - 1.0).abs() < 1e-6);
+    assert_eq!(Quaternion::slerp(0.0, &a, &b), a);
+  }
+
+  #[test]
+  fn offset_ray_origin_clears_error_bounds() {
+    let p = Point::new(1.0, 2.0, 3.0);
+    let n = Normal::new(0.0, 0.0, 1.0);
+    let p_error = Vector::new(1e-3, 1e-3, 1e-3);
+
+    let above = offset_ray_origin(&p, &p_error, &n, &Vector::new(0.0, 1.0, 1.0));
+    let below = offset_ray_origin(&p, &p_error, &n, &Vector::new(0.0, 1.0, -1.0));
+
+    assert!(above.z > p.z + p_error.z && above.x == p.x);
+    assert!(below.z < p.z - p_error.z);
+  }
+
+  #[test]
+  fn quadratic_roots() {
+    assert_eq!(quadratic(1.0, -3.0, 2.0), Some((1.0, 2.0)));
+    assert_eq!(quadratic(1.0, 0.0, 1.0), None);
+  }
+}
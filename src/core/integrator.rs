@@ -1,58 +1,58 @@
-extern crate rand;
+use crate::camera::Camera;
+use crate::geometry::{ RayDifferential, Vector, abs_dot };
+use crate::intersection::Intersection;
+use crate::reflection::{ BsdfSample, Bsdf, BxDFType };
+use crate::renderer::Renderer;
+use crate::sampler::{ Sample, Sampler };
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
 
-use camera::Camera;
-use geometry::{ RayDifferential, Vector, abs_dot };
-use intersection::Intersection;
-use reflection::{ BsdfSample, Bsdf, BxDFType, Transmission, Reflection, Specular };
-use renderer::Renderer;
-use sampler::Sample;
-use scene::Scene;
-use spectrum::Spectrum;
-
-use rand::TaskRng;
+use rand::rngs::ThreadRng;
 
 pub trait Integrator {
-  fn preprocess(scene: &Scene, camera: &Camera) {}
-  fn request_samples() {}
+  fn preprocess(&self, _scene: &Scene, _camera: &dyn Camera) {}
+  fn request_samples(&self, _sampler: &dyn Sampler, _sample: &mut Sample, _scene: &Scene) {}
 }
 
 pub trait SurfaceIntegrator : Integrator {
-  fn Li<'a>(&self, scene: &'a Scene<'a>, renderer: &Renderer, ray: &RayDifferential,
-    intersection: &mut Intersection, sample: &Sample, rng: &mut TaskRng) -> Spectrum;
+  fn li(&self, scene: &Scene, renderer: &dyn Renderer, ray: &RayDifferential,
+    intersection: &mut Intersection, sample: &Sample, rng: &mut ThreadRng) -> Spectrum;
 }
 
-pub fn specular_reflect<'a>(ray: &RayDifferential, bsdf: &'a Bsdf<'a>,
-  rng: &mut TaskRng, intersection: &Intersection, renderer: &Renderer,
+pub fn specular_reflect(ray: &RayDifferential, bsdf: &Bsdf,
+  rng: &mut ThreadRng, intersection: &Intersection, renderer: &dyn Renderer,
   scene: &Scene, sample: &Sample) -> Spectrum {
-  fail!("not implemented");
-
   let wo = -ray.ray.d;
   let mut wi = Vector::new(0.0, 0.0, 0.0);
-  let p = bsdf.dg_shading.p;
   let n = bsdf.dg_shading.nn;
   let mut pdf = 0.0;
   let (f, _) = bsdf.sample_f(&wo, &mut wi, &BsdfSample::from_random(rng),
-    &mut pdf, &mut(Reflection | Specular));
-
-  let mut l = Spectrum::new(0.0);
+    &mut pdf, &mut (BxDFType::REFLECTION | BxDFType::SPECULAR));
 
   if pdf > 0.0 && !f.is_black() && abs_dot(wi, n) != 0.0 {
-
+    let rd = intersection.spawn_ray(&wi, &ray.ray);
+    let li = renderer.li(scene, &rd, sample);
+    return f * li * (abs_dot(wi, n) / pdf);
   }
 
-  return l;
+  Spectrum::new(0.0)
 }
 
-pub fn specular_transmit<'a>(ray: &RayDifferential, bsdf: &'a Bsdf<'a>,
-  rng: &mut TaskRng, intersection: &Intersection, renderer: &Renderer,
+pub fn specular_transmit(ray: &RayDifferential, bsdf: &Bsdf,
+  rng: &mut ThreadRng, intersection: &Intersection, renderer: &dyn Renderer,
   scene: &Scene, sample: &Sample) -> Spectrum {
-  fail!("not implemented");
-
   let wo = -ray.ray.d;
   let mut wi = Vector::new(0.0, 0.0, 0.0);
-  let p = bsdf.dg_shading.p;
   let n = bsdf.dg_shading.nn;
   let mut pdf = 0.0;
   let (f, _) = bsdf.sample_f(&wo, &mut wi, &BsdfSample::from_random(rng),
-    &mut pdf, &mut (Transmission | Specular));
+    &mut pdf, &mut (BxDFType::TRANSMISSION | BxDFType::SPECULAR));
+
+  if pdf > 0.0 && !f.is_black() && abs_dot(wi, n) != 0.0 {
+    let rd = intersection.spawn_ray(&wi, &ray.ray);
+    let li = renderer.li(scene, &rd, sample);
+    return f * li * (abs_dot(wi, n) / pdf);
+  }
+
+  Spectrum::new(0.0)
 }
@@ -1,37 +1,77 @@
 use std::rc::Rc;
-use std::cell::RefCell;
 
-use diffgeom::DifferentialGeometry;
-use geometry::{ RayDifferential, Vector };
-use primitive::Primitive;
-use reflection::{ Bsdf, Bssrdf };
-use spectrum::Spectrum;
-use transform::Transform;
+use crate::diffgeom::DifferentialGeometry;
+use crate::geometry::{ Float, Point, Ray, RayDifferential, Vector, SHADOW_EPSILON, offset_ray_origin };
+use crate::primitive::Primitive;
+use crate::reflection::{ Bsdf, Bssrdf };
+use crate::spectrum::Spectrum;
+use crate::transform::Transform;
 
 pub struct Intersection {
   pub dg:              DifferentialGeometry,
-  pub primitive:       Option<Rc<RefCell<Box<Primitive>>>>,
+  pub primitive:       Option<Rc<dyn Primitive>>,
   pub world_to_object: Transform,
   pub object_to_world: Transform,
-  pub shape_id:        uint,
-  pub primitive_id:    uint,
-  pub ray_epsilon:     f32
+  pub shape_id:        usize,
+  pub primitive_id:    usize,
+  pub p_error:         Vector,
+  /// Whether `dg` was turned over after the hit, e.g. on the cut of a CSG
+  /// difference. The primitive knows nothing of it, so the flip is applied
+  /// to the BSDF it builds.
+  pub flip_normals:    bool
 }
 
 impl Intersection {
+  pub fn new(dg: DifferentialGeometry, p_error: Vector, world_to_object: &Transform,
+      object_to_world: &Transform, shape_id: usize, primitive_id: usize) -> Intersection {
+    Intersection {
+      dg,
+      primitive: None,
+      world_to_object: *world_to_object,
+      object_to_world: *object_to_world,
+      shape_id,
+      primitive_id,
+      p_error,
+      flip_normals: false
+    }
+  }
+
   pub fn get_bsdf(&mut self, ray: &RayDifferential) -> Option<Bsdf> {
     self.dg.compute_differentials(ray);
-    self.primitive.get_ref().borrow().get_bsdf(&self.dg, &self.object_to_world)
+    let primitive = self.primitive.as_ref()?;
+    if !self.flip_normals {
+      return primitive.get_bsdf(&self.dg, &self.object_to_world);
+    }
+
+    // Shade the surface as the primitive reports it, then turn it over
+    let mut dg = self.dg.clone();
+    dg.flip();
+    let mut bsdf = primitive.get_bsdf(&dg, &self.object_to_world)?;
+    bsdf.flip();
+    Some(bsdf)
   }
 
   pub fn get_bssrdf(&mut self, ray: &RayDifferential) -> Option<Bssrdf> {
     self.dg.compute_differentials(ray);
-    self.primitive.get_ref().borrow().get_bssrdf(&self.dg, &self.object_to_world)
+    self.primitive.as_ref().and_then(|p| p.get_bssrdf(&self.dg, &self.object_to_world))
+  }
+
+  /// Ray leaving the hit point in direction `d`, one bounce deeper than
+  /// `parent`
+  pub fn spawn_ray(&self, d: &Vector, parent: &Ray) -> RayDifferential {
+    let o = offset_ray_origin(&self.dg.p, &self.p_error, &self.dg.nn, d);
+    RayDifferential::from_parent(&o, d, parent)
+  }
+
+  /// Ray from the hit point towards `p`, stopping just short of it
+  pub fn spawn_ray_to(&self, p: &Point, time: Float) -> Ray {
+    let o = offset_ray_origin(&self.dg.p, &self.p_error, &self.dg.nn, &(*p - self.dg.p));
+    Ray::new(&o, &(*p - o), 0.0, 1.0 - SHADOW_EPSILON, time)
   }
 
-  pub fn Le(&self, wo: &Vector) -> Spectrum {
-    match self.primitive.get_ref().borrow().get_area_light() {
-      Some(x) => x.L(&self.dg.p, &self.dg.nn, wo),
+  pub fn le(&self, wo: &Vector) -> Spectrum {
+    match self.primitive.as_ref().and_then(|p| p.get_area_light()) {
+      Some(x) => x.l(&self.dg.p, &self.dg.nn, wo),
       None    => Spectrum::new(0.0)
     }
   }
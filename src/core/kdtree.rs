@@ -1,14 +1,17 @@
-use geometry::{ BBox, Point, distance_squared, Union };
+use std::cmp::Ordering;
 
+use crate::geometry::{ Float, BBox, Point, distance_squared, Union };
+
+#[derive(Clone, Default)]
 pub struct KdNode {
-  split_pos:      f32,
-  split_axis:     uint,
+  split_pos:      Float,
+  split_axis:     usize,
   has_left_child: bool,
-  right_child:    uint
+  right_child:    usize
 }
 
 impl KdNode {
-  pub fn init(&mut self, p: f32, a: uint) {
+  pub fn init(&mut self, p: Float, a: usize) {
     self.split_pos      = p;
     self.split_axis     = a;
     self.right_child    = (1 << 29) - 1;
@@ -25,90 +28,89 @@ impl KdNode {
 pub struct KdTree<T> {
   nodes:          Vec<KdNode>,
   node_data:      Vec<T>,
-  next_free_node: uint,
-  number_nodes:   uint
+  next_free_node: usize,
+  number_nodes:   usize
 }
 
 pub trait KdNodeData {
   fn get_point(&self) -> Point;
 }
 
-impl<T: KdNodeData + Clone + TotalOrd> KdTree<T> {
-  pub fn new(d: &mut Vec<T>) -> KdTree<T> {
+impl<T: KdNodeData + Clone + Ord> KdTree<T> {
+  pub fn new(d: &mut [T]) -> KdTree<T> {
     let mut tree = KdTree {
-      nodes:      Vec::new(),
-      node_data:    Vec::new(),
+      nodes:          vec![KdNode::default(); d.len()],
+      node_data:      d.to_vec(),
       next_free_node: 1,
       number_nodes:   d.len()
     };
 
-    tree.recursive_build(0, 0, tree.number_nodes, d);
+    if !d.is_empty() {
+      tree.recursive_build(0, 0, tree.number_nodes, d);
+    }
 
-    return tree;
+    tree
   }
 
-  fn recursive_build(&mut self, node_num: uint, start: uint, end: uint, build_nodes: &mut Vec<T>) {
+  fn recursive_build(&mut self, node_num: usize, start: usize, end: usize, build_nodes: &mut [T]) {
     if start + 1 == end {
-      self.nodes.get_mut(node_num).init_leaf();
-      self.node_data.grow_set(node_num, build_nodes.get(start),
-        build_nodes.get(start).clone());
+      self.nodes[node_num].init_leaf();
+      self.node_data[node_num] = build_nodes[start].clone();
       return;
     }
 
-    let mut bound = BBox::from_point(&Point::zero());
-    for i in range(start, end) {
-      bound = bound.union(&build_nodes.get(i).get_point());
+    let mut bound = BBox::from_point(&build_nodes[start].get_point());
+    for node in build_nodes[start..end].iter() {
+      bound = bound.union(&node.get_point());
     }
 
     let split_axis = bound.maximum_extent();
     let split_pos  = (start + end) / 2;
 
-    let sorter = |a: &T, b: &T| -> Ordering {
+    build_nodes[start..end].sort_by(|a: &T, b: &T| {
       let ap = a.get_point()[split_axis];
-      let bp = a.get_point()[split_axis];
+      let bp = b.get_point()[split_axis];
 
-      match (ap == bp, ap < bp) {
-        (true, false) => a.cmp(b),
-        (false, true) => Less,
-        _             => Greater
+      match ap.partial_cmp(&bp) {
+        Some(Ordering::Equal) | None => a.cmp(b),
+        Some(o) => o
       }
-    };
+    });
 
-    self.nodes.get_mut(node_num).init(
-      build_nodes.get(split_pos).get_point()[split_axis], split_axis);
-    self.node_data.grow_set(node_num, build_nodes.get(split_pos),
-      build_nodes.get(split_pos).clone());
-    build_nodes.sort_by(sorter);
+    self.nodes[node_num].init(build_nodes[split_pos].get_point()[split_axis], split_axis);
+    self.node_data[node_num] = build_nodes[split_pos].clone();
 
     if start < split_pos {
-      self.nodes.get_mut(node_num).has_left_child = true;
+      self.nodes[node_num].has_left_child = true;
       let child_num = self.next_free_node;
       self.next_free_node += 1;
       self.recursive_build(child_num, start, split_pos, build_nodes);
     }
 
     if split_pos + 1 < end {
-      self.nodes.get_mut(node_num).right_child = self.next_free_node;
+      self.nodes[node_num].right_child = self.next_free_node;
       self.next_free_node += 1;
-      let right = self.nodes.get(node_num).right_child;
+      let right = self.nodes[node_num].right_child;
       self.recursive_build(right, split_pos + 1, end, build_nodes);
     }
   }
 
-  pub fn lookup(&self, node_num: uint, p: &Point, max_dist_squared: &mut f32,
-      process: |&Point, &T, f32, f32| -> bool) {
-    self.lookup_private(0, p, max_dist_squared, process);
+  pub fn lookup<F>(&self, p: &Point, max_dist_squared: &mut Float, mut process: F)
+      where F: FnMut(&Point, &T, Float, &mut Float) {
+    if self.number_nodes > 0 {
+      self.lookup_private(0, p, max_dist_squared, &mut process);
+    }
   }
 
-  fn lookup_private(&self, node_num: uint, p: &Point, max_dist_squared: &mut f32,
-      process: |&Point, &T, f32, f32| -> bool) {
-    let node = self.nodes.get(node_num);
+  fn lookup_private<F>(&self, node_num: usize, p: &Point, max_dist_squared: &mut Float,
+      process: &mut F) where F: FnMut(&Point, &T, Float, &mut Float) {
+    let node = &self.nodes[node_num];
     let axis = node.split_axis;
 
     if axis == 3 {
-      let dist2 = distance_squared(&self.node_data.get(node_num).get_point(), p);
+      let dist2 = distance_squared(&self.node_data[node_num].get_point(), p);
       if dist2 < *max_dist_squared {
-        process(p, self.node_data.get(node_num), dist2, *max_dist_squared);
+        process(p, &self.node_data[node_num], dist2, max_dist_squared);
       }
       return;
     }
@@ -116,27 +118,59 @@ impl<T: KdNodeData + Clone + TotalOrd> KdTree<T> {
     let dist2 = (p[axis] - node.split_pos) * (p[axis] - node.split_pos);
     if p[axis] <= node.split_pos {
       if node.has_left_child {
-        self.lookup_private(node_num + 1, p, max_dist_squared,
-          |a, b, c, d| process(a, b, c, d));
+        self.lookup_private(node_num + 1, p, max_dist_squared, process);
       }
       if dist2 < *max_dist_squared && node.right_child < self.number_nodes {
-        self.lookup_private(node.right_child, p, max_dist_squared,
-          |a, b, c, d| process(a, b, c, d));
+        self.lookup_private(node.right_child, p, max_dist_squared, process);
       }
     } else {
       if node.right_child < self.number_nodes {
-        self.lookup_private(node.right_child, p, max_dist_squared,
-          |a, b, c, d| process(a, b, c, d));
+        self.lookup_private(node.right_child, p, max_dist_squared, process);
       }
       if dist2 < *max_dist_squared && node.has_left_child {
-        self.lookup_private(node_num + 1, p, max_dist_squared,
-          |a, b, c, d| process(a, b, c, d));
+        self.lookup_private(node_num + 1, p, max_dist_squared, process);
       }
     }
 
-    let dist2 = distance_squared(&self.node_data.get(node_num).get_point(), p);
+    let dist2 = distance_squared(&self.node_data[node_num].get_point(), p);
     if dist2 < *max_dist_squared {
-      process(p, self.node_data.get(node_num), dist2, *max_dist_squared);
+      process(p, &self.node_data[node_num], dist2, max_dist_squared);
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+  struct Photon(i32, i32, i32);
+
+  impl KdNodeData for Photon {
+    fn get_point(&self) -> Point {
+      Point::new(self.0 as Float, self.1 as Float, self.2 as Float)
+    }
+  }
+
+  #[test]
+  fn lookup_finds_points_within_radius() {
+    let mut photons = Vec::new();
+    for x in 0..5 {
+      for y in 0..5 {
+        photons.push(Photon(x, y, 0));
+      }
+    }
+
+    let tree = KdTree::new(&mut photons);
+    let mut max_dist_squared = 1.1;
+    let mut found = Vec::new();
+
+    tree.lookup(&Point::new(2.0, 2.0, 0.0), &mut max_dist_squared, |_, photon, _, _| {
+      found.push(photon.clone());
+    });
+
+    found.sort();
+    assert_eq!(found, vec![Photon(1, 2, 0), Photon(2, 1, 0), Photon(2, 2, 0),
+      Photon(2, 3, 0), Photon(3, 2, 0)]);
+  }
+}
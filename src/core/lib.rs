@@ -1,12 +1,8 @@
-#![crate_id="rbrtcore#0.0.2"]
-#![comment = "RBRT Core Library"]
-#![license = "BSD"]
-#![crate_type = "lib"]
-
-extern crate rand;
-
 pub mod camera;
+pub mod csg;
 pub mod diffgeom;
+pub mod efloat;
+pub mod error;
 pub mod film;
 pub mod filter;
 pub mod geometry;
@@ -30,4 +26,7 @@ pub mod spectrum;
 pub mod spectrum_consts;
 pub mod spherical;
 pub mod texture;
+/// Test fixtures, also used by the tests of the other crates
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod transform;
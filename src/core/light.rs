@@ -1,106 +1,114 @@
-use geometry::{ Point, Normal, Vector, Ray, RayDifferential, distance, round_up_pow_2 };
-use montecarlo::{ sample02, van_der_corput };
-use renderer::Renderer;
-use sampler::Sample;
-use scene::Scene;
-use spectrum::Spectrum;
-use spherical::{ sh_terms, sh_evaluate };
-use transform::Transform;
-
-use std::f32::INFINITY;
-use rand::{ TaskRng, Rng };
+use crate::geometry::{
+  Float, Point, Normal, Vector, Ray, RayDifferential,
+  SHADOW_EPSILON, offset_ray_origin, round_up_pow_2 };
+use crate::montecarlo::{ sample02, van_der_corput };
+use crate::renderer::Renderer;
+use crate::sampler::Sample;
+use crate::scene::Scene;
+use crate::spectrum::Spectrum;
+use crate::spherical::{ sh_terms, sh_evaluate };
+use crate::transform::Transform;
+
+use rand::Rng;
+use rand::rngs::ThreadRng;
 
 pub struct LightSampleOffsets {
-  pub num_samples:      uint,
-  pub component_offset: uint,
-  pub position_offset:  uint
+  pub num_samples:      usize,
+  pub component_offset: usize,
+  pub position_offset:  usize
 }
 
 pub struct LightSample {
-  upos: (f32, f32),
-  ucomponent: f32
+  pub upos: (Float, Float),
+  pub ucomponent: Float
 }
 
 impl LightSample {
-  pub fn new(up0: f32, up1: f32, ucomp: f32) -> LightSample {
+  pub fn new(up0: Float, up1: Float, ucomp: Float) -> LightSample {
     LightSample { upos: (up0, up1), ucomponent: ucomp }
   }
 
-  pub fn from_sample(sample: &Sample, offsets: &LightSampleOffsets, n: uint) -> LightSample {
-    let up0 = sample.twoD[offsets.position_offset][2 * n];
-    let up1 = sample.twoD[offsets.position_offset][2 * n + 1];
-    let ucomp = sample.oneD[offsets.component_offset][n];
+  pub fn from_sample(sample: &Sample, offsets: &LightSampleOffsets, n: usize) -> LightSample {
+    let up0 = sample.two_d[offsets.position_offset][2 * n];
+    let up1 = sample.two_d[offsets.position_offset][2 * n + 1];
+    let ucomp = sample.one_d[offsets.component_offset][n];
 
     LightSample::new(up0, up1, ucomp)
   }
 
-  pub fn from_random(rng: &mut TaskRng) -> LightSample {
+  pub fn from_random(rng: &mut ThreadRng) -> LightSample {
     LightSample { upos: rng.gen(), ucomponent: rng.gen() }
   }
 }
 
 pub struct LightBase {
-  num_samples: uint,
-  light_to_world: Transform,
-  world_to_light: Transform
+  pub num_samples: usize,
+  pub light_to_world: Transform,
+  pub world_to_light: Transform
 }
 
-pub trait Light<'a> {
-  fn get_base(&'a self) -> LightBase;
-  fn get_base_mut(&'a mut self) -> LightBase;
+pub trait Light {
+  fn get_base(&self) -> &LightBase;
+  fn get_base_mut(&mut self) -> &mut LightBase;
 
-  fn is_delta_light(&'a self) -> bool;
+  fn is_delta_light(&self) -> bool;
 
-  fn power(&'a self, scene: &Scene) -> Spectrum;
-  fn pdf(&'a self, p: &Point, wi: &Vector) -> f32;
+  fn power(&self, scene: &Scene) -> Spectrum;
+  fn pdf(&self, p: &Point, wi: &Vector) -> Float;
 
-  fn Le(&'a self, ray: &RayDifferential) -> Spectrum {
+  fn le(&self, _ray: &RayDifferential) -> Spectrum {
     Spectrum::new(0.0)
   }
 
-  fn sh_project(&'a self, p: &Point, p_epsilon: f32, lmax: uint, scene: &Scene,
-      compute_light_visibility: bool, time: f32,
-      rng: &mut TaskRng, coeffs_v: &mut Vec<Spectrum>) {
+  #[allow(clippy::too_many_arguments)]
+  fn sh_project(&self, p: &Point, p_error: &Vector, n: &Normal, lmax: usize, scene: &Scene,
+      compute_light_visibility: bool, time: Float,
+      rng: &mut ThreadRng, coeffs: &mut [Spectrum]) {
     let ns = round_up_pow_2(self.get_base().num_samples);
-    let scramble1D = rng.gen::<uint>();
-    let scramble2D = rng.gen::<(uint, uint)>();
-    let mut ylm = Vec::from_elem(sh_terms(lmax as int), 0.0f32);
-    let len = coeffs_v.len();
-    let mut coeffs = coeffs_v.mut_slice(0, len);
+    let scramble_1d = rng.gen::<u32>();
+    let scramble_2d = rng.gen::<(u32, u32)>();
+    let mut ylm: Vec<Float> = vec![0.0; sh_terms(lmax as i32)];
 
-    for i in range(0, ns) {
+    for i in 0..ns {
       let mut u = (0.0, 0.0);
-      sample02(i, scramble2D, &mut u);
-      let light_sample = LightSample::new(u.val0(), u.val1(),
-        van_der_corput(i, scramble1D));
+      sample02(i as u32, scramble_2d, &mut u);
+      let light_sample = LightSample::new(u.0, u.1,
+        van_der_corput(i as u32, scramble_1d));
       let mut vis = VisibilityTester::new();
       let mut wi = Vector::zero();
       let mut pdf = 0.0;
-      let li = self.sample_l(p, p_epsilon, &light_sample, time,
+      let li = self.sample_l(p, p_error, n, &light_sample, time,
         &mut wi, &mut pdf, &mut vis);
 
       if !li.is_black() && pdf > 0.0 &&
           (!compute_light_visibility || vis.unoccluded(scene)) {
-        sh_evaluate(&wi, lmax as int, &mut ylm);
-        for j in range(0, sh_terms(lmax as int)) {
-          coeffs[j] = coeffs[j] + (li * *ylm.get(j) / (pdf * ns as f32));
+        sh_evaluate(&wi, lmax as i32, &mut ylm);
+        for j in 0..sh_terms(lmax as i32) {
+          coeffs[j] = coeffs[j] + (li * ylm[j] / (pdf * ns as Float));
         }
       }
     }
   }
 
-  fn sample_l(&'a self, p: &Point, p_epsilon: f32, ls: &LightSample, time: f32,
-      wi: &mut Vector, pdf: &mut f32, vis: &mut VisibilityTester) -> Spectrum;
+  #[allow(clippy::too_many_arguments)]
+  fn sample_l(&self, p: &Point, p_error: &Vector, n: &Normal, ls: &LightSample, time: Float,
+      wi: &mut Vector, pdf: &mut Float, vis: &mut VisibilityTester) -> Spectrum;
 }
 
-pub trait AreaLight<'a> : Light<'a> {
-  fn L(&self, p: &Point, n: &Normal, w: &Vector) -> Spectrum;
+pub trait AreaLight : Light {
+  fn l(&self, p: &Point, n: &Normal, w: &Vector) -> Spectrum;
 }
 
 pub struct VisibilityTester {
   pub r: Ray
 }
 
+impl Default for VisibilityTester {
+  fn default() -> VisibilityTester {
+    VisibilityTester::new()
+  }
+}
+
 impl VisibilityTester {
   pub fn new() -> VisibilityTester {
     VisibilityTester { r: Ray::zero() }
@@ -110,17 +118,21 @@ impl VisibilityTester {
     !scene.intersect_p(&self.r)
   }
 
-  pub fn transmittance(&self, scene: &Scene, renderer: &Renderer, sample: &Sample,
-      rng: &mut TaskRng) -> Spectrum {
+  pub fn transmittance(&self, scene: &Scene, renderer: &dyn Renderer, sample: &Sample,
+      rng: &mut ThreadRng) -> Spectrum {
     renderer.transmittance(scene, &RayDifferential::new(&self.r), sample, rng)
   }
 
-  pub fn set_segment(&mut self, p1: &Point, eps1: f32, p2: &Point, eps2: f32, time: f32) {
-    let dist = distance(p1, p2);
-    self.r = Ray::new(p1, &((p2 - *p1) / dist), eps1, dist * (1.0 - eps2), time);
+  /// Test the segment from the surface point `p1`, whose position is
+  /// known up to `p1_error`, to `p2`
+  pub fn set_segment(&mut self, p1: &Point, p1_error: &Vector, n1: &Normal, p2: &Point,
+      time: Float) {
+    let o = offset_ray_origin(p1, p1_error, n1, &(*p2 - *p1));
+    self.r = Ray::new(&o, &(*p2 - o), 0.0, 1.0 - SHADOW_EPSILON, time);
   }
 
-  pub fn set_ray(&mut self, p: &Point, eps: f32, w: &Vector, time: f32) {
-    self.r = Ray::new(p, w, eps, INFINITY, time);
+  pub fn set_ray(&mut self, p: &Point, p_error: &Vector, n: &Normal, w: &Vector, time: Float) {
+    let o = offset_ray_origin(p, p_error, n, w);
+    self.r = Ray::new(&o, w, 0.0, Float::INFINITY, time);
   }
 }
@@ -1,16 +1,16 @@
-use diffgeom::DifferentialGeometry;
-use geometry::{ Vector, Normal, cross, cross_n, normalize, face_forward };
-use reflection::Bsdf;
-use texture::Texture;
+use crate::diffgeom::DifferentialGeometry;
+use crate::geometry::{ Float, Vector, Normal, cross, cross_n, normalize, face_forward };
+use crate::reflection::{ Bsdf, Bssrdf };
+use crate::texture::Texture;
 
 pub trait Material {
   fn get_bsdf(&self, dg_geom: &DifferentialGeometry, dg_shading: &DifferentialGeometry) -> Bsdf;
-  fn get_bssrdf(&self, dg_geom: &DifferentialGeometry, dg_shading: &DifferentialGeometry) -> Option<Bsdf> {
+  fn get_bssrdf(&self, _dg_geom: &DifferentialGeometry, _dg_shading: &DifferentialGeometry) -> Option<Bssrdf> {
     None
   }
 }
 
-pub fn bump(d: &Texture<f32>, dg_geom: &DifferentialGeometry,
+pub fn bump(d: &dyn Texture<Float>, dg_geom: &DifferentialGeometry,
     dg_shading: DifferentialGeometry) -> DifferentialGeometry {
   // Compute offset positions and evaluate displacement
   let mut dg_eval = dg_shading.clone();
@@ -57,5 +57,5 @@ pub fn bump(d: &Texture<f32>, dg_geom: &DifferentialGeometry,
   // Orient shading normal to match geometric normal
   dg_bump.nn = face_forward(dg_bump.nn, dg_geom.nn);
 
-  return dg_bump;
+  dg_bump
 }
@@ -1,5 +1,10 @@
-use geometry::{ clamp, mod_t };
+use std::ops::{ Add, Mul };
 
+use crate::error::RenderError;
+use crate::geometry::{ Float, clamp, mod_t };
+use crate::spectrum::Spectrum;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ImageWrap {
   Repeat,
   Black,
@@ -7,50 +12,276 @@ pub enum ImageWrap {
 }
 
 pub struct ResampleWeight {
-  pub first_texel: uint,
-  pub weight: [f32, ..4]
+  pub first_texel: usize,
+  pub weight: [Float; 4]
+}
+
+/// Texel types that can be stored and filtered in a `MipMap`
+pub trait MipMapType : Copy + Add<Output = Self> + Mul<Float, Output = Self> {
+  fn zero() -> Self;
+}
+
+impl MipMapType for Float {
+  fn zero() -> Float {
+    0.0
+  }
+}
+
+impl MipMapType for Spectrum {
+  fn zero() -> Spectrum {
+    Spectrum::new(0.0)
+  }
 }
 
-pub trait MipMapType {
-  fn usize(&self) -> uint;
-  fn vsize(&self) -> uint;
+/// A single level of the image pyramid
+pub struct MipMapLevel<T> {
+  pub usize: usize,
+  pub vsize: usize,
+  pub texels: Vec<T>
+}
+
+impl<T: MipMapType> MipMapLevel<T> {
+  fn get(&self, s: usize, t: usize) -> T {
+    self.texels[t * self.usize + s]
+  }
+
+  /// Box filter this level down to half its resolution
+  fn downsample(&self) -> MipMapLevel<T> {
+    let usize = (self.usize / 2).max(1);
+    let vsize = (self.vsize / 2).max(1);
+    let mut texels = Vec::with_capacity(usize * vsize);
+
+    for t in 0..vsize {
+      for s in 0..usize {
+        let (s0, t0) = (2 * s, 2 * t);
+        let (s1, t1) = ((s0 + 1).min(self.usize - 1), (t0 + 1).min(self.vsize - 1));
+        let (s0, t0) = (s0.min(self.usize - 1), t0.min(self.vsize - 1));
+
+        texels.push((self.get(s0, t0) + self.get(s1, t0) +
+          self.get(s0, t1) + self.get(s1, t1)) * 0.25);
+      }
+    }
+
+    MipMapLevel { usize, vsize, texels }
+  }
 }
 
 pub struct MipMap<T> {
   pub do_trilinear: bool,
-  pub max_anisotropy: f32,
+  pub max_anisotropy: Float,
   pub wrap_mode: ImageWrap,
-  pub width: uint,
-  pub height: uint,
-  pub num_levels: uint,
-  pub pyramid: ~[T]
+  pub width: usize,
+  pub height: usize,
+  pub num_levels: usize,
+  pub pyramid: Vec<MipMapLevel<T>>
 }
 
 impl<T: MipMapType> MipMap<T> {
-  pub fn texel(&self, level: uint, s: uint, t: uint) -> T {
+  /// Build the image pyramid for a `width` x `height` image stored in
+  /// row-major order
+  pub fn new(width: usize, height: usize, data: Vec<T>, do_trilinear: bool,
+      max_anisotropy: Float, wrap_mode: ImageWrap) -> Result<MipMap<T>, RenderError> {
+    if width == 0 || height == 0 {
+      return Err(RenderError::InvalidTexture(
+        format!("image resolution {}x{} is empty", width, height)));
+    }
+
+    if data.len() != width * height {
+      return Err(RenderError::InvalidTexture(
+        format!("expected {} texels for a {}x{} image, found {}",
+          width * height, width, height, data.len())));
+    }
+
+    let mut pyramid = vec![MipMapLevel { usize: width, vsize: height, texels: data }];
+    while pyramid.last().is_some_and(|l| l.usize > 1 || l.vsize > 1) {
+      let next = pyramid[pyramid.len() - 1].downsample();
+      pyramid.push(next);
+    }
+
+    Ok(MipMap {
+      do_trilinear,
+      max_anisotropy,
+      wrap_mode,
+      width,
+      height,
+      num_levels: pyramid.len(),
+      pyramid
+    })
+  }
+
+  pub fn texel(&self, level: usize, s: i32, t: i32) -> T {
     let l = &self.pyramid[level];
+    let (us, vs) = (l.usize as i32, l.vsize as i32);
     let (ss, tt) = match self.wrap_mode {
-      Repeat => (mod_t(s, l.usize()), mod_t(t, l.vsize())),
-      Clamp  => (clamp(s, 0, l.usize() - 1), clamp(t, 0, l.vsize() - 1)),
-      Black  => fail!("not implemented")
+      ImageWrap::Repeat => (mod_t(s, us), mod_t(t, vs)),
+      ImageWrap::Clamp  => (clamp(s, 0, us - 1), clamp(t, 0, vs - 1)),
+      ImageWrap::Black  => {
+        if s < 0 || s >= us || t < 0 || t >= vs {
+          return T::zero();
+        }
+        (s, t)
+      }
     };
 
-    fail!("not implemented");
+    l.get(ss as usize, tt as usize)
   }
 
-  pub fn lookup_w(&self, s: f32, t: f32, w: f32) -> T {
-    fail!("not implemented");
+  /// Bilinearly interpolate the four texels around `(s, t)` in `level`
+  fn triangle(&self, level: usize, s: Float, t: Float) -> T {
+    let level = level.min(self.num_levels - 1);
+    let l = &self.pyramid[level];
+    let s = s * l.usize as Float - 0.5;
+    let t = t * l.vsize as Float - 0.5;
+    let (s0, t0) = (s.floor() as i32, t.floor() as i32);
+    let (ds, dt) = (s - s0 as Float, t - t0 as Float);
+
+    self.texel(level, s0, t0) * ((1.0 - ds) * (1.0 - dt)) +
+      self.texel(level, s0, t0 + 1) * ((1.0 - ds) * dt) +
+      self.texel(level, s0 + 1, t0) * (ds * (1.0 - dt)) +
+      self.texel(level, s0 + 1, t0 + 1) * (ds * dt)
   }
 
-  pub fn lookup(&self, s: f32, t: f32, ds0: f32, dt0: f32, ds1: f32, dt1: f32) -> T {
-    fail!("not implemented");
+  /// Trilinear lookup with an isotropic filter of the given `width`
+  pub fn lookup_w(&self, s: Float, t: Float, width: Float) -> T {
+    let level = (self.num_levels - 1) as Float + width.max(1e-8).log2();
+
+    if level < 0.0 {
+      self.triangle(0, s, t)
+    } else if level >= (self.num_levels - 1) as Float {
+      self.texel(self.num_levels - 1, 0, 0)
+    } else {
+      let ilevel = level.floor() as usize;
+      let delta = level - ilevel as Float;
+
+      self.triangle(ilevel, s, t) * (1.0 - delta) + self.triangle(ilevel + 1, s, t) * delta
+    }
   }
 
-  fn triangle(&self, level: uint, s: f32, t: f32) -> T {
-    fail!("not implemented");
+  /// Filtered lookup over the footprint spanned by the two differentials,
+  /// either trilinear or using an elliptically weighted average
+  pub fn lookup(&self, s: Float, t: Float, ds0: Float, dt0: Float, ds1: Float, dt1: Float) -> T {
+    if self.do_trilinear {
+      let width = 2.0 * ds0.abs().max(dt0.abs()).max(ds1.abs()).max(dt1.abs());
+      return self.lookup_w(s, t, width);
+    }
+
+    // Make (ds0, dt0) the major axis and clamp the ellipse eccentricity
+    let (mut ds0, mut dt0, mut ds1, mut dt1) = (ds0, dt0, ds1, dt1);
+    if ds0 * ds0 + dt0 * dt0 < ds1 * ds1 + dt1 * dt1 {
+      std::mem::swap(&mut ds0, &mut ds1);
+      std::mem::swap(&mut dt0, &mut dt1);
+    }
+
+    let major_length = (ds0 * ds0 + dt0 * dt0).sqrt();
+    let mut minor_length = (ds1 * ds1 + dt1 * dt1).sqrt();
+
+    if minor_length * self.max_anisotropy < major_length && minor_length > 0.0 {
+      let scale = major_length / (minor_length * self.max_anisotropy);
+      ds1 *= scale;
+      dt1 *= scale;
+      minor_length *= scale;
+    }
+
+    if minor_length == 0.0 {
+      return self.triangle(0, s, t);
+    }
+
+    let lod = ((self.num_levels - 1) as Float + minor_length.log2()).max(0.0);
+    let ilod = lod.floor() as usize;
+    let d = lod - ilod as Float;
+
+    self.ewa(ilod, s, t, ds0, dt0, ds1, dt1) * (1.0 - d) +
+      self.ewa(ilod + 1, s, t, ds0, dt0, ds1, dt1) * d
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  fn ewa(&self, level: usize, s: Float, t: Float, ds0: Float, dt0: Float, ds1: Float, dt1: Float) -> T {
+    if level >= self.num_levels {
+      return self.texel(self.num_levels - 1, 0, 0);
+    }
+
+    // Convert the ellipse into this level's texel space
+    let l = &self.pyramid[level];
+    let (us, vs) = (l.usize as Float, l.vsize as Float);
+    let s = s * us - 0.5;
+    let t = t * vs - 0.5;
+    let (ds0, dt0, ds1, dt1) = (ds0 * us, dt0 * vs, ds1 * us, dt1 * vs);
+
+    // Implicit ellipse coefficients, normalized so that the boundary is at 1
+    let a = dt0 * dt0 + dt1 * dt1 + 1.0;
+    let b = -2.0 * (ds0 * dt0 + ds1 * dt1);
+    let c = ds0 * ds0 + ds1 * ds1 + 1.0;
+    let inv_f = 1.0 / (a * c - b * b * 0.25);
+    let (a, b, c) = (a * inv_f, b * inv_f, c * inv_f);
+
+    let det = -b * b + 4.0 * a * c;
+    let inv_det = 1.0 / det;
+    let u_sqrt = (det * c).sqrt();
+    let v_sqrt = (a * det).sqrt();
+    let s0 = (s - 2.0 * inv_det * u_sqrt).ceil() as i32;
+    let s1 = (s + 2.0 * inv_det * u_sqrt).floor() as i32;
+    let t0 = (t - 2.0 * inv_det * v_sqrt).ceil() as i32;
+    let t1 = (t + 2.0 * inv_det * v_sqrt).floor() as i32;
+
+    let mut sum = T::zero();
+    let mut sum_wts = 0.0;
+
+    for it in t0..=t1 {
+      let tt = it as Float - t;
+      for is in s0..=s1 {
+        let ss = is as Float - s;
+        let r2 = a * ss * ss + b * ss * tt + c * tt * tt;
+
+        if r2 < 1.0 {
+          let weight = (-2.0 * r2).exp() - Float::exp(-2.0);
+          sum = sum + self.texel(level, is, it) * weight;
+          sum_wts += weight;
+        }
+      }
+    }
+
+    if sum_wts > 0.0 {
+      sum * (1.0 / sum_wts)
+    } else {
+      self.triangle(level, s, t)
+    }
   }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn new_rejects_mismatched_data() {
+    match MipMap::new(2, 2, vec![0.0 as Float; 3], true, 8.0, ImageWrap::Repeat) {
+      Err(RenderError::InvalidTexture(_)) => {},
+      _ => panic!("expected an invalid texture error")
+    }
+  }
+
+  #[test]
+  fn pyramid_averages_down_to_a_single_texel() {
+    let mipmap = MipMap::new(4, 2, (0..8).map(|x| x as Float).collect(),
+      true, 8.0, ImageWrap::Clamp).unwrap();
+
+    assert_eq!(mipmap.num_levels, 3);
+    assert_eq!(mipmap.texel(mipmap.num_levels - 1, 0, 0), 3.5);
+  }
+
+  #[test]
+  fn black_wrap_returns_zero_outside_the_image() {
+    let mipmap = MipMap::new(2, 2, vec![1.0 as Float; 4], false, 8.0, ImageWrap::Black).unwrap();
+
+    assert_eq!(mipmap.texel(0, -1, 0), 0.0);
+    assert_eq!(mipmap.texel(0, 1, 1), 1.0);
+  }
+
+  #[test]
+  fn lookups_of_a_constant_image_are_constant() {
+    let mipmap = MipMap::new(8, 8, vec![0.5 as Float; 64], false, 8.0, ImageWrap::Repeat).unwrap();
 
-  fn ewa(&self, level: uint, s: f32, t: f32, ds0: f32, dt0: f32, ds1: f32, dt1: f32) -> T {
-    fail!("not implemented");
+    assert!((mipmap.lookup_w(0.3, 0.7, 0.1) - 0.5).abs() < 1e-5);
+    assert!((mipmap.lookup(0.3, 0.7, 0.05, 0.0, 0.0, 0.02) - 0.5).abs() < 1e-5);
   }
 }
@@ -1,8 +1,6 @@
-use geometry::{ Vector };
-use std::f32;
-use std::slice;
+use crate::geometry::{ Float, PI, Vector, lerp };
 
-static primes : [uint, ..1000] = [
+static PRIMES : [usize; 1000] = [
      2,    3,    5,    7,   11,   13,   17,   19,   23,   29,
     31,   37,   41,   43,   47,   53,   59,   61,   67,   71,
     73,   79,   83,   89,   97,  101,  103,  107,  109,  113,
@@ -104,120 +102,145 @@ static primes : [uint, ..1000] = [
   7727, 7741, 7753, 7757, 7759, 7789, 7793, 7817, 7823, 7829,
   7841, 7853, 7867, 7873, 7877, 7879, 7883, 7901, 7907, 7919 ];
 
-pub static one_minus_epsilon : f32 = 0.9999999403953552;
+pub static ONE_MINUS_EPSILON : Float = 0.99999994;
 
 pub struct Distribution1D {
-  func:     ~[f32],
-  cdf:      ~[f32],
-  func_int: f32,
-  count:    uint
+  func:     Vec<Float>,
+  cdf:      Vec<Float>,
+  func_int: Float,
+  count:    usize
 }
 
 impl Distribution1D {
-  pub fn sample_discrete(&self, u: f32) -> (uint, f32) {
-    let offset = match self.cdf.iter().position(|&x| x > u) {
-      None  => 0,
-      Some(x) => x
-    };
+  pub fn sample_discrete(&self, u: Float) -> (usize, Float) {
+    let offset = self.cdf.iter().position(|&x| x > u).unwrap_or(0);
 
-    let pdf = self.func[offset] / (self.func_int * self.count as f32);
+    let pdf = self.func[offset] / (self.func_int * self.count as Float);
 
     (offset, pdf)
   }
 }
 
 pub struct PermutedHalton {
-  dims: uint,
-  b: ~[uint],
-  permute: ~[uint]
+  pub dims: usize,
+  pub b: Vec<usize>,
+  pub permute: Vec<usize>
 }
 
 impl PermutedHalton {
-  pub fn new(d: uint) -> PermutedHalton {
-    let mut x = Vec::from_elem(d, 0u);
-    let mut b = x.as_mut_slice();
-    let mut sum_bases = 0;
-
-    for i in range(0, d) {
-      b[i] = primes[i];
-      sum_bases += b[i];
-    }
-
-    let mut y = Vec::from_elem(sum_bases, 0u);
-    let mut permute = y.as_mut_slice();
-    let mut idx = 0;
+  pub fn new(d: usize) -> PermutedHalton {
+    let b = PRIMES[..d].to_vec();
+    let sum_bases = b.iter().sum();
+    let permute = vec![0usize; sum_bases];
 
-    for i in range(0, d) {
-      // generate_permutation(p, b[i], rng);
-      idx += b[i];
-    }
+    // for i in range(0, d) { generate_permutation(p, b[i], rng); }
 
-    PermutedHalton { dims: d, b: b.to_owned(), permute: permute.to_owned() }
+    PermutedHalton { dims: d, b, permute }
   }
 }
 
-pub fn stratified_sample_1D(samp: &mut [f32], num_samples: uint, jitter: bool) {
-  let inv_tot = 1.0 / num_samples as f32;
+pub fn stratified_sample_1d(samp: &mut [Float], num_samples: usize, jitter: bool) {
+  let inv_tot = 1.0 / num_samples as Float;
 
-  for i in range(0, num_samples) {
+  for (i, s) in samp.iter_mut().enumerate().take(num_samples) {
     let delta = if jitter { 1.0 } else { 0.5 };
-    samp[i] = ((i as f32 + delta) * inv_tot).min(0.9);
+    *s = ((i as Float + delta) * inv_tot).min(0.9);
   }
 }
 
-pub fn uniform_sample_hemisphere(u1: f32, u2: f32) -> Vector {
+pub fn uniform_sample_hemisphere(u1: Float, u2: Float) -> Vector {
   let z = u1;
-  let r = 0.0f32.max(1.0 - z * z).sqrt();
-  let phi = 2.0 * f32::consts::PI * u2;
+  let r = Float::max(0.0, 1.0 - z * z).sqrt();
+  let phi = 2.0 * PI * u2;
   let x = r * phi.cos();
   let y = r * phi.sin();
 
   Vector::new(x, y, z)
 }
 
-pub fn uniform_sample_sphere(u1: f32, u2: f32) -> Vector {
+pub fn uniform_sample_sphere(u1: Float, u2: Float) -> Vector {
   let z = 1.0 - 2.0 * u1;
-  let r = 0.0f32.max(1.0 - z * z);
-  let phi = 2.0 * f32::consts::PI * u2;
+  let r = Float::max(0.0, 1.0 - z * z).sqrt();
+  let phi = 2.0 * PI * u2;
   let x = r * phi.cos();
   let y = r * phi.sin();
 
   Vector::new(x, y, z)
 }
 
-pub fn uniform_sphere_pdf() -> f32 {
-  1.0 / (4.0 * f32::consts::PI)
+pub fn uniform_sphere_pdf() -> Float {
+  1.0 / (4.0 * PI)
 }
 
-pub fn uniform_sample_disk(u1: f32, u2: f32) -> (f32, f32) {
+pub fn uniform_sample_disk(u1: Float, u2: Float) -> (Float, Float) {
   let r = u1.sqrt();
-  let t = 2.0 * f32::consts::PI * u2;
+  let t = 2.0 * PI * u2;
   let x = r * t.cos();
   let y = r * t.sin();
 
   (x, y)
 }
 
-pub fn uniform_sample_triangle(u1: f32, u2: f32) -> (f32, f32) {
+pub fn uniform_sample_triangle(u1: Float, u2: Float) -> (Float, Float) {
   let su1 = u1.sqrt();
 
   (1.0 - su1, u2 * su1)
 }
 
-pub fn uniform_sample_cone(u1: f32, u2: f32, costhetamax: f32) -> Vector {
+pub fn uniform_sample_cone(u1: Float, u2: Float, costhetamax: Float) -> Vector {
   let costheta = (1.0 - u1) + u1 * costhetamax;
   let sintheta = (1.0 - costheta * costheta).sqrt();
-  let phi    = u2 * 2.0 * f32::consts::PI;
+  let phi    = u2 * 2.0 * PI;
 
   Vector::new(phi.cos() * sintheta, phi.sin() * sintheta, costheta)
 }
 
-pub fn concentric_sample_disk(u1: f32, u2: f32) -> (f32, f32) {
+/// `uniform_sample_cone` around the `z` axis of the frame `x`, `y`, `z`
+pub fn uniform_sample_cone_frame(u1: Float, u2: Float, costhetamax: Float,
+    x: &Vector, y: &Vector, z: &Vector) -> Vector {
+  let v = uniform_sample_cone(u1, u2, costhetamax);
+
+  *x * v.x + *y * v.y + *z * v.z
+}
+
+pub fn uniform_cone_pdf(costhetamax: Float) -> Float {
+  1.0 / (2.0 * PI * (1.0 - costhetamax))
+}
+
+/// Sample `x` in `[0, 1)` with a density that varies linearly from `a` to `b`
+pub fn sample_linear(u: Float, a: Float, b: Float) -> Float {
+  if u == 0.0 && a == 0.0 {
+    return 0.0;
+  }
+
+  let x = u * (a + b) / (a + lerp(u, a * a, b * b).sqrt());
+  x.min(ONE_MINUS_EPSILON)
+}
+
+/// Sample the unit square with a density interpolating the corner weights
+/// `w`, given in the order `(0, 0)`, `(1, 0)`, `(0, 1)`, `(1, 1)`
+pub fn sample_bilinear(u1: Float, u2: Float, w: &[Float; 4]) -> (Float, Float) {
+  let y = sample_linear(u2, w[0] + w[1], w[2] + w[3]);
+  let x = sample_linear(u1, lerp(y, w[0], w[2]), lerp(y, w[1], w[3]));
+
+  (x, y)
+}
+
+pub fn bilinear_pdf(x: Float, y: Float, w: &[Float; 4]) -> Float {
+  let sum = w[0] + w[1] + w[2] + w[3];
+  if sum == 0.0 {
+    return 1.0;
+  }
+
+  4.0 * ((1.0 - x) * (1.0 - y) * w[0] + x * (1.0 - y) * w[1] + (1.0 - x) * y * w[2] + x * y * w[3]) / sum
+}
+
+pub fn concentric_sample_disk(u1: Float, u2: Float) -> (Float, Float) {
   let sx = 2.0 * u1 - 1.0;
   let sy = 2.0 * u2 - 1.0;
 
-  let mut r : f32;
-  let mut t : f32;
+  let r : Float;
+  let mut t : Float;
 
   if sx == 0.0 && sy == 0.0 {
     return (0.0, 0.0);
@@ -231,34 +254,32 @@ pub fn concentric_sample_disk(u1: f32, u2: f32) -> (f32, f32) {
       r = sy;
       t = 2.0 - sx / r;
     }
+  } else if sx <= sy {
+    r = -sx;
+    t = 4.0 - sy / r;
   } else {
-    if sx <= sy {
-      r = -sx;
-      t = 4.0 - sy / r;
-    } else {
-      r = -sy;
-      t = 6.0 + sx / r;
-    }
+    r = -sy;
+    t = 6.0 + sx / r;
   }
 
-  t *= f32::consts::PI / 4.0;
+  t *= PI / 4.0;
 
   (r * t.cos(), r * t.sin())
 }
 
-pub fn van_der_corput(n: uint, scramble: uint) -> f32 {
-  let mut x = (n << 16) | (n >> 16);
+pub fn van_der_corput(n: u32, scramble: u32) -> Float {
+  let mut x = n.rotate_left(16);
   x = ((x & 0x00ff00ff) << 8) | ((x & 0xff00ff00) >> 8);
   x = ((x & 0x0f0f0f0f) << 4) | ((x & 0xf0f0f0f0) >> 4);
   x = ((x & 0x33333333) << 2) | ((x & 0xcccccccc) >> 2);
   x = ((x & 0x55555555) << 1) | ((x & 0xaaaaaaaa) >> 8);
   x ^= scramble;
 
-  (((x >> 8) & 0xffffff) as f32 / (1 << 24) as f32).min(0.9)
+  (((x >> 8) & 0xffffff) as Float / (1 << 24) as Float).min(0.9)
 }
 
-pub fn sobol2(n: uint, scramble: uint) -> f32 {
-  let mut v = 1 << 31;
+pub fn sobol2(n: u32, scramble: u32) -> Float {
+  let mut v: u32 = 1 << 31;
   let mut m = n;
   let mut s = scramble;
 
@@ -270,9 +291,9 @@ pub fn sobol2(n: uint, scramble: uint) -> f32 {
     v ^= v >> 1;
   }
 
-  one_minus_epsilon.min(((s >> 8) & 0xffffff) as f32 / (1 << 24) as f32)
+  ONE_MINUS_EPSILON.min(((s >> 8) & 0xffffff) as Float / (1 << 24) as Float)
 }
 
-pub fn sample02(n: uint, scramble: (uint, uint), sample: &mut (f32, f32)) {
-  *sample = (van_der_corput(n, scramble.val0()), sobol2(n, scramble.val1()));
+pub fn sample02(n: u32, scramble: (u32, u32), sample: &mut (Float, Float)) {
+  *sample = (van_der_corput(n, scramble.0), sobol2(n, scramble.1));
 }
@@ -1,19 +1,25 @@
-use geometry::{ BBox, Point, distance_squared };
+use crate::geometry::{ Float, BBox, Point, distance_squared };
 
-#[deriving(Clone)]
+#[derive(Clone)]
 pub struct OctNode<T> {
   pub children: Vec<Option<OctNode<T>>>,
   pub data:     Vec<T>
 }
 
+impl<T: Clone> Default for OctNode<T> {
+  fn default() -> OctNode<T> {
+    OctNode::new()
+  }
+}
+
 impl<T: Clone> OctNode<T> {
   pub fn new() -> OctNode<T> {
-    OctNode { children: Vec::from_elem(8, None), data: Vec::new() }
+    OctNode { children: vec![None; 8], data: Vec::new() }
   }
 }
 
 pub struct Octree<T> {
-  pub max_depth: uint,
+  pub max_depth: usize,
   pub bound:   BBox,
   pub root:    OctNode<T>
 }
@@ -23,7 +29,7 @@ impl<T: Clone> Octree<T> {
     Octree::with_depth(b, 16)
   }
 
-  pub fn with_depth(b: BBox, d: uint) -> Octree<T> {
+  pub fn with_depth(b: BBox, d: usize) -> Octree<T> {
     Octree { bound: b, max_depth: d, root: OctNode::new() }
   }
 
@@ -32,14 +38,14 @@ impl<T: Clone> Octree<T> {
       distance_squared(&data_bound.p_min, &data_bound.p_max), 0);
   }
 
-  pub fn lookup(&self, p: &Point, process: |&T| -> bool) {
+  pub fn lookup<F>(&self, p: &Point, mut process: F) where F: FnMut(&T) -> bool {
     if self.bound.inside(p) {
-      self.lookup_private(&self.root, &self.bound, p, process);
+      self.lookup_private(&self.root, &self.bound, p, &mut process);
     }
   }
 
-  fn add_private(max_depth: uint, node: &mut OctNode<T>, node_bound: &BBox,
-    data_item: T, data_bound: &BBox, diag2: f32, depth: uint) {
+  fn add_private(max_depth: usize, node: &mut OctNode<T>, node_bound: &BBox,
+    data_item: T, data_bound: &BBox, diag2: Float, depth: usize) {
 
     if depth == max_depth || distance_squared(&node_bound.p_min, &node_bound.p_max) < diag2 {
       node.data.push(data_item);
@@ -63,27 +69,19 @@ impl<T: Clone> Octree<T> {
       x[1] && y[1] && z[1]
     ];
 
-    for i in range(0u, 8) {
+    for (i, child) in node.children.iter_mut().enumerate() {
       if !over[i] {
         continue;
       }
 
-      match *node.children.get(i) {
-        None => {
-          let mut n = OctNode::new();
-
-          let child_bound = octree_child_bound(i, node_bound, &pmid);
-          Octree::add_private(max_depth, &mut n,
-            &child_bound, data_item.clone(), data_bound, diag2, depth + 1);
-
-          node.children.grow_set(i, &Some(OctNode::new()), Some(n));
-        },
-        _  => ()
-      };
+      let child_bound = octree_child_bound(i, node_bound, &pmid);
+      Octree::add_private(max_depth, child.get_or_insert_with(OctNode::new),
+        &child_bound, data_item.clone(), data_bound, diag2, depth + 1);
     }
   }
 
-  fn lookup_private(&self, node: &OctNode<T>, node_bound: &BBox, p: &Point, process: |&T| -> bool) -> bool {
+  fn lookup_private<F>(&self, node: &OctNode<T>, node_bound: &BBox, p: &Point,
+      process: &mut F) -> bool where F: FnMut(&T) -> bool {
     for d in node.data.iter() {
       if process(d) {
         return false;
@@ -96,11 +94,7 @@ impl<T: Clone> Octree<T> {
       if p.y > pmid.y { 2 } else { 0 } +
       if p.z > pmid.z { 1 } else { 0 };
 
-    if node.children.get(child).is_none() {
-      return true;
-    }
-
-    match *node.children.get(child) {
+    match node.children[child] {
       None    => true,
       Some(ref x) => {
         let child_bound = octree_child_bound(child, node_bound, &pmid);
@@ -110,8 +104,8 @@ impl<T: Clone> Octree<T> {
   }
 }
 
-fn octree_child_bound(child: uint, node_bound: &BBox, pmid: &Point) -> BBox {
-  let mut child_bound = node_bound.clone();
+fn octree_child_bound(child: usize, node_bound: &BBox, pmid: &Point) -> BBox {
+  let mut child_bound = *node_bound;
 
   child_bound.p_min.x = if (child & 4) != 0 { pmid.x } else { node_bound.p_min.x };
   child_bound.p_min.y = if (child & 2) != 0 { pmid.y } else { node_bound.p_min.y };
@@ -121,5 +115,5 @@ fn octree_child_bound(child: uint, node_bound: &BBox, pmid: &Point) -> BBox {
   child_bound.p_max.y = if (child & 2) != 0 { node_bound.p_max.y } else { pmid.y };
   child_bound.p_max.z = if (child & 1) != 0 { node_bound.p_max.z } else { pmid.z };
 
-  return child_bound;
+  child_bound
 }
@@ -1,32 +1,102 @@
-use geometry::{ Point, Vector, Normal };
+use std::cell::Cell;
 
-#[deriving(Clone)]
+use crate::error::RenderError;
+use crate::geometry::{ Float, Point, Vector, Normal };
+
+#[derive(Clone)]
 pub struct ParamSetItem<T> {
-  name: String,
-  data: Vec<T>,
-  looked_up: bool
+  pub name: String,
+  pub data: Vec<T>,
+  pub looked_up: Cell<bool>
 }
 
+#[derive(Default)]
 pub struct ParamSet {
-  bools: Vec<ParamSetItem<bool>>,
-  ints: Vec<ParamSetItem<int>>,
-  floats: Vec<ParamSetItem<f32>>,
-  points: Vec<ParamSetItem<Point>>,
-  vectors: Vec<ParamSetItem<Vector>>,
-  normals: Vec<ParamSetItem<Normal>>
+  pub bools: Vec<ParamSetItem<bool>>,
+  pub ints: Vec<ParamSetItem<i32>>,
+  pub floats: Vec<ParamSetItem<Float>>,
+  pub points: Vec<ParamSetItem<Point>>,
+  pub vectors: Vec<ParamSetItem<Vector>>,
+  pub normals: Vec<ParamSetItem<Normal>>
 }
 
 impl ParamSet {
-  pub fn add_float(&mut self, name: &String, data: Vec<f32>) {
+  pub fn new() -> ParamSet {
+    ParamSet::default()
+  }
+
+  pub fn add_int(&mut self, name: &str, data: Vec<i32>) {
+    self.erase_int(name);
+    self.ints.push(ParamSetItem { name: name.to_string(), data, looked_up: Cell::new(false) });
+  }
+
+  pub fn erase_int(&mut self, name: &str) {
+    self.ints.retain(|x| x.name != name);
+  }
+
+  pub fn add_float(&mut self, name: &str, data: Vec<Float>) {
     self.erase_float(name);
-    self.floats.push(ParamSetItem { name: name.clone(), data: data, looked_up: false });
+    self.floats.push(ParamSetItem { name: name.to_string(), data, looked_up: Cell::new(false) });
+  }
+
+  pub fn erase_float(&mut self, name: &str) {
+    self.floats.retain(|x| x.name != name);
+  }
+
+  /// Look up a single integer parameter, falling back to `default` if
+  /// it is not present. Fails if the parameter holds more than one value.
+  pub fn find_one_int(&self, name: &str, default: i32) -> Result<i32, RenderError> {
+    find_one(&self.ints, name, default)
   }
 
-  pub fn erase_float(&mut self, name: &String) {
-    self.floats.retain(|x| x.name != *name);
+  /// Look up a single float parameter, falling back to `default` if
+  /// it is not present. Fails if the parameter holds more than one value.
+  pub fn find_one_float(&self, name: &str, default: Float) -> Result<Float, RenderError> {
+    find_one(&self.floats, name, default)
   }
+}
+
+fn find_one<T: Copy>(items: &[ParamSetItem<T>], name: &str, default: T) -> Result<T, RenderError> {
+  match items.iter().find(|x| x.name == name) {
+    None => Ok(default),
+    Some(item) if item.data.len() == 1 => {
+      item.looked_up.set(true);
+      Ok(item.data[0])
+    },
+    Some(item) => Err(RenderError::InvalidParameter {
+      name: name.to_string(),
+      message: format!("expected a single value, found {}", item.data.len())
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn find_one_int_falls_back_to_default() {
+    let params = ParamSet::new();
+    assert_eq!(params.find_one_int("maxdepth", 5).unwrap(), 5);
+  }
+
+  #[test]
+  fn find_one_int_marks_parameter_as_used() {
+    let mut params = ParamSet::new();
+    params.add_int("maxdepth", vec![3]);
+
+    assert_eq!(params.find_one_int("maxdepth", 5).unwrap(), 3);
+    assert!(params.ints[0].looked_up.get());
+  }
+
+  #[test]
+  fn find_one_float_rejects_arrays() {
+    let mut params = ParamSet::new();
+    params.add_float("radius", vec![1.0, 2.0]);
 
-  pub fn find_one_int(&self, name: &str, default: int) -> int {
-    fail!("not implemented")
+    match params.find_one_float("radius", 1.0) {
+      Err(RenderError::InvalidParameter { name, .. }) => assert_eq!(name, "radius"),
+      _ => panic!("expected an invalid parameter error")
+    }
   }
 }
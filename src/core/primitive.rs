@@ -1,12 +1,370 @@
-use diffgeom::DifferentialGeometry;
-use geometry::Ray;
-use light::AreaLight;
-use reflection::{ Bsdf, Bssrdf };
-use transform::Transform;
+use std::rc::{ Rc, Weak };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::diffgeom::DifferentialGeometry;
+use crate::error::RenderError;
+use crate::geometry::{ BBox, Ray, normalize };
+use crate::intersection::Intersection;
+use crate::light::AreaLight;
+use crate::material::Material;
+use crate::reflection::{ Bsdf, Bssrdf };
+use crate::shape::Shape;
+use crate::transform::{ AnimatedTransform, Applicable, Transform };
+
+static NEXT_PRIMITIVE_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Unique id for a newly created primitive
+pub fn next_primitive_id() -> usize {
+  NEXT_PRIMITIVE_ID.fetch_add(1, Ordering::Relaxed)
+}
 
 pub trait Primitive {
+  /// Find the closest hit along `ray`, shrinking `ray.maxt` to its
+  /// distance. Aggregates set `primitive` on the intersection to the
+  /// child that was hit.
+  fn intersect(&self, ray: &mut Ray) -> Option<Intersection>;
   fn intersect_p(&self, ray: &Ray) -> bool;
+  fn world_bound(&self) -> BBox;
+  /// BSDF at a hit on this primitive. Aggregates return `None`, the
+  /// intersections they report name the primitive that was hit.
   fn get_bsdf(&self, dg: &DifferentialGeometry, object_to_world: &Transform) -> Option<Bsdf>;
   fn get_bssrdf(&self, dg: &DifferentialGeometry, object_to_world: &Transform) -> Option<Bssrdf>;
-  fn get_area_light(&self) -> Option<Box<AreaLight>>;
+  fn get_area_light(&self) -> Option<Rc<dyn AreaLight>>;
+
+  fn can_intersect(&self) -> bool { true }
+
+  /// Split a primitive that cannot be intersected directly, e.g. one
+  /// holding a subdivision surface, into simpler primitives
+  fn refine(&self) -> Result<Vec<Rc<dyn Primitive>>, RenderError> {
+    Err(RenderError::InvalidScene("primitive cannot be refined".to_string()))
+  }
+}
+
+/// Refine `primitive` until only directly intersectable primitives remain.
+/// Aggregates run this over their primitives when they are built, so
+/// refinement only happens for geometry that ends up in an accelerator. It
+/// fails on a primitive that can neither be intersected nor refined.
+pub fn fully_refine(primitive: Rc<dyn Primitive>) -> Result<Vec<Rc<dyn Primitive>>, RenderError> {
+  let mut todo = vec![primitive];
+  let mut refined = Vec::new();
+
+  while let Some(p) = todo.pop() {
+    if p.can_intersect() {
+      refined.push(p);
+    } else {
+      todo.extend(p.refine()?);
+    }
+  }
+
+  Ok(refined)
+}
+
+/// A shape together with the material it is shaded with and the area
+/// light it emits through, if any
+pub struct GeometricPrimitive {
+  pub shape: Rc<dyn Shape>,
+  pub material: Rc<dyn Material>,
+  pub area_light: Option<Rc<dyn AreaLight>>,
+  pub primitive_id: usize,
+  this: Weak<GeometricPrimitive>
+}
+
+impl GeometricPrimitive {
+  /// Created inside an `Rc`, so that its intersections can refer back to it
+  pub fn new(shape: Rc<dyn Shape>, material: Rc<dyn Material>,
+      area_light: Option<Rc<dyn AreaLight>>) -> Rc<GeometricPrimitive> {
+    Rc::new_cyclic(|this| GeometricPrimitive {
+      shape,
+      material,
+      area_light,
+      primitive_id: next_primitive_id(),
+      this: this.clone()
+    })
+  }
+}
+
+impl Primitive for GeometricPrimitive {
+  fn intersect(&self, ray: &mut Ray) -> Option<Intersection> {
+    let (t_hit, p_error, mut dg) = self.shape.intersect(ray)?;
+    dg.shape = Some(self.shape.clone());
+    ray.maxt = t_hit;
+
+    let base = self.shape.get_base();
+    let mut isect = Intersection::new(dg, p_error, &base.world_to_object, &base.object_to_world,
+      base.shape_id, self.primitive_id);
+    isect.primitive = self.this.upgrade().map(|p| p as Rc<dyn Primitive>);
+
+    Some(isect)
+  }
+
+  fn intersect_p(&self, ray: &Ray) -> bool {
+    self.shape.intersect_p(ray)
+  }
+
+  fn world_bound(&self) -> BBox {
+    self.shape.world_bound()
+  }
+
+  fn get_bsdf(&self, dg: &DifferentialGeometry, object_to_world: &Transform) -> Option<Bsdf> {
+    let dg_shading = self.shape.get_shading_geometry(object_to_world, dg);
+    Some(self.material.get_bsdf(dg, &dg_shading))
+  }
+
+  fn get_bssrdf(&self, dg: &DifferentialGeometry, object_to_world: &Transform) -> Option<Bssrdf> {
+    let dg_shading = self.shape.get_shading_geometry(object_to_world, dg);
+    self.material.get_bssrdf(dg, &dg_shading)
+  }
+
+  fn get_area_light(&self) -> Option<Rc<dyn AreaLight>> {
+    self.area_light.clone()
+  }
+
+  fn can_intersect(&self) -> bool {
+    self.shape.can_intersect()
+  }
+
+  /// The pieces of the shape, sharing this primitive's material and light
+  fn refine(&self) -> Result<Vec<Rc<dyn Primitive>>, RenderError> {
+    Ok(self.shape.refine()?.into_iter()
+      .map(|s| GeometricPrimitive::new(s, self.material.clone(), self.area_light.clone()) as Rc<dyn Primitive>)
+      .collect())
+  }
+}
+
+/// Instance of a primitive, usually an aggregate shared by many
+/// instances, placed in the world by its own transform. Hits carry the
+/// id of the instance, so that instances of one primitive can be told
+/// apart, while their `primitive` is still the one inside the instance,
+/// which supplies the material.
+pub struct TransformedPrimitive {
+  pub primitive: Rc<dyn Primitive>,
+  pub primitive_to_world: AnimatedTransform,
+  pub primitive_id: usize
+}
+
+impl TransformedPrimitive {
+  pub fn new(primitive: Rc<dyn Primitive>, primitive_to_world: AnimatedTransform) -> TransformedPrimitive {
+    TransformedPrimitive { primitive, primitive_to_world, primitive_id: next_primitive_id() }
+  }
+}
+
+impl Primitive for TransformedPrimitive {
+  fn intersect(&self, ray: &mut Ray) -> Option<Intersection> {
+    let p2w = self.primitive_to_world.interpolate(ray.time);
+    let w2p = Transform::inverse(&p2w);
+    let mut r: Ray = w2p.apply(*ray);
+
+    let mut isect = self.primitive.intersect(&mut r)?;
+    ray.maxt = r.maxt;
+    isect.primitive_id = self.primitive_id;
+
+    if !p2w.is_identity() {
+      isect.world_to_object = isect.world_to_object * w2p;
+      isect.object_to_world = p2w * isect.object_to_world;
+
+      let dg = &mut isect.dg;
+      (dg.p, isect.p_error) = p2w.apply_point_with_abs_error(&dg.p, &isect.p_error);
+      dg.nn = normalize(p2w.apply(dg.nn));
+      dg.dpdu = p2w.apply(dg.dpdu);
+      dg.dpdv = p2w.apply(dg.dpdv);
+      dg.dndu = p2w.apply(dg.dndu);
+      dg.dndv = p2w.apply(dg.dndv);
+    }
+
+    Some(isect)
+  }
+
+  fn intersect_p(&self, ray: &Ray) -> bool {
+    let w2p = Transform::inverse(&self.primitive_to_world.interpolate(ray.time));
+    self.primitive.intersect_p(&w2p.apply(*ray))
+  }
+
+  fn world_bound(&self) -> BBox {
+    self.primitive_to_world.motion_bounds(&self.primitive.world_bound())
+  }
+
+  fn get_bsdf(&self, _dg: &DifferentialGeometry, _object_to_world: &Transform) -> Option<Bsdf> {
+    None
+  }
+
+  fn get_bssrdf(&self, _dg: &DifferentialGeometry, _object_to_world: &Transform) -> Option<Bssrdf> {
+    None
+  }
+
+  fn get_area_light(&self) -> Option<Rc<dyn AreaLight>> {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::{ Float, Normal, Point, RayDifferential, Vector };
+  use crate::shape::{ ShapeBase, ShapeIntersection };
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  /// Square `x0..x1` by `-1..1` in the plane `z = 0`, split in halves
+  /// along x when it is not intersectable
+  struct Square {
+    base: ShapeBase,
+    x0: Float,
+    x1: Float,
+    whole: bool
+  }
+
+  fn square(x0: Float, x1: Float, whole: bool) -> Rc<dyn Shape> {
+    Rc::new(Square { base: ShapeBase::new(&identity(), &identity(), false), x0, x1, whole })
+  }
+
+  impl Shape for Square {
+    fn get_base(&self) -> &ShapeBase {
+      &self.base
+    }
+
+    fn object_bound(&self) -> BBox {
+      BBox::new(&Point::new(self.x0, -1.0, 0.0), &Point::new(self.x1, 1.0, 0.0))
+    }
+
+    fn area(&self) -> Float {
+      2.0 * (self.x1 - self.x0)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<ShapeIntersection> {
+      let t = -ray.o.z / ray.d.z;
+      let p = ray.apply(t);
+      if !(t > ray.mint && t < ray.maxt && p.x >= self.x0 && p.x <= self.x1 && p.y.abs() <= 1.0) {
+        return None;
+      }
+
+      let dg = DifferentialGeometry::from_shape(p, Vector::new(1.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0),
+        Normal::zero(), Normal::zero(), p.x, p.y, Some(self));
+      Some((t, Vector::zero(), dg))
+    }
+
+    /// Marks the shading geometry, so the material can tell it apart
+    fn get_shading_geometry(&self, _o2w: &Transform, dg: &DifferentialGeometry) -> DifferentialGeometry {
+      let mut shading = dg.clone();
+      shading.u = 42.0;
+      shading
+    }
+
+    fn can_intersect(&self) -> bool {
+      self.whole
+    }
+
+    fn refine(&self) -> Result<Vec<Rc<dyn Shape>>, RenderError> {
+      let mid = 0.5 * (self.x0 + self.x1);
+      Ok(vec![square(self.x0, mid, true), square(mid, self.x1, true)])
+    }
+  }
+
+  /// Material keeping the geometry it was given in an empty BSDF
+  struct Probe;
+
+  impl Material for Probe {
+    fn get_bsdf(&self, dg_geom: &DifferentialGeometry, dg_shading: &DifferentialGeometry) -> Bsdf {
+      Bsdf {
+        dg_shading: dg_shading.clone(),
+        eta: 1.0,
+        nn: dg_shading.nn,
+        ng: dg_geom.nn,
+        sn: Vector::new(1.0, 0.0, 0.0),
+        tn: Vector::new(0.0, 1.0, 0.0),
+        nbxdfs: 0,
+        bxdfs: Default::default()
+      }
+    }
+  }
+
+  fn down(x: Float) -> Ray {
+    Ray::new(&Point::new(x, 0.0, 5.0), &Vector::new(0.0, 0.0, -1.0), 0.0, Float::INFINITY, 0.0)
+  }
+
+  #[test]
+  fn intersection_refers_back_to_the_primitive() {
+    let shape = square(-1.0, 1.0, true);
+    let primitive = GeometricPrimitive::new(shape.clone(), Rc::new(Probe), None);
+
+    let mut ray = down(0.5);
+    let mut isect = primitive.intersect(&mut ray).unwrap();
+    assert_eq!(ray.maxt, 5.0);
+    assert_eq!(isect.shape_id, shape.get_base().shape_id);
+    assert_eq!(isect.primitive_id, primitive.primitive_id);
+    assert!(Rc::ptr_eq(isect.dg.shape.as_ref().unwrap(), &shape));
+    assert!(isect.le(&Vector::new(0.0, 0.0, 1.0)).is_black());
+
+    // The BSDF is built by the material from the shading geometry
+    let bsdf = isect.get_bsdf(&RayDifferential::new(&ray)).unwrap();
+    assert_eq!(bsdf.dg_shading.u, 42.0);
+    assert_eq!(bsdf.ng, isect.dg.nn);
+
+    assert!(!primitive.intersect_p(&down(1.5)));
+    assert_eq!(primitive.world_bound().p_max.x, 1.0);
+  }
+
+  #[test]
+  fn refine_shares_the_material() {
+    let primitive = GeometricPrimitive::new(square(-1.0, 1.0, false), Rc::new(Probe), None);
+    assert!(!primitive.can_intersect());
+
+    let refined = fully_refine(primitive).unwrap();
+    assert_eq!(refined.len(), 2);
+    assert!(refined.iter().all(|p| p.can_intersect()));
+
+    let mut ray = down(-0.5);
+    let mut hits: Vec<Intersection> = refined.iter().filter_map(|p| p.intersect(&mut ray)).collect();
+    assert_eq!(hits.len(), 1);
+    assert!(hits[0].get_bsdf(&RayDifferential::new(&ray)).is_some());
+  }
+
+  /// Shape that cannot be intersected and leaves `refine` to the default
+  struct Unrefinable(ShapeBase);
+
+  impl Shape for Unrefinable {
+    fn get_base(&self) -> &ShapeBase { &self.0 }
+    fn object_bound(&self) -> BBox { BBox::from_point(&Point::zero()) }
+    fn area(&self) -> Float { 0.0 }
+    fn intersect(&self, _ray: &Ray) -> Option<ShapeIntersection> { None }
+    fn can_intersect(&self) -> bool { false }
+  }
+
+  #[test]
+  fn refining_without_refine_is_an_error() {
+    let shape = Rc::new(Unrefinable(ShapeBase::new(&identity(), &identity(), false)));
+    let primitive = GeometricPrimitive::new(shape, Rc::new(Probe), None);
+
+    assert!(matches!(fully_refine(primitive), Err(RenderError::InvalidScene(_))));
+  }
+
+  #[test]
+  fn instances_share_their_primitive() {
+    let shared: Rc<dyn Primitive> = GeometricPrimitive::new(square(-1.0, 1.0, true), Rc::new(Probe), None);
+    let moved = TransformedPrimitive::new(shared.clone(),
+      AnimatedTransform::fixed(&Transform::translate(&Vector::new(3.0, 0.0, 0.0))));
+    let p2w = Transform::translate(&Vector::new(0.0, 0.0, 2.0)) * Transform::scale(2.0, 2.0, 2.0);
+    let scaled = TransformedPrimitive::new(shared.clone(), AnimatedTransform::fixed(&p2w));
+
+    let mut ray = down(3.5);
+    let isect = moved.intersect(&mut ray).unwrap();
+    assert!((ray.maxt - 5.0).abs() < 1e-4);
+    assert!((isect.dg.p.x - 3.5).abs() < 1e-4 && isect.dg.p.z.abs() <= isect.p_error.z.max(1e-4));
+    assert_eq!(isect.primitive_id, moved.primitive_id);
+    assert!(Rc::ptr_eq(isect.primitive.as_ref().unwrap(), &shared));
+    assert_ne!(scaled.intersect(&mut down(0.5)).unwrap().primitive_id, isect.primitive_id);
+    assert!(!moved.intersect_p(&down(0.0)));
+
+    let mut ray = down(1.5);
+    let mut isect = scaled.intersect(&mut ray).unwrap();
+    assert!((ray.maxt - 3.0).abs() < 1e-4);
+    assert!((isect.dg.p.z - 2.0).abs() < 1e-4);
+    assert!((isect.dg.nn.z - 1.0).abs() < 1e-5);
+    assert!((isect.dg.dpdu.x - 2.0).abs() < 1e-5);
+    assert!(isect.get_bsdf(&RayDifferential::new(&ray)).is_some());
+
+    assert_eq!(moved.world_bound().p_max.x, 4.0);
+    assert_eq!(scaled.world_bound().p_max.x, 2.0);
+  }
 }
@@ -1,37 +1,36 @@
-use std::io;
+use std::io::{ self, Write };
 use std::cmp::max;
 
+use crate::geometry::Float;
+
 pub struct ProgressReporter {
-  total_work: uint,
-  work_done: uint,
-  plusses_printed: uint,
-  total_plusses: uint,
+  total_work: usize,
+  work_done: usize,
+  total_plusses: usize,
   title: String
 }
 
 impl ProgressReporter {
-  pub fn new(tw: uint, bar_length: uint, title: String) -> ProgressReporter {
-    let total_plusses = max(2, bar_length - title.len());
-    let plusses_printed = 0;
-    let work_done = 0;
+  pub fn new(tw: usize, bar_length: usize, title: String) -> ProgressReporter {
+    let total_plusses = max(2, bar_length.saturating_sub(title.len()));
 
     let mut buf = format!("\r{}: [", title);
 
-    for i in range(0, total_plusses) {
-      buf = buf.append(" ");
+    for _ in 0..total_plusses {
+      buf.push(' ');
     }
 
-    buf = buf.append("] ");
+    buf.push_str("] ");
 
     print!("{}", buf);
 
-    return ProgressReporter { total_work: tw, work_done: 0, plusses_printed: 0, total_plusses: total_plusses, title: title }
+    ProgressReporter { total_work: tw, work_done: 0, total_plusses, title }
   }
 
-  pub fn update(&mut self, num: uint) {
+  pub fn update(&mut self, num: usize) {
     self.work_done += num;
-    let percent_done = (self.work_done as f32) / (self.total_work as f32);
-    let mut plusses_needed = (self.total_plusses as f32 * percent_done) as uint;
+    let percent_done = (self.work_done as Float) / (self.total_work as Float);
+    let mut plusses_needed = (self.total_plusses as Float * percent_done) as usize;
 
     if plusses_needed > self.total_plusses {
       plusses_needed = self.total_plusses;
@@ -41,31 +40,31 @@ impl ProgressReporter {
     let mut i = 0;
 
     while i < plusses_needed {
-      buf = buf.append("+");
+      buf.push('+');
       i += 1;
     }
 
     while i < self.total_plusses {
-      buf = buf.append(" ");
+      buf.push(' ');
       i += 1;
     }
 
-    buf = buf.append("] ");
+    buf.push_str("] ");
 
     print!("{}", buf);
-    io::stdio::flush();
+    let _ = io::stdout().flush();
   }
 
   pub fn done(&mut self) {
     let mut buf = format!("\r{}: [", self.title);
 
-    for _ in range(0, self.total_plusses) {
-      buf = buf.append("+");
+    for _ in 0..self.total_plusses {
+      buf.push('+');
     }
 
-    buf = buf.append("] ");
+    buf.push_str("] ");
 
     println!("{}", buf);
-    io::stdio::flush();
+    let _ = io::stdout().flush();
   }
 }
@@ -1,164 +1,196 @@
-use diffgeom::DifferentialGeometry;
-use geometry::{ Normal, Vector, dot };
-use rand::{ TaskRng, Rng };
-use sampler::Sample;
-use spectrum::Spectrum;
+use crate::diffgeom::DifferentialGeometry;
+use crate::geometry::{ Float, Normal, Vector, dot };
+use crate::sampler::Sample;
+use crate::spectrum::Spectrum;
+
+use bitflags::bitflags;
+use rand::Rng;
+use rand::rngs::ThreadRng;
 
 pub struct BsdfSample {
-  pub udir: (f32, f32),
-  pub ucomponent: f32
+  pub udir: (Float, Float),
+  pub ucomponent: Float
 }
 
 impl BsdfSample {
-  pub fn new(up0: f32, up1: f32, ucomp: f32) -> BsdfSample {
+  pub fn new(up0: Float, up1: Float, ucomp: Float) -> BsdfSample {
     BsdfSample { udir: (up0, up1), ucomponent: ucomp }
   }
 
-  pub fn from_random(rng: &mut TaskRng) -> BsdfSample {
-    BsdfSample { udir: rng.gen::<(f32, f32)>(), ucomponent: rng.gen::<f32>() }
+  pub fn from_random(rng: &mut ThreadRng) -> BsdfSample {
+    BsdfSample { udir: rng.gen::<(Float, Float)>(), ucomponent: rng.gen::<Float>() }
   }
 
-  pub fn from_sample(sample: &Sample, offsets: &BsdfSampleOffsets, n: uint) -> BsdfSample {
-    let a = sample.twoD[offsets.dir_offset][2 * n];
-    let b = sample.twoD[offsets.dir_offset][2 * n + 1];
-    let u = sample.oneD[offsets.component_offset][n];
+  pub fn from_sample(sample: &Sample, offsets: &BsdfSampleOffsets, n: usize) -> BsdfSample {
+    let a = sample.two_d[offsets.dir_offset][2 * n];
+    let b = sample.two_d[offsets.dir_offset][2 * n + 1];
+    let u = sample.one_d[offsets.component_offset][n];
 
     BsdfSample { udir: (a, b), ucomponent: u }
   }
 }
 
 pub struct BsdfSampleOffsets {
-  pub num_samples: uint,
-  pub component_offset: uint,
-  pub dir_offset: uint,
+  pub num_samples: usize,
+  pub component_offset: usize,
+  pub dir_offset: usize,
 }
 
-bitflags!(
-  flags BxDFType: u32 {
-  static NoType      = 0x00000000,
-  static Reflection    = 0x00000001,
-  static Transmission  = 0x00000010,
-  static Diffuse     = 0x00000100,
-  static Glossy      = 0x00001000,
-  static Specular    = 0x00010000,
-  static AllTypes    = Diffuse.bits | Glossy.bits | Specular.bits,
-  static AllReflection   = Reflection.bits | AllTypes.bits,
-  static AllTransmission = Transmission.bits | AllTypes.bits,
-  static All       = AllReflection.bits | AllTransmission.bits
+bitflags! {
+  pub struct BxDFType: u32 {
+    const NO_TYPE          = 0x00000000;
+    const REFLECTION       = 0x00000001;
+    const TRANSMISSION     = 0x00000010;
+    const DIFFUSE          = 0x00000100;
+    const GLOSSY           = 0x00001000;
+    const SPECULAR         = 0x00010000;
+    const ALL_TYPES        = Self::DIFFUSE.bits | Self::GLOSSY.bits | Self::SPECULAR.bits;
+    const ALL_REFLECTION   = Self::REFLECTION.bits | Self::ALL_TYPES.bits;
+    const ALL_TRANSMISSION = Self::TRANSMISSION.bits | Self::ALL_TYPES.bits;
+    const ALL              = Self::ALL_REFLECTION.bits | Self::ALL_TRANSMISSION.bits;
   }
-)
+}
 
 pub struct BxDFBase {
   pub bxdf_type: BxDFType
 }
 
-pub trait BxDF<'a> {
-  fn get_base(&'a self) -> &'a BxDFBase;
-  fn get_base_mut(&'a mut self) -> &'a mut BxDFBase;
+pub trait BxDF {
+  fn get_base(&self) -> &BxDFBase;
+  fn get_base_mut(&mut self) -> &mut BxDFBase;
 
-  fn f(&'a self, wo: &Vector, wi: &Vector) -> Spectrum;
-  fn sample_f(&'a self, wo: &Vector, wi: &mut Vector, u1: f32, u2: f32, pdf: &mut f32) -> (Spectrum, f32);
-  fn rho(&'a self, wo: Vector, num_samples: uint, samples: &[f32]) -> Spectrum;
-  fn rho2(&'a self, num_samples: uint, samples1: &[f32], samples2: &[f32]) -> Spectrum;
-  fn pdf(&'a self, wi: &Vector, wo: &Vector) -> f32;
+  fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum;
+  fn sample_f(&self, wo: &Vector, wi: &mut Vector, u1: Float, u2: Float, pdf: &mut Float) -> (Spectrum, Float);
+  fn rho(&self, wo: Vector, num_samples: usize, samples: &[Float]) -> Spectrum;
+  fn rho2(&self, num_samples: usize, samples1: &[Float], samples2: &[Float]) -> Spectrum;
+  fn pdf(&self, wi: &Vector, wo: &Vector) -> Float;
 
-  fn matches_flags(&'a self, flags: BxDFType) -> bool {
-    self.get_base().bxdf_type.contains(flags)
+  fn matches_flags(&self, flags: BxDFType) -> bool {
+    flags.contains(self.get_base().bxdf_type)
   }
 }
 
-pub struct Bsdf<'a> {
+pub struct Bsdf {
   pub dg_shading: DifferentialGeometry,
-  pub eta: f32,
+  pub eta: Float,
   pub nn: Normal,
   pub ng: Normal,
   pub sn: Vector,
   pub tn: Vector,
-  pub nbxdfs: uint,
-  pub bxdfs: [Option<Box<BxDF<'a>>>, ..8]
+  pub nbxdfs: usize,
+  pub bxdfs: [Option<Box<dyn BxDF>>; 8]
 }
 
-impl<'a> Bsdf<'a> {
-  pub fn add(&'a mut self, bxdf: Box<BxDF<'a>>) {
+impl Bsdf {
+  pub fn add(&mut self, bxdf: Box<dyn BxDF>) {
     self.bxdfs[self.nbxdfs] = Some(bxdf);
     self.nbxdfs += 1;
   }
 
-  pub fn world_to_local(&'a self, v: &Vector) -> Vector {
+  /// Turn the local frame over, so that the surface is shaded from its
+  /// other side
+  pub fn flip(&mut self) {
+    self.dg_shading.flip();
+    self.nn = -self.nn;
+    self.ng = -self.ng;
+    self.tn = -self.tn;
+  }
+
+  pub fn world_to_local(&self, v: &Vector) -> Vector {
     Vector::new(dot(*v, self.sn), dot(*v, self.tn), dot(*v, self.nn))
   }
 
-  pub fn local_to_world(&'a self, v: &Vector) -> Vector {
+  pub fn local_to_world(&self, v: &Vector) -> Vector {
     Vector::new(self.sn.x * v.x + self.tn.x * v.y + self.nn.x * v.z,
       self.sn.y * v.x + self.tn.y * v.y + self.nn.y * v.z,
-      self.sn.z * v.x + self.tn.z * v.z + self.nn.z * v.z)
+      self.sn.z * v.x + self.tn.z * v.y + self.nn.z * v.z)
+  }
+
+  pub fn num_components(&self, flags: BxDFType) -> usize {
+    self.bxdfs[..self.nbxdfs].iter().flatten().filter(|x| x.matches_flags(flags)).count()
+  }
+
+  /// Restrict `flags` to reflection or transmission, depending on which
+  /// side of the geometric surface `wo_w` and `wi_w` lie on
+  fn side_flags(&self, wo_w: &Vector, wi_w: &Vector, flags: BxDFType) -> BxDFType {
+    if dot(*wi_w, self.ng) * dot(*wo_w, self.ng) > 0.0 {
+      flags - BxDFType::TRANSMISSION
+    } else {
+      flags - BxDFType::REFLECTION
+    }
   }
 
-  pub fn sample_f(&'a self, woW: &Vector, wiW: &mut Vector, bsdf_sample: &BsdfSample,
-    pdf: &mut f32, flags: &mut BxDFType) -> (Spectrum, BxDFType) {
-    let matching_components = 0;
+  pub fn sample_f(&self, wo_w: &Vector, wi_w: &mut Vector, bsdf_sample: &BsdfSample,
+    pdf: &mut Float, flags: &mut BxDFType) -> (Spectrum, BxDFType) {
+    let matching_components = self.num_components(*flags);
 
     if matching_components == 0 {
       *pdf = 0.0;
 
-      return (Spectrum::new(0.0), NoType);
+      return (Spectrum::new(0.0), BxDFType::NO_TYPE);
     }
 
-    let mut bxdf : Option<&Box<BxDF<'a>>> = None;
-    let mut count = 0;
-    let n = self.nbxdfs;
-
-    for i in range(0, n) {
-      match self.bxdfs[i] {
-        Some(ref x) => {
-          if x.matches_flags(*flags) && count == 0 {
-            count -= 1;
-            bxdf = Some(x);
-            break;
-          } else {
-            count -= 1;
-          }
-        },
-        None => (),
+    // Pick one of the matching components uniformly
+    let which = ((bsdf_sample.ucomponent * matching_components as Float) as usize)
+      .min(matching_components - 1);
+    let bxdf = match self.bxdfs[..self.nbxdfs].iter().flatten()
+        .filter(|x| x.matches_flags(*flags)).nth(which) {
+      Some(bxdf) => bxdf,
+      None => {
+        *pdf = 0.0;
+        return (Spectrum::new(0.0), BxDFType::NO_TYPE);
       }
-    }
+    };
 
-    let wo = self.world_to_local(woW);
+    let wo = self.world_to_local(wo_w);
     let mut wi = Vector::new(0.0, 0.0, 0.0);
-    let (mut f, _) = bxdf.unwrap().sample_f(&wo, &mut wi, bsdf_sample.udir.val0(),
-      bsdf_sample.udir.val1(), pdf);
+    *pdf = 0.0;
+    let (mut f, _) = bxdf.sample_f(&wo, &mut wi, bsdf_sample.udir.0,
+      bsdf_sample.udir.1, pdf);
 
     if *pdf == 0.0 {
-      return (Spectrum::new(0.0), NoType);
+      return (Spectrum::new(0.0), BxDFType::NO_TYPE);
     }
 
-    *wiW = self.local_to_world(&wi);
+    let sampled_type = bxdf.get_base().bxdf_type;
+    *wi_w = self.local_to_world(&wi);
+    let is_specular = sampled_type.contains(BxDFType::SPECULAR);
 
-    if bxdf.unwrap().get_base().bxdf_type & Specular != NoType {
-      f = Spectrum::new(0.0);
-      if dot(*wiW, self.ng) * dot(*woW, self.ng) > 0.0 {
-        *flags = *flags - Transmission;
-      } else {
-        *flags = *flags - Reflection;
+    // Non-specular components contribute to the overall pdf and value
+    if !is_specular && matching_components > 1 {
+      for x in self.bxdfs[..self.nbxdfs].iter().flatten() {
+        if !std::ptr::eq(x.as_ref(), bxdf.as_ref()) && x.matches_flags(*flags) {
+          *pdf += x.pdf(&wo, &wi);
+        }
       }
+    }
 
-      for i in range(0, self.nbxdfs) {
-        match self.bxdfs[i] {
-          Some(ref x) => {
-            if x.matches_flags(*flags) {
-              f = f + x.f(&wo, &wi);
-            }
-          },
-          None => ()
+    if matching_components > 1 {
+      *pdf /= matching_components as Float;
+    }
+
+    if !is_specular {
+      let side = self.side_flags(wo_w, wi_w, *flags);
+      f = Spectrum::new(0.0);
+
+      for x in self.bxdfs[..self.nbxdfs].iter().flatten() {
+        if x.matches_flags(side) {
+          f = f + x.f(&wo, &wi);
         }
       }
     }
 
-    (f, bxdf.unwrap().get_base().bxdf_type)
+    (f, sampled_type)
   }
 
-  pub fn f(&self, woW: &Vector, wiW: &Vector, flags: BxDFType) -> Spectrum {
-    fail!("not implemented");
+  pub fn f(&self, wo_w: &Vector, wi_w: &Vector, flags: BxDFType) -> Spectrum {
+    let wo = self.world_to_local(wo_w);
+    let wi = self.world_to_local(wi_w);
+    let side = self.side_flags(wo_w, wi_w, flags);
+
+    self.bxdfs[..self.nbxdfs].iter().flatten()
+      .filter(|x| x.matches_flags(side))
+      .fold(Spectrum::new(0.0), |f, x| f + x.f(&wo, &wi))
   }
 }
 
@@ -1,13 +1,13 @@
-use geometry::{ RayDifferential };
-use sampler::{ Sample };
-use scene::{ Scene };
-use spectrum::{ Spectrum };
+use crate::geometry::{ RayDifferential };
+use crate::sampler::{ Sample };
+use crate::scene::{ Scene };
+use crate::spectrum::{ Spectrum };
 
-use rand::TaskRng;
+use rand::rngs::ThreadRng;
 
 pub trait Renderer {
   fn render(&self, scene: &Scene);
-  fn Li(&self, scene: &Scene, ray: &RayDifferential, sample: &Sample) -> Spectrum;
+  fn li(&self, scene: &Scene, ray: &RayDifferential, sample: &Sample) -> Spectrum;
   fn transmittance(&self, scene: &Scene, ray: &RayDifferential,
-    sample: &Sample, rng: &mut TaskRng) -> Spectrum;
+    sample: &Sample, rng: &mut ThreadRng) -> Spectrum;
 }
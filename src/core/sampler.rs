@@ -1,3 +1,5 @@
+use crate::geometry::Float;
+
 pub struct SamplerBase;
 
 pub trait Sampler {
@@ -5,38 +7,74 @@ pub trait Sampler {
   fn maximum_sample_count(&self);
 }
 
+#[derive(Clone, Copy, Default)]
 pub struct CameraSampleBase {
-  image_x: f32,
-  image_y: f32,
-  lens_u:  f32,
-  lens_v:  f32,
-  time:    f32
+  pub image_x: Float,
+  pub image_y: Float,
+  pub lens_u:  Float,
+  pub lens_v:  Float,
+  pub time:    Float
 }
 
+/// A sample holds the camera sample plus the 1D and 2D sample patterns
+/// that integrators request through `add_1d` and `add_2d`.
+#[derive(Clone, Default)]
 pub struct Sample {
-  camera_sample:  CameraSampleBase,
-  pub n1D:     Vec<uint>,
-  pub n2D:     Vec<uint>,
-  pub oneD:    ~[~[f32]],
-  pub twoD:    ~[~[f32]]
+  pub camera_sample: CameraSampleBase,
+  pub n1d:     Vec<usize>,
+  pub n2d:     Vec<usize>,
+  pub one_d:   Vec<Vec<Float>>,
+  pub two_d:   Vec<Vec<Float>>
 }
 
 impl Sample {
-  pub fn new(sampler: &Sampler) -> Sample {
-    fail!("")
+  pub fn new() -> Sample {
+    Sample::default()
+  }
+
+  pub fn add_1d(&mut self, num: usize) -> usize {
+    self.n1d.push(num);
+    self.one_d.push(vec![0.0; num]);
+    self.n1d.len() - 1
   }
 
-  pub fn add_1d(&mut self, num: uint) -> uint {
-    self.n1D.push(num);
-    self.n1D.len() - 1
+  pub fn add_2d(&mut self, num: usize) -> usize {
+    self.n2d.push(num);
+    self.two_d.push(vec![0.0; 2 * num]);
+    self.n2d.len() - 1
   }
 
-  pub fn add_2d(&mut self, num: uint) -> uint {
-    self.n2D.push(num);
-    self.n2D.len() - 1
+  /// Create `count` samples with the same layout of requested
+  /// sample patterns as this one
+  pub fn duplicate(&self, count: usize) -> Vec<Sample> {
+    (0..count).map(|_| {
+      Sample {
+        camera_sample: CameraSampleBase::default(),
+        n1d:   self.n1d.clone(),
+        n2d:   self.n2d.clone(),
+        one_d: self.n1d.iter().map(|&n| vec![0.0; n]).collect(),
+        two_d: self.n2d.iter().map(|&n| vec![0.0; 2 * n]).collect()
+      }
+    }).collect()
   }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn duplicate_keeps_requested_layout() {
+    let mut sample = Sample::new();
+    let light_offset = sample.add_1d(4);
+    let bsdf_offset = sample.add_2d(3);
+
+    let samples = sample.duplicate(2);
 
-  pub fn duplicate(&self, count: uint) -> Sample {
-    fail!("Unimplemented method");
+    assert_eq!(samples.len(), 2);
+    for s in samples.iter() {
+      assert_eq!(s.one_d[light_offset].len(), 4);
+      assert_eq!(s.two_d[bsdf_offset].len(), 6);
+    }
   }
 }
@@ -1,14 +1,31 @@
-use geometry::{ BBox, Ray };
-use light::Light;
-use primitive::Primitive;
+use crate::error::RenderError;
+use crate::geometry::{ BBox, Ray };
+use crate::intersection::Intersection;
+use crate::light::Light;
+use crate::primitive::Primitive;
 
-pub struct Scene<'a> {
-  pub aggregate: Box<Primitive>,
+pub struct Scene {
+  pub aggregate: Box<dyn Primitive>,
   pub bound:   BBox,
-  pub lights:  Vec<Box<Light<'a>>>
+  pub lights:  Vec<Box<dyn Light>>
 }
 
-impl<'a> Scene<'a> {
+impl Scene {
+  pub fn new(aggregate: Box<dyn Primitive>, bound: BBox,
+      lights: Vec<Box<dyn Light>>) -> Result<Scene, RenderError> {
+    for i in 0..3 {
+      if bound.p_min[i].is_nan() || bound.p_max[i].is_nan() {
+        return Err(RenderError::InvalidScene("scene bound is not a number".to_string()));
+      }
+    }
+
+    Ok(Scene { aggregate, bound, lights })
+  }
+
+  pub fn intersect(&self, ray: &mut Ray) -> Option<Intersection> {
+    self.aggregate.intersect(ray)
+  }
+
   pub fn intersect_p(&self, ray: &Ray) -> bool {
     self.aggregate.intersect_p(ray)
   }
@@ -1,29 +1,113 @@
-use transform::{ Applicable, Transform };
-use geometry::{ Ray, BBox };
+use std::rc::Rc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
 
-#[deriving(Clone)]
+use crate::diffgeom::DifferentialGeometry;
+use crate::error::RenderError;
+use crate::geometry::{ Float, Ray, BBox, Normal, Point, Vector, abs_dot, distance_squared };
+use crate::transform::{ Applicable, Transform };
+
+static NEXT_SHAPE_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Clone)]
 pub struct ShapeBase {
   pub object_to_world: Transform,
-  pub world_to_oject: Transform,
-  pub shape_id: uint,
-  pub next_shape_id: uint,
+  pub world_to_object: Transform,
+  pub shape_id: usize,
   pub reverse_orientation: bool,
   pub transform_swaps_handedness: bool
 }
 
-pub trait Shape : Clone {
-  fn get_base(&self) -> ShapeBase;
+impl ShapeBase {
+  /// Base for a new shape, assigning it the next free shape id
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform,
+      reverse_orientation: bool) -> ShapeBase {
+    ShapeBase {
+      object_to_world: *object_to_world,
+      world_to_object: *world_to_object,
+      shape_id: NEXT_SHAPE_ID.fetch_add(1, Ordering::Relaxed),
+      reverse_orientation,
+      transform_swaps_handedness: object_to_world.swaps_handedness()
+    }
+  }
+
+  /// Transform taking data a shape keeps in world space into the world of
+  /// an instance seen through `object_to_world`, or `None` when the shape
+  /// is not instanced
+  pub fn instance_to_world(&self, object_to_world: &Transform) -> Option<Transform> {
+    if *object_to_world == self.object_to_world {
+      None
+    } else {
+      Some(*object_to_world * self.world_to_object)
+    }
+  }
+}
+
+/// Result of a successful `Shape::intersect`: the parametric distance
+/// along the ray, the error bound on the hit point and its geometry
+pub type ShapeIntersection = (Float, Vector, DifferentialGeometry);
+
+pub trait Shape {
+  fn get_base(&self) -> &ShapeBase;
   fn object_bound(&self) -> BBox;
-  fn area(&self) -> f32;
-  fn intersect_p(&self, ray: &Ray) -> bool;
+  fn area(&self) -> Float;
+
+  /// Intersect the world space `ray` with the shape. The returned
+  /// geometry has no `shape` set, the owning primitive attaches it.
+  fn intersect(&self, ray: &Ray) -> Option<ShapeIntersection>;
+
+  fn intersect_p(&self, ray: &Ray) -> bool {
+    self.intersect(ray).is_some()
+  }
+
+  fn get_shading_geometry(&self, _object_to_world: &Transform,
+      dg: &DifferentialGeometry) -> DifferentialGeometry {
+    dg.clone()
+  }
 
   fn world_bound(&self) -> BBox {
     self.get_base().object_to_world.apply(self.object_bound())
   }
 
-  fn pdf(&self) -> f32 {
-    1.0 / self.area()
+  /// Sample a point uniformly over the surface, with its normal. Shapes
+  /// that cannot be sampled return `None` and cannot carry area lights.
+  fn sample(&self, _u1: Float, _u2: Float) -> Option<(Point, Normal)> {
+    None
+  }
+
+  /// Sample a point on the surface as seen from `p`. The default samples
+  /// by area; shapes that can do better sample by solid angle. Sampling by
+  /// solid angle may also give `None` for a direction that misses the
+  /// shape, such samples carry no light and `pdf` accounts for them.
+  fn sample_from(&self, _p: &Point, u1: Float, u2: Float) -> Option<(Point, Normal)> {
+    self.sample(u1, u2)
+  }
+
+  /// Density of `sample_from(p, ..)` with respect to solid angle at `p`
+  /// for the direction `wi`. This is the density to weight samples by; the
+  /// default assumes `sample` is uniform by area, shapes whose sampling is
+  /// not have to override it.
+  fn pdf(&self, p: &Point, wi: &Vector) -> Float {
+    area_pdf_to_solid_angle(self, p, wi)
   }
 
   fn can_intersect(&self) -> bool { true }
+
+  /// Split a shape that cannot be intersected directly into simpler
+  /// shapes. These may need further refining themselves.
+  fn refine(&self) -> Result<Vec<Rc<dyn Shape>>, RenderError> {
+    Err(RenderError::InvalidScene(format!("shape {} cannot be refined", self.get_base().shape_id)))
+  }
+}
+
+/// Solid angle density at `p` of sampling `shape` by area, zero when `wi`
+/// misses it
+pub fn area_pdf_to_solid_angle<S: Shape + ?Sized>(shape: &S, p: &Point, wi: &Vector) -> Float {
+  let ray = Ray::new(p, wi, 0.0, Float::INFINITY, 0.0);
+  let dg = match shape.intersect(&ray) {
+    Some((_, _, dg)) => dg,
+    None => return 0.0
+  };
+
+  let pdf = distance_squared(p, &dg.p) / (abs_dot(dg.nn, -*wi) * shape.area());
+  if pdf.is_infinite() { 0.0 } else { pdf }
 }
@@ -1,10 +1,14 @@
-pub fn xyz_to_rgb(xyz: &[f32, ..3], rgb: &mut [f32, ..3]) {
-  rgb[0] =  3.240479 * xyz[0] - 1.537150 * xyz[1] - 0.498535 * xyz[2];
+use std::ops::{ Add, Sub, Mul, Div };
+
+use crate::geometry::Float;
+
+pub fn xyz_to_rgb(xyz: &[Float; 3], rgb: &mut [Float; 3]) {
+  rgb[0] =  3.240479 * xyz[0] - 1.53715 * xyz[1] - 0.498535 * xyz[2];
   rgb[1] = -0.969256 * xyz[0] + 1.875991 * xyz[1] + 0.041556 * xyz[2];
   rgb[2] =  0.055648 * xyz[0] - 0.204043 * xyz[1] + 1.057311 * xyz[2];
 }
 
-pub fn rgb_to_xyz(rgb: &[f32, ..3], xyz: &mut [f32, ..3]) {
+pub fn rgb_to_xyz(rgb: &[Float; 3], xyz: &mut [Float; 3]) {
   xyz[0] = 0.412453 * rgb[0] + 0.357580 * rgb[1] + 0.180423 * rgb[2];
   xyz[1] = 0.212671 * rgb[0] + 0.715160 * rgb[1] + 0.072169 * rgb[2];
   xyz[2] = 0.019334 * rgb[0] + 0.119193 * rgb[1] + 0.950227 * rgb[2];
@@ -22,65 +26,84 @@ pub trait CoefficientSpectrum {
 
 }
 
-#[deriving(Clone)]
-pub struct Spectrum;
+/// RGB spectrum, storing one coefficient per color channel
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Spectrum {
+  pub c: [Float; 3]
+}
 
 impl Spectrum {
-  pub fn new(v: f32) -> Spectrum {
-    Spectrum
+  pub fn new(v: Float) -> Spectrum {
+    Spectrum { c: [v, v, v] }
+  }
+
+  pub fn from_rgb(rgb: &[Float; 3]) -> Spectrum {
+    Spectrum { c: *rgb }
+  }
+
+  pub fn to_rgb(&self) -> [Float; 3] {
+    self.c
+  }
+
+  pub fn y(&self) -> Float {
+    let mut xyz = [0.0; 3];
+    rgb_to_xyz(&self.c, &mut xyz);
+    xyz[1]
   }
 
   pub fn is_black(&self) -> bool {
-    fail!("not implemented");
+    self.c.iter().all(|&c| c == 0.0)
   }
-}
 
-impl Add<Spectrum, Spectrum> for Spectrum {
-  fn add(&self, rhs: &Spectrum) -> Spectrum {
-    fail!("not implemented");
+  fn map<F: Fn(Float, Float) -> Float>(&self, rhs: &Spectrum, op: F) -> Spectrum {
+    Spectrum { c: [op(self.c[0], rhs.c[0]), op(self.c[1], rhs.c[1]), op(self.c[2], rhs.c[2])] }
   }
 }
 
-pub trait SpectrumRhsMul<S> {
-  fn mul_with_spectrum(&self, lhs: &Spectrum) -> S;
-}
+impl Add<Spectrum> for Spectrum {
+  type Output = Spectrum;
 
-impl<S, R: SpectrumRhsMul<S>> Mul<R, S> for Spectrum {
-  fn mul(&self, rhs: &R) -> S {
-    rhs.mul_with_spectrum(self)
+  fn add(self, rhs: Spectrum) -> Spectrum {
+    self.map(&rhs, |a, b| a + b)
   }
 }
 
-impl SpectrumRhsMul<Spectrum> for f32 {
-  fn mul_with_spectrum(&self, lhs: &Spectrum) -> Spectrum {
-    Spectrum::new(0.0)
+impl Sub<Spectrum> for Spectrum {
+  type Output = Spectrum;
+
+  fn sub(self, rhs: Spectrum) -> Spectrum {
+    self.map(&rhs, |a, b| a - b)
   }
 }
 
-impl SpectrumRhsMul<Spectrum> for Spectrum {
-  fn mul_with_spectrum(&self, lhs: &Spectrum) -> Spectrum {
-    Spectrum::new(0.0)
+impl Mul<Float> for Spectrum {
+  type Output = Spectrum;
+
+  fn mul(self, rhs: Float) -> Spectrum {
+    self.map(&Spectrum::new(rhs), |a, b| a * b)
   }
 }
 
-pub trait SpectrumRhsDiv<S> {
-  fn div_with_spectrum(&self, lhs: &Spectrum) -> S;
-}
+impl Mul<Spectrum> for Spectrum {
+  type Output = Spectrum;
 
-impl<S, R: SpectrumRhsDiv<S>> Div<R, S> for Spectrum {
-  fn div(&self, rhs: &R) -> S {
-    rhs.div_with_spectrum(self)
+  fn mul(self, rhs: Spectrum) -> Spectrum {
+    self.map(&rhs, |a, b| a * b)
   }
 }
 
-impl SpectrumRhsDiv<Spectrum> for f32 {
-  fn div_with_spectrum(&self, lhs: &Spectrum) -> Spectrum {
-    Spectrum::new(0.0)
+impl Div<Float> for Spectrum {
+  type Output = Spectrum;
+
+  fn div(self, rhs: Float) -> Spectrum {
+    self.map(&Spectrum::new(rhs), |a, b| a / b)
   }
 }
 
-impl SpectrumRhsDiv<Spectrum> for Spectrum {
-  fn div_with_spectrum(&self, lhs: &Spectrum) -> Spectrum {
-    Spectrum::new(0.0)
+impl Div<Spectrum> for Spectrum {
+  type Output = Spectrum;
+
+  fn div(self, rhs: Spectrum) -> Spectrum {
+    self.map(&rhs, |a, b| a / b)
   }
 }
@@ -1,4 +1,6 @@
-pub static CIE_X : [f32, ..471] = [
+#![allow(clippy::excessive_precision)]
+
+pub static CIE_X : [f32; 471] = [
   0.0001299000, 0.0001458470, 0.0001638021, 0.0001840037,
   0.0002066902, 0.0002321000, 0.0002607280, 0.0002930750,
   0.0003293880, 0.0003699140, 0.0004149000, 0.0004641587,
@@ -118,7 +120,7 @@ pub static CIE_X : [f32, ..471] = [
   0.000001905497, 0.000001776509, 0.000001656215, 0.000001544022,
   0.000001439440, 0.000001341977, 0.000001251141 ];
 
-pub static CIE_Y : [f32, ..471] = [
+pub static CIE_Y : [f32; 471] = [
   0.000003917000, 0.000004393581, 0.000004929604, 0.000005532136,
   0.000006208245, 0.000006965000, 0.000007813219, 0.000008767336,
   0.000009839844, 0.00001104323, 0.00001239000, 0.00001388641,
@@ -238,7 +240,7 @@ pub static CIE_Y : [f32, ..471] = [
   0.0000006881098, 0.0000006415300, 0.0000005980895, 0.0000005575746,
   0.0000005198080, 0.0000004846123, 0.0000004518100 ];
 
-pub static CIE_Z : [f32, ..471] = [
+pub static CIE_Z : [f32; 471] = [
   0.0006061000, 0.0006808792, 0.0007651456, 0.0008600124,
   0.0009665928, 0.001086000, 0.001220586, 0.001372729,
   0.001543579, 0.001734286, 0.001946000, 0.002177777,
@@ -358,7 +360,7 @@ pub static CIE_Z : [f32, ..471] = [
   0.0, 0.0, 0.0, 0.0,
   0.0, 0.0, 0.0 ];
 
-pub static CIE_lambda : [f32, ..471] = [
+pub static CIE_LAMBDA : [f32; 471] = [
   360.0, 361.0, 362.0, 363.0, 364.0, 365.0, 366.0,
   367.0, 368.0, 369.0, 370.0, 371.0, 372.0, 373.0,
   374.0, 375.0, 376.0, 377.0, 378.0, 379.0, 380.0,
@@ -428,7 +430,7 @@ pub static CIE_lambda : [f32, ..471] = [
   822.0, 823.0, 824.0, 825.0, 826.0, 827.0, 828.0,
   829.0, 830.0 ];
 
-pub static RGB2SpectLambda : [f32, ..32] = [
+pub static RGB_TO_SPECT_LAMBDA : [f32; 32] = [
   380.000000, 390.967743, 401.935486, 412.903229, 423.870972, 434.838715,
   445.806458, 456.774200, 467.741943, 478.709686, 489.677429, 500.645172,
   511.612915, 522.580627, 533.548340, 544.516052, 555.483765, 566.451477,
@@ -436,7 +438,7 @@ pub static RGB2SpectLambda : [f32, ..32] = [
   643.225464, 654.193176, 665.160889, 676.128601, 687.096313, 698.064026,
   709.031738, 720.000000 ];
 
-pub static RGBRefl2SpectWhite : [f32, ..32] = [
+pub static RGB_REFL_TO_SPECT_WHITE : [f32; 32] = [
   1.0618958571272863e+00, 1.0615019980348779e+00,
   1.0614335379927147e+00, 1.0622711654692485e+00,
   1.0622036218416742e+00, 1.0625059965187085e+00,
@@ -454,7 +456,7 @@ pub static RGBRefl2SpectWhite : [f32, ..32] = [
   1.0599810758292072e+00, 1.0602547314449409e+00,
   1.0601263046243634e+00, 1.0606565756823634e+00 ];
 
-pub static RGBRefl2SpectCyan : [f32, ..32] = [
+pub static RGB_REFL_TO_SPECT_CYAN : [f32; 32] = [
   1.0414628021426751e+00, 1.0328661533771188e+00,
   1.0126146228964314e+00, 1.0350460524836209e+00,
   1.0078661447098567e+00, 1.0422280385081280e+00,
@@ -472,7 +474,7 @@ pub static RGBRefl2SpectCyan : [f32, ..32] = [
   1.7119799082865147e-02, 4.9211089759759801e-03,
   5.8762925143334985e-03, 2.5259399415550079e-02 ];
 
-pub static RGBRefl2SpectMagenta : [f32, ..32] = [
+pub static RGB_REFL_TO_SPECT_MAGENTA : [f32; 32] = [
   9.9422138151236850e-01, 9.8986937122975682e-01,
   9.8293658286116958e-01, 9.9627868399859310e-01,
   1.0198955019000133e+00, 1.0166395501210359e+00,
@@ -490,7 +492,7 @@ pub static RGBRefl2SpectMagenta : [f32, ..32] = [
   9.9598944191059791e-01, 8.6301351503809076e-01,
   8.9150987853523145e-01, 8.4866492652845082e-01 ];
 
-pub static RGBRefl2SpectYellow : [f32, ..32] = [
+pub static RGB_REFL_TO_SPECT_YELLOW : [f32; 32] = [
   5.5740622924920873e-03, -4.7982831631446787e-03,
   -5.2536564298613798e-03, -6.4571480044499710e-03,
   -5.9693514658007013e-03, -2.1836716037686721e-03,
@@ -508,7 +510,7 @@ pub static RGBRefl2SpectYellow : [f32, ..32] = [
   1.0477492815668303e+00, 1.0493272144017338e+00,
   1.0435963333422726e+00, 1.0392280772051465e+00 ];
 
-pub static RGBRefl2SpectRed : [f32, ..32] = [
+pub static RGB_REFL_TO_SPECT_RED : [f32; 32] = [
   1.6575604867086180e-01, 1.1846442802747797e-01,
   1.2408293329637447e-01, 1.1371272058349924e-01,
   7.8992434518899132e-02, 3.2205603593106549e-02,
@@ -526,7 +528,7 @@ pub static RGBRefl2SpectRed : [f32, ..32] = [
   9.7451138326568698e-01, 9.8543269570059944e-01,
   9.3495763980962043e-01, 9.8713907792319400e-01 ];
 
-pub static RGBRefl2SpectGreen : [f32, ..32] = [
+pub static RGB_REFL_TO_SPECT_GREEN : [f32; 32] = [
   2.6494153587602255e-03, -5.0175013429732242e-03,
   -1.2547236272489583e-02, -9.4554964308388671e-03,
   -1.2526086181600525e-02, -7.9170697760437767e-03,
@@ -544,7 +546,7 @@ pub static RGBRefl2SpectGreen : [f32, ..32] = [
   -7.8685832338754313e-03, -8.3657578711085132e-06,
   5.4301225442817177e-03, -2.7745589759259194e-03 ];
 
-pub static RGBRefl2SpectBlue : [f32, ..32] = [
+pub static RGB_REFL_TO_SPECT_BLUE : [f32; 32] = [
   9.9209771469720676e-01, 9.8876426059369127e-01,
   9.9539040744505636e-01, 9.9529317353008218e-01,
   9.9181447411633950e-01, 1.0002584039673432e+00,
@@ -562,7 +564,7 @@ pub static RGBRefl2SpectBlue : [f32, ..32] = [
   3.0501024937233868e-02, 2.1243054765241080e-02,
   6.9596532104356399e-03, 4.1733649330980525e-03 ];
 
-pub static RGBIllum2SpectWhite : [f32, ..32] = [
+pub static RGB_ILLUM_TO_SPECT_WHITE : [f32; 32] = [
   1.1565232050369776e+00, 1.1567225000119139e+00,
   1.1566203150243823e+00, 1.1555782088080084e+00,
   1.1562175509215700e+00, 1.1567674012207332e+00,
@@ -580,7 +582,7 @@ pub static RGBIllum2SpectWhite : [f32, ..32] = [
   8.7635244612244578e-01, 8.8000368331709111e-01,
   8.8065665428441120e-01, 8.8304706460276905e-01 ];
 
-pub static RGBIllum2SpectCyan : [f32, ..32] = [
+pub static RGB_ILLUM_TO_SPECT_CYAN : [f32; 32] = [
   1.1334479663682135e+00, 1.1266762330194116e+00,
   1.1346827504710164e+00, 1.1357395805744794e+00,
   1.1356371830149636e+00, 1.1361152989346193e+00,
@@ -598,7 +600,7 @@ pub static RGBIllum2SpectCyan : [f32, ..32] = [
   -9.4722817708236418e-03, -5.5329541006658815e-03,
   -4.5428914028274488e-03, -1.2541015360921132e-02 ];
 
-pub static RGBIllum2SpectMagenta : [f32, ..32] = [
+pub static RGB_ILLUM_TO_SPECT_MAGENTA : [f32; 32] = [
   1.0371892935878366e+00, 1.0587542891035364e+00,
   1.0767271213688903e+00, 1.0762706844110288e+00,
   1.0795289105258212e+00, 1.0743644742950074e+00,
@@ -616,7 +618,7 @@ pub static RGBIllum2SpectMagenta : [f32, ..32] = [
   9.8333849623218872e-01, 1.0707246342802621e+00,
   1.0634247770423768e+00, 1.0150875475729566e+00 ];
 
-pub static RGBIllum2SpectYellow : [f32, ..32] = [
+pub static RGB_ILLUM_TO_SPECT_YELLOW : [f32; 32] = [
   2.7756958965811972e-03, 3.9673820990646612e-03,
   -1.4606936788606750e-04, 3.6198394557748065e-04,
   -2.5819258699309733e-04, -5.0133191628082274e-05,
@@ -634,7 +636,7 @@ pub static RGBIllum2SpectYellow : [f32, ..32] = [
   5.9419261278443136e-01, 5.6517682326634266e-01,
   5.6061186014968556e-01, 5.8228610381018719e-01 ];
 
-pub static RGBIllum2SpectRed : [f32, ..32] = [
+pub static RGB_ILLUM_TO_SPECT_RED : [f32; 32] = [
   5.4711187157291841e-02, 5.5609066498303397e-02,
   6.0755873790918236e-02, 5.6232948615962369e-02,
   4.6169940535708678e-02, 3.8012808167818095e-02,
@@ -652,7 +654,7 @@ pub static RGBIllum2SpectRed : [f32, ..32] = [
   9.7433478377305371e-01, 9.9134364616871407e-01,
   9.8866287772174755e-01, 9.9713856089735531e-01 ];
 
-pub static RGBIllum2SpectGreen : [f32, ..32] = [
+pub static RGB_ILLUM_TO_SPECT_GREEN : [f32; 32] = [
   2.5168388755514630e-02, 3.9427438169423720e-02,
   6.2059571596425793e-03, 7.1120859807429554e-03,
   2.1760044649139429e-04, 7.3271839984290210e-12,
@@ -670,7 +672,7 @@ pub static RGBIllum2SpectGreen : [f32, ..32] = [
   -6.4630764968453287e-03, 1.0250854718507939e-02,
   4.2387394733956134e-02, 2.1252716926861620e-02 ];
 
-pub static RGBIllum2SpectBlue : [f32, ..32] = [
+pub static RGB_ILLUM_TO_SPECT_BLUE : [f32; 32] = [
   1.0570490759328752e+00, 1.0538466912851301e+00,
   1.0550494258140670e+00, 1.0530407754701832e+00,
   1.0579930596460185e+00, 1.0578439494812371e+00,
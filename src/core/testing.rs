@@ -0,0 +1,92 @@
+use std::rc::Rc;
+
+use crate::diffgeom::DifferentialGeometry;
+use crate::error::RenderError;
+use crate::geometry::{ Float, BBox, Normal, Point, Ray, Vector, coordinate_system, cross, dot, normalize };
+use crate::intersection::Intersection;
+use crate::light::AreaLight;
+use crate::primitive::{ Primitive, next_primitive_id };
+use crate::reflection::{ Bsdf, Bssrdf };
+use crate::transform::Transform;
+
+/// Analytic sphere, as core has no shapes of its own. With `row` set it
+/// stands for a row of `row + 1` spheres along x, which it refines into.
+pub struct Ball {
+  pub center: Point,
+  pub radius: Float,
+  pub row: usize,
+  pub id: usize
+}
+
+pub fn ball(center: Point, radius: Float, row: usize) -> Rc<dyn Primitive> {
+  Rc::new(Ball { center, radius, row, id: next_primitive_id() })
+}
+
+impl Primitive for Ball {
+  fn intersect(&self, ray: &mut Ray) -> Option<Intersection> {
+    let oc = ray.o - self.center;
+    let a = dot(ray.d, ray.d);
+    let b = 2.0 * dot(ray.d, oc);
+    let c = dot(oc, oc) - self.radius * self.radius;
+    let root = (b * b - 4.0 * a * c).sqrt();
+
+    let t = [(-b - root) / (2.0 * a), (-b + root) / (2.0 * a)].iter().copied()
+      .find(|&t| t > ray.mint && t < ray.maxt)?;
+    ray.maxt = t;
+
+    let p = ray.apply(t);
+    let (dpdu, dpdv) = coordinate_system(&normalize(p - self.center));
+    let dg = DifferentialGeometry::new(p, dpdu, dpdv, Normal::zero(), Normal::zero(), 0.0, 0.0, None);
+    let identity = Transform::translate(&Vector::zero());
+
+    // Loose on purpose, the roots above are not computed with error bounds
+    let p_error = Vector::new(1.0, 1.0, 1.0) * (1e-4 * self.radius);
+
+    Some(Intersection::new(dg, p_error, &identity, &identity, 0, self.id))
+  }
+
+  fn intersect_p(&self, ray: &Ray) -> bool {
+    self.intersect(&mut ray.clone()).is_some()
+  }
+
+  fn world_bound(&self) -> BBox {
+    let r = Vector::new(self.radius, self.radius, self.radius);
+    let end = Vector::new(self.row as Float, 0.0, 0.0);
+    BBox::new(&(self.center - r), &(self.center + end + r))
+  }
+
+  /// Empty BSDF whose shading normal is rebuilt from the ball, the way
+  /// `GeometricPrimitive` asks its shape for the shading geometry
+  fn get_bsdf(&self, dg: &DifferentialGeometry, _object_to_world: &Transform) -> Option<Bsdf> {
+    let mut dg_shading = dg.clone();
+    dg_shading.nn = Normal::from_vector(&normalize(dg.p - self.center));
+    let sn = normalize(dg.dpdu);
+
+    Some(Bsdf {
+      eta: 1.0,
+      nn: dg_shading.nn,
+      ng: dg.nn,
+      sn,
+      tn: cross(dg_shading.nn, sn),
+      dg_shading,
+      nbxdfs: 0,
+      bxdfs: Default::default()
+    })
+  }
+
+  fn get_bssrdf(&self, _dg: &DifferentialGeometry, _object_to_world: &Transform) -> Option<Bssrdf> {
+    None
+  }
+
+  fn get_area_light(&self) -> Option<Rc<dyn AreaLight>> {
+    None
+  }
+
+  fn can_intersect(&self) -> bool {
+    self.row == 0
+  }
+
+  fn refine(&self) -> Result<Vec<Rc<dyn Primitive>>, RenderError> {
+    Ok((0..=self.row).map(|i| ball(self.center + Vector::new(i as Float, 0.0, 0.0), self.radius, 0)).collect())
+  }
+}
@@ -1,4 +1,4 @@
-use diffgeom::DifferentialGeometry;
+use crate::diffgeom::DifferentialGeometry;
 
 pub trait Texture<T> {
   fn evaluate(&self, dg: &DifferentialGeometry) -> T;
@@ -1,77 +1,79 @@
-use geometry::{
-  Vector, Point, Normal, Ray, BBox,
+use std::cmp::Ordering;
+use std::ops::{ Mul, Index };
+
+use crate::efloat::gamma;
+use crate::error::RenderError;
+use crate::geometry::{
+  Float, Vector, Point, Normal, Ray, BBox, Quaternion,
   Length, Union,
-  normalize, cross, radians };
+  normalize, cross, radians, lerp };
 
-fn not_one(x: f32) -> bool {
-  x < 0.999 || x > 1.001
+fn not_one(x: Float) -> bool {
+  !(0.999..=1.001).contains(&x)
 }
 
-#[deriving(Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Matrix {
-  m: ~[f32]
+  m: [[Float; 4]; 4]
 }
 
 impl Matrix {
   pub fn zero() -> Matrix {
-    Matrix { m: box [0.0, ..16] }
+    Matrix { m: [[0.0; 4]; 4] }
   }
 
-  pub fn from_data(d: ~[f32]) -> Matrix {
+  pub fn from_data(d: [[Float; 4]; 4]) -> Matrix {
     Matrix { m: d }
   }
 
-  pub fn new(t00: f32, t01: f32, t02: f32, t03: f32,
-    t10: f32, t11: f32, t12: f32, t13: f32,
-    t20: f32, t21: f32, t22: f32, t23: f32,
-    t30: f32, t31: f32, t32: f32, t33: f32) -> Matrix {
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(t00: Float, t01: Float, t02: Float, t03: Float,
+    t10: Float, t11: Float, t12: Float, t13: Float,
+    t20: Float, t21: Float, t22: Float, t23: Float,
+    t30: Float, t31: Float, t32: Float, t33: Float) -> Matrix {
 
-    Matrix { m: box [t00, t01, t02, t03,
-      t10, t11, t12, t13,
-      t20, t21, t22, t23,
-      t30, t31, t32, t33] }
+    Matrix { m: [[t00, t01, t02, t03],
+      [t10, t11, t12, t13],
+      [t20, t21, t22, t23],
+      [t30, t31, t32, t33]] }
   }
 
   pub fn transpose(m: &Matrix) -> Matrix {
-    Matrix::new(m.m[0* 4 + 0], m.m[1* 4 + 0], m.m[2* 4 + 0], m.m[3* 4 + 0],
-          m.m[0* 4 + 1], m.m[1* 4 + 1], m.m[2* 4 + 1], m.m[3* 4 + 1],
-          m.m[0* 4 + 2], m.m[1* 4 + 2], m.m[2* 4 + 2], m.m[3* 4 + 2],
-          m.m[0* 4 + 3], m.m[1* 4 + 3], m.m[2* 4 + 3], m.m[3* 4 + 3])
-  }
-
-  pub fn inverse(m: &Matrix) -> Matrix {
-    let mut indxc = [0, ..4];
-    let mut indxr = [0, ..4];
-    let mut minv  = box [0.0f32, ..16];
-    let mut ipiv  = [0, ..4];
-
-    for i in range(0u, 4) {
-      for j in range(0u, 4) {
-        minv[i * 4 + j] = m.m[i * 4 + j];
-      }
-    }
-
-    for i in range(0u, 4) {
-      let mut irow = -1;
-      let mut icol = -1;
+    Matrix::new(m.m[0][0], m.m[1][0], m.m[2][0], m.m[3][0],
+          m.m[0][1], m.m[1][1], m.m[2][1], m.m[3][1],
+          m.m[0][2], m.m[1][2], m.m[2][2], m.m[3][2],
+          m.m[0][3], m.m[1][3], m.m[2][3], m.m[3][3])
+  }
+
+  /// Gauss-Jordan inverse, failing with `SingularMatrix` if `m` has no inverse
+  #[allow(clippy::needless_range_loop)]
+  pub fn inverse(m: &Matrix) -> Result<Matrix, RenderError> {
+    let mut indxc = [0; 4];
+    let mut indxr = [0; 4];
+    let mut ipiv  = [0; 4];
+    let mut minv  = m.m;
+
+    for i in 0..4 {
+      let mut irow = 0;
+      let mut icol = 0;
       let mut big = 0.0;
 
-      for j in range(0u, 4) {
+      for j in 0..4 {
         if ipiv[j] == 1 {
           continue;
         }
 
-        for k in range(0u, 4) {
+        for k in 0..4 {
           if ipiv[k] == 0 {
-            if minv[j * 4 + k].abs() < big {
+            if minv[j][k].abs() < big {
               continue;
             }
 
-            big = minv[j * 4 + k].abs();
+            big = minv[j][k].abs();
             irow = j;
             icol = k;
           } else if ipiv[k] > 1 {
-            fail!("Singular matrix in Matrix::inverse");
+            return Err(RenderError::SingularMatrix);
           }
         }
       }
@@ -79,80 +81,78 @@ impl Matrix {
       ipiv[icol] += 1;
 
       if irow != icol {
-        for k in range(0u, 4) {
-          let t = minv[irow * 4 + k];
-          minv[irow * 4 + k] = minv[icol * 4 + k];
-          minv[icol * 4 + k] = t;
-        }
+        minv.swap(irow, icol);
       }
 
       indxr[i] = irow;
       indxc[i] = icol;
 
-      if minv[icol * 4 + icol] == 0.0 {
-        fail!("Singular matrix in Matrix::inverse");
+      if minv[icol][icol] == 0.0 {
+        return Err(RenderError::SingularMatrix);
       }
 
-      let pivinv = 1.0 / minv[icol * 4 + icol];
-      minv[icol * 4 + icol] = 1.0;
+      let pivinv = 1.0 / minv[icol][icol];
+      minv[icol][icol] = 1.0;
 
-      for j in range(0u, 4) {
-        minv[icol * 4 + j] *= pivinv;
+      for j in 0..4 {
+        minv[icol][j] *= pivinv;
       }
 
-      for j in range(0u, 4) {
+      for j in 0..4 {
         if j == icol {
           continue;
         }
 
-        let save = minv[j * 4 + icol];
-        minv[j * 4 + icol] = 0.0;
+        let save = minv[j][icol];
+        minv[j][icol] = 0.0;
 
-        for k in range(0u, 4) {
-          minv[j * 4 + k] -= minv[icol * 4 + k] * save;
+        for k in 0..4 {
+          minv[j][k] -= minv[icol][k] * save;
         }
       }
     }
 
-    for i in range(0u, 4) {
+    for i in 0..4 {
       let j = 3 - i;
 
       if indxr[j] == indxc[j] {
         continue;
       }
 
-      for k in range(0u, 4) {
-        let t = minv[k * 4 + indxr[j]];
-        minv[k * 4 + indxr[j]] = minv[k * 4 + indxc[j]];
-        minv[k * 4 + indxc[j]] = t;
+      for row in minv.iter_mut() {
+        row.swap(indxr[j], indxc[j]);
       }
     }
 
-    Matrix::from_data(minv)
+    Ok(Matrix::from_data(minv))
   }
 }
 
-impl Eq for Matrix {
-  fn eq(&self, m: &Matrix) -> bool {
-    self.m.iter().zip(m.m.iter()).all(|(&a, &b)| a == b)
+impl Index<usize> for Matrix {
+  type Output = [Float; 4];
+
+  fn index(&self, i: usize) -> &[Float; 4] {
+    &self.m[i]
   }
 }
 
-impl Mul<Matrix, Matrix> for Matrix {
-  fn mul(&self, m: &Matrix) -> Matrix {
+impl Mul<Matrix> for Matrix {
+  type Output = Matrix;
+
+  fn mul(self, m: Matrix) -> Matrix {
     let mut r = Matrix::zero();
 
-    for i in range(0u, 4) {
-      for j in range(0u, 4) {
-        r.m[i* 4 + j] =
-          self.m[i * 4 + 0] * m.m[0 * 4 + j] +
-          self.m[i * 4 + 1] * m.m[1 * 4 + j] +
-          self.m[i * 4 + 2] * m.m[2 * 4 + j] +
-          self.m[i * 4 + 3] * m.m[3 * 4 + j];
+    for i in 0..4 {
+      for j in 0..4 {
+        r.m[i][j] =
+          self.m[i][0] * m.m[0][j] +
+          self.m[i][1] * m.m[1][j] +
+          self.m[i][2] * m.m[2][j] +
+          self.m[i][3] * m.m[3][j];
       }
     }
 
-    return r;
+    r
   }
 }
 
@@ -164,7 +164,7 @@ pub trait Applicable<S, R> {
 pub trait TransformRhs<S> {
   fn apply_to_transform(&self, lhs: &Transform) -> S;
   fn apply_to_transform_directly(&self, lhs: &Transform, r: &mut S) {
-    fail!("Unimplemented method. {}");
+    *r = self.apply_to_transform(lhs);
   }
 }
 
@@ -178,23 +178,24 @@ impl<S, R: TransformRhs<S>> Applicable<R, S> for Transform {
   }
 }
 
-#[deriving(Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Transform {
   m: Matrix,
   m_inv: Matrix,
 }
 
 impl Transform {
-  pub fn from_matrix(m: Matrix) -> Transform {
-    Transform { m: m.clone(), m_inv: m.clone() }
+  /// Build a transform from `m`, failing if `m` cannot be inverted
+  pub fn from_matrix(m: Matrix) -> Result<Transform, RenderError> {
+    Ok(Transform { m, m_inv: Matrix::inverse(&m)? })
   }
 
   pub fn new(m: Matrix, m_inv: Matrix) -> Transform {
-    Transform { m: m, m_inv: m_inv }
+    Transform { m, m_inv }
   }
 
   pub fn inverse(t: &Transform) -> Transform {
-    Transform::new(t.m_inv.clone(), t.m.clone())
+    Transform::new(t.m_inv, t.m)
   }
 
   pub fn transpose(t: &Transform) -> Transform {
@@ -217,7 +218,7 @@ impl Transform {
     Transform::new(m, m_inv)
   }
 
-  pub fn scale(x: f32, y: f32, z: f32) -> Transform {
+  pub fn scale(x: Float, y: Float, z: Float) -> Transform {
     let m = Matrix::new(
         x, 0.0, 0.0, 0.0,
       0.0,   y, 0.0, 0.0,
@@ -233,7 +234,7 @@ impl Transform {
     Transform::new(m, m_inv)
   }
 
-  pub fn rotate_x(angle: f32) -> Transform {
+  pub fn rotate_x(angle: Float) -> Transform {
     let sin_t = radians(angle).sin();
     let cos_t = radians(angle).cos();
 
@@ -243,10 +244,10 @@ impl Transform {
       0.0, sin_t,  cos_t, 0.0,
       0.0,   0.0,  0.0, 1.0);
 
-    Transform::new(m.clone(), Matrix::transpose(&m.clone()))
+    Transform::new(m, Matrix::transpose(&m))
   }
 
-  pub fn rotate_y(angle: f32) -> Transform {
+  pub fn rotate_y(angle: Float) -> Transform {
     let sin_t = radians(angle).sin();
     let cos_t = radians(angle).cos();
 
@@ -256,10 +257,10 @@ impl Transform {
       -sin_t, 0.0, cos_t, 0.0,
          0.0, 0.0,   0.0, 1.0);
 
-    Transform::new(m.clone(), Matrix::transpose(&m.clone()))
+    Transform::new(m, Matrix::transpose(&m))
   }
 
-  pub fn rotate_z(angle: f32) -> Transform {
+  pub fn rotate_z(angle: Float) -> Transform {
     let sin_t = radians(angle).sin();
     let cos_t = radians(angle).cos();
 
@@ -269,99 +270,178 @@ impl Transform {
         0.0,   0.0,  1.0, 0.0,
         0.0,   0.0,  0.0, 1.0);
 
-    Transform::new(m.clone(), Matrix::transpose(&m.clone()))
+    Transform::new(m, Matrix::transpose(&m))
   }
 
-  pub fn rotate(angle: f32, axis: &Vector) -> Transform {
+  pub fn rotate(angle: Float, axis: &Vector) -> Transform {
     let a = normalize(*axis);
     let s = radians(angle).sin();
-    let c = radians(angle).sin();
+    let c = radians(angle).cos();
 
-    let mut d = box [0.0, ..16];
+    let mut d = [[0.0; 4]; 4];
 
-    d[0 * 4 + 0] = a.x * a.x + (1.0 - a.x * a.x) * c;
-    d[0 * 4 + 1] = a.x * a.y + (1.0 - c) - a.z * s;
-    d[0 * 4 + 2] = a.x * a.z + (1.0 - c) + a.y * s;
-    d[0 * 4 + 3] = 0.0;
+    d[0][0] = a.x * a.x + (1.0 - a.x * a.x) * c;
+    d[0][1] = a.x * a.y * (1.0 - c) - a.z * s;
+    d[0][2] = a.x * a.z * (1.0 - c) + a.y * s;
+    d[0][3] = 0.0;
 
-    d[1 * 4 + 0] = a.x * a.y + (1.0 - c) + a.z * s;
-    d[1 * 4 + 1] = a.y * a.y + (1.0 - a.y * a.y) * c;
-    d[1 * 4 + 2] = a.y * a.z + (1.0 - c) - a.x * s;
-    d[1 * 4 + 3] = 0.0;
+    d[1][0] = a.x * a.y * (1.0 - c) + a.z * s;
+    d[1][1] = a.y * a.y + (1.0 - a.y * a.y) * c;
+    d[1][2] = a.y * a.z * (1.0 - c) - a.x * s;
+    d[1][3] = 0.0;
 
-    d[2 * 4 + 0] = a.x * a.z + (1.0 - c) - a.y * s;
-    d[2 * 4 + 1] = a.y * a.z + (1.0 - c) + a.x * s;
-    d[2 * 4 + 2] = a.z * a.z + (1.0 - a.z * a.z) * c;
-    d[2 * 4 + 3] = 0.0;
+    d[2][0] = a.x * a.z * (1.0 - c) - a.y * s;
+    d[2][1] = a.y * a.z * (1.0 - c) + a.x * s;
+    d[2][2] = a.z * a.z + (1.0 - a.z * a.z) * c;
+    d[2][3] = 0.0;
 
-    d[3 * 4 + 0] = 0.0;
-    d[3 * 4 + 1] = 0.0;
-    d[3 * 4 + 2] = 0.0;
-    d[3 * 4 + 3] = 1.0;
+    d[3][0] = 0.0;
+    d[3][1] = 0.0;
+    d[3][2] = 0.0;
+    d[3][3] = 1.0;
 
     let m = Matrix::from_data(d);
 
-    Transform::new(m.clone(), Matrix::transpose(&m.clone()))
+    Transform::new(m, Matrix::transpose(&m))
   }
 
-  pub fn look_at(pos: &Point, look: &Point, up: &Vector) -> Transform {
-    let mut m = box [0.0f32, ..16];
+  pub fn look_at(pos: &Point, look: &Point, up: &Vector) -> Result<Transform, RenderError> {
+    let mut m = [[0.0 as Float; 4]; 4];
 
-    m[0 * 4 + 3] = pos.x;
-    m[1 * 4 + 3] = pos.y;
-    m[2 * 4 + 3] = pos.z;
-    m[3 * 4 + 3] =   1.0;
+    m[0][3] = pos.x;
+    m[1][3] = pos.y;
+    m[2][3] = pos.z;
+    m[3][3] =   1.0;
 
-    let dir   = normalize(*look - *pos);
-    let left  = normalize(cross(normalize(*up), dir));
+    if (*look - *pos).length() == 0.0 {
+      return Err(RenderError::InvalidParameter {
+        name: "look".to_string(),
+        message: "camera looks at its own position".to_string()
+      });
+    }
+
+    let dir  = normalize(*look - *pos);
+    let left = cross(normalize(*up), dir);
+
+    // A zero `up` normalizes to NaN, which is caught here too
+    let left_length = left.length();
+    if left_length == 0.0 || left_length.is_nan() {
+      return Err(RenderError::InvalidParameter {
+        name: "up".to_string(),
+        message: format!("up vector {:?} is parallel to the viewing direction", up)
+      });
+    }
+
+    let left  = normalize(left);
     let newup = cross(dir, left);
 
-    m[0 * 4 + 0] = left.x;
-    m[1 * 4 + 0] = left.y;
-    m[2 * 4 + 0] = left.z;
-    m[3 * 4 + 0] =  0.0;
+    m[0][0] = left.x;
+    m[1][0] = left.y;
+    m[2][0] = left.z;
+    m[3][0] =  0.0;
 
-    m[0 * 4 + 1] = newup.x;
-    m[1 * 4 + 1] = newup.y;
-    m[2 * 4 + 1] = newup.z;
-    m[3 * 4 + 1] =   0.0;
+    m[0][1] = newup.x;
+    m[1][1] = newup.y;
+    m[2][1] = newup.z;
+    m[3][1] =   0.0;
 
-    m[0 * 4 + 2] = dir.x;
-    m[1 * 4 + 2] = dir.y;
-    m[2 * 4 + 2] = dir.z;
-    m[3 * 4 + 2] =   0.0;
+    m[0][2] = dir.x;
+    m[1][2] = dir.y;
+    m[2][2] = dir.z;
+    m[3][2] =   0.0;
 
     let cam_to_world = Matrix::from_data(m);
-    Transform::new(Matrix::inverse(&cam_to_world), cam_to_world)
+    Ok(Transform::new(Matrix::inverse(&cam_to_world)?, cam_to_world))
   }
 
-  pub fn orthographic(znear: f32, zfar: f32) -> Transform {
+  pub fn orthographic(znear: Float, zfar: Float) -> Transform {
     Transform::scale(1.0, 1.0, 1.0 / (zfar - znear))
     *
     Transform::translate(&Vector::new(0.0, 0.0, -znear))
   }
 
-  pub fn perspective(fov: f32, n: f32, f: f32) -> Transform {
+  pub fn perspective(fov: Float, n: Float, f: Float) -> Transform {
+    let a = f / (f - n);
+    let b = -f * n / (f - n);
+
     let persp = Matrix::new(
-      1.0, 0.0,         0.0,              0.0,
-      0.0, 1.0,         0.0,              0.0,
-      0.0, 0.0, f / (f - n), -f * n / (f - n),
-      0.0, 0.0,         1.0,              0.0);
+      1.0, 0.0, 0.0, 0.0,
+      0.0, 1.0, 0.0, 0.0,
+      0.0, 0.0,   a,   b,
+      0.0, 0.0, 1.0, 0.0);
+
+    let persp_inv = Matrix::new(
+      1.0, 0.0,     0.0,      0.0,
+      0.0, 1.0,     0.0,      0.0,
+      0.0, 0.0,     0.0,      1.0,
+      0.0, 0.0, 1.0 / b, -a / b);
+
+    let inv_tan_ang = 1.0 / (radians(fov) / 2.0).tan();
+    Transform::scale(inv_tan_ang, inv_tan_ang, 1.0) * Transform::new(persp, persp_inv)
+  }
+
+  pub fn get_matrix(&self) -> &Matrix {
+    &self.m
+  }
+
+  pub fn get_inverse_matrix(&self) -> &Matrix {
+    &self.m_inv
+  }
+
+  /// Transform `p`, also returning a bound on the rounding error of the
+  /// transformed point
+  pub fn apply_point_with_error(&self, p: &Point) -> (Point, Vector) {
+    let m = &self.m.m;
+    let (x, y, z) = (p.x, p.y, p.z);
+    let abs_sum = |i: usize| (m[i][0] * x).abs() + (m[i][1] * y).abs() +
+      (m[i][2] * z).abs() + m[i][3].abs();
+
+    let p_error = Vector::new(abs_sum(0), abs_sum(1), abs_sum(2)) * gamma(3);
+    (self.apply(*p), p_error)
+  }
+
+  /// Transform `p` whose coordinates already carry the error `p_error`,
+  /// returning the combined error of the transformed point
+  pub fn apply_point_with_abs_error(&self, p: &Point, p_error: &Vector) -> (Point, Vector) {
+    let m = &self.m.m;
+    let (x, y, z) = (p.x, p.y, p.z);
+    let err = |i: usize|
+      (gamma(3) + 1.0) * (m[i][0].abs() * p_error.x + m[i][1].abs() * p_error.y +
+        m[i][2].abs() * p_error.z) +
+      gamma(3) * ((m[i][0] * x).abs() + (m[i][1] * y).abs() + (m[i][2] * z).abs() + m[i][3].abs());
+
+    (self.apply(*p), Vector::new(err(0), err(1), err(2)))
+  }
+
+  /// Transform `v`, also returning a bound on the rounding error of the
+  /// transformed vector
+  pub fn apply_vector_with_error(&self, v: &Vector) -> (Vector, Vector) {
+    let m = &self.m.m;
+    let abs_sum = |i: usize| (m[i][0] * v.x).abs() + (m[i][1] * v.y).abs() + (m[i][2] * v.z).abs();
 
-    let inv_tan_ang = 1.0 / radians(fov).tan() / 2.0;
-    Transform::scale(inv_tan_ang, inv_tan_ang, 1.0) * Transform::from_matrix(persp)
+    let v_error = Vector::new(abs_sum(0), abs_sum(1), abs_sum(2)) * gamma(3);
+    (self.apply(*v), v_error)
+  }
+
+  /// Transform `r`, also returning the error bounds on the new origin
+  /// and direction
+  pub fn apply_ray_with_error(&self, r: &Ray) -> (Ray, Vector, Vector) {
+    let (_, o_error) = self.apply_point_with_error(&r.o);
+    let (_, d_error) = self.apply_vector_with_error(&r.d);
+
+    (self.apply(*r), o_error, d_error)
   }
 
   pub fn is_identity(&self) -> bool {
-    for i in range(0u, 4) {
-      for j in range(0u, 4) {
-        if self.m.m[i * 4 + j] != if i == j { 1.0 } else { 0.0 } {
+    for i in 0..4 {
+      for j in 0..4 {
+        if self.m.m[i][j] != if i == j { 1.0 } else { 0.0 } {
           return false;
         }
       }
     }
 
-    return true;
+    true
   }
 
   pub fn has_scale(&self) -> bool {
@@ -373,20 +453,18 @@ impl Transform {
   }
 
   pub fn swaps_handedness(&self) -> bool {
-    ((self.m.m[0 * 4 + 0]  *
-      (self.m.m[1 * 4 + 1] * self.m.m[2 * 4 + 2] -
-       self.m.m[1 * 4 + 2] * self.m.m[2 * 4 + 1])) -
-     (self.m.m[0 * 4 + 1]  *
-      (self.m.m[1 * 4 + 0] * self.m.m[2 * 4 + 2] -
-       self.m.m[1 * 4 + 2] * self.m.m[2 * 4 + 0])) -
-     (self.m.m[0 * 4 + 2]  *
-      (self.m.m[1 * 4 + 0] * self.m.m[2 * 4 + 1] -
-       self.m.m[1 * 4 + 1] * self.m.m[2 * 4 + 0]))) < 0.0
+    let m = &self.m.m;
+
+    ((m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])) -
+     (m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])) +
+     (m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]))) < 0.0
   }
 }
 
-impl Mul<Transform, Transform> for Transform {
-  fn mul(&self, t: &Transform) -> Transform {
+impl Mul<Transform> for Transform {
+  type Output = Transform;
+
+  fn mul(self, t: Transform) -> Transform {
     let m1 = self.m  * t.m;
     let m2 = t.m_inv * self.m_inv;
 
@@ -396,25 +474,19 @@ impl Mul<Transform, Transform> for Transform {
 
 impl TransformRhs<Point> for Point {
   fn apply_to_transform(&self, lhs: &Transform) -> Point {
-    let (x, y, z) = (self.x, self.y, self.z);
-    let xp = lhs.m.m[0 * 4 + 0] * x + lhs.m.m[0 * 4 + 1] * y + lhs.m.m[0 * 4 + 2] * z + lhs.m.m[0 * 4 + 3];
-    let yp = lhs.m.m[1 * 4 + 0] * x + lhs.m.m[1 * 4 + 1] * y + lhs.m.m[1 * 4 + 2] * z + lhs.m.m[1 * 4 + 3];
-    let zp = lhs.m.m[2 * 4 + 0] * x + lhs.m.m[2 * 4 + 1] * y + lhs.m.m[2 * 4 + 2] * z + lhs.m.m[2 * 4 + 3];
-    let wp = lhs.m.m[3 * 4 + 0] * x + lhs.m.m[3 * 4 + 1] * y + lhs.m.m[3 * 4 + 2] * z + lhs.m.m[3 * 4 + 3];
-
-    if wp == 1.0 {
-      Point::new(xp, yp, zp)
-    } else {
-      Point::new(xp, yp, zp) / wp
-    }
+    let mut r = Point::zero();
+    self.apply_to_transform_directly(lhs, &mut r);
+    r
   }
 
   fn apply_to_transform_directly(&self, lhs: &Transform, r: &mut Point) {
     let (x, y, z) = (self.x, self.y, self.z);
-    r.x   = lhs.m.m[0* 4 + 0] * x + lhs.m.m[0* 4 + 1] * y + lhs.m.m[0* 4 + 2] * z + lhs.m.m[0* 4 + 3];
-    r.y   = lhs.m.m[1* 4 + 0] * x + lhs.m.m[1* 4 + 1] * y + lhs.m.m[1* 4 + 2] * z + lhs.m.m[1* 4 + 3];
-    r.z   = lhs.m.m[2* 4 + 0] * x + lhs.m.m[2* 4 + 1] * y + lhs.m.m[2* 4 + 2] * z + lhs.m.m[2* 4 + 3];
-    let w = lhs.m.m[3* 4 + 0] * x + lhs.m.m[3* 4 + 1] * y + lhs.m.m[3* 4 + 2] * z + lhs.m.m[3* 4 + 3];
+    let m = &lhs.m.m;
+
+    r.x   = m[0][0] * x + m[0][1] * y + m[0][2] * z + m[0][3];
+    r.y   = m[1][0] * x + m[1][1] * y + m[1][2] * z + m[1][3];
+    r.z   = m[2][0] * x + m[2][1] * y + m[2][2] * z + m[2][3];
+    let w = m[3][0] * x + m[3][1] * y + m[3][2] * z + m[3][3];
 
     if w != 1.0 {
       r.x /= w;
@@ -427,44 +499,30 @@ impl TransformRhs<Point> for Point {
 impl TransformRhs<Vector> for Vector {
   fn apply_to_transform(&self, lhs: &Transform) -> Vector {
     let (x, y, z) = (self.x, self.y, self.z);
+    let m = &lhs.m.m;
 
     Vector::new(
-      lhs.m.m[0* 4 + 0] * x + lhs.m.m[0* 4 + 1] * y + lhs.m.m[0* 4 + 2] * z,
-      lhs.m.m[1* 4 + 0] * x + lhs.m.m[1* 4 + 1] * y + lhs.m.m[1* 4 + 2] * z,
-      lhs.m.m[2* 4 + 0] * x + lhs.m.m[2* 4 + 1] * y + lhs.m.m[2* 4 + 2] * z)
-  }
-
-  fn apply_to_transform_directly(&self, lhs: &Transform, r: &mut Vector) {
-    let (x, y, z) = (self.x, self.y, self.z);
-
-    r.x = lhs.m.m[0* 4 + 0] * x + lhs.m.m[0* 4 + 1] * y + lhs.m.m[0* 4 + 2] * z;
-    r.y = lhs.m.m[1* 4 + 0] * x + lhs.m.m[1* 4 + 1] * y + lhs.m.m[1* 4 + 2] * z;
-    r.z = lhs.m.m[2* 4 + 0] * x + lhs.m.m[2* 4 + 1] * y + lhs.m.m[2* 4 + 2] * z;
+      m[0][0] * x + m[0][1] * y + m[0][2] * z,
+      m[1][0] * x + m[1][1] * y + m[1][2] * z,
+      m[2][0] * x + m[2][1] * y + m[2][2] * z)
   }
 }
 
 impl TransformRhs<Normal> for Normal {
   fn apply_to_transform(&self, lhs: &Transform) -> Normal {
     let (x, y, z) = (self.x, self.y, self.z);
+    let m_inv = &lhs.m_inv.m;
 
     Normal::new(
-      lhs.m_inv.m[0* 4 + 0] * x + lhs.m_inv.m[1* 4 + 0] * y + lhs.m_inv.m[2* 4 + 0] * z,
-      lhs.m_inv.m[0* 4 + 1] * x + lhs.m_inv.m[1* 4 + 1] * y + lhs.m_inv.m[2* 4 + 1] * z,
-      lhs.m_inv.m[0* 4 + 2] * x + lhs.m_inv.m[1* 4 + 2] * y + lhs.m_inv.m[2* 4 + 2] * z)
-  }
-
-  fn apply_to_transform_directly(&self, lhs: &Transform, r: &mut Normal) {
-    let (x, y, z) = (self.x, self.y, self.z);
-
-    r.x = lhs.m_inv.m[0* 4 + 0] * x + lhs.m_inv.m[1* 4 + 0] * y + lhs.m_inv.m[2* 4 + 0] * z;
-    r.y = lhs.m_inv.m[0* 4 + 1] * x + lhs.m_inv.m[1* 4 + 1] * y + lhs.m_inv.m[2* 4 + 1] * z;
-    r.z = lhs.m_inv.m[0* 4 + 2] * x + lhs.m_inv.m[1* 4 + 2] * y + lhs.m_inv.m[2* 4 + 2] * z;
+      m_inv[0][0] * x + m_inv[1][0] * y + m_inv[2][0] * z,
+      m_inv[0][1] * x + m_inv[1][1] * y + m_inv[2][1] * z,
+      m_inv[0][2] * x + m_inv[1][2] * y + m_inv[2][2] * z)
   }
 }
 
 impl TransformRhs<BBox> for BBox {
   fn apply_to_transform(&self, lhs: &Transform) -> BBox {
-    let mut b = BBox::from_point(&Point::new(self.p_min.x, self.p_min.y, self.p_min.y).apply_to_transform(lhs));
+    let mut b = BBox::from_point(&Point::new(self.p_min.x, self.p_min.y, self.p_min.z).apply_to_transform(lhs));
 
     b = b.union(&Point::new(self.p_max.x, self.p_min.y, self.p_min.z).apply_to_transform(lhs));
     b = b.union(&Point::new(self.p_min.x, self.p_max.y, self.p_min.z).apply_to_transform(lhs));
@@ -474,51 +532,471 @@ impl TransformRhs<BBox> for BBox {
     b = b.union(&Point::new(self.p_max.x, self.p_min.y, self.p_max.z).apply_to_transform(lhs));
     b = b.union(&Point::new(self.p_max.x, self.p_max.y, self.p_max.z).apply_to_transform(lhs));
 
-    return b;
+    b
   }
 }
 
 impl TransformRhs<Ray> for Ray {
   fn apply_to_transform(&self, lhs: &Transform) -> Ray {
-    let mut r = self.clone();
+    let (mut o, o_error) = lhs.apply_point_with_error(&self.o);
+    let d: Vector = lhs.apply(self.d);
+    let mut r = *self;
+
+    // Move the origin to the far edge of its error bounds, so the
+    // transformed ray cannot start behind the surface it left
+    let length_squared = d.length_squared();
+    if length_squared > 0.0 {
+      let dt = (d.x.abs() * o_error.x + d.y.abs() * o_error.y + d.z.abs() * o_error.z) /
+        length_squared;
+      o = o + d * dt;
+      r.maxt -= dt;
+    }
+
+    r.o = o;
+    r.d = d;
+    r
+  }
+}
 
-    lhs.apply_to(self.o, &mut r.o);
-    lhs.apply_to(self.d, &mut r.d);
+/// Transform interpolated between two keyframes over a time interval
+#[derive(Debug, Clone, Copy)]
+pub struct AnimatedTransform {
+  pub start_time: Float,
+  pub end_time: Float,
+  pub start_transform: Transform,
+  pub end_transform: Transform,
+  actually_animated: bool,
+  t: [Vector; 2],
+  r: [Quaternion; 2],
+  s: [Matrix; 2]
+}
+
+impl AnimatedTransform {
+  /// Fails when a keyframe is singular, or when only one of them
+  /// mirrors space, as every path between them passes through a singular
+  /// transform
+  pub fn new(start_transform: &Transform, start_time: Float,
+      end_transform: &Transform, end_time: Float) -> Result<AnimatedTransform, RenderError> {
+    if start_transform.swaps_handedness() != end_transform.swaps_handedness() {
+      return Err(RenderError::SingularMatrix);
+    }
 
-    return r;
+    let (t0, mut r0, s0) = AnimatedTransform::decompose(&start_transform.m)?;
+    let (t1, mut r1, s1) = AnimatedTransform::decompose(&end_transform.m)?;
+
+    // Interpolate along the shortest path
+    if r0.dot(&r1) < 0.0 {
+      r1 = -r1;
+    }
+    r0 = normalize(r0);
+    r1 = normalize(r1);
+
+    Ok(AnimatedTransform {
+      start_time,
+      end_time,
+      start_transform: *start_transform,
+      end_transform: *end_transform,
+      actually_animated: start_transform != end_transform,
+      t: [t0, t1],
+      r: [r0, r1],
+      s: [s0, s1]
+    })
+  }
+
+  /// Transform that does not move over time
+  pub fn fixed(t: &Transform) -> AnimatedTransform {
+    let (t0, r0, s0) = AnimatedTransform::decompose(&t.m)
+      .unwrap_or((Vector::zero(), Quaternion::identity(), t.m));
+
+    AnimatedTransform {
+      start_time: 0.0,
+      end_time: 1.0,
+      start_transform: *t,
+      end_transform: *t,
+      actually_animated: false,
+      t: [t0, t0],
+      r: [r0, r0],
+      s: [s0, s0]
+    }
   }
 
-  fn apply_to_transform_directly(&self, lhs: &Transform, r: &mut Ray) {
-    lhs.apply_to(self.o, &mut r.o);
-    lhs.apply_to(self.d, &mut r.d);
+  /// Split `m` into translation, rotation and scale, so that
+  /// `m = T * R * S`. A mirroring `m` leaves the reflection in `S`.
+  pub fn decompose(m: &Matrix) -> Result<(Vector, Quaternion, Matrix), RenderError> {
+    let t = Vector::new(m.m[0][3], m.m[1][3], m.m[2][3]);
+
+    let mut mm = *m;
+    for i in 0..3 {
+      mm.m[i][3] = 0.0;
+      mm.m[3][i] = 0.0;
+    }
+    mm.m[3][3] = 1.0;
+
+    // Polar decomposition: average R with its inverse transpose until
+    // it converges to the rotation
+    let mut r = mm;
+    for _ in 0..100 {
+      let r_it = Matrix::inverse(&Matrix::transpose(&r))?;
+      let mut r_next = Matrix::zero();
+      let mut norm: Float = 0.0;
+
+      for i in 0..4 {
+        for j in 0..4 {
+          r_next.m[i][j] = 0.5 * (r.m[i][j] + r_it.m[i][j]);
+        }
+        let n = (r.m[i][0] - r_next.m[i][0]).abs() +
+          (r.m[i][1] - r_next.m[i][1]).abs() +
+          (r.m[i][2] - r_next.m[i][2]).abs();
+        norm = norm.max(n);
+      }
+
+      r = r_next;
+      if norm <= 0.0001 {
+        break;
+      }
+    }
 
-    if self != r {
-      r.mint  = self.mint;
-      r.maxt  = self.maxt;
-      r.time  = self.time;
-      r.depth = self.depth;
+    // Only proper rotations have a quaternion
+    let det = r.m[0][0] * (r.m[1][1] * r.m[2][2] - r.m[1][2] * r.m[2][1]) -
+      r.m[0][1] * (r.m[1][0] * r.m[2][2] - r.m[1][2] * r.m[2][0]) +
+      r.m[0][2] * (r.m[1][0] * r.m[2][1] - r.m[1][1] * r.m[2][0]);
+    if det < 0.0 {
+      for row in r.m.iter_mut().take(3) {
+        for x in row.iter_mut().take(3) {
+          *x = -*x;
+        }
+      }
     }
+
+    let rquat = Quaternion::from_transform(&Transform::new(r, Matrix::transpose(&r)));
+    let s = Matrix::inverse(&r)? * mm;
+
+    Ok((t, rquat, s))
+  }
+
+  pub fn is_animated(&self) -> bool {
+    self.actually_animated
+  }
+
+  /// Transform at the given time, clamped to the keyframe interval
+  pub fn interpolate(&self, time: Float) -> Transform {
+    if !self.actually_animated || time <= self.start_time {
+      return self.start_transform;
+    }
+
+    if time >= self.end_time {
+      return self.end_transform;
+    }
+
+    let dt = (time - self.start_time) / (self.end_time - self.start_time);
+    let trans = self.t[0] * (1.0 - dt) + self.t[1] * dt;
+    let rotate = Quaternion::slerp(dt, &self.r[0], &self.r[1]);
+
+    let mut scale = Matrix::zero();
+    for i in 0..3 {
+      for j in 0..3 {
+        scale.m[i][j] = lerp(dt, self.s[0].m[i][j], self.s[1].m[i][j]);
+      }
+    }
+    scale.m[3][3] = 1.0;
+
+    // The keyframe scales are both positive or both negative definite,
+    // as `new` checks, so every blend of them can be inverted
+    match Transform::from_matrix(scale) {
+      Ok(scale) => Transform::translate(&trans) * rotate.to_transform() * scale,
+      Err(_) => if dt < 0.5 { self.start_transform } else { self.end_transform }
+    }
+  }
+
+  /// Conservative bound of `b` over the whole time interval. The paths
+  /// of the corners of `b` are sampled, and each sample is padded by how
+  /// far its corner can move before the next one.
+  pub fn motion_bounds(&self, b: &BBox) -> BBox {
+    if !self.actually_animated {
+      return self.start_transform.apply(*b);
+    }
+
+    let steps = 64;
+    let step = 1.0 / (steps - 1) as Float;
+
+    // Angle turned over the interval; slerp turns at a constant rate, and
+    // the margin covers the normalised lerp it uses for small angles
+    let angle = 2.0 * self.r[0].dot(&self.r[1]).clamp(-1.0, 1.0).acos() * 1.001;
+    let translation = (self.t[1] - self.t[0]).length();
+
+    let mut ret: Option<BBox> = None;
+    for i in 0..8 {
+      let corner = Point::new(
+        if i & 1 == 0 { b.p_min.x } else { b.p_max.x },
+        if i & 2 == 0 { b.p_min.y } else { b.p_max.y },
+        if i & 4 == 0 { b.p_min.z } else { b.p_max.z });
+
+      // Bound on the corner's speed: translation, rotation of its largest
+      // scaled offset, and the change of scale, which rotation preserves
+      let (s0, s1) = (apply_linear(&self.s[0], &corner), apply_linear(&self.s[1], &corner));
+      let speed = translation + angle * s0.length().max(s1.length()) + (s1 - s0).length();
+      let pad = 0.5 * speed * step;
+
+      for j in 0..steps {
+        let time = lerp(j as Float * step, self.start_time, self.end_time);
+        let mut pb = BBox::from_point(&self.interpolate(time).apply(corner));
+        pb.expand(pad);
+
+        ret = Some(match ret {
+          Some(r) => r.union(&pb),
+          None => pb
+        });
+      }
+    }
+
+    ret.unwrap_or(*b)
+  }
+
+  /// Transform `r` by the interpolated transform at the ray's own time
+  pub fn apply_ray(&self, r: &Ray) -> Ray {
+    self.interpolate(r.time).apply(*r)
   }
-}
 
-impl Eq for Transform {
-  fn eq(&self, t: &Transform) -> bool {
-    self.m == t.m && self.m_inv == t.m_inv
+  pub fn apply_point(&self, time: Float, p: &Point) -> Point {
+    self.interpolate(time).apply(*p)
+  }
+
+  pub fn apply_vector(&self, time: Float, v: &Vector) -> Vector {
+    self.interpolate(time).apply(*v)
   }
 }
 
-impl Ord for Transform {
-  fn lt(&self, t: &Transform) -> bool {
-    for i in range(0u, 4) {
-      for j in range(0u, 4) {
-        if self.m.m[i * 4 + j] == t.m.m[i * 4 + j] {
+/// Upper left 3x3 part of `m` applied to `p` as a vector
+fn apply_linear(m: &Matrix, p: &Point) -> Vector {
+  let m = &m.m;
+  Vector::new(
+    m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z,
+    m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z,
+    m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z)
+}
+
+impl PartialOrd for Transform {
+  fn partial_cmp(&self, t: &Transform) -> Option<Ordering> {
+    for i in 0..4 {
+      for j in 0..4 {
+        if self.m.m[i][j] == t.m.m[i][j] {
           continue;
         }
 
-        return self.m.m[i * 4 + j] < t.m.m[i * 4 + j];
+        return self.m.m[i][j].partial_cmp(&t.m.m[i][j]);
+      }
+    }
+
+    Some(Ordering::Equal)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::dot;
+
+  fn assert_matrix_eq(a: &Matrix, b: &Matrix) {
+    for i in 0..4 {
+      for j in 0..4 {
+        assert!((a.m[i][j] - b.m[i][j]).abs() < 1e-4, "{:?} != {:?}", a, b);
+      }
+    }
+  }
+
+  fn assert_round_trips(t: &Transform) {
+    assert_matrix_eq(&(t.m * t.m_inv), &Matrix::new(
+      1.0, 0.0, 0.0, 0.0,
+      0.0, 1.0, 0.0, 0.0,
+      0.0, 0.0, 1.0, 0.0,
+      0.0, 0.0, 0.0, 1.0));
+    assert_matrix_eq(&Matrix::inverse(&t.m).unwrap(), &t.m_inv);
+  }
+
+  #[test]
+  fn inverse_of_general_matrix() {
+    let m = Matrix::new(
+      2.0, 0.0, 1.0, 3.0,
+      1.0, 1.0, 0.0, -2.0,
+      0.0, 4.0, 1.0, 1.0,
+      0.0, 0.0, 0.0, 1.0);
+
+    assert_round_trips(&Transform::from_matrix(m).unwrap());
+  }
+
+  #[test]
+  fn inverse_of_singular_matrix_fails() {
+    let m = Matrix::new(
+      1.0, 2.0, 3.0, 0.0,
+      2.0, 4.0, 6.0, 0.0,
+      0.0, 0.0, 1.0, 0.0,
+      0.0, 0.0, 0.0, 1.0);
+
+    match Matrix::inverse(&m) {
+      Err(RenderError::SingularMatrix) => {},
+      _ => panic!("expected a singular matrix error")
+    }
+    assert!(Transform::from_matrix(m).is_err());
+  }
+
+  #[test]
+  fn transpose_swaps_rows_and_columns() {
+    let t = Transform::translate(&Vector::new(1.0, 2.0, 3.0));
+    let tt = Transform::transpose(&t);
+
+    assert_eq!(tt.m.m[3][0], 1.0);
+    assert_eq!(tt.m_inv.m[3][2], -3.0);
+    assert_eq!(Transform::transpose(&tt), t);
+  }
+
+  #[test]
+  fn rotate_about_axis_matches_rotate_x() {
+    let r = Transform::rotate(30.0, &Vector::new(2.0, 0.0, 0.0));
+
+    assert_matrix_eq(&r.m, &Transform::rotate_x(30.0).m);
+    assert_round_trips(&r);
+  }
+
+  #[test]
+  fn rotate_about_arbitrary_axis_keeps_axis_fixed() {
+    let axis = Vector::new(1.0, 1.0, 1.0);
+    let r = Transform::rotate(120.0, &axis);
+    let p: Vector = r.apply(Vector::new(1.0, 0.0, 0.0));
+
+    assert!((p.x - 0.0).abs() < 1e-5 && (p.y - 1.0).abs() < 1e-5 && p.z.abs() < 1e-5);
+    assert_round_trips(&r);
+  }
+
+  #[test]
+  fn look_at_maps_eye_to_origin() {
+    let t = Transform::look_at(&Point::new(1.0, 2.0, 3.0), &Point::new(1.0, 2.0, 10.0),
+      &Vector::new(0.0, 1.0, 0.0)).unwrap();
+    let eye: Point = t.apply(Point::new(1.0, 2.0, 3.0));
+    let ahead: Point = t.apply(Point::new(1.0, 2.0, 5.0));
+
+    assert!(eye.x.abs() < 1e-5 && eye.y.abs() < 1e-5 && eye.z.abs() < 1e-5);
+    assert!((ahead.z - 2.0).abs() < 1e-5);
+    assert_round_trips(&t);
+  }
+
+  #[test]
+  fn look_at_rejects_degenerate_frames() {
+    let (pos, look) = (Point::new(0.0, 0.0, 0.0), Point::new(0.0, 2.0, 0.0));
+
+    let along_up = Transform::look_at(&pos, &look, &Vector::new(0.0, 3.0, 0.0));
+    assert!(matches!(along_up, Err(RenderError::InvalidParameter { ref name, .. }) if name == "up"));
+    assert!(Transform::look_at(&pos, &look, &Vector::zero()).is_err());
+    assert!(Transform::look_at(&pos, &pos, &Vector::new(0.0, 1.0, 0.0)).is_err());
+  }
+
+  #[test]
+  fn perspective_maps_near_and_far_planes() {
+    let t = Transform::perspective(90.0, 1.0, 100.0);
+    let near: Point = t.apply(Point::new(1.0, 0.0, 1.0));
+    let far: Point = t.apply(Point::new(0.0, 0.0, 100.0));
+
+    assert!((near.x - 1.0).abs() < 1e-5 && near.z.abs() < 1e-5);
+    assert!((far.z - 1.0).abs() < 1e-5);
+    assert_round_trips(&t);
+  }
+
+  #[test]
+  fn normals_stay_perpendicular_under_non_uniform_scale() {
+    let t = Transform::scale(4.0, 1.0, 0.5) * Transform::rotate_z(30.0);
+    let v = Vector::new(1.0, -1.0, 0.0);
+    let n = Normal::new(1.0, 1.0, 0.0);
+
+    let tv: Vector = t.apply(v);
+    let tn: Normal = t.apply(n);
+
+    assert!(dot(tv, tn).abs() < 1e-5);
+  }
+
+  #[test]
+  fn bbox_transform_covers_all_corners() {
+    let b = BBox::new(&Point::new(0.0, 0.0, 0.0), &Point::new(1.0, 2.0, 3.0));
+    let tb: BBox = Transform::translate(&Vector::new(1.0, 0.0, 0.0)).apply(b);
+
+    assert_eq!(tb.p_min, Point::new(1.0, 0.0, 0.0));
+    assert_eq!(tb.p_max, Point::new(2.0, 2.0, 3.0));
+  }
+
+  #[test]
+  fn quaternion_round_trips_rotation() {
+    let r = Transform::rotate(75.0, &Vector::new(1.0, 2.0, -1.0));
+    let q = Quaternion::from_transform(&r);
+
+    assert_matrix_eq(&q.to_transform().m, &r.m);
+  }
+
+  #[test]
+  fn decompose_recovers_translation_rotation_and_scale() {
+    let m = (Transform::translate(&Vector::new(1.0, 2.0, 3.0)) *
+      Transform::rotate_y(40.0) * Transform::scale(2.0, 1.0, 0.5)).m;
+    let (t, r, s) = AnimatedTransform::decompose(&m).unwrap();
+
+    assert_eq!(t, Vector::new(1.0, 2.0, 3.0));
+    assert_matrix_eq(&r.to_transform().m, &Transform::rotate_y(40.0).m);
+    assert_matrix_eq(&s, &Transform::scale(2.0, 1.0, 0.5).m);
+  }
+
+  #[test]
+  fn animated_transform_interpolates_between_keyframes() {
+    let start = Transform::translate(&Vector::new(0.0, 0.0, 0.0)) * Transform::rotate_z(0.0);
+    let end = Transform::translate(&Vector::new(2.0, 0.0, 0.0)) * Transform::rotate_z(90.0);
+    let at = AnimatedTransform::new(&start, 0.0, &end, 1.0).unwrap();
+
+    assert_eq!(at.interpolate(-1.0), start);
+    assert_eq!(at.interpolate(2.0), end);
+
+    let mid = at.interpolate(0.5);
+    assert_matrix_eq(&mid.m,
+      &(Transform::translate(&Vector::new(1.0, 0.0, 0.0)) * Transform::rotate_z(45.0)).m);
+
+    let mut r = Ray::new(&Point::new(0.0, 0.0, 0.0), &Vector::new(1.0, 0.0, 0.0), 0.0, 1.0, 0.5);
+    r = at.apply_ray(&r);
+    assert!((r.o.x - 1.0).abs() < 1e-5);
+    assert!((r.d.x - r.d.y).abs() < 1e-5);
+  }
+
+  #[test]
+  fn motion_bounds_cover_the_whole_path() {
+    let start = Transform::translate(&Vector::new(0.0, 0.0, 0.0));
+    let end = Transform::translate(&Vector::new(5.0, 0.0, 0.0));
+    let at = AnimatedTransform::new(&start, 0.0, &end, 1.0).unwrap();
+    let b = BBox::new(&Point::new(-1.0, -1.0, -1.0), &Point::new(1.0, 1.0, 1.0));
+    let mb = at.motion_bounds(&b);
+
+    assert!(mb.p_min.x <= -1.0 && mb.p_max.x >= 6.0);
+    assert!(mb.p_max.x < 6.1);
+  }
+
+  #[test]
+  fn motion_bounds_hold_under_fast_rotation() {
+    let start = Transform::rotate_z(0.0) * Transform::scale(1.0, 1.0, 1.0);
+    let end = Transform::rotate_z(170.0) * Transform::scale(2.0, 1.0, 1.0);
+    let at = AnimatedTransform::new(&start, 0.0, &end, 1.0).unwrap();
+    let b = BBox::new(&Point::new(9.0, -0.5, -0.5), &Point::new(10.0, 0.5, 0.5));
+    let mb = at.motion_bounds(&b);
+
+    for i in 0..=10000 {
+      let t = at.interpolate(i as Float / 10000.0);
+      for p in [b.p_min, b.p_max, Point::new(10.0, 0.5, 0.0), Point::new(10.0, -0.5, 0.0)] {
+        assert!(mb.inside(&t.apply(p)));
       }
     }
+  }
+
+  #[test]
+  fn keyframes_of_opposite_handedness_are_rejected() {
+    let identity = Transform::scale(1.0, 1.0, 1.0);
+    let mirror = Transform::scale(-1.0, 1.0, 1.0);
+    assert!(AnimatedTransform::new(&identity, 0.0, &mirror, 1.0).is_err());
 
-    return false;
+    // Mirrored at both ends, the reflection is interpolated with the scale
+    let moved = Transform::translate(&Vector::new(2.0, 0.0, 0.0)) * mirror;
+    let at = AnimatedTransform::new(&mirror, 0.0, &moved, 1.0).unwrap();
+    let p = at.interpolate(0.5).apply(Point::new(1.0, 1.0, 0.0));
+    assert!((p.x - 0.0).abs() < 1e-5 && (p.y - 1.0).abs() < 1e-5);
   }
 }
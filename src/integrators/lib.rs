@@ -1,9 +1 @@
-#![crate_id="rbrtintegrators#0.0.2"]
-#![comment = "RBRT Core Library"]
-#![license = "BSD"]
-#![crate_type = "lib"]
-
-extern crate rand;
-extern crate rbrtcore;
-
 pub mod whitted;
@@ -1,3 +1,4 @@
+use rbrtcore::error::RenderError;
 use rbrtcore::geometry::{ RayDifferential, Vector, abs_dot };
 use rbrtcore::integrator::{
   Integrator,
@@ -8,16 +9,16 @@ use rbrtcore::integrator::{
 use rbrtcore::intersection::Intersection;
 use rbrtcore::light::{ LightSample, VisibilityTester };
 use rbrtcore::paramset::ParamSet;
-use rbrtcore::reflection::AllTypes;
+use rbrtcore::reflection::BxDFType;
 use rbrtcore::renderer::Renderer;
 use rbrtcore::sampler::Sample;
 use rbrtcore::scene::Scene;
 use rbrtcore::spectrum::Spectrum;
 
-use rand::TaskRng;
+use rand::rngs::ThreadRng;
 
 pub struct WhittedIntegrator {
-  max_depth: uint
+  max_depth: usize
 }
 
 impl Integrator for WhittedIntegrator {
@@ -25,35 +26,46 @@ impl Integrator for WhittedIntegrator {
 }
 
 impl WhittedIntegrator {
-  pub fn new(max_depth: uint) -> WhittedIntegrator {
-    WhittedIntegrator { max_depth: max_depth }
+  pub fn new(max_depth: usize) -> WhittedIntegrator {
+    WhittedIntegrator { max_depth }
   }
 
-  pub fn from_paramset(params: &ParamSet) -> WhittedIntegrator {
-    WhittedIntegrator::new(params.find_one_int("maxdepth", 5) as uint)
+  pub fn from_paramset(params: &ParamSet) -> Result<WhittedIntegrator, RenderError> {
+    let max_depth = params.find_one_int("maxdepth", 5)?;
+    if max_depth < 0 {
+      return Err(RenderError::InvalidParameter {
+        name: "maxdepth".to_string(),
+        message: format!("must not be negative, found {}", max_depth)
+      });
+    }
+
+    Ok(WhittedIntegrator::new(max_depth as usize))
   }
 }
 
 impl SurfaceIntegrator for WhittedIntegrator {
-  fn Li<'a>(&self, scene: &'a Scene<'a>, renderer: &Renderer, ray: &RayDifferential,
-      intersection: &mut Intersection, sample: &Sample, rng: &mut TaskRng) -> Spectrum {
+  fn li(&self, scene: &Scene, renderer: &dyn Renderer, ray: &RayDifferential,
+      intersection: &mut Intersection, sample: &Sample, rng: &mut ThreadRng) -> Spectrum {
     // Evaluate bsdf at hit point
-    let bsdf = intersection.get_bsdf(ray);
+    let bsdf = match intersection.get_bsdf(ray) {
+      Some(bsdf) => bsdf,
+      None       => return Spectrum::new(0.0)
+    };
 
     // Initialize common variables for whitted integrator
-    let p = bsdf.get_ref().dg_shading.p;
-    let n = bsdf.get_ref().dg_shading.nn;
+    let p = bsdf.dg_shading.p;
+    let n = bsdf.dg_shading.nn;
     let wo = -ray.ray.d;
 
     // Compute emitted light if ray hit an area light source
-    let mut L = intersection.Le(&wo);
+    let mut l = intersection.le(&wo);
 
     // Add contribution of each light source
     for light in scene.lights.iter() {
       let mut wi = Vector::new(0.0, 0.0, 0.0);
       let mut pdf = 0.0;
       let mut visibility = VisibilityTester::new();
-      let li = light.sample_l(&p, intersection.ray_epsilon,
+      let li = light.sample_l(&p, &intersection.p_error, &intersection.dg.nn,
         &LightSample::from_random(rng), ray.ray.time,
         &mut wi, &mut pdf, &mut visibility);
 
@@ -61,22 +73,22 @@ impl SurfaceIntegrator for WhittedIntegrator {
         continue;
       }
 
-      let f = bsdf.get_ref().f(&wo, &wi, AllTypes);
+      let f = bsdf.f(&wo, &wi, BxDFType::ALL_TYPES);
 
       if !f.is_black() && visibility.unoccluded(scene) {
-        L = L + f * li *
+        l = l + f * li *
           (visibility.transmittance(scene, renderer, sample, rng) * (abs_dot(wi, n) / pdf));
       }
     }
 
     if ray.ray.depth + 1 < self.max_depth {
       // Trace rays for specular reflection and refraction
-      L = L + specular_reflect(ray, bsdf.get_ref(), rng, intersection, renderer,
+      l = l + specular_reflect(ray, &bsdf, rng, intersection, renderer,
         scene, sample);
-      L = L + specular_transmit(ray, bsdf.get_ref(), rng, intersection, renderer,
+      l = l + specular_transmit(ray, &bsdf, rng, intersection, renderer,
         scene, sample);
     }
 
-    return L;
+    l
   }
 }
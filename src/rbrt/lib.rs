@@ -1,8 +1,3 @@
-#![crate_id="rbrt#0.0.2"]
-#![comment = "RBRT Main Application"]
-#![license = "BSD"]
-#![crate_type = "bin"]
-
 pub fn main() {
-  
+
 }
@@ -0,0 +1,475 @@
+use std::ops::{ Add, Mul, Sub };
+use std::rc::Rc;
+
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::efloat::gamma;
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{
+  Float, BBox, Normal, Point, Ray, Vector, Length, Union,
+  abs_dot, coordinate_system, cross, distance_squared, dot, lerp, normalize };
+use rbrtcore::montecarlo::{ bilinear_pdf, sample_bilinear };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::transform::{ Applicable, Transform };
+
+use crate::quadric::weingarten;
+use crate::triangle::check_len;
+
+/// Midpoint rule samples per axis used to integrate the area
+const AREA_SAMPLES: usize = 16;
+
+/// Vertex data shared by all patches of a quad mesh. Like `TriangleMesh`,
+/// positions and normals are stored in world space.
+pub struct BilinearPatchMesh {
+  pub base: ShapeBase,
+  pub num_patches: usize,
+  pub vertex_indices: Vec<usize>,
+  pub p: Vec<Point>,
+  pub n: Option<Vec<Normal>>,
+  pub uv: Option<Vec<(Float, Float)>>
+}
+
+impl BilinearPatchMesh {
+  /// Every patch takes four indices, of its corners at `(0, 0)`, `(1, 0)`,
+  /// `(0, 1)` and `(1, 1)`
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform,
+      reverse_orientation: bool, vertex_indices: Vec<usize>, p: Vec<Point>,
+      n: Option<Vec<Normal>>, uv: Option<Vec<(Float, Float)>>) -> Result<BilinearPatchMesh, RenderError> {
+    if !vertex_indices.len().is_multiple_of(4) {
+      return Err(RenderError::InvalidParameter {
+        name: "indices".to_string(),
+        message: format!("number of vertex indices {} is not a multiple of 4", vertex_indices.len())
+      });
+    }
+
+    if let Some(&i) = vertex_indices.iter().find(|&&i| i >= p.len()) {
+      return Err(RenderError::InvalidParameter {
+        name: "indices".to_string(),
+        message: format!("vertex index {} out of range for {} vertices", i, p.len())
+      });
+    }
+
+    check_len("N", &n, p.len())?;
+    check_len("uv", &uv, p.len())?;
+
+    Ok(BilinearPatchMesh {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      num_patches: vertex_indices.len() / 4,
+      vertex_indices,
+      p: p.iter().map(|&x| object_to_world.apply(x)).collect(),
+      n: n.map(|n| n.iter().map(|&x| object_to_world.apply(x)).collect()),
+      uv
+    })
+  }
+
+  /// One `BilinearPatch` shape per quad, all sharing the mesh buffers
+  pub fn patches(mesh: &Rc<BilinearPatchMesh>) -> Vec<BilinearPatch> {
+    (0..mesh.num_patches).map(|i| BilinearPatch::new(mesh.clone(), i)).collect()
+  }
+}
+
+/// Bilinear interpolation of corner values in patch order
+fn bilerp<T>(c: &[T; 4], u: Float, v: Float) -> T
+    where T: Copy + Mul<Float, Output = T> + Add<Output = T> {
+  c[0] * ((1.0 - u) * (1.0 - v)) + c[1] * (u * (1.0 - v)) + c[2] * ((1.0 - u) * v) + c[3] * (u * v)
+}
+
+/// Partial derivatives `(ds/du, ds/dv, dt/du, dt/dv)` of the mesh texture
+/// coordinates with respect to the patch parameters
+fn uv_jacobian(uv: &[(Float, Float); 4], u: Float, v: Float) -> [Float; 4] {
+  [lerp(v, uv[1].0 - uv[0].0, uv[3].0 - uv[2].0), lerp(u, uv[2].0 - uv[0].0, uv[3].0 - uv[1].0),
+   lerp(v, uv[1].1 - uv[0].1, uv[3].1 - uv[2].1), lerp(u, uv[2].1 - uv[0].1, uv[3].1 - uv[1].1)]
+}
+
+/// Turn derivatives along the patch parameters into derivatives along the
+/// texture coordinates, leaving them alone for a degenerate mapping
+fn remap<T>(j: &[Float; 4], du: T, dv: T) -> (T, T)
+    where T: Copy + Mul<Float, Output = T> + Sub<Output = T> {
+  let [dsdu, dsdv, dtdu, dtdv] = *j;
+  let det = dsdu * dtdv - dtdu * dsdv;
+  if det.abs() < 1e-8 {
+    return (du, dv);
+  }
+
+  let inv_det = 1.0 / det;
+  ((du * dtdv - dv * dtdu) * inv_det, (dv * dsdu - du * dsdv) * inv_det)
+}
+
+/// Patch parameters of the texture coordinates `st`, by Newton iteration
+fn invert_uv(uv: &[(Float, Float); 4], st: (Float, Float)) -> (Float, Float) {
+  let (mut u, mut v) = (0.5, 0.5);
+
+  for _ in 0..8 {
+    let s = lerp(v, lerp(u, uv[0].0, uv[1].0), lerp(u, uv[2].0, uv[3].0)) - st.0;
+    let t = lerp(v, lerp(u, uv[0].1, uv[1].1), lerp(u, uv[2].1, uv[3].1)) - st.1;
+    let [dsdu, dsdv, dtdu, dtdv] = uv_jacobian(uv, u, v);
+    let det = dsdu * dtdv - dtdu * dsdv;
+    if det == 0.0 {
+      break;
+    }
+
+    u = (u - (s * dtdv - t * dsdv) / det).clamp(0.0, 1.0);
+    v = (v - (t * dsdu - s * dtdu) / det).clamp(0.0, 1.0);
+  }
+
+  (u, v)
+}
+
+/// Possibly non planar quad, interpolating its corners bilinearly
+pub struct BilinearPatch {
+  pub mesh: Rc<BilinearPatchMesh>,
+  v: usize
+}
+
+impl BilinearPatch {
+  pub fn new(mesh: Rc<BilinearPatchMesh>, n: usize) -> BilinearPatch {
+    BilinearPatch { mesh, v: 4 * n }
+  }
+
+  fn indices(&self) -> [usize; 4] {
+    let vi = &self.mesh.vertex_indices;
+    [vi[self.v], vi[self.v + 1], vi[self.v + 2], vi[self.v + 3]]
+  }
+
+  fn corners(&self) -> [Point; 4] {
+    let i = self.indices();
+    [self.mesh.p[i[0]], self.mesh.p[i[1]], self.mesh.p[i[2]], self.mesh.p[i[3]]]
+  }
+
+  fn uvs(&self) -> [(Float, Float); 4] {
+    match self.mesh.uv {
+      Some(ref uv) => {
+        let i = self.indices();
+        [uv[i[0]], uv[i[1]], uv[i[2]], uv[i[3]]]
+      },
+      None => [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]
+    }
+  }
+
+  fn normals(&self) -> Option<[Normal; 4]> {
+    self.mesh.n.as_ref().map(|n| {
+      let i = self.indices();
+      [n[i[0]], n[i[1]], n[i[2]], n[i[3]]]
+    })
+  }
+
+  fn partials(&self, u: Float, v: Float) -> (Vector, Vector) {
+    let [p00, p10, p01, p11] = self.corners();
+    ((p10 - p00) * (1.0 - v) + (p11 - p01) * v, (p01 - p00) * (1.0 - u) + (p11 - p10) * u)
+  }
+
+  /// Area of the surface element at each corner, which `sample` uses as
+  /// the weights of its bilinear density
+  fn corner_weights(&self) -> [Float; 4] {
+    let w = |u: Float, v: Float| {
+      let (dpdu, dpdv) = self.partials(u, v);
+      cross(dpdu, dpdv).length()
+    };
+
+    [w(0.0, 0.0), w(1.0, 0.0), w(0.0, 1.0), w(1.0, 1.0)]
+  }
+
+  /// Face normal at `(u, v)`, oriented by the shading normals if there are
+  /// any, like `Triangle` does
+  fn oriented_normal(&self, u: Float, v: Float) -> Normal {
+    let (dpdu, dpdv) = self.partials(u, v);
+    let n = Normal::from_vector(&normalize(cross(dpdu, dpdv)));
+
+    let flip = match self.normals() {
+      Some(ns) => dot(n, bilerp(&ns, u, v)) < 0.0,
+      None => self.mesh.base.reverse_orientation ^ self.mesh.base.transform_swaps_handedness
+    };
+
+    if flip { -n } else { n }
+  }
+
+  /// Closest hit of `ray`, as the ray parameter and the patch parameters,
+  /// solving the quadratic in `u` and then for `v` and `t` along the line
+  /// of constant `u`
+  fn hit(&self, ray: &Ray) -> Option<(Float, Float, Float)> {
+    let [p00, p10, p01, p11] = self.corners();
+
+    let a = dot(cross(p10 - p00, p01 - p11), ray.d);
+    let c = dot(cross(p00 - ray.o, ray.d), p01 - p00);
+    let b = dot(cross(p10 - ray.o, ray.d), p11 - p10) - (a + c);
+
+    let det = b * b - 4.0 * a * c;
+    if det < 0.0 {
+      return None;
+    }
+
+    // Numerically stable roots, with a single root for a linear equation
+    let roots = if a == 0.0 {
+      if b == 0.0 {
+        return None;
+      }
+      [-c / b, -1.0]
+    } else {
+      let q = -0.5 * (b + det.sqrt().copysign(b));
+      [q / a, if q != 0.0 { c / q } else { -1.0 }]
+    };
+
+    let mut closest: Option<(Float, Float, Float)> = None;
+    for &u in roots.iter().filter(|&&u| (0.0..=1.0).contains(&u)) {
+      let uo = p00 * (1.0 - u) + p10 * u;
+      let ud = (p01 * (1.0 - u) + p11 * u) - uo;
+      let delta_o = uo - ray.o;
+      let perp = cross(ray.d, ud);
+      let p2 = perp.length_squared();
+      if p2 == 0.0 {
+        continue;
+      }
+
+      let t = dot(delta_o, cross(ud, perp)) / p2;
+      let v = dot(delta_o, cross(ray.d, perp)) / p2;
+      let max_t = closest.map_or(ray.maxt, |hit| hit.0);
+      if t > ray.mint && t > 0.0 && t < max_t && (0.0..=1.0).contains(&v) {
+        closest = Some((t, u, v));
+      }
+    }
+
+    closest
+  }
+}
+
+impl Shape for BilinearPatch {
+  fn get_base(&self) -> &ShapeBase {
+    &self.mesh.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    let w2o = &self.mesh.base.world_to_object;
+    let p = self.corners();
+    p[1..].iter().fold(BBox::from_point(&w2o.apply(p[0])), |b, &p| b.union(&w2o.apply(p)))
+  }
+
+  /// The patch lies within the convex hull of its corners
+  fn world_bound(&self) -> BBox {
+    let p = self.corners();
+    p[1..].iter().fold(BBox::from_point(&p[0]), |b, p| b.union(p))
+  }
+
+  /// Midpoint rule integration, exact for planar patches
+  fn area(&self) -> Float {
+    let h = 1.0 / AREA_SAMPLES as Float;
+    let mut area = 0.0;
+
+    for i in 0..AREA_SAMPLES {
+      for j in 0..AREA_SAMPLES {
+        let (dpdu, dpdv) = self.partials((i as Float + 0.5) * h, (j as Float + 0.5) * h);
+        area += cross(dpdu, dpdv).length();
+      }
+    }
+
+    area * h * h
+  }
+
+  fn intersect(&self, ray: &Ray) -> Option<ShapeIntersection> {
+    let (t, u, v) = self.hit(ray)?;
+    let [p00, p10, p01, p11] = self.corners();
+
+    let p = bilerp(&self.corners(), u, v);
+    let (dpdu, dpdv) = self.partials(u, v);
+    let d2pduv = (p00 - p01) + (p11 - p10);
+    let (dndu, dndv) = weingarten(dpdu, dpdv, Vector::zero(), d2pduv, Vector::zero());
+
+    // Derivatives along the mesh texture coordinates
+    let uv = self.uvs();
+    let st = bilerp(&uv.map(|(s, t)| Vector::new(s, t, 0.0)), u, v);
+    let j = uv_jacobian(&uv, u, v);
+    let (dpds, dpdt) = remap(&j, dpdu, dpdv);
+    let (dnds, dndt) = remap(&j, dndu, dndv);
+
+    let mut dg = DifferentialGeometry::from_shape(p, dpds, dpdt, dnds, dndt, st.x, st.y, Some(self));
+    dg.nn = self.oriented_normal(u, v);
+
+    let abs_sum = [p00, p10, p01, p11].iter()
+      .fold(Vector::zero(), |s, p| s + Vector::new(p.x.abs(), p.y.abs(), p.z.abs()));
+
+    Some((t, abs_sum * gamma(6), dg))
+  }
+
+  fn intersect_p(&self, ray: &Ray) -> bool {
+    self.hit(ray).is_some()
+  }
+
+  /// Samples a bilinear density through the corner weights, which is
+  /// uniform by area on planar patches
+  fn sample(&self, u1: Float, u2: Float) -> Option<(Point, Normal)> {
+    let (u, v) = sample_bilinear(u1, u2, &self.corner_weights());
+    Some((bilerp(&self.corners(), u, v), self.oriented_normal(u, v)))
+  }
+
+  /// Density of `sample`, which is not uniform on non planar patches
+  fn pdf(&self, p: &Point, wi: &Vector) -> Float {
+    let ray = Ray::new(p, wi, 0.0, Float::INFINITY, 0.0);
+    let (t, u, v) = match self.hit(&ray) {
+      Some(hit) => hit,
+      None => return 0.0
+    };
+
+    let (dpdu, dpdv) = self.partials(u, v);
+    let n = cross(dpdu, dpdv);
+    let pdf_area = bilinear_pdf(u, v, &self.corner_weights()) / n.length();
+
+    let pdf = distance_squared(p, &ray.apply(t)) * pdf_area / abs_dot(normalize(n), -*wi);
+    if pdf.is_infinite() { 0.0 } else { pdf }
+  }
+
+  fn get_shading_geometry(&self, object_to_world: &Transform,
+      dg: &DifferentialGeometry) -> DifferentialGeometry {
+    let mut normals = match self.normals() {
+      Some(n) => n,
+      None => return dg.clone()
+    };
+
+    // Mesh normals are in the world the mesh was built in, which an
+    // instance places elsewhere
+    if let Some(t) = self.get_base().instance_to_world(object_to_world) {
+      normals = normals.map(|n| t.apply(n));
+    }
+
+    let uv = self.uvs();
+    let (u, v) = invert_uv(&uv, (dg.u, dg.v));
+    let ns = normalize(bilerp(&normals, u, v));
+
+    let mut ss = normalize(dg.dpdu);
+    let mut ts = cross(ss, ns);
+    if ts.length_squared() > 0.0 {
+      ts = normalize(ts);
+      ss = cross(ts, ns);
+    } else {
+      (ss, ts) = coordinate_system(&Vector::from_normal(&ns));
+    }
+
+    let [n00, n10, n01, n11] = normals;
+    let dndu = (n10 - n00) * (1.0 - v) + (n11 - n01) * v;
+    let dndv = (n01 - n00) * (1.0 - u) + (n11 - n10) * u;
+    let (dnds, dndt) = remap(&uv_jacobian(&uv, u, v), dndu, dndv);
+
+    let mut shading = DifferentialGeometry::from_shape(dg.p, ss, ts, dnds, dndt,
+      dg.u, dg.v, Some(self));
+    shading.shape = dg.shape.clone();
+    shading.dudx = dg.dudx;
+    shading.dvdx = dg.dvdx;
+    shading.dudy = dg.dudy;
+    shading.dvdy = dg.dvdy;
+    shading.dpdx = dg.dpdx;
+    shading.dpdy = dg.dpdy;
+
+    shading
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rbrtcore::shape::area_pdf_to_solid_angle;
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  fn patch(p: [Point; 4], n: Option<Vec<Normal>>, uv: Option<Vec<(Float, Float)>>) -> BilinearPatch {
+    let mesh = BilinearPatchMesh::new(&identity(), &identity(), false, vec![0, 1, 2, 3], p.to_vec(), n, uv);
+    BilinearPatchMesh::patches(&Rc::new(mesh.unwrap())).remove(0)
+  }
+
+  /// Saddle through the corners of the unit square, with `z = x y`
+  fn saddle() -> BilinearPatch {
+    patch([Point::new(0.0, 0.0, 0.0), Point::new(1.0, 0.0, 0.0),
+      Point::new(0.0, 1.0, 0.0), Point::new(1.0, 1.0, 1.0)], None, None)
+  }
+
+  fn down(x: Float, y: Float) -> Ray {
+    Ray::new(&Point::new(x, y, 5.0), &Vector::new(0.0, 0.0, -1.0), 0.0, Float::INFINITY, 0.0)
+  }
+
+  #[test]
+  fn new_validates_buffers() {
+    let p = vec![Point::zero(); 4];
+
+    assert!(BilinearPatchMesh::new(&identity(), &identity(), false, vec![0, 1, 2], p.clone(), None, None).is_err());
+    assert!(BilinearPatchMesh::new(&identity(), &identity(), false, vec![0, 1, 2, 4], p.clone(), None, None).is_err());
+    assert!(BilinearPatchMesh::new(&identity(), &identity(), false, vec![0, 1, 2, 3], p,
+      None, Some(vec![(0.0, 0.0)])).is_err());
+  }
+
+  #[test]
+  fn intersect_saddle_exactly() {
+    let saddle = saddle();
+    let (t, p_error, dg) = saddle.intersect(&down(0.5, 0.4)).unwrap();
+
+    assert!((t - (5.0 - 0.2)).abs() < 1e-5);
+    assert!((dg.p.z - 0.2).abs() <= p_error.z.max(1e-6));
+    assert!((dg.u - 0.5).abs() < 1e-5 && (dg.v - 0.4).abs() < 1e-5);
+
+    // Analytic partials of (u, v, u v)
+    assert!((dg.dpdu.z - 0.4).abs() < 1e-5 && (dg.dpdv.z - 0.5).abs() < 1e-5);
+    let n = normalize(Vector::new(-0.4, -0.5, 1.0));
+    assert!((dot(dg.nn, n) - 1.0).abs() < 1e-5);
+
+    assert!(!saddle.intersect_p(&down(1.2, 0.5)));
+    let sideways = Ray::new(&Point::new(-1.0, 0.5, 0.25), &Vector::new(1.0, 0.0, 0.0), 0.0, Float::INFINITY, 0.0);
+    let (t, _, _) = saddle.intersect(&sideways).unwrap();
+    assert!((t - 1.5).abs() < 1e-5);
+  }
+
+  #[test]
+  fn uv_mapping_rescales_partials() {
+    let uv = vec![(0.0, 0.0), (2.0, 0.0), (0.0, 4.0), (2.0, 4.0)];
+    let square = patch([Point::new(0.0, 0.0, 0.0), Point::new(1.0, 0.0, 0.0),
+      Point::new(0.0, 1.0, 0.0), Point::new(1.0, 1.0, 0.0)], None, Some(uv));
+    let (_, _, dg) = square.intersect(&down(0.25, 0.5)).unwrap();
+
+    assert!((dg.u - 0.5).abs() < 1e-5 && (dg.v - 2.0).abs() < 1e-5);
+    assert!((dg.dpdu.x - 0.5).abs() < 1e-5 && (dg.dpdv.y - 0.25).abs() < 1e-5);
+    assert!((square.area() - 1.0).abs() < 1e-5);
+  }
+
+  #[test]
+  fn sampling_is_uniform_on_planar_patches() {
+    // Trapezoid, whose surface element varies across the patch
+    let trapezoid = patch([Point::new(0.0, 0.0, 0.0), Point::new(2.0, 0.0, 0.0),
+      Point::new(0.0, 1.0, 0.0), Point::new(1.0, 1.0, 0.0)], None, None);
+    assert!((trapezoid.area() - 1.5).abs() < 1e-5);
+
+    let p = Point::new(0.5, 0.5, 2.0);
+    let down = Vector::new(0.0, 0.0, -1.0);
+    assert!((trapezoid.pdf(&p, &down) - 4.0 / 1.5).abs() < 1e-3);
+
+    for &(u1, u2) in [(0.1, 0.1), (0.5, 0.5), (0.9, 0.8)].iter() {
+      let (ps, n) = trapezoid.sample(u1, u2).unwrap();
+      assert!(ps.z.abs() < 1e-6 && (n.z - 1.0).abs() < 1e-6);
+      assert!(ps.x <= 2.0 - ps.y + 1e-5);
+    }
+  }
+
+  #[test]
+  fn pdf_follows_the_sampling_density_on_curved_patches() {
+    let saddle = saddle();
+    let p = Point::new(0.5, 0.5, 3.0);
+    let down = Vector::new(0.0, 0.0, -1.0);
+
+    // Corner weights overestimate the surface element at the centre, so
+    // the centre is sampled more often than uniform area sampling would
+    let uniform = area_pdf_to_solid_angle(&saddle, &p, &down);
+    assert!(saddle.pdf(&p, &down) > 1.03 * uniform);
+  }
+
+  #[test]
+  fn shading_normals_are_interpolated() {
+    let n = vec![Normal::new(-1.0, 0.0, 1.0), Normal::new(1.0, 0.0, 1.0),
+      Normal::new(-1.0, 0.0, 1.0), Normal::new(1.0, 0.0, 1.0)];
+    let square = patch([Point::new(0.0, 0.0, 0.0), Point::new(1.0, 0.0, 0.0),
+      Point::new(0.0, 1.0, 0.0), Point::new(1.0, 1.0, 0.0)], Some(n), None);
+
+    let (_, _, dg) = square.intersect(&down(0.5, 0.3)).unwrap();
+    let shading = square.get_shading_geometry(&identity(), &dg);
+    assert!(shading.nn.x.abs() < 1e-5 && (shading.nn.z - 1.0).abs() < 1e-5);
+    assert!(shading.dndu.x > 0.0);
+
+    let (_, _, dg) = square.intersect(&down(0.9, 0.3)).unwrap();
+    assert!(square.get_shading_geometry(&identity(), &dg).nn.x > 0.5);
+  }
+}
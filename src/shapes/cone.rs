@@ -0,0 +1,244 @@
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::geometry::{
+  Float, BBox, Normal, Point, Ray, Vector,
+  clamp, coordinate_system, distance, distance_squared, normalize, radians };
+use rbrtcore::montecarlo::{ uniform_cone_pdf, uniform_sample_cone_frame };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection, area_pdf_to_solid_angle };
+use rbrtcore::transform::{ Applicable, Transform };
+
+use crate::quadric::{ azimuth, nearest_hit, ray_point_error, weingarten };
+
+/// Cone with its base of `radius` at `z = 0` and its apex at `z = height`,
+/// swept up to `phi_max`.
+///
+/// Seen from a point, a cone does not fill a cone of directions the way a
+/// sphere does, so `sample_from` samples the directions towards its world
+/// space bounding sphere instead, and directions that miss the cone give
+/// no sample. From inside the bounding sphere there is no such cone of
+/// directions, and it falls back to sampling by area.
+pub struct Cone {
+  base: ShapeBase,
+  pub height: Float,
+  pub radius: Float,
+  pub phi_max: Float
+}
+
+impl Cone {
+  /// `phi_max` is given in degrees
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform, reverse_orientation: bool,
+      height: Float, radius: Float, phi_max: Float) -> Cone {
+    Cone {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      height,
+      radius,
+      phi_max: radians(clamp(phi_max, 0.0, 360.0))
+    }
+  }
+
+  /// Cosine of the half angle of the cone of directions from `p` towards
+  /// the world space bounding sphere, and its axis. `None` inside it.
+  fn bounding_cone(&self, p: &Point) -> Option<(Float, Vector)> {
+    let bound = self.world_bound();
+    let center = (bound.p_min + bound.p_max) * 0.5;
+    let radius = distance(&center, &bound.p_max);
+
+    let dist2 = distance_squared(p, &center);
+    if dist2 <= radius * radius {
+      return None;
+    }
+
+    let sin_theta_max2 = radius * radius / dist2;
+    Some((Float::max(0.0, 1.0 - sin_theta_max2).sqrt(), normalize(center - *p)))
+  }
+
+  /// Closest hit of the world space `r`, as the ray parameter, object space
+  /// hit point, its azimuth and error bound
+  fn hit(&self, r: &Ray) -> Option<(Float, Point, Float, Vector)> {
+    let (ray, o_error, d_error) = self.base.world_to_object.apply_ray_with_error(r);
+    let (o, d) = (ray.o, ray.d);
+
+    let k = (self.radius / self.height) * (self.radius / self.height);
+    let a = d.x * d.x + d.y * d.y - k * d.z * d.z;
+    let b = 2.0 * (d.x * o.x + d.y * o.y - k * d.z * (o.z - self.height));
+    let c = o.x * o.x + o.y * o.y - k * (o.z - self.height) * (o.z - self.height);
+
+    let (t_hit, (phit, phi)) = nearest_hit(&ray, a, b, c, |t| {
+      let phit = ray.apply(t);
+      let phi = azimuth(&phit);
+
+      if phit.z < 0.0 || phit.z > self.height || phi > self.phi_max {
+        None
+      } else {
+        Some((phit, phi))
+      }
+    })?;
+
+    Some((t_hit, phit, phi, ray_point_error(&ray, &o_error, &d_error, t_hit)))
+  }
+}
+
+impl Shape for Cone {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    BBox::new(&Point::new(-self.radius, -self.radius, 0.0),
+      &Point::new(self.radius, self.radius, self.height))
+  }
+
+  fn area(&self) -> Float {
+    self.radius * (self.height * self.height + self.radius * self.radius).sqrt() *
+      self.phi_max / 2.0
+  }
+
+  fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+    let (t_hit, phit, phi, p_error) = self.hit(r)?;
+
+    let u = phi / self.phi_max;
+    let v = phit.z / self.height;
+
+    let dpdu = Vector::new(-self.phi_max * phit.y, self.phi_max * phit.x, 0.0);
+    let dpdv = Vector::new(-phit.x / (1.0 - v), -phit.y / (1.0 - v), self.height);
+
+    let d2pduu = Vector::new(phit.x, phit.y, 0.0) * (-self.phi_max * self.phi_max);
+    let d2pduv = Vector::new(phit.y, -phit.x, 0.0) * (self.phi_max / (1.0 - v));
+    let d2pdvv = Vector::new(0.0, 0.0, 0.0);
+    let (dndu, dndv) = weingarten(dpdu, dpdv, d2pduu, d2pduv, d2pdvv);
+
+    let o2w = &self.base.object_to_world;
+    let (p, p_error) = o2w.apply_point_with_abs_error(&phit, &p_error);
+    let dg = DifferentialGeometry::from_shape(p, o2w.apply(dpdu), o2w.apply(dpdv),
+      o2w.apply(dndu), o2w.apply(dndv), u, v, Some(self));
+
+    Some((t_hit, p_error, dg))
+  }
+
+  fn intersect_p(&self, r: &Ray) -> bool {
+    self.hit(r).is_some()
+  }
+
+  /// Uniform over the side, whose area grows linearly with the distance
+  /// from the apex
+  fn sample(&self, u1: Float, u2: Float) -> Option<(Point, Normal)> {
+    let o2w = &self.base.object_to_world;
+    let s = u1.sqrt();
+    let (sin_t, cos_t) = (u2 * self.phi_max).sin_cos();
+    let p = Point::new(s * self.radius * cos_t, s * self.radius * sin_t, (1.0 - s) * self.height);
+
+    // Outward normal of the side, independent of the distance to the apex
+    let n = Normal::new(self.height * cos_t, self.height * sin_t, self.radius);
+    let ns = normalize(o2w.apply(n));
+
+    Some((o2w.apply(p), if self.base.reverse_orientation { -ns } else { ns }))
+  }
+
+  fn sample_from(&self, p: &Point, u1: Float, u2: Float) -> Option<(Point, Normal)> {
+    let (cos_theta_max, wc) = match self.bounding_cone(p) {
+      Some(cone) => cone,
+      None => return self.sample(u1, u2)
+    };
+
+    let (wc_x, wc_y) = coordinate_system(&wc);
+    let r = Ray::new(p, &uniform_sample_cone_frame(u1, u2, cos_theta_max, &wc_x, &wc_y, &wc),
+      0.0, Float::INFINITY, 0.0);
+
+    let (_, _, dg) = self.intersect(&r)?;
+    Some((dg.p, dg.nn))
+  }
+
+  fn pdf(&self, p: &Point, wi: &Vector) -> Float {
+    let cos_theta_max = match self.bounding_cone(p) {
+      Some((cos_theta_max, _)) => cos_theta_max,
+      None => return area_pdf_to_solid_angle(self, p, wi)
+    };
+
+    if self.intersect_p(&Ray::new(p, wi, 0.0, Float::INFINITY, 0.0)) {
+      uniform_cone_pdf(cos_theta_max)
+    } else {
+      0.0
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rbrtcore::geometry::{ PI, dot };
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  #[test]
+  fn intersect_side_and_area() {
+    let cone = Cone::new(&identity(), &identity(), false, 2.0, 1.0, 360.0);
+    let ray = Ray::new(&Point::new(-5.0, 0.0, 1.0), &Vector::new(1.0, 0.0, 0.0), 0.0, Float::INFINITY, 0.0);
+    let (t_hit, p_error, dg) = cone.intersect(&ray).unwrap();
+
+    // Halfway up the cone the radius is 0.5
+    assert!((t_hit - 4.5).abs() < 1e-5);
+    assert!((dg.p.x + 0.5).abs() <= p_error.x.max(1e-5));
+    assert!((dg.v - 0.5).abs() < 1e-5);
+    assert!((cone.area() - PI * (5.0 as Float).sqrt()).abs() < 1e-4);
+
+    let above = Ray::new(&Point::new(-5.0, 0.0, 2.5), &Vector::new(1.0, 0.0, 0.0), 0.0, Float::INFINITY, 0.0);
+    assert!(!cone.intersect_p(&above));
+  }
+
+  #[test]
+  fn ray_along_the_axis_hits_the_apex_region() {
+    let cone = Cone::new(&identity(), &identity(), false, 1.0, 1.0, 360.0);
+    let ray = Ray::new(&Point::new(0.1, 0.0, -5.0), &Vector::new(0.0, 0.0, 1.0), 0.0, Float::INFINITY, 0.0);
+    let (_, _, dg) = cone.intersect(&ray).unwrap();
+
+    assert!((dg.p.z - 0.9).abs() < 1e-4);
+    assert!(dg.nn.z.abs() > 0.5);
+  }
+
+  #[test]
+  fn sample_lies_on_the_side() {
+    let cone = Cone::new(&identity(), &identity(), false, 2.0, 1.0, 360.0);
+
+    for &(u1, u2) in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.7)].iter() {
+      let (p, n) = cone.sample(u1, u2).unwrap();
+      let r = (p.x * p.x + p.y * p.y).sqrt();
+      assert!((r - (2.0 - p.z) / 2.0).abs() < 1e-5);
+
+      // The normal is perpendicular to the line running up to the apex
+      assert!(dot(n, Point::new(0.0, 0.0, 2.0) - p).abs() < 1e-5);
+      assert!(n.z > 0.0);
+    }
+  }
+
+  #[test]
+  fn sample_from_samples_the_bounding_cone() {
+    let cone = Cone::new(&identity(), &identity(), false, 2.0, 1.0, 360.0);
+    let p = Point::new(6.0, 0.0, 1.0);
+
+    let mut hits = 0;
+    for i in 0..64 {
+      let (u1, u2) = ((i % 8) as Float / 8.0 + 0.0625, (i / 8) as Float / 8.0 + 0.0625);
+      if let Some((ps, n)) = cone.sample_from(&p, u1, u2) {
+        let r = (ps.x * ps.x + ps.y * ps.y).sqrt();
+        assert!((r - (2.0 - ps.z) / 2.0).abs() < 1e-4);
+        assert!(dot(n, p - ps) > 0.0);
+        hits += 1;
+      }
+    }
+    assert!(hits > 8 && hits < 64);
+
+    // Uniform over the cone towards the bounding sphere, zero off the cone
+    let sphere_radius = (3.0 as Float).sqrt();
+    let dist2 = 6.0 * 6.0;
+    let cos_theta_max = (1.0 - sphere_radius * sphere_radius / dist2).sqrt();
+    let pdf = cone.pdf(&p, &Vector::new(-1.0, 0.0, 0.0));
+    assert!((pdf - uniform_cone_pdf(cos_theta_max)).abs() < 1e-3);
+    assert_eq!(cone.pdf(&p, &Vector::new(-1.0, 0.0, 0.5)), 0.0);
+
+    // Inside the bounding sphere it samples by area
+    let inside = Point::new(0.0, 0.0, 0.5);
+    let up = Vector::new(1.0, 0.0, 0.0);
+    assert_eq!(cone.pdf(&inside, &up), area_pdf_to_solid_angle(&cone, &inside, &up));
+  }
+}
@@ -0,0 +1,433 @@
+use std::rc::Rc;
+
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{
+  Float, BBox, Normal, Point, Ray, Vector, Length, Union,
+  abs_dot, clamp, coordinate_system, cross, distance, dot, lerp, normalize };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::transform::{ Applicable, Transform };
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CurveType {
+  /// Ribbon that always faces the incoming ray
+  Flat,
+  /// Flat ribbon shaded as if it were a thin cylinder
+  Cylinder,
+  /// Ribbon oriented by normals interpolated along the curve
+  Ribbon
+}
+
+fn lerp_point(t: Float, a: Point, b: Point) -> Point {
+  a * (1.0 - t) + b * t
+}
+
+/// Point on the curve restricted to `u0..u2`, e.g. `(u0, u0, u0)` is its
+/// first control point and `(u0, u0, u1)` the second
+fn blossom_bezier(p: &[Point; 4], u0: Float, u1: Float, u2: Float) -> Point {
+  let a = [lerp_point(u0, p[0], p[1]), lerp_point(u0, p[1], p[2]), lerp_point(u0, p[2], p[3])];
+  let b = [lerp_point(u1, a[0], a[1]), lerp_point(u1, a[1], a[2])];
+  lerp_point(u2, b[0], b[1])
+}
+
+/// Split a segment at its midpoint, the two halves sharing the middle
+/// control point
+fn subdivide_bezier(cp: &[Point; 4]) -> [Point; 7] {
+  [cp[0],
+   (cp[0] + cp[1]) / 2.0,
+   (cp[0] + cp[1] * 2.0 + cp[2]) / 4.0,
+   (cp[0] + cp[1] * 3.0 + cp[2] * 3.0 + cp[3]) / 8.0,
+   (cp[1] + cp[2] * 2.0 + cp[3]) / 4.0,
+   (cp[2] + cp[3]) / 2.0,
+   cp[3]]
+}
+
+/// Point on the segment at `u` and the derivative there
+fn eval_bezier(cp: &[Point; 4], u: Float) -> (Point, Vector) {
+  let cp1 = [lerp_point(u, cp[0], cp[1]), lerp_point(u, cp[1], cp[2]), lerp_point(u, cp[2], cp[3])];
+  let cp2 = [lerp_point(u, cp1[0], cp1[1]), lerp_point(u, cp1[1], cp1[2])];
+
+  let deriv = if (cp2[1] - cp2[0]).length_squared() > 0.0 {
+    (cp2[1] - cp2[0]) * 3.0
+  } else {
+    // Coincident control points at an end, fall back to the chord
+    cp[3] - cp[0]
+  };
+
+  (lerp_point(u, cp2[0], cp2[1]), deriv)
+}
+
+/// Whether the bounds of `cp` grown by half of `width` can overlap the ray,
+/// which runs along +z from the origin in ray space
+fn overlaps_ray(cp: &[Point], width: Float, z_max: Float) -> bool {
+  let range = |axis: usize| cp.iter().fold((Float::INFINITY, Float::NEG_INFINITY),
+    |(lo, hi), p| (lo.min(p[axis]), hi.max(p[axis])));
+  let ((x0, x1), (y0, y1), (z0, z1)) = (range(0), range(1), range(2));
+
+  x1 + 0.5 * width >= 0.0 && x0 - 0.5 * width <= 0.0 &&
+    y1 + 0.5 * width >= 0.0 && y0 - 0.5 * width <= 0.0 &&
+    z1 + 0.5 * width >= 0.0 && z0 - 0.5 * width <= z_max
+}
+
+/// Control points, widths and normals shared by all segments of a curve
+pub struct CurveCommon {
+  pub curve_type: CurveType,
+  /// Control points in object space
+  pub cp_obj: [Point; 4],
+  pub width: [Float; 2],
+  pub n: [Normal; 2],
+  pub normal_angle: Float,
+  pub inv_sin_normal_angle: Float
+}
+
+impl CurveCommon {
+  /// `n` gives the ribbon orientation at both ends and is required for
+  /// `CurveType::Ribbon`
+  pub fn new(cp: [Point; 4], width0: Float, width1: Float, curve_type: CurveType,
+      n: Option<[Normal; 2]>) -> Result<CurveCommon, RenderError> {
+    let n = match (curve_type, n) {
+      (_, Some(n)) => [normalize(n[0]), normalize(n[1])],
+      (CurveType::Ribbon, None) => return Err(RenderError::InvalidParameter {
+        name: "N".to_string(),
+        message: "ribbon curves need a normal at each end".to_string()
+      }),
+      (_, None) => [Normal::new(0.0, 0.0, 0.0); 2]
+    };
+
+    let normal_angle = clamp(dot(n[0], n[1]), 0.0, 1.0).acos();
+
+    Ok(CurveCommon {
+      curve_type,
+      cp_obj: cp,
+      width: [width0, width1],
+      n,
+      normal_angle,
+      inv_sin_normal_angle: 1.0 / normal_angle.sin()
+    })
+  }
+
+  /// Ribbon normal at `u`, spherically interpolated between the ends
+  fn normal(&self, u: Float) -> Normal {
+    if self.normal_angle == 0.0 {
+      return self.n[0];
+    }
+
+    let sin0 = ((1.0 - u) * self.normal_angle).sin() * self.inv_sin_normal_angle;
+    let sin1 = (u * self.normal_angle).sin() * self.inv_sin_normal_angle;
+    self.n[0] * sin0 + self.n[1] * sin1
+  }
+
+  fn width_at(&self, u: Float) -> Float {
+    lerp(u, self.width[0], self.width[1])
+  }
+}
+
+/// Hit found by `Curve::recursive_intersect`, in curve parameters
+#[derive(Clone, Copy)]
+struct CurveHit {
+  t: Float,
+  u: Float,
+  v: Float,
+  hit_width: Float
+}
+
+/// The `u_min..u_max` part of a cubic Bézier curve with a width, for hair,
+/// fur and grass. `u` runs along the curve and `v` across its width.
+pub struct Curve {
+  base: ShapeBase,
+  pub common: Rc<CurveCommon>,
+  pub u_min: Float,
+  pub u_max: Float
+}
+
+impl Curve {
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform, reverse_orientation: bool,
+      common: Rc<CurveCommon>, u_min: Float, u_max: Float) -> Curve {
+    Curve {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      common,
+      u_min,
+      u_max
+    }
+  }
+
+  /// Split the curve into `n_segments` shapes of equal parametric length,
+  /// giving tighter bounds to the accelerator
+  pub fn segments(object_to_world: &Transform, world_to_object: &Transform,
+      reverse_orientation: bool, common: Rc<CurveCommon>, n_segments: usize) -> Vec<Curve> {
+    (0..n_segments).map(|i| {
+      let u0 = i as Float / n_segments as Float;
+      let u1 = (i + 1) as Float / n_segments as Float;
+      Curve::new(object_to_world, world_to_object, reverse_orientation, common.clone(), u0, u1)
+    }).collect()
+  }
+
+  /// Control points of this segment in object space
+  fn control_points(&self) -> [Point; 4] {
+    let (cp, u0, u1) = (&self.common.cp_obj, self.u_min, self.u_max);
+    [blossom_bezier(cp, u0, u0, u0), blossom_bezier(cp, u0, u0, u1),
+     blossom_bezier(cp, u0, u1, u1), blossom_bezier(cp, u1, u1, u1)]
+  }
+
+  /// Find the closest hit of the ray space segment `cp` over `u0..u1`,
+  /// splitting it `depth` more times before testing it as a line. Stops
+  /// at the first hit when `any` is set.
+  #[allow(clippy::too_many_arguments)]
+  fn recursive_intersect(&self, ray: &Ray, ray_length: Float, cp: &[Point; 4], u0: Float, u1: Float,
+      depth: u32, any: bool, best: &mut Option<CurveHit>) {
+    if depth > 0 {
+      let split = subdivide_bezier(cp);
+      let u = [u0, (u0 + u1) / 2.0, u1];
+
+      for seg in 0..2 {
+        let cps = [split[3 * seg], split[3 * seg + 1], split[3 * seg + 2], split[3 * seg + 3]];
+        let max_width = self.common.width_at(u[seg]).max(self.common.width_at(u[seg + 1]));
+        let z_max = ray_length * best.map_or(ray.maxt, |h| h.t);
+
+        if overlaps_ray(&cps, max_width, z_max) {
+          self.recursive_intersect(ray, ray_length, &cps, u[seg], u[seg + 1], depth - 1, any, best);
+          if any && best.is_some() {
+            return;
+          }
+        }
+      }
+
+      return;
+    }
+
+    // Reject hits beyond the tangent perpendiculars at either end
+    let edge = (cp[1].y - cp[0].y) * -cp[0].y + cp[0].x * (cp[0].x - cp[1].x);
+    if edge < 0.0 {
+      return;
+    }
+    let edge = (cp[2].y - cp[3].y) * -cp[3].y + cp[3].x * (cp[3].x - cp[2].x);
+    if edge < 0.0 {
+      return;
+    }
+
+    // Parameter of the point on the chord closest to the ray
+    let (sx, sy) = (cp[3].x - cp[0].x, cp[3].y - cp[0].y);
+    let denom = sx * sx + sy * sy;
+    if denom == 0.0 {
+      return;
+    }
+    let w = (-cp[0].x * sx - cp[0].y * sy) / denom;
+
+    let u = clamp(lerp(w, u0, u1), u0, u1);
+    let mut hit_width = self.common.width_at(u);
+    if self.common.curve_type == CurveType::Ribbon {
+      hit_width *= abs_dot(self.common.normal(u), ray.d) / ray_length;
+    }
+
+    let (pc, dpcdw) = eval_bezier(cp, clamp(w, 0.0, 1.0));
+    let pt_curve_dist2 = pc.x * pc.x + pc.y * pc.y;
+    if pt_curve_dist2 > hit_width * hit_width * 0.25 {
+      return;
+    }
+    if pc.z <= ray.mint * ray_length || pc.z > ray_length * best.map_or(ray.maxt, |h| h.t) {
+      return;
+    }
+
+    // Which side of the curve the ray passes on gives v
+    let pt_curve_dist = pt_curve_dist2.sqrt();
+    let edge_func = dpcdw.x * -pc.y + pc.x * dpcdw.y;
+    let v = if edge_func > 0.0 {
+      0.5 + pt_curve_dist / hit_width
+    } else {
+      0.5 - pt_curve_dist / hit_width
+    };
+
+    *best = Some(CurveHit { t: pc.z / ray_length, u, v, hit_width });
+  }
+
+  /// Object space ray and the transform into the space where it starts
+  /// at the origin and runs along +z, along with the closest hit
+  fn hit(&self, r: &Ray, any: bool) -> Option<(Ray, Transform, CurveHit)> {
+    let ray = self.base.world_to_object.apply_ray_with_error(r).0;
+    let cp_obj = self.control_points();
+
+    let mut dx = cross(ray.d, cp_obj[3] - cp_obj[0]);
+    if dx.length_squared() == 0.0 {
+      dx = coordinate_system(&ray.d).0;
+    }
+
+    let object_to_ray = Transform::look_at(&ray.o, &(ray.o + ray.d), &dx).ok()?;
+    let cp = [object_to_ray.apply(cp_obj[0]), object_to_ray.apply(cp_obj[1]),
+      object_to_ray.apply(cp_obj[2]), object_to_ray.apply(cp_obj[3])];
+
+    let ray_length = ray.d.length();
+    let max_width = self.common.width_at(self.u_min).max(self.common.width_at(self.u_max));
+    if !overlaps_ray(&cp, max_width, ray_length * ray.maxt) {
+      return None;
+    }
+
+    // Split until the segments are close enough to straight lines
+    let mut l0: Float = 0.0;
+    for i in 0..2 {
+      let d = (cp[i] + cp[i + 2]) - cp[i + 1] * 2.0;
+      l0 = l0.max(d.x.abs()).max(d.y.abs()).max(d.z.abs());
+    }
+
+    let eps = self.common.width[0].max(self.common.width[1]) * 0.05;
+    let r0 = (2.0 as Float).sqrt() * 6.0 * l0 / (8.0 * eps);
+    let max_depth = if r0 < 1.0 { 0 } else { clamp(r0.log2().round() as i32 / 2, 0, 10) as u32 };
+
+    let mut best = None;
+    self.recursive_intersect(&ray, ray_length, &cp, self.u_min, self.u_max, max_depth, any, &mut best);
+
+    best.map(|hit| (ray, object_to_ray, hit))
+  }
+}
+
+impl Shape for Curve {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    let cp = self.control_points();
+    let bound = BBox::from_point(&cp[0]).union(&cp[1]).union(&cp[2]).union(&cp[3]);
+    let width = self.common.width_at(self.u_min).max(self.common.width_at(self.u_max)) * 0.5;
+
+    BBox::new(&(bound.p_min - Vector::new(width, width, width)),
+      &(bound.p_max + Vector::new(width, width, width)))
+  }
+
+  /// Approximated by the length of the control polygon
+  fn area(&self) -> Float {
+    let cp = self.control_points();
+    let avg_width = (self.common.width_at(self.u_min) + self.common.width_at(self.u_max)) * 0.5;
+    let approx_length: Float = (0..3).map(|i| distance(&cp[i], &cp[i + 1])).sum();
+
+    approx_length * avg_width
+  }
+
+  fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+    let (ray, object_to_ray, hit) = self.hit(r, false)?;
+    let CurveHit { t, u, v, hit_width } = hit;
+
+    let dpdu = eval_bezier(&self.common.cp_obj, u).1;
+    let dpdv = if self.common.curve_type == CurveType::Ribbon {
+      normalize(cross(self.common.normal(u), dpdu)) * hit_width
+    } else {
+      // Perpendicular to the curve in the plane facing the ray
+      let dpdu_plane = object_to_ray.apply(dpdu);
+      let mut dpdv_plane = normalize(Vector::new(-dpdu_plane.y, dpdu_plane.x, 0.0)) * hit_width;
+
+      if self.common.curve_type == CurveType::Cylinder {
+        // Rotate around the curve to fake a round cross section
+        let theta = lerp(v, -90.0, 90.0);
+        dpdv_plane = Transform::rotate(-theta, &dpdu_plane).apply(dpdv_plane);
+      }
+
+      Transform::inverse(&object_to_ray).apply(dpdv_plane)
+    };
+
+    let p_error = Vector::new(2.0 * hit_width, 2.0 * hit_width, 2.0 * hit_width);
+    let zero = Normal::new(0.0, 0.0, 0.0);
+
+    let o2w = &self.base.object_to_world;
+    let (p, p_error) = o2w.apply_point_with_abs_error(&ray.apply(t), &p_error);
+    let dg = DifferentialGeometry::from_shape(p, o2w.apply(dpdu), o2w.apply(dpdv),
+      zero, zero, u, v, Some(self));
+
+    Some((t, p_error, dg))
+  }
+
+  fn intersect_p(&self, r: &Ray) -> bool {
+    self.hit(r, true).is_some()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  fn straight(width0: Float, width1: Float, curve_type: CurveType, n: Option<[Normal; 2]>) -> Curve {
+    let cp = [Point::new(0.0, 0.0, 0.0), Point::new(1.0, 0.0, 0.0),
+      Point::new(2.0, 0.0, 0.0), Point::new(3.0, 0.0, 0.0)];
+    let common = Rc::new(CurveCommon::new(cp, width0, width1, curve_type, n).unwrap());
+    Curve::new(&identity(), &identity(), false, common, 0.0, 1.0)
+  }
+
+  fn down(x: Float, y: Float) -> Ray {
+    Ray::new(&Point::new(x, y, 5.0), &Vector::new(0.0, 0.0, -1.0), 0.0, Float::INFINITY, 0.0)
+  }
+
+  #[test]
+  fn flat_curve_hit_gives_u_along_and_v_across() {
+    let curve = straight(0.5, 0.5, CurveType::Flat, None);
+    let (t_hit, _, dg) = curve.intersect(&down(1.5, 0.1)).unwrap();
+
+    assert!((t_hit - 5.0).abs() < 1e-4);
+    assert!((dg.u - 0.5).abs() < 1e-4);
+    assert!(((dg.v - 0.5).abs() - 0.2).abs() < 1e-3);
+    assert!(dg.nn.z.abs() > 0.99);
+
+    assert!(!curve.intersect_p(&down(1.5, 0.3)));
+    assert!(!curve.intersect_p(&down(3.2, 0.0)));
+  }
+
+  #[test]
+  fn width_is_interpolated_along_the_curve() {
+    let curve = straight(1.0, 0.0, CurveType::Flat, None);
+
+    assert!(curve.intersect_p(&down(0.3, 0.3)));
+    assert!(!curve.intersect_p(&down(2.7, 0.3)));
+    assert!((curve.area() - 1.5).abs() < 1e-5);
+  }
+
+  #[test]
+  fn cylinder_normals_turn_towards_the_edges() {
+    let curve = straight(0.5, 0.5, CurveType::Cylinder, None);
+    let (_, _, center) = curve.intersect(&down(1.5, 0.0)).unwrap();
+    let (_, _, edge) = curve.intersect(&down(1.5, 0.24)).unwrap();
+
+    assert!(center.nn.z.abs() > 0.99);
+    assert!(edge.nn.z.abs() < 0.2);
+  }
+
+  #[test]
+  fn ribbon_width_follows_its_orientation() {
+    let facing_y = Some([Normal::new(0.0, 1.0, 0.0); 2]);
+    let ribbon = straight(0.5, 0.5, CurveType::Ribbon, facing_y);
+
+    // Seen edge-on from above the ribbon vanishes
+    assert!(!ribbon.intersect_p(&down(1.5, 0.01)));
+
+    let side = Ray::new(&Point::new(1.5, 5.0, 0.1), &Vector::new(0.0, -1.0, 0.0), 0.0, Float::INFINITY, 0.0);
+    let (t_hit, _, dg) = ribbon.intersect(&side).unwrap();
+    assert!((t_hit - 5.0).abs() < 1e-4);
+    assert!(dg.nn.y.abs() > 0.99);
+
+    assert!(CurveCommon::new([Point::zero(); 4], 1.0, 1.0, CurveType::Ribbon, None).is_err());
+  }
+
+  #[test]
+  fn segments_cover_the_curve_and_bound_it() {
+    let cp = [Point::new(0.0, 0.0, 0.0), Point::new(1.0, 2.0, 0.0),
+      Point::new(2.0, -2.0, 0.0), Point::new(3.0, 0.0, 0.0)];
+    let common = Rc::new(CurveCommon::new(cp, 0.1, 0.1, CurveType::Flat, None).unwrap());
+    let segments = Curve::segments(&identity(), &identity(), false, common.clone(), 4);
+
+    assert_eq!(segments.len(), 4);
+    assert_eq!(segments[3].u_max, 1.0);
+
+    // Every point on the curve lies in the bound of its segment
+    for i in 0..=20 {
+      let u = i as Float / 20.0;
+      let (p, _) = eval_bezier(&common.cp_obj, u);
+      let seg = &segments[((u * 4.0) as usize).min(3)];
+      assert!(seg.world_bound().inside(&p));
+    }
+
+    // A ray through a point on the curve finds it
+    let (p, _) = eval_bezier(&common.cp_obj, 0.3);
+    let hit = segments.iter().filter_map(|s| s.intersect(&down(p.x, p.y))).next().unwrap();
+    assert!((hit.2.p.y - p.y).abs() < 0.06);
+  }
+}
@@ -0,0 +1,154 @@
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::efloat::gamma;
+use rbrtcore::geometry::{ Float, BBox, Normal, Point, Ray, Vector, clamp, lerp, normalize, radians };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::transform::{ Applicable, Transform };
+
+use crate::quadric::{ azimuth, nearest_hit, weingarten };
+
+/// Open cylinder around the object space z axis, spanning `z_min..z_max`
+/// and swept up to `phi_max`
+pub struct Cylinder {
+  base: ShapeBase,
+  pub radius: Float,
+  pub z_min: Float,
+  pub z_max: Float,
+  pub phi_max: Float
+}
+
+impl Cylinder {
+  /// `phi_max` is given in degrees
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform,
+      reverse_orientation: bool, radius: Float, z0: Float, z1: Float, phi_max: Float) -> Cylinder {
+    Cylinder {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      radius,
+      z_min: z0.min(z1),
+      z_max: z0.max(z1),
+      phi_max: radians(clamp(phi_max, 0.0, 360.0))
+    }
+  }
+
+  /// Closest hit of the world space `r`, as the ray parameter, object space
+  /// hit point reprojected onto the cylinder and its azimuth
+  fn hit(&self, r: &Ray) -> Option<(Float, Point, Float)> {
+    let ray = self.base.world_to_object.apply_ray_with_error(r).0;
+    let (o, d) = (ray.o, ray.d);
+
+    let a = d.x * d.x + d.y * d.y;
+    let b = 2.0 * (d.x * o.x + d.y * o.y);
+    let c = o.x * o.x + o.y * o.y - self.radius * self.radius;
+
+    let (t_hit, (phit, phi)) = nearest_hit(&ray, a, b, c, |t| {
+      let mut phit = ray.apply(t);
+      let hit_radius = (phit.x * phit.x + phit.y * phit.y).sqrt();
+      phit.x *= self.radius / hit_radius;
+      phit.y *= self.radius / hit_radius;
+
+      let phi = azimuth(&phit);
+      if phit.z < self.z_min || phit.z > self.z_max || phi > self.phi_max {
+        None
+      } else {
+        Some((phit, phi))
+      }
+    })?;
+
+    Some((t_hit, phit, phi))
+  }
+}
+
+impl Shape for Cylinder {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    BBox::new(&Point::new(-self.radius, -self.radius, self.z_min),
+      &Point::new(self.radius, self.radius, self.z_max))
+  }
+
+  fn area(&self) -> Float {
+    (self.z_max - self.z_min) * self.radius * self.phi_max
+  }
+
+  fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+    let (t_hit, phit, phi) = self.hit(r)?;
+
+    let u = phi / self.phi_max;
+    let v = (phit.z - self.z_min) / (self.z_max - self.z_min);
+
+    let dpdu = Vector::new(-self.phi_max * phit.y, self.phi_max * phit.x, 0.0);
+    let dpdv = Vector::new(0.0, 0.0, self.z_max - self.z_min);
+
+    let d2pduu = Vector::new(phit.x, phit.y, 0.0) * (-self.phi_max * self.phi_max);
+    let zero = Vector::new(0.0, 0.0, 0.0);
+    let (dndu, dndv) = weingarten(dpdu, dpdv, d2pduu, zero, zero);
+
+    // Only x and y were reprojected, z comes straight from the ray
+    let p_error = Vector::new(phit.x.abs(), phit.y.abs(), 0.0) * gamma(3);
+
+    let o2w = &self.base.object_to_world;
+    let (p, p_error) = o2w.apply_point_with_abs_error(&phit, &p_error);
+    let dg = DifferentialGeometry::from_shape(p, o2w.apply(dpdu), o2w.apply(dpdv),
+      o2w.apply(dndu), o2w.apply(dndv), u, v, Some(self));
+
+    Some((t_hit, p_error, dg))
+  }
+
+  fn intersect_p(&self, r: &Ray) -> bool {
+    self.hit(r).is_some()
+  }
+
+  fn sample(&self, u1: Float, u2: Float) -> Option<(Point, Normal)> {
+    let o2w = &self.base.object_to_world;
+    let z = lerp(u1, self.z_min, self.z_max);
+    let (sin_t, cos_t) = (u2 * self.phi_max).sin_cos();
+    let p = Point::new(self.radius * cos_t, self.radius * sin_t, z);
+    let ns = normalize(o2w.apply(Normal::new(p.x, p.y, 0.0)));
+
+    Some((o2w.apply(p), if self.base.reverse_orientation { -ns } else { ns }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rbrtcore::geometry::PI;
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  #[test]
+  fn intersect_side_and_clip_in_z() {
+    let cylinder = Cylinder::new(&identity(), &identity(), false, 1.0, -1.0, 1.0, 360.0);
+    let ray = Ray::new(&Point::new(-5.0, 0.0, 0.5), &Vector::new(1.0, 0.0, 0.0), 0.0, Float::INFINITY, 0.0);
+    let (t_hit, _, dg) = cylinder.intersect(&ray).unwrap();
+
+    assert!((t_hit - 4.0).abs() < 1e-5);
+    assert!((dg.v - 0.75).abs() < 1e-5);
+    assert!((dg.nn.x.abs() - 1.0).abs() < 1e-5);
+    assert!((cylinder.area() - 4.0 * PI).abs() < 1e-4);
+
+    let above = Ray::new(&Point::new(-5.0, 0.0, 1.5), &Vector::new(1.0, 0.0, 0.0), 0.0, Float::INFINITY, 0.0);
+    assert!(!cylinder.intersect_p(&above));
+
+    let along_axis = Ray::new(&Point::new(0.0, 0.0, -5.0), &Vector::new(0.0, 0.0, 1.0), 0.0, Float::INFINITY, 0.0);
+    assert!(!cylinder.intersect_p(&along_axis));
+  }
+
+  #[test]
+  fn partial_sweep_uses_far_side() {
+    let half = Cylinder::new(&identity(), &identity(), false, 1.0, 0.0, 1.0, 180.0);
+    let ray = Ray::new(&Point::new(0.0, -5.0, 0.5), &Vector::new(0.0, 1.0, 0.0), 0.0, Float::INFINITY, 0.0);
+    let (t_hit, _, dg) = half.intersect(&ray).unwrap();
+
+    assert!((t_hit - 6.0).abs() < 1e-5);
+    assert!((dg.u - 0.5).abs() < 1e-5);
+
+    // Curvature only around the axis: dn/du follows dp/du scaled by 1/r
+    assert!((dg.dndu.x - dg.dpdu.x).abs() < 1e-4 && (dg.dndu.y - dg.dpdu.y).abs() < 1e-4);
+    assert!(dg.dndv.z.abs() < 1e-6);
+  }
+}
@@ -0,0 +1,171 @@
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::geometry::{ Float, BBox, Normal, Point, Ray, Vector, clamp, lerp, normalize, radians };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::transform::{ Applicable, Transform };
+
+use crate::quadric::azimuth;
+
+/// Disk, or annulus when `inner_radius` is positive, lying in the plane
+/// `z = height` and swept up to `phi_max`
+pub struct Disk {
+  base: ShapeBase,
+  pub height: Float,
+  pub radius: Float,
+  pub inner_radius: Float,
+  pub phi_max: Float
+}
+
+impl Disk {
+  /// `phi_max` is given in degrees
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform, reverse_orientation: bool,
+      height: Float, radius: Float, inner_radius: Float, phi_max: Float) -> Disk {
+    Disk {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      height,
+      radius,
+      inner_radius,
+      phi_max: radians(clamp(phi_max, 0.0, 360.0))
+    }
+  }
+
+  /// Hit of the world space `r` with the disk plane, as the ray parameter,
+  /// object space hit point and azimuth
+  fn hit(&self, r: &Ray) -> Option<(Float, Point, Float)> {
+    let ray = self.base.world_to_object.apply_ray_with_error(r).0;
+
+    if ray.d.z == 0.0 {
+      return None;
+    }
+
+    let t_hit = (self.height - ray.o.z) / ray.d.z;
+    if t_hit <= ray.mint || t_hit > ray.maxt {
+      return None;
+    }
+
+    let mut phit = ray.apply(t_hit);
+    let dist2 = phit.x * phit.x + phit.y * phit.y;
+    if dist2 > self.radius * self.radius || dist2 < self.inner_radius * self.inner_radius {
+      return None;
+    }
+
+    let phi = azimuth(&phit);
+    if phi > self.phi_max {
+      return None;
+    }
+
+    // The hit lies exactly in the plane, so z carries no error
+    phit.z = self.height;
+
+    Some((t_hit, phit, phi))
+  }
+}
+
+impl Shape for Disk {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    BBox::new(&Point::new(-self.radius, -self.radius, self.height),
+      &Point::new(self.radius, self.radius, self.height))
+  }
+
+  fn area(&self) -> Float {
+    self.phi_max * 0.5 * (self.radius * self.radius - self.inner_radius * self.inner_radius)
+  }
+
+  fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+    let (t_hit, phit, phi) = self.hit(r)?;
+
+    let hit_radius = (phit.x * phit.x + phit.y * phit.y).sqrt();
+    let u = phi / self.phi_max;
+    let v = (self.radius - hit_radius) / (self.radius - self.inner_radius);
+
+    let dpdu = Vector::new(-self.phi_max * phit.y, self.phi_max * phit.x, 0.0);
+    let dpdv = Vector::new(phit.x, phit.y, 0.0) *
+      ((self.inner_radius - self.radius) / hit_radius);
+    let dndu = Normal::new(0.0, 0.0, 0.0);
+    let dndv = Normal::new(0.0, 0.0, 0.0);
+
+    let o2w = &self.base.object_to_world;
+    let (p, p_error) = o2w.apply_point_with_abs_error(&phit, &Vector::new(0.0, 0.0, 0.0));
+    let dg = DifferentialGeometry::from_shape(p, o2w.apply(dpdu), o2w.apply(dpdv),
+      o2w.apply(dndu), o2w.apply(dndv), u, v, Some(self));
+
+    Some((t_hit, p_error, dg))
+  }
+
+  fn intersect_p(&self, r: &Ray) -> bool {
+    self.hit(r).is_some()
+  }
+
+  /// Uniform over the annulus sector, so it agrees with `area`
+  fn sample(&self, u1: Float, u2: Float) -> Option<(Point, Normal)> {
+    let o2w = &self.base.object_to_world;
+    let r = lerp(u1, self.inner_radius * self.inner_radius, self.radius * self.radius).sqrt();
+    let (sin_t, cos_t) = (u2 * self.phi_max).sin_cos();
+    let p = Point::new(r * cos_t, r * sin_t, self.height);
+    let ns = normalize(o2w.apply(Normal::new(0.0, 0.0, 1.0)));
+
+    Some((o2w.apply(p), if self.base.reverse_orientation { -ns } else { ns }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rbrtcore::geometry::PI;
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  fn down_at(x: Float, y: Float) -> Ray {
+    Ray::new(&Point::new(x, y, 5.0), &Vector::new(0.0, 0.0, -1.0), 0.0, Float::INFINITY, 0.0)
+  }
+
+  #[test]
+  fn intersect_annulus() {
+    let disk = Disk::new(&identity(), &identity(), false, 1.0, 2.0, 0.5, 360.0);
+    let (t_hit, p_error, dg) = disk.intersect(&down_at(1.0, 0.0)).unwrap();
+
+    assert!((t_hit - 4.0).abs() < 1e-5);
+    assert!((dg.p.z - 1.0).abs() <= p_error.z);
+    assert!((dg.v - 2.0 / 3.0).abs() < 1e-5);
+    assert!((dg.nn.z.abs() - 1.0).abs() < 1e-5);
+
+    assert!(!disk.intersect_p(&down_at(0.2, 0.0)));
+    assert!(!disk.intersect_p(&down_at(2.5, 0.0)));
+    assert!((disk.area() - PI * (4.0 - 0.25)).abs() < 1e-4);
+  }
+
+  #[test]
+  fn partial_sweep_and_parallel_rays() {
+    let quarter = Disk::new(&identity(), &identity(), false, 0.0, 1.0, 0.0, 90.0);
+
+    assert!(quarter.intersect_p(&down_at(0.5, 0.5)));
+    assert!(!quarter.intersect_p(&down_at(-0.5, 0.5)));
+
+    let parallel = Ray::new(&Point::new(-5.0, 0.0, 0.0), &Vector::new(1.0, 0.0, 0.0), 0.0, Float::INFINITY, 0.0);
+    assert!(!quarter.intersect_p(&parallel));
+  }
+
+  #[test]
+  fn sample_covers_the_annulus_and_converts_pdf() {
+    let disk = Disk::new(&identity(), &identity(), false, 1.0, 2.0, 1.0, 360.0);
+
+    for &(u1, u2) in [(0.0, 0.0), (0.5, 0.3), (1.0, 0.9)].iter() {
+      let (p, n) = disk.sample(u1, u2).unwrap();
+      let r = (p.x * p.x + p.y * p.y).sqrt();
+      assert!((1.0 - 1e-5..=2.0 + 1e-5).contains(&r));
+      assert!((p.z - 1.0).abs() < 1e-6 && (n.z - 1.0).abs() < 1e-6);
+    }
+
+    // Straight down from distance 4 the solid angle pdf is d^2 / area
+    let down = Vector::new(0.0, 0.0, -1.0);
+    let pdf = disk.pdf(&Point::new(1.5, 0.0, 5.0), &down);
+    assert!((pdf - 16.0 / disk.area()).abs() < 1e-3);
+    assert_eq!(disk.pdf(&Point::new(0.0, 0.0, 5.0), &down), 0.0);
+  }
+}
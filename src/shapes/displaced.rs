@@ -0,0 +1,453 @@
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{
+  Float, BBox, Normal, Point, Ray, Vector, Length, Union,
+  coordinate_system, cross, distance, dot, normalize };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::texture::Texture;
+use rbrtcore::transform::Applicable;
+
+use crate::triangle::TriangleMesh;
+
+/// Number of times an edge of the input mesh may be split, so that tiny
+/// rates cannot run away
+const MAX_LEVEL: usize = 8;
+
+/// How finely a `DisplacedMesh` is tessellated before it is displaced
+#[derive(Clone, Copy, Debug)]
+pub enum Tessellation {
+  /// Split edges longer than this, in world space
+  MaxEdgeLength(Float),
+  /// Split edges that subtend more than `rate` radians seen from `eye`.
+  /// The camera field of view over the image resolution gives about one
+  /// edge per pixel.
+  ScreenSpace { eye: Point, rate: Float }
+}
+
+impl Tessellation {
+  /// Only depends on the edge itself, so that neighbouring triangles agree
+  /// on their shared edges and the result has no cracks
+  fn splits(&self, p0: &Point, p1: &Point) -> bool {
+    let length = distance(p0, p1);
+
+    match *self {
+      Tessellation::MaxEdgeLength(max) => length > max,
+      Tessellation::ScreenSpace { eye, rate } => length > rate * distance(&eye, &((*p0 + *p1) * 0.5))
+    }
+  }
+
+  fn rate(&self) -> Float {
+    match *self {
+      Tessellation::MaxEdgeLength(max) => max,
+      Tessellation::ScreenSpace { rate, .. } => rate
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+struct Vertex {
+  p: Point,
+  n: Normal,
+  uv: (Float, Float)
+}
+
+/// Adaptive 1-to-2, 1-to-3 or 1-to-4 splitting of triangles, sharing
+/// the midpoint of every split edge
+struct Tessellator<'a> {
+  tessellation: &'a Tessellation,
+  vertices: Vec<Vertex>,
+  midpoints: HashMap<(usize, usize), usize>,
+  /// Subdivision level of every edge created by a split, input edges are
+  /// at level 0
+  levels: HashMap<(usize, usize), usize>,
+  indices: Vec<usize>
+}
+
+fn edge(a: usize, b: usize) -> (usize, usize) {
+  (a.min(b), a.max(b))
+}
+
+impl<'a> Tessellator<'a> {
+  fn level(&self, a: usize, b: usize) -> usize {
+    self.levels.get(&edge(a, b)).copied().unwrap_or(0)
+  }
+
+  /// The cap is tied to the edge rather than to the triangles, so both
+  /// triangles on an edge agree on whether it splits
+  fn splits(&self, a: usize, b: usize) -> bool {
+    self.level(a, b) < MAX_LEVEL && self.tessellation.splits(&self.vertices[a].p, &self.vertices[b].p)
+  }
+
+  fn midpoint(&mut self, a: usize, b: usize) -> usize {
+    let key = edge(a, b);
+    if let Some(&m) = self.midpoints.get(&key) {
+      return m;
+    }
+
+    let (va, vb) = (self.vertices[key.0], self.vertices[key.1]);
+    let n = va.n + vb.n;
+    let m = Vertex {
+      p: (va.p + vb.p) * 0.5,
+      n: if n.length() > 0.0 { normalize(n) } else { va.n },
+      uv: ((va.uv.0 + vb.uv.0) * 0.5, (va.uv.1 + vb.uv.1) * 0.5)
+    };
+
+    self.vertices.push(m);
+    let m = self.vertices.len() - 1;
+    let level = self.level(a, b) + 1;
+    self.midpoints.insert(key, m);
+    self.levels.insert(edge(a, m), level);
+    self.levels.insert(edge(m, b), level);
+    m
+  }
+
+  fn split(&mut self, tri: [usize; 3]) {
+    let mut todo = vec![tri];
+
+    while let Some(t) = todo.pop() {
+      let s = [self.splits(t[0], t[1]), self.splits(t[1], t[2]), self.splits(t[2], t[0])];
+
+      // Rotate the corners so that the split edges come first, keeping the
+      // winding
+      let r = match s {
+        [false, false, false] => {
+          self.indices.extend_from_slice(&t);
+          continue;
+        },
+        [true, false, false] | [true, true, false] | [true, true, true] => 0,
+        [false, true, false] | [false, true, true] => 1,
+        [false, false, true] | [true, false, true] => 2
+      };
+      let [v0, v1, v2] = [t[r], t[(r + 1) % 3], t[(r + 2) % 3]];
+      let n_split = s.iter().filter(|&&x| x).count();
+
+      // Edges inside the triangle are only shared by its children, and sit
+      // one level below the deepest edge around them
+      let inner = 1 + (0..3).map(|i| self.level(t[i], t[(i + 1) % 3])).max().unwrap_or(0);
+
+      let (children, inner_edges) = match n_split {
+        1 => {
+          let m0 = self.midpoint(v0, v1);
+          (vec![[v0, m0, v2], [m0, v1, v2]], vec![(m0, v2)])
+        },
+        2 => {
+          let (m0, m1) = (self.midpoint(v0, v1), self.midpoint(v1, v2));
+
+          // Cut the remaining quad along its shorter diagonal
+          let p = |i: usize| self.vertices[i].p;
+          if distance(&p(v0), &p(m1)) < distance(&p(m0), &p(v2)) {
+            (vec![[m0, v1, m1], [v0, m0, m1], [v0, m1, v2]], vec![(m0, m1), (v0, m1)])
+          } else {
+            (vec![[m0, v1, m1], [v0, m0, v2], [m0, m1, v2]], vec![(m0, m1), (m0, v2)])
+          }
+        },
+        _ => {
+          let (m0, m1, m2) = (self.midpoint(v0, v1), self.midpoint(v1, v2), self.midpoint(v2, v0));
+          (vec![[v0, m0, m2], [m0, v1, m1], [m2, m1, v2], [m0, m1, m2]],
+            vec![(m0, m1), (m1, m2), (m2, m0)])
+        }
+      };
+
+      for (a, b) in inner_edges {
+        self.levels.insert(edge(a, b), inner);
+      }
+      todo.extend(children);
+    }
+  }
+}
+
+/// Area weighted vertex normals of the triangles in `indices`
+fn vertex_normals(p: &[Point], indices: &[usize]) -> Vec<Vector> {
+  let mut n = vec![Vector::zero(); p.len()];
+
+  for tri in indices.chunks(3) {
+    let face = cross(p[tri[1]] - p[tri[0]], p[tri[2]] - p[tri[0]]);
+    for &i in tri {
+      n[i] = n[i] + face;
+    }
+  }
+
+  n
+}
+
+/// Triangle mesh whose vertices are offset along their normals by a
+/// texture, after adaptive tessellation
+pub struct DisplacedMesh {
+  mesh: Rc<TriangleMesh>,
+  displacement: Rc<dyn Texture<Float>>,
+  max_displacement: Float,
+  tessellation: Tessellation,
+  /// Area of the displaced mesh, which has to be tessellated to measure
+  area: OnceCell<Float>
+}
+
+impl DisplacedMesh {
+  /// The displacement is clamped to `max_displacement`, which pads the
+  /// bounds of the unrefined shape
+  pub fn new(mesh: Rc<TriangleMesh>, displacement: Rc<dyn Texture<Float>>,
+      max_displacement: Float, tessellation: Tessellation) -> Result<DisplacedMesh, RenderError> {
+    if mesh.p.is_empty() {
+      return Err(RenderError::InvalidParameter {
+        name: "P".to_string(),
+        message: "a displaced mesh needs at least one vertex".to_string()
+      });
+    }
+
+    if !(max_displacement >= 0.0 && max_displacement.is_finite()) {
+      return Err(RenderError::InvalidParameter {
+        name: "maxdisplacement".to_string(),
+        message: format!("must be finite and non negative, found {}", max_displacement)
+      });
+    }
+
+    let rate = tessellation.rate();
+    if !(rate > 0.0 && rate.is_finite()) {
+      return Err(RenderError::InvalidParameter {
+        name: "tessellationrate".to_string(),
+        message: format!("must be finite and positive, found {}", rate)
+      });
+    }
+
+    Ok(DisplacedMesh { mesh, displacement, max_displacement, tessellation, area: OnceCell::new() })
+  }
+
+  /// Tessellated and displaced mesh, with normals of the displaced surface
+  pub fn tessellate(&self) -> TriangleMesh {
+    let mesh = &self.mesh;
+    let base = &mesh.base;
+
+    // Displace along the shading normals, or along the geometric normals
+    // oriented like `DifferentialGeometry` does
+    let n: Vec<Normal> = match mesh.n {
+      Some(ref n) => n.iter().map(|&n| normalize(n)).collect(),
+      None => {
+        let flip = base.reverse_orientation ^ base.transform_swaps_handedness;
+        vertex_normals(&mesh.p, &mesh.vertex_indices).into_iter()
+          .map(|n| Normal::from_vector(&normalize(if flip { -n } else { n })))
+          .collect()
+      }
+    };
+
+    let vertices = mesh.p.iter().zip(n).enumerate().map(|(i, (&p, n))| Vertex {
+      p, n,
+      uv: mesh.uv.as_ref().map_or((0.0, 0.0), |uv| uv[i])
+    }).collect();
+
+    let mut tessellator = Tessellator {
+      tessellation: &self.tessellation,
+      vertices,
+      midpoints: HashMap::new(),
+      levels: HashMap::new(),
+      indices: Vec::new()
+    };
+
+    for tri in mesh.vertex_indices.chunks(3) {
+      tessellator.split([tri[0], tri[1], tri[2]]);
+    }
+
+    let Tessellator { vertices, indices, .. } = tessellator;
+
+    let p: Vec<Point> = vertices.iter().map(|v| {
+      let (dpdu, dpdv) = coordinate_system(&Vector::from_normal(&v.n));
+      let mut dg = DifferentialGeometry::from_shape(v.p, dpdu, dpdv,
+        Normal::zero(), Normal::zero(), v.uv.0, v.uv.1, None);
+      dg.nn = v.n;
+
+      let d = self.displacement.evaluate(&dg).clamp(-self.max_displacement, self.max_displacement);
+      v.p + Vector::from_normal(&v.n) * d
+    }).collect();
+
+    let n = vertex_normals(&p, &indices).into_iter().zip(&vertices).map(|(n, v)| {
+      if n.length() == 0.0 {
+        return v.n;
+      }
+
+      let n = Normal::from_vector(&normalize(n));
+      if dot(n, v.n) < 0.0 { -n } else { n }
+    });
+
+    // `TriangleMesh` takes object space data, like every other shape
+    let w2o = &base.world_to_object;
+    let p = p.iter().map(|&p| w2o.apply(p)).collect();
+    let n = n.map(|n| w2o.apply(n)).collect();
+    let uv = mesh.uv.as_ref().map(|_| vertices.iter().map(|v| v.uv).collect());
+
+    // Indices only ever refer to vertices created above
+    TriangleMesh::new(&base.object_to_world, w2o, base.reverse_orientation, indices, p,
+      Some(n), None, uv, mesh.alpha.clone())
+      .expect("tessellated mesh is valid")
+  }
+}
+
+impl Shape for DisplacedMesh {
+  fn get_base(&self) -> &ShapeBase {
+    &self.mesh.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    self.mesh.base.world_to_object.apply(self.world_bound())
+  }
+
+  /// Bound of the input mesh grown by the largest displacement, so that
+  /// it can be used before the mesh is refined
+  fn world_bound(&self) -> BBox {
+    let p = &self.mesh.p;
+    let mut bound = p[1..].iter().fold(BBox::from_point(&p[0]), |b, p| b.union(p));
+    bound.expand(self.max_displacement);
+    bound
+  }
+
+  fn area(&self) -> Float {
+    *self.area.get_or_init(|| self.tessellate().into_shapes().iter().map(|t| t.area()).sum())
+  }
+
+  /// Never hits, the displacement is only applied when `refine`
+  /// tessellates the mesh
+  fn intersect(&self, _ray: &Ray) -> Option<ShapeIntersection> {
+    None
+  }
+
+  fn can_intersect(&self) -> bool { false }
+
+  fn refine(&self) -> Result<Vec<Rc<dyn Shape>>, RenderError> {
+    Ok(self.tessellate().into_shapes())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rbrtcore::transform::Transform;
+
+  struct ConstantTexture(Float);
+
+  impl Texture<Float> for ConstantTexture {
+    fn evaluate(&self, _dg: &DifferentialGeometry) -> Float {
+      self.0
+    }
+  }
+
+  /// Displacement growing linearly with world space `x`
+  struct Ramp(Float);
+
+  impl Texture<Float> for Ramp {
+    fn evaluate(&self, dg: &DifferentialGeometry) -> Float {
+      self.0 * dg.p.x
+    }
+  }
+
+  /// Square of side 2 at `z = 1`, facing up
+  fn quad() -> Rc<TriangleMesh> {
+    let t = Transform::translate(&Vector::new(0.0, 0.0, 1.0));
+    let p = vec![Point::new(-1.0, -1.0, 0.0), Point::new(1.0, -1.0, 0.0),
+      Point::new(1.0, 1.0, 0.0), Point::new(-1.0, 1.0, 0.0)];
+    let uv = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+
+    Rc::new(TriangleMesh::new(&t, &Transform::inverse(&t), false, vec![0, 1, 2, 0, 2, 3],
+      p, None, None, Some(uv), None).unwrap())
+  }
+
+  fn displaced(d: Rc<dyn Texture<Float>>, max: Float, tessellation: Tessellation) -> DisplacedMesh {
+    DisplacedMesh::new(quad(), d, max, tessellation).unwrap()
+  }
+
+  /// Edges used by a single triangle, which for a crack free tessellation
+  /// of the quad only lie on its outline
+  fn boundary_edges(mesh: &TriangleMesh) -> Vec<(usize, usize)> {
+    let mut count = HashMap::new();
+    for tri in mesh.vertex_indices.chunks(3) {
+      for i in 0..3 {
+        let (a, b) = (tri[i], tri[(i + 1) % 3]);
+        *count.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+      }
+    }
+
+    count.into_iter().filter(|&(_, c)| c == 1).map(|(e, _)| e).collect()
+  }
+
+  #[test]
+  fn new_validates_parameters() {
+    let d: Rc<dyn Texture<Float>> = Rc::new(ConstantTexture(0.0));
+
+    assert!(DisplacedMesh::new(quad(), d.clone(), -1.0, Tessellation::MaxEdgeLength(0.5)).is_err());
+    assert!(DisplacedMesh::new(quad(), d.clone(), 1.0, Tessellation::MaxEdgeLength(0.0)).is_err());
+    assert!(DisplacedMesh::new(quad(), d.clone(), 1.0,
+      Tessellation::ScreenSpace { eye: Point::zero(), rate: Float::NAN }).is_err());
+
+    let t = Transform::translate(&Vector::zero());
+    let empty = TriangleMesh::new(&t, &t, false, Vec::new(), Vec::new(), None, None, None, None).unwrap();
+    assert!(DisplacedMesh::new(Rc::new(empty), d, 1.0, Tessellation::MaxEdgeLength(0.5)).is_err());
+  }
+
+  #[test]
+  fn constant_displacement_lifts_the_refined_mesh() {
+    let shape = displaced(Rc::new(ConstantTexture(0.25)), 0.5, Tessellation::MaxEdgeLength(0.5));
+    let mesh = shape.tessellate();
+
+    assert!(!shape.can_intersect());
+    assert!(mesh.num_triangles > 2);
+    for (p, n) in mesh.p.iter().zip(mesh.n.as_ref().unwrap()) {
+      assert!((p.z - 1.25).abs() < 1e-5);
+      assert!((n.z - 1.0).abs() < 1e-5);
+    }
+
+    for tri in mesh.vertex_indices.chunks(3) {
+      for i in 0..3 {
+        assert!(distance(&mesh.p[tri[i]], &mesh.p[tri[(i + 1) % 3]]) <= 0.5 + 1e-5);
+      }
+    }
+
+    let bound = shape.world_bound();
+    assert!(bound.p_min.z <= 0.5 && bound.p_max.z >= 1.5);
+    assert!((shape.area() - 4.0).abs() < 1e-4);
+
+    let ray = Ray::new(&Point::new(0.3, 0.1, 5.0), &Vector::new(0.0, 0.0, -1.0), 0.0, Float::INFINITY, 0.0);
+    let t_hit = shape.refine().unwrap().iter()
+      .filter_map(|t| t.intersect(&ray).map(|hit| hit.0))
+      .fold(Float::INFINITY, Float::min);
+    assert!((t_hit - 3.75).abs() < 1e-4);
+  }
+
+  #[test]
+  fn displacement_is_clamped_and_normals_follow_the_surface() {
+    let clamped = displaced(Rc::new(ConstantTexture(5.0)), 0.5, Tessellation::MaxEdgeLength(1.0));
+    assert!(clamped.tessellate().p.iter().all(|p| (p.z - 1.5).abs() < 1e-5));
+
+    // A ramp turns the quad into a tilted plane
+    let tilted = displaced(Rc::new(Ramp(0.5)), 1.0, Tessellation::MaxEdgeLength(0.7)).tessellate();
+    let expected = normalize(Vector::new(-0.5, 0.0, 1.0));
+    for (p, n) in tilted.p.iter().zip(tilted.n.as_ref().unwrap()) {
+      assert!((p.z - (1.0 + 0.5 * p.x)).abs() < 1e-5);
+      assert!((dot(*n, expected) - 1.0).abs() < 1e-4);
+    }
+  }
+
+  #[test]
+  fn screen_space_rate_is_adaptive_and_crack_free() {
+    let eye = Point::new(-1.0, -1.0, 1.5);
+    let mesh = displaced(Rc::new(ConstantTexture(0.0)), 0.0,
+      Tessellation::ScreenSpace { eye, rate: 0.3 }).tessellate();
+
+    let near = mesh.vertex_indices.chunks(3).filter(|tri| mesh.p[tri[0]].x < 0.0).count();
+    assert!(near > 2 * (mesh.num_triangles - near));
+
+    // T-junctions would leave single use edges inside the quad
+    let outline: Float = boundary_edges(&mesh).iter().map(|&(a, b)| distance(&mesh.p[a], &mesh.p[b])).sum();
+    assert!((outline - 8.0).abs() < 1e-4);
+  }
+
+  #[test]
+  fn level_cap_is_shared_by_neighbouring_triangles() {
+    // Edges next to the eye always want splitting, so only the cap stops them
+    let eye = Point::new(-0.5, -0.3, 1.0);
+    let mesh = displaced(Rc::new(ConstantTexture(0.0)), 0.0,
+      Tessellation::ScreenSpace { eye, rate: 0.1 }).tessellate();
+
+    let outline: Float = boundary_edges(&mesh).iter().map(|&(a, b)| distance(&mesh.p[a], &mesh.p[b])).sum();
+    assert!((outline - 8.0).abs() < 1e-4);
+  }
+}
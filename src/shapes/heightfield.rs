@@ -0,0 +1,426 @@
+use std::rc::Rc;
+
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::efloat::gamma;
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{
+  Float, BBox, Normal, Point, Ray, Vector, Length,
+  coordinate_system, cross, dot, normalize };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::transform::{ Applicable, Transform };
+
+use crate::triangle::TriangleMesh;
+
+/// Ray-triangle test returning the ray parameter and the barycentrics of
+/// the hit, or `None` outside `ray.mint..ray.maxt`
+fn intersect_triangle(ray: &Ray, p0: Point, p1: Point, p2: Point) -> Option<(Float, [Float; 3])> {
+  let e1 = p1 - p0;
+  let e2 = p2 - p0;
+  let s1 = cross(ray.d, e2);
+  let divisor = dot(s1, e1);
+  if divisor == 0.0 {
+    return None;
+  }
+  let inv_divisor = 1.0 / divisor;
+
+  let s = ray.o - p0;
+  let b1 = dot(s, s1) * inv_divisor;
+  if !(0.0..=1.0).contains(&b1) {
+    return None;
+  }
+
+  let s2 = cross(s, e1);
+  let b2 = dot(ray.d, s2) * inv_divisor;
+  if b2 < 0.0 || b1 + b2 > 1.0 {
+    return None;
+  }
+
+  let t = dot(e2, s2) * inv_divisor;
+  if t <= ray.mint || t > ray.maxt {
+    return None;
+  }
+
+  Some((t, [1.0 - b1 - b2, b1, b2]))
+}
+
+/// Height field over the unit square in object space, sampled at `nx` by
+/// `ny` regularly spaced z values. Each grid cell is split into two
+/// triangles along its diagonal, the same ones `refine` produces.
+pub struct Heightfield {
+  base: ShapeBase,
+  nx: usize,
+  ny: usize,
+  z: Vec<Float>,
+  /// Vertex normals in object space, from central differences of `z`
+  n: Vec<Normal>,
+  z_min: Float,
+  z_max: Float
+}
+
+impl Heightfield {
+  /// `z` holds `nx * ny` heights in row-major order, x varying fastest
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform,
+      reverse_orientation: bool, nx: usize, ny: usize, z: Vec<Float>) -> Result<Heightfield, RenderError> {
+    if nx < 2 || ny < 2 {
+      return Err(RenderError::InvalidParameter {
+        name: "nu".to_string(),
+        message: format!("a {}x{} grid has no cells", nx, ny)
+      });
+    }
+
+    if z.len() != nx * ny {
+      return Err(RenderError::InvalidParameter {
+        name: "Pz".to_string(),
+        message: format!("expected {} heights for a {}x{} grid, found {}", nx * ny, nx, ny, z.len())
+      });
+    }
+
+    let height = |x: usize, y: usize| z[y * nx + x];
+    let mut n = Vec::with_capacity(nx * ny);
+    for y in 0..ny {
+      for x in 0..nx {
+        let (x0, x1) = (x.saturating_sub(1), (x + 1).min(nx - 1));
+        let (y0, y1) = (y.saturating_sub(1), (y + 1).min(ny - 1));
+        let dzdx = (height(x1, y) - height(x0, y)) * (nx - 1) as Float / (x1 - x0) as Float;
+        let dzdy = (height(x, y1) - height(x, y0)) * (ny - 1) as Float / (y1 - y0) as Float;
+
+        n.push(normalize(Normal::new(-dzdx, -dzdy, 1.0)));
+      }
+    }
+
+    let z_min = z.iter().cloned().fold(Float::INFINITY, Float::min);
+    let z_max = z.iter().cloned().fold(Float::NEG_INFINITY, Float::max);
+
+    Ok(Heightfield {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      nx,
+      ny,
+      z,
+      n,
+      z_min,
+      z_max
+    })
+  }
+
+  /// Number of samples along x
+  pub fn nx(&self) -> usize {
+    self.nx
+  }
+
+  /// Number of samples along y
+  pub fn ny(&self) -> usize {
+    self.ny
+  }
+
+  /// Heights in row-major order, x varying fastest
+  pub fn z(&self) -> &[Float] {
+    &self.z
+  }
+
+  fn point(&self, i: usize) -> Point {
+    Point::new((i % self.nx) as Float / (self.nx - 1) as Float,
+      (i / self.nx) as Float / (self.ny - 1) as Float, self.z[i])
+  }
+
+  /// Vertex indices of the two triangles of cell `(x, y)`, the first one
+  /// below the diagonal
+  fn cell_triangles(&self, x: usize, y: usize) -> [[usize; 3]; 2] {
+    let v00 = y * self.nx + x;
+    let (v10, v01, v11) = (v00 + 1, v00 + self.nx, v00 + self.nx + 1);
+
+    [[v00, v10, v11], [v00, v11, v01]]
+  }
+
+  /// Cell containing `(u, v)`, the triangle of it that holds the point
+  /// and the point's barycentrics in that triangle
+  fn locate(&self, u: Float, v: Float) -> (usize, usize, usize, [Float; 3]) {
+    let (fx, fy) = (u * (self.nx - 1) as Float, v * (self.ny - 1) as Float);
+    let x = (fx.floor().max(0.0) as usize).min(self.nx - 2);
+    let y = (fy.floor().max(0.0) as usize).min(self.ny - 2);
+    let (fx, fy) = (fx - x as Float, fy - y as Float);
+
+    if fx >= fy {
+      (x, y, 0, [1.0 - fx, fx - fy, fy])
+    } else {
+      (x, y, 1, [1.0 - fy, fx, fy - fx])
+    }
+  }
+
+  /// Height gradient of triangle `tri` of cell `(x, y)` in object space
+  fn slopes(&self, x: usize, y: usize, tri: usize) -> (Float, Float) {
+    let [v00, v11] = [y * self.nx + x, (y + 1) * self.nx + x + 1];
+    let (z00, z11) = (self.z[v00], self.z[v11]);
+    let (sx, sy) = ((self.nx - 1) as Float, (self.ny - 1) as Float);
+
+    if tri == 0 {
+      let z10 = self.z[v00 + 1];
+      ((z10 - z00) * sx, (z11 - z10) * sy)
+    } else {
+      let z01 = self.z[v00 + self.nx];
+      ((z11 - z01) * sx, (z01 - z00) * sy)
+    }
+  }
+
+  fn intersect_cell(&self, ray: &Ray, x: usize, y: usize) -> Option<(Float, Point)> {
+    self.cell_triangles(x, y).iter()
+      .filter_map(|&[i0, i1, i2]| {
+        let (p0, p1, p2) = (self.point(i0), self.point(i1), self.point(i2));
+        let (t, b) = intersect_triangle(ray, p0, p1, p2)?;
+        Some((t, p0 * b[0] + p1 * b[1] + p2 * b[2]))
+      })
+      .min_by(|a, b| a.0.total_cmp(&b.0))
+  }
+
+  /// Closest hit of the world space `r`, walking the cells under the ray
+  /// with a 2D DDA. Returns the ray parameter and object space hit point.
+  fn hit(&self, r: &Ray) -> Option<(Float, Point)> {
+    let ray = self.base.world_to_object.apply_ray_with_error(r).0;
+    let (t0, t1) = self.object_bound().intersect_p(&ray)?;
+
+    let p_entry = ray.apply(t0);
+    let res = [self.nx - 1, self.ny - 1];
+    let mut cell = [0i64; 2];
+    let mut next_crossing = [Float::INFINITY; 2];
+    let mut delta = [0.0; 2];
+    let mut step = [0i64; 2];
+    let mut out = [0i64; 2];
+
+    for axis in 0..2 {
+      let n = res[axis] as Float;
+      cell[axis] = ((p_entry[axis] * n).floor() as i64).clamp(0, res[axis] as i64 - 1);
+
+      if ray.d[axis] > 0.0 {
+        next_crossing[axis] = t0 + ((cell[axis] + 1) as Float / n - p_entry[axis]) / ray.d[axis];
+        delta[axis] = 1.0 / (n * ray.d[axis]);
+        step[axis] = 1;
+        out[axis] = res[axis] as i64;
+      } else if ray.d[axis] < 0.0 {
+        next_crossing[axis] = t0 + (cell[axis] as Float / n - p_entry[axis]) / ray.d[axis];
+        delta[axis] = -1.0 / (n * ray.d[axis]);
+        step[axis] = -1;
+        out[axis] = -1;
+      }
+    }
+
+    loop {
+      if let Some(hit) = self.intersect_cell(&ray, cell[0] as usize, cell[1] as usize) {
+        return Some(hit);
+      }
+
+      let axis = if next_crossing[0] < next_crossing[1] { 0 } else { 1 };
+      if t1 < next_crossing[axis] {
+        return None;
+      }
+
+      cell[axis] += step[axis];
+      if cell[axis] == out[axis] {
+        return None;
+      }
+      next_crossing[axis] += delta[axis];
+    }
+  }
+}
+
+impl Shape for Heightfield {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    BBox::new(&Point::new(0.0, 0.0, self.z_min), &Point::new(1.0, 1.0, self.z_max))
+  }
+
+  fn area(&self) -> Float {
+    let mut area = 0.0;
+    for y in 0..self.ny - 1 {
+      for x in 0..self.nx - 1 {
+        for [i0, i1, i2] in self.cell_triangles(x, y) {
+          let (p0, p1, p2) = (self.point(i0), self.point(i1), self.point(i2));
+          area += 0.5 * cross(p1 - p0, p2 - p0).length();
+        }
+      }
+    }
+
+    area
+  }
+
+  fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+    let (t_hit, phit) = self.hit(r)?;
+
+    let (u, v) = (phit.x.clamp(0.0, 1.0), phit.y.clamp(0.0, 1.0));
+    let (x, y, tri, _) = self.locate(u, v);
+    let (dzdx, dzdy) = self.slopes(x, y, tri);
+    let dpdu = Vector::new(1.0, 0.0, dzdx);
+    let dpdv = Vector::new(0.0, 1.0, dzdy);
+    let zero = Normal::new(0.0, 0.0, 0.0);
+
+    // The hit is a barycentric combination of points that are all exact
+    let p_error = Vector::new(phit.x.abs(), phit.y.abs(), phit.z.abs()) * gamma(7);
+
+    let o2w = &self.base.object_to_world;
+    let (p, p_error) = o2w.apply_point_with_abs_error(&phit, &p_error);
+    let dg = DifferentialGeometry::from_shape(p, o2w.apply(dpdu), o2w.apply(dpdv),
+      zero, zero, u, v, Some(self));
+
+    Some((t_hit, p_error, dg))
+  }
+
+  fn intersect_p(&self, r: &Ray) -> bool {
+    self.hit(r).is_some()
+  }
+
+  /// Shading frame from the vertex normals, interpolated across the
+  /// triangle that was hit.
+  ///
+  /// The vertex normals are kept in object space, so `object_to_world`
+  /// takes them to wherever an instance places the field
+  fn get_shading_geometry(&self, object_to_world: &Transform,
+      dg: &DifferentialGeometry) -> DifferentialGeometry {
+    let o2w = object_to_world;
+    let (x, y, tri, b) = self.locate(dg.u, dg.v);
+    let i = self.cell_triangles(x, y)[tri];
+    let n = [self.n[i[0]], self.n[i[1]], self.n[i[2]]];
+
+    let ns = normalize(o2w.apply(n[0] * b[0] + n[1] * b[1] + n[2] * b[2]));
+    let mut ss = normalize(dg.dpdu);
+    let mut ts = cross(ss, ns);
+    if ts.length_squared() > 0.0 {
+      ts = normalize(ts);
+      ss = cross(ts, ns);
+    } else {
+      (ss, ts) = coordinate_system(&Vector::from_normal(&ns));
+    }
+
+    // Normal derivatives across the triangle, in grid steps scaled to uv
+    let (sx, sy) = ((self.nx - 1) as Float, (self.ny - 1) as Float);
+    let (dndu, dndv) = if tri == 0 {
+      ((n[1] - n[0]) * sx, (n[2] - n[1]) * sy)
+    } else {
+      ((n[1] - n[2]) * sx, (n[2] - n[0]) * sy)
+    };
+
+    let mut shading = DifferentialGeometry::from_shape(dg.p, ss, ts, o2w.apply(dndu),
+      o2w.apply(dndv), dg.u, dg.v, Some(self));
+    shading.shape = dg.shape.clone();
+    shading.dudx = dg.dudx;
+    shading.dvdx = dg.dvdx;
+    shading.dudy = dg.dudy;
+    shading.dvdy = dg.dvdy;
+    shading.dpdx = dg.dpdx;
+    shading.dpdy = dg.dpdy;
+
+    shading
+  }
+
+  /// Triangle mesh over the same grid, with uvs and vertex normals
+  fn refine(&self) -> Result<Vec<Rc<dyn Shape>>, RenderError> {
+    let p: Vec<Point> = (0..self.z.len()).map(|i| self.point(i)).collect();
+    let uv = p.iter().map(|p| (p.x, p.y)).collect();
+    let indices = (0..self.ny - 1)
+      .flat_map(|y| (0..self.nx - 1).map(move |x| (x, y)))
+      .flat_map(|(x, y)| self.cell_triangles(x, y).concat())
+      .collect();
+
+    let mesh = TriangleMesh::new(&self.base.object_to_world, &self.base.world_to_object,
+      self.base.reverse_orientation, indices, p, Some(self.n.clone()), None, Some(uv), None)?;
+
+    Ok(mesh.into_shapes())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  /// Height field of `f(x, y)` sampled on an `n` by `n` grid
+  fn sampled<F: Fn(Float, Float) -> Float>(n: usize, f: F) -> Heightfield {
+    let step = 1.0 / (n - 1) as Float;
+    let z = (0..n * n).map(|i| f((i % n) as Float * step, (i / n) as Float * step)).collect();
+    Heightfield::new(&identity(), &identity(), false, n, n, z).unwrap()
+  }
+
+  fn ray(o: Point, d: Vector) -> Ray {
+    Ray::new(&o, &d, 0.0, Float::INFINITY, 0.0)
+  }
+
+  #[test]
+  fn vertical_ray_hits_flat_field_with_uv() {
+    let field = sampled(3, |_, _| 0.5);
+    let (t_hit, _, dg) = field.intersect(&ray(Point::new(0.3, 0.7, 2.0), Vector::new(0.0, 0.0, -1.0))).unwrap();
+
+    assert!((t_hit - 1.5).abs() < 1e-5);
+    assert!((dg.u - 0.3).abs() < 1e-5 && (dg.v - 0.7).abs() < 1e-5);
+    assert!((dg.nn.z - 1.0).abs() < 1e-5);
+    assert!((field.area() - 1.0).abs() < 1e-5);
+  }
+
+  #[test]
+  fn dda_walks_across_cells() {
+    let ramp = sampled(5, |x, _| x);
+    let (t_hit, _, dg) = ramp.intersect(&ray(Point::new(-1.0, 0.5, 0.3), Vector::new(1.0, 0.0, 0.0))).unwrap();
+
+    assert!((t_hit - 1.3).abs() < 1e-5);
+    assert!((dg.p.z - 0.3).abs() < 1e-5);
+
+    // Going the other way the ray leaves the grid under the ramp
+    assert!(!ramp.intersect_p(&ray(Point::new(2.0, 0.5, 1.5), Vector::new(-1.0, 0.0, 0.0))));
+    assert!(ramp.intersect_p(&ray(Point::new(2.0, 0.5, 0.9), Vector::new(-1.0, 0.1, -0.05))));
+  }
+
+  #[test]
+  fn shading_normals_are_continuous_across_cells() {
+    let bowl = sampled(5, |x, y| x * x + y * y);
+    let shade = |x: Float| {
+      let (_, _, dg) = bowl.intersect(&ray(Point::new(x, 0.6, 5.0), Vector::new(0.0, 0.0, -1.0))).unwrap();
+      (dg.nn, bowl.get_shading_geometry(&identity(), &dg).nn)
+    };
+
+    let (geometric_left, shading_left) = shade(0.4999);
+    let (geometric_right, shading_right) = shade(0.5001);
+
+    assert!((geometric_left.x - geometric_right.x).abs() > 0.05);
+    assert!((shading_left.x - shading_right.x).abs() < 1e-3);
+  }
+
+  #[test]
+  fn shading_normals_follow_instances() {
+    let bowl = sampled(5, |x, y| x * x + y * y);
+    let (_, _, dg) = bowl.intersect(&ray(Point::new(0.5, 0.6, 5.0), Vector::new(0.0, 0.0, -1.0))).unwrap();
+    let n = bowl.get_shading_geometry(&identity(), &dg).nn;
+
+    // An instance turned about z turns the shading normal with it
+    let instanced = Transform::rotate_z(90.0) * bowl.get_base().object_to_world;
+    let turned = bowl.get_shading_geometry(&instanced, &dg).nn;
+    assert!((turned.x + n.y).abs() < 1e-5 && (turned.y - n.x).abs() < 1e-5);
+    assert!((turned.z - n.z).abs() < 1e-5);
+  }
+
+  #[test]
+  fn refine_matches_direct_intersection() {
+    let bowl = sampled(4, |x, y| x * y);
+    let r = ray(Point::new(0.2, 0.8, 3.0), Vector::new(0.1, -0.2, -1.0));
+    let (t_direct, _, _) = bowl.intersect(&r).unwrap();
+
+    let triangles = bowl.refine().unwrap();
+    assert_eq!(triangles.len(), 18);
+
+    let t_refined = triangles.iter()
+      .filter_map(|t| t.intersect(&r).map(|hit| hit.0))
+      .fold(Float::INFINITY, Float::min);
+    assert!((t_direct - t_refined).abs() < 1e-4);
+  }
+
+  #[test]
+  fn new_validates_grid() {
+    assert!(Heightfield::new(&identity(), &identity(), false, 1, 3, vec![0.0; 3]).is_err());
+    assert!(Heightfield::new(&identity(), &identity(), false, 2, 2, vec![0.0; 3]).is_err());
+
+    let field = Heightfield::new(&identity(), &identity(), false, 3, 2, vec![0.5; 6]).unwrap();
+    assert_eq!((field.nx(), field.ny(), field.z().len()), (3, 2, 6));
+  }
+}
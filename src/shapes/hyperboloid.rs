@@ -0,0 +1,214 @@
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{ Float, PI, BBox, Point, Ray, Vector, clamp, radians };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::transform::{ Applicable, Transform };
+
+use crate::quadric::{ nearest_hit, ray_point_error, weingarten };
+
+/// Hyperboloid of one sheet `a (x^2 + y^2) - c z^2 = 1` swept by rotating
+/// the segment `p1..p2` around the z axis up to `phi_max`
+pub struct Hyperboloid {
+  base: ShapeBase,
+  pub p1: Point,
+  pub p2: Point,
+  pub z_min: Float,
+  pub z_max: Float,
+  pub phi_max: Float,
+  pub r_max: Float,
+  pub a: Float,
+  pub c: Float
+}
+
+impl Hyperboloid {
+  /// `phi_max` is given in degrees. Fails when the segment cannot sweep a
+  /// hyperboloid, e.g. when both points lie at the same height
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform, reverse_orientation: bool,
+      p1: Point, p2: Point, phi_max: Float) -> Result<Hyperboloid, RenderError> {
+    if p1.z == p2.z {
+      return Err(RenderError::InvalidParameter {
+        name: "p1".to_string(),
+        message: "p1 and p2 must lie at different heights".to_string()
+      });
+    }
+
+    let radius1 = (p1.x * p1.x + p1.y * p1.y).sqrt();
+    let radius2 = (p2.x * p2.x + p2.y * p2.y).sqrt();
+
+    // Solve for the coefficients from p2 and a point further along the
+    // line through both, stepping on while the system is degenerate
+    let (q1, q2) = if p2.z == 0.0 { (p2, p1) } else { (p1, p2) };
+    let mut pp = q1;
+    let mut coefficients = None;
+
+    for _ in 0..64 {
+      pp = pp + (q2 - q1) * 2.0;
+      let xy1 = pp.x * pp.x + pp.y * pp.y;
+      let xy2 = q2.x * q2.x + q2.y * q2.y;
+      let a = (1.0 / xy1 - (pp.z * pp.z) / (xy1 * q2.z * q2.z)) /
+        (1.0 - (xy2 * pp.z * pp.z) / (xy1 * q2.z * q2.z));
+      let c = (a * xy2 - 1.0) / (q2.z * q2.z);
+
+      if a.is_finite() && c.is_finite() {
+        coefficients = Some((a, c));
+        break;
+      }
+    }
+
+    let (a, c) = coefficients.ok_or_else(|| RenderError::InvalidParameter {
+      name: "p1".to_string(),
+      message: "p1 and p2 do not define a hyperboloid".to_string()
+    })?;
+
+    Ok(Hyperboloid {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      p1,
+      p2,
+      z_min: p1.z.min(p2.z),
+      z_max: p1.z.max(p2.z),
+      phi_max: radians(clamp(phi_max, 0.0, 360.0)),
+      r_max: radius1.max(radius2),
+      a,
+      c
+    })
+  }
+
+  /// Azimuth of `phit` relative to the point of the generating segment at
+  /// the same height, and that height as the `v` parameter
+  fn sweep(&self, phit: &Point) -> (Float, Float) {
+    let v = (phit.z - self.p1.z) / (self.p2.z - self.p1.z);
+    let pr = self.p1 * (1.0 - v) + self.p2 * v;
+
+    let mut phi = (pr.x * phit.y - phit.x * pr.y).atan2(phit.x * pr.x + phit.y * pr.y);
+    if phi < 0.0 {
+      phi += 2.0 * PI;
+    }
+
+    (phi, v)
+  }
+
+  /// Closest hit of the world space `r`, as the ray parameter, object space
+  /// hit point, its `(phi, v)` parameters and error bound
+  fn hit(&self, r: &Ray) -> Option<(Float, Point, (Float, Float), Vector)> {
+    let (ray, o_error, d_error) = self.base.world_to_object.apply_ray_with_error(r);
+    let (o, d) = (ray.o, ray.d);
+
+    let a = self.a * (d.x * d.x + d.y * d.y) - self.c * d.z * d.z;
+    let b = 2.0 * (self.a * (d.x * o.x + d.y * o.y) - self.c * d.z * o.z);
+    let c = self.a * (o.x * o.x + o.y * o.y) - self.c * o.z * o.z - 1.0;
+
+    let (t_hit, (phit, params)) = nearest_hit(&ray, a, b, c, |t| {
+      let phit = ray.apply(t);
+      let (phi, v) = self.sweep(&phit);
+
+      if phit.z < self.z_min || phit.z > self.z_max || phi > self.phi_max {
+        None
+      } else {
+        Some((phit, (phi, v)))
+      }
+    })?;
+
+    Some((t_hit, phit, params, ray_point_error(&ray, &o_error, &d_error, t_hit)))
+  }
+}
+
+impl Shape for Hyperboloid {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    BBox::new(&Point::new(-self.r_max, -self.r_max, self.z_min),
+      &Point::new(self.r_max, self.r_max, self.z_max))
+  }
+
+  fn area(&self) -> Float {
+    let (p1, p2) = (&self.p1, &self.p2);
+    let sqr = |x: Float| x * x;
+    let quad = |x: Float| sqr(sqr(x));
+
+    self.phi_max / 6.0 * (2.0 * quad(p1.x) - 2.0 * p1.x * p1.x * p1.x * p2.x + 2.0 * quad(p2.x) +
+      2.0 * (p1.y * p1.y + p1.y * p2.y + p2.y * p2.y) * (sqr(p1.y - p2.y) + sqr(p1.z - p2.z)) +
+      p2.x * p2.x * (5.0 * p1.y * p1.y + 2.0 * p1.y * p2.y - 4.0 * p2.y * p2.y +
+        2.0 * sqr(p1.z - p2.z)) +
+      p1.x * p1.x * (-4.0 * p1.y * p1.y + 2.0 * p1.y * p2.y + 5.0 * p2.y * p2.y +
+        2.0 * sqr(p1.z - p2.z)) -
+      2.0 * p1.x * p2.x * (p2.x * p2.x - p1.y * p1.y + 5.0 * p1.y * p2.y - p2.y * p2.y -
+        p1.z * p1.z + 2.0 * p1.z * p2.z - p2.z * p2.z))
+  }
+
+  fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+    let (t_hit, phit, (phi, v), p_error) = self.hit(r)?;
+
+    let u = phi / self.phi_max;
+    let (sin_phi, cos_phi) = phi.sin_cos();
+    let (p1, p2) = (&self.p1, &self.p2);
+
+    let dpdu = Vector::new(-self.phi_max * phit.y, self.phi_max * phit.x, 0.0);
+    let dpdv = Vector::new((p2.x - p1.x) * cos_phi - (p2.y - p1.y) * sin_phi,
+      (p2.x - p1.x) * sin_phi + (p2.y - p1.y) * cos_phi, p2.z - p1.z);
+
+    let d2pduu = Vector::new(phit.x, phit.y, 0.0) * (-self.phi_max * self.phi_max);
+    let d2pduv = Vector::new(-dpdv.y, dpdv.x, 0.0) * self.phi_max;
+    let d2pdvv = Vector::new(0.0, 0.0, 0.0);
+    let (dndu, dndv) = weingarten(dpdu, dpdv, d2pduu, d2pduv, d2pdvv);
+
+    let o2w = &self.base.object_to_world;
+    let (p, p_error) = o2w.apply_point_with_abs_error(&phit, &p_error);
+    let dg = DifferentialGeometry::from_shape(p, o2w.apply(dpdu), o2w.apply(dpdv),
+      o2w.apply(dndu), o2w.apply(dndv), u, v, Some(self));
+
+    Some((t_hit, p_error, dg))
+  }
+
+  fn intersect_p(&self, r: &Ray) -> bool {
+    self.hit(r).is_some()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  fn hyperboloid(p1: Point, p2: Point) -> Hyperboloid {
+    Hyperboloid::new(&identity(), &identity(), false, p1, p2, 360.0).unwrap()
+  }
+
+  #[test]
+  fn vertical_segment_sweeps_a_cylinder() {
+    let h = hyperboloid(Point::new(1.0, 0.0, 0.0), Point::new(1.0, 0.0, 1.0));
+    assert!((h.a - 1.0).abs() < 1e-5 && h.c.abs() < 1e-5);
+    assert!((h.area() - 2.0 * PI).abs() < 1e-4);
+
+    let ray = Ray::new(&Point::new(-5.0, 0.0, 0.5), &Vector::new(1.0, 0.0, 0.0), 0.0, Float::INFINITY, 0.0);
+    let (t_hit, _, dg) = h.intersect(&ray).unwrap();
+    assert!((t_hit - 4.0).abs() < 1e-4);
+    assert!((dg.v - 0.5).abs() < 1e-4);
+  }
+
+  #[test]
+  fn skewed_segment_pinches_in_the_middle() {
+    // Rotating a segment that does not meet the axis gives a waist at z = 0
+    let h = hyperboloid(Point::new(1.0, -1.0, -1.0), Point::new(1.0, 1.0, 1.0));
+    let ray = Ray::new(&Point::new(-5.0, 0.0, 0.0), &Vector::new(1.0, 0.0, 0.0), 0.0, Float::INFINITY, 0.0);
+    let (t_hit, p_error, dg) = h.intersect(&ray).unwrap();
+
+    assert!((t_hit - 4.0).abs() < 1e-4);
+    assert!((dg.p.x + 1.0).abs() <= p_error.x.max(1e-4));
+    assert!((h.r_max - (2.0 as Float).sqrt()).abs() < 1e-5);
+    assert!(!h.intersect_p(&Ray::new(&Point::new(-5.0, 0.0, 1.5), &Vector::new(1.0, 0.0, 0.0),
+      0.0, Float::INFINITY, 0.0)));
+  }
+
+  #[test]
+  fn flat_segment_is_rejected() {
+    let result = Hyperboloid::new(&identity(), &identity(), false,
+      Point::new(1.0, 0.0, 0.0), Point::new(2.0, 0.0, 0.0), 360.0);
+    assert!(result.is_err());
+  }
+}
@@ -0,0 +1,15 @@
+pub mod bilinear;
+pub mod cone;
+pub mod curve;
+pub mod cylinder;
+pub mod disk;
+pub mod displaced;
+pub mod heightfield;
+pub mod hyperboloid;
+pub mod loopsubdiv;
+pub mod nurbs;
+pub mod paraboloid;
+mod quadric;
+pub mod sdf;
+pub mod sphere;
+pub mod triangle;
@@ -0,0 +1,458 @@
+use std::cell::OnceCell;
+use std::collections::{ HashMap, HashSet };
+use std::rc::Rc;
+
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{
+  Float, PI, BBox, Normal, Point, Ray, Vector, Length, Union,
+  cross, dot, normalize };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::transform::Transform;
+
+use crate::triangle::TriangleMesh;
+
+/// Most subdivision levels a surface may ask for, each of which makes
+/// four times as many faces
+pub const MAX_LEVELS: usize = 6;
+
+type Edge = (usize, usize);
+
+fn edge(a: usize, b: usize) -> Edge {
+  (a.min(b), a.max(b))
+}
+
+/// Weight of each one-ring neighbour for an interior vertex of `valence`
+fn beta(valence: usize) -> Float {
+  if valence == 3 {
+    3.0 / 16.0
+  } else {
+    3.0 / (8.0 * valence as Float)
+  }
+}
+
+/// Weight of each one-ring neighbour when pushing an interior vertex of
+/// `valence` to the limit surface
+fn loop_gamma(valence: usize) -> Float {
+  1.0 / (valence as Float + 3.0 / (8.0 * beta(valence)))
+}
+
+/// Adjacency of one subdivision level
+struct Topology {
+  edges: Vec<Edge>,
+  edge_index: HashMap<Edge, usize>,
+  /// Per edge, the vertex opposite to it in each adjacent face
+  opposite: Vec<Vec<usize>>,
+  /// Per edge, whether it is a boundary, non-manifold or crease edge
+  sharp: Vec<bool>,
+  neighbors: Vec<Vec<usize>>,
+  sharp_neighbors: Vec<Vec<usize>>,
+  /// Per vertex, the `(next, prev)` vertices of each incident face in
+  /// winding order
+  fans: Vec<Vec<(usize, usize)>>
+}
+
+/// Control mesh at one level of subdivision, in object space
+struct SubdivMesh {
+  p: Vec<Point>,
+  indices: Vec<usize>,
+  creases: HashSet<Edge>
+}
+
+impl SubdivMesh {
+  fn topology(&self) -> Topology {
+    let mut edges = Vec::new();
+    let mut edge_index = HashMap::new();
+    let mut opposite: Vec<Vec<usize>> = Vec::new();
+    let mut fans = vec![Vec::new(); self.p.len()];
+
+    for f in self.indices.chunks(3) {
+      for i in 0..3 {
+        let (a, b, c) = (f[i], f[(i + 1) % 3], f[(i + 2) % 3]);
+        let e = edge(a, b);
+        let idx = *edge_index.entry(e).or_insert_with(|| {
+          edges.push(e);
+          opposite.push(Vec::new());
+          edges.len() - 1
+        });
+
+        opposite[idx].push(c);
+        fans[a].push((b, c));
+      }
+    }
+
+    let sharp: Vec<bool> = edges.iter().zip(&opposite)
+      .map(|(e, o)| o.len() != 2 || self.creases.contains(e))
+      .collect();
+
+    let mut neighbors = vec![Vec::new(); self.p.len()];
+    let mut sharp_neighbors = vec![Vec::new(); self.p.len()];
+    for (&(a, b), &is_sharp) in edges.iter().zip(&sharp) {
+      neighbors[a].push(b);
+      neighbors[b].push(a);
+
+      if is_sharp {
+        sharp_neighbors[a].push(b);
+        sharp_neighbors[b].push(a);
+      }
+    }
+
+    Topology { edges, edge_index, opposite, sharp, neighbors, sharp_neighbors, fans }
+  }
+
+  /// `p` moved towards `ring`, giving each ring vertex the weight `w`
+  fn weight_ring(&self, p: Point, ring: &[usize], w: Float) -> Point {
+    if ring.is_empty() {
+      return p;
+    }
+
+    ring.iter().fold(p * (1.0 - ring.len() as Float * w), |acc, &i| acc + self.p[i] * w)
+  }
+
+  /// Apply the smooth rule to interior vertices and darts, the crease rule
+  /// to vertices with two sharp edges and keep corners in place
+  fn vertex_rule(&self, topo: &Topology, v: usize, smooth: fn(usize) -> Float, crease: Float) -> Point {
+    let sharp = &topo.sharp_neighbors[v];
+
+    match sharp.len() {
+      0 | 1 => {
+        let ring = &topo.neighbors[v];
+        self.weight_ring(self.p[v], ring, smooth(ring.len()))
+      },
+      2 => self.weight_ring(self.p[v], sharp, crease),
+      _ => self.p[v]
+    }
+  }
+
+  /// One step of Loop subdivision, splitting every face into four
+  fn subdivide(&self) -> SubdivMesh {
+    let topo = self.topology();
+
+    let mut p: Vec<Point> = (0..self.p.len())
+      .map(|v| self.vertex_rule(&topo, v, beta, 1.0 / 8.0))
+      .collect();
+
+    let edge_base = p.len();
+    for (e, &(a, b)) in topo.edges.iter().enumerate() {
+      let ends = self.p[a] + self.p[b];
+
+      p.push(if topo.sharp[e] {
+        ends * 0.5
+      } else {
+        let (c, d) = (topo.opposite[e][0], topo.opposite[e][1]);
+        ends * (3.0 / 8.0) + (self.p[c] + self.p[d]) * (1.0 / 8.0)
+      });
+    }
+
+    let split = |a: usize, b: usize| edge_base + topo.edge_index[&edge(a, b)];
+
+    let mut indices = Vec::with_capacity(4 * self.indices.len());
+    for f in self.indices.chunks(3) {
+      let (a, b, c) = (f[0], f[1], f[2]);
+      let (ab, bc, ca) = (split(a, b), split(b, c), split(c, a));
+
+      indices.extend_from_slice(&[a, ab, ca, b, bc, ab, c, ca, bc, ab, bc, ca]);
+    }
+
+    let creases = self.creases.iter()
+      .flat_map(|&(a, b)| {
+        let m = split(a, b);
+        [edge(a, m), edge(m, b)]
+      })
+      .collect();
+
+    SubdivMesh { p, indices, creases }
+  }
+
+  fn limit_positions(&self, topo: &Topology) -> Vec<Point> {
+    (0..self.p.len()).map(|v| self.vertex_rule(topo, v, loop_gamma, 1.0 / 5.0)).collect()
+  }
+
+  /// Limit surface normals from the tangents of the one-ring, oriented to
+  /// agree with the winding of the faces around each vertex
+  fn limit_normals(&self, topo: &Topology) -> Vec<Normal> {
+    let mut face_normals = vec![Vector::zero(); self.p.len()];
+    for f in self.indices.chunks(3) {
+      let n = cross(self.p[f[1]] - self.p[f[0]], self.p[f[2]] - self.p[f[0]]);
+      for &v in f {
+        face_normals[v] = face_normals[v] + n;
+      }
+    }
+
+    (0..self.p.len()).map(|v| {
+      let reference = face_normals[v];
+      let n = match ordered_ring(&topo.fans[v]) {
+        Some((ring, true)) if topo.sharp_neighbors[v].len() < 2 => self.interior_normal(v, &ring),
+        Some((ring, false)) if topo.sharp_neighbors[v].len() == 2 => self.boundary_normal(v, &ring),
+        _ => reference
+      };
+
+      let n = if n.length_squared() == 0.0 { reference } else { n };
+      let n = if dot(n, reference) < 0.0 { -n } else { n };
+
+      if n.length_squared() == 0.0 {
+        Normal::new(0.0, 0.0, 0.0)
+      } else {
+        Normal::from_vector(&normalize(n))
+      }
+    }).collect()
+  }
+
+  fn interior_normal(&self, v: usize, ring: &[usize]) -> Vector {
+    let valence = ring.len() as Float;
+    let (mut s, mut t) = (Vector::zero(), Vector::zero());
+
+    for (i, &r) in ring.iter().enumerate() {
+      let (sin, cos) = (2.0 * PI * i as Float / valence).sin_cos();
+      let d = self.p[r] - self.p[v];
+      s = s + d * cos;
+      t = t + d * sin;
+    }
+
+    cross(s, t)
+  }
+
+  fn boundary_normal(&self, v: usize, ring: &[usize]) -> Vector {
+    let d: Vec<Vector> = ring.iter().map(|&r| self.p[r] - self.p[v]).collect();
+    let valence = d.len();
+    let s = d[valence - 1] - d[0];
+
+    let t = match valence {
+      2 => d[0] + d[1],
+      3 => d[1],
+      4 => d[1] * 2.0 + d[2] * 2.0 - d[0] - d[3],
+      _ => {
+        let theta = PI / (valence - 1) as Float;
+        let t = (1..valence - 1).fold((d[0] + d[valence - 1]) * theta.sin(), |t, k| {
+          t + d[k] * ((2.0 * theta.cos() - 2.0) * (k as Float * theta).sin())
+        });
+        -t
+      }
+    };
+
+    cross(s, t)
+  }
+}
+
+/// Order the neighbours of a vertex from its incident faces, returning
+/// whether the ring closes around it. Fails for non-manifold vertices.
+fn ordered_ring(fan: &[(usize, usize)]) -> Option<(Vec<usize>, bool)> {
+  let start = fan.iter().find(|&&(next, _)| !fan.iter().any(|&(_, prev)| prev == next));
+  let closed = start.is_none();
+  let mut ring = vec![start.or(fan.first())?.0];
+
+  while let Some(&(_, prev)) = fan.iter().find(|&&(next, _)| next == ring[ring.len() - 1]) {
+    if closed && prev == ring[0] {
+      break;
+    }
+
+    ring.push(prev);
+    if ring.len() > fan.len() + 1 {
+      return None;
+    }
+  }
+
+  let expected = if closed { fan.len() } else { fan.len() + 1 };
+  if ring.len() == expected { Some((ring, closed)) } else { None }
+}
+
+/// Loop subdivision surface over a triangle control mesh. It cannot be
+/// intersected directly and refines to the triangles of `limit_mesh`.
+pub struct LoopSubdiv {
+  base: ShapeBase,
+  n_levels: usize,
+  vertex_indices: Vec<usize>,
+  /// Control points in object space
+  p: Vec<Point>,
+  /// Edges of the control mesh kept sharp, like boundary edges
+  creases: Vec<Edge>,
+  /// Area of the limit mesh, computed when first asked for
+  area: OnceCell<Float>
+}
+
+impl LoopSubdiv {
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform,
+      reverse_orientation: bool, n_levels: usize, vertex_indices: Vec<usize>,
+      p: Vec<Point>, creases: Vec<Edge>) -> Result<LoopSubdiv, RenderError> {
+    if n_levels > MAX_LEVELS {
+      return Err(RenderError::InvalidParameter {
+        name: "nlevels".to_string(),
+        message: format!("{} subdivision levels, at most {} are supported", n_levels, MAX_LEVELS)
+      });
+    }
+
+    if vertex_indices.is_empty() || !vertex_indices.len().is_multiple_of(3) {
+      return Err(RenderError::InvalidParameter {
+        name: "indices".to_string(),
+        message: format!("number of vertex indices {} is not a positive multiple of 3",
+          vertex_indices.len())
+      });
+    }
+
+    if let Some(&i) = vertex_indices.iter().find(|&&i| i >= p.len()) {
+      return Err(RenderError::InvalidParameter {
+        name: "indices".to_string(),
+        message: format!("vertex index {} out of range for {} vertices", i, p.len())
+      });
+    }
+
+    let edges: HashSet<Edge> = vertex_indices.chunks(3)
+      .flat_map(|f| [edge(f[0], f[1]), edge(f[1], f[2]), edge(f[2], f[0])])
+      .collect();
+
+    if let Some(&(a, b)) = creases.iter().find(|&&(a, b)| !edges.contains(&edge(a, b))) {
+      return Err(RenderError::InvalidParameter {
+        name: "creases".to_string(),
+        message: format!("crease {}-{} is not an edge of the control mesh", a, b)
+      });
+    }
+
+    Ok(LoopSubdiv {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      n_levels,
+      vertex_indices,
+      p,
+      creases,
+      area: OnceCell::new()
+    })
+  }
+
+  /// Subdivide the control mesh `n_levels` times and move the result onto
+  /// the limit surface, with limit normals at every vertex
+  pub fn limit_mesh(&self) -> TriangleMesh {
+    let mut mesh = SubdivMesh {
+      p: self.p.clone(),
+      indices: self.vertex_indices.clone(),
+      creases: self.creases.iter().map(|&(a, b)| edge(a, b)).collect()
+    };
+
+    for _ in 0..self.n_levels {
+      mesh = mesh.subdivide();
+    }
+
+    let topo = mesh.topology();
+    let n = mesh.limit_normals(&topo);
+    let p = mesh.limit_positions(&topo);
+
+    // The buffers are consistent by construction, the control mesh was
+    // validated in `new`
+    TriangleMesh::new(&self.base.object_to_world, &self.base.world_to_object,
+      self.base.reverse_orientation, mesh.indices, p, Some(n), None, None, None)
+      .expect("subdivided mesh is valid")
+  }
+}
+
+impl Shape for LoopSubdiv {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  /// The limit surface lies within the convex hull of the control points
+  fn object_bound(&self) -> BBox {
+    self.p[1..].iter().fold(BBox::from_point(&self.p[0]), |b, p| b.union(p))
+  }
+
+  fn area(&self) -> Float {
+    *self.area.get_or_init(|| self.limit_mesh().into_shapes().iter().map(|t| t.area()).sum())
+  }
+
+  /// Never hits, rays are traced against the limit mesh from `refine`
+  fn intersect(&self, _ray: &Ray) -> Option<ShapeIntersection> {
+    None
+  }
+
+  fn can_intersect(&self) -> bool { false }
+
+  fn refine(&self) -> Result<Vec<Rc<dyn Shape>>, RenderError> {
+    Ok(self.limit_mesh().into_shapes())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  fn tetrahedron(n_levels: usize, creases: Vec<Edge>) -> Result<LoopSubdiv, RenderError> {
+    let p = vec![Point::new(1.0, 1.0, 1.0), Point::new(-1.0, -1.0, 1.0),
+      Point::new(-1.0, 1.0, -1.0), Point::new(1.0, -1.0, -1.0)];
+    let indices = vec![0, 1, 3, 0, 3, 2, 0, 2, 1, 1, 2, 3];
+
+    LoopSubdiv::new(&identity(), &identity(), false, n_levels, indices, p, creases)
+  }
+
+  #[test]
+  fn closed_mesh_shrinks_smoothly_inside_its_hull() {
+    let subdiv = tetrahedron(2, Vec::new()).unwrap();
+    let mesh = subdiv.limit_mesh();
+
+    assert!(!subdiv.can_intersect());
+    assert_eq!(mesh.num_triangles, 64);
+    assert_eq!(mesh.p.len(), 34);
+
+    let hull = subdiv.object_bound();
+    let n = mesh.n.as_ref().unwrap();
+    for (p, n) in mesh.p.iter().zip(n) {
+      assert!(p.x.abs() < 1.0 && p.y.abs() < 1.0 && p.z.abs() < 1.0);
+      assert!(hull.inside(p));
+
+      // Normals of a convex surface around the origin point outwards
+      assert!(dot(*n, *p - Point::zero()) > 0.0);
+    }
+  }
+
+  #[test]
+  fn flat_patch_with_boundary_stays_flat() {
+    let p = vec![Point::new(0.0, 0.0, 0.0), Point::new(1.0, 0.0, 0.0),
+      Point::new(1.0, 1.0, 0.0), Point::new(0.0, 1.0, 0.0)];
+    let subdiv = LoopSubdiv::new(&identity(), &identity(), false, 3, vec![0, 1, 2, 0, 2, 3], p,
+      Vec::new()).unwrap();
+    let mesh = subdiv.limit_mesh();
+
+    assert!(mesh.p.iter().all(|p| p.z == 0.0));
+    assert!(mesh.n.unwrap().iter().all(|n| (n.z - 1.0).abs() < 1e-5));
+    assert!(subdiv.area() > 0.5 && subdiv.area() < 1.0);
+  }
+
+  #[test]
+  fn creases_on_every_edge_keep_the_corners() {
+    let all_edges = vec![(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)];
+    let subdiv = tetrahedron(2, all_edges).unwrap();
+    let mesh = subdiv.limit_mesh();
+
+    // Original vertices keep their indices and, as corners, their position
+    for (limit, control) in mesh.p.iter().zip(&subdiv.p) {
+      assert!((*limit - *control).length() < 1e-5);
+    }
+
+    // Points split off a crease stay on the straight edge
+    let midpoint = (subdiv.p[0] + subdiv.p[1]) * 0.5;
+    assert!(mesh.p.iter().any(|p| (*p - midpoint).length() < 1e-5));
+  }
+
+  #[test]
+  fn refine_yields_intersectable_triangles() {
+    let subdiv = tetrahedron(1, Vec::new()).unwrap();
+    assert!(!subdiv.can_intersect());
+    let refined = subdiv.refine().unwrap();
+
+    assert_eq!(refined.len(), 16);
+    assert!(refined.iter().all(|s| s.can_intersect()));
+
+    let ray = Ray::new(&Point::new(0.1, 0.2, -5.0), &Vector::new(0.0, 0.0, 1.0), 0.0, Float::INFINITY, 0.0);
+    assert_eq!(refined.iter().filter(|s| s.intersect_p(&ray)).count(), 2);
+  }
+
+  #[test]
+  fn new_validates_indices_and_creases() {
+    assert!(tetrahedron(1, vec![(0, 4)]).is_err());
+
+    let p = vec![Point::zero(); 3];
+    assert!(LoopSubdiv::new(&identity(), &identity(), false, 1, vec![0, 1], p.clone(), Vec::new()).is_err());
+    assert!(LoopSubdiv::new(&identity(), &identity(), false, 1, vec![0, 1, 3], p, Vec::new()).is_err());
+    assert!(tetrahedron(MAX_LEVELS + 1, Vec::new()).is_err());
+  }
+}
@@ -0,0 +1,412 @@
+use std::cell::OnceCell;
+use std::rc::Rc;
+
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{ Float, BBox, Normal, Point, Ray, Vector, Length, Union, coordinate_system, cross, lerp, normalize };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::transform::Transform;
+
+use crate::triangle::TriangleMesh;
+
+/// Default number of segments per direction the surface is diced into
+pub const DEFAULT_DICE: usize = 30;
+
+/// Knot vector and parameter range of a B-spline along one direction
+pub struct NurbsBasis {
+  pub order: usize,
+  pub knots: Vec<Float>,
+  pub t_min: Float,
+  pub t_max: Float
+}
+
+impl NurbsBasis {
+  /// `knots` holds one knot per control point plus `order` more
+  pub fn new(order: usize, knots: Vec<Float>, t_min: Float, t_max: Float) -> Result<NurbsBasis, RenderError> {
+    let invalid = |message: String| RenderError::InvalidParameter { name: "knots".to_string(), message };
+
+    if order < 2 || knots.len() < 2 * order {
+      return Err(invalid(format!("{} knots cannot define a basis of order {}", knots.len(), order)));
+    }
+
+    if knots.windows(2).any(|k| k[1] < k[0]) {
+      return Err(invalid("knots must not decrease".to_string()));
+    }
+
+    let n = knots.len() - order;
+    if !(t_min < t_max && t_min >= knots[order - 1] && t_max <= knots[n]) {
+      return Err(invalid(format!("parameter range [{}, {}] is outside the knots [{}, {}]",
+        t_min, t_max, knots[order - 1], knots[n])));
+    }
+
+    Ok(NurbsBasis { order, knots, t_min, t_max })
+  }
+
+  /// Number of control points along this direction
+  pub fn num_control_points(&self) -> usize {
+    self.knots.len() - self.order
+  }
+
+  /// Knot span containing `t`, the last one for `t` at the end
+  fn span(&self, t: Float) -> usize {
+    let n = self.num_control_points();
+    let mut i = self.order - 1;
+    while i + 1 < n && t >= self.knots[i + 1] {
+      i += 1;
+    }
+    i
+  }
+
+  /// Non zero basis functions of `degree` over `span`, which belong to
+  /// control points `span - degree..=span`
+  fn basis_functions(&self, span: usize, t: Float, degree: usize) -> Vec<Float> {
+    let k = &self.knots;
+    let mut n = vec![0.0; degree + 1];
+    let mut left = vec![0.0; degree + 1];
+    let mut right = vec![0.0; degree + 1];
+    n[0] = 1.0;
+
+    for j in 1..=degree {
+      left[j] = t - k[span + 1 - j];
+      right[j] = k[span + j] - t;
+
+      let mut saved = 0.0;
+      for r in 0..j {
+        let denominator = right[r + 1] + left[j - r];
+        let temp = if denominator == 0.0 { 0.0 } else { n[r] / denominator };
+        n[r] = saved + right[r + 1] * temp;
+        saved = left[j - r] * temp;
+      }
+      n[j] = saved;
+    }
+
+    n
+  }
+
+  /// Span of `t`, with the values and derivatives of the `order` basis
+  /// functions that are non zero over it
+  fn evaluate(&self, t: Float) -> (usize, Vec<Float>, Vec<Float>) {
+    let span = self.span(t);
+    let p = self.order - 1;
+    let n = self.basis_functions(span, t, p);
+    let lower = self.basis_functions(span, t, p - 1);
+
+    // Derivatives from the basis functions one degree lower
+    let k = &self.knots;
+    let ratio = |value: Float, k0: Float, k1: Float| if k1 == k0 { 0.0 } else { value / (k1 - k0) };
+    let dn = (0..=p).map(|r| {
+      let i = span - p + r;
+      let a = if r > 0 { ratio(lower[r - 1], k[i], k[i + p]) } else { 0.0 };
+      let b = if r < p { ratio(lower[r], k[i + 1], k[i + p + 1]) } else { 0.0 };
+      p as Float * (a - b)
+    }).collect();
+
+    (span, n, dn)
+  }
+}
+
+/// Rational B-spline surface. It is never intersected directly but diced
+/// into a triangle mesh carrying the analytic normals and tangents.
+pub struct Nurbs {
+  base: ShapeBase,
+  u: NurbsBasis,
+  v: NurbsBasis,
+  /// Control points, with `u` varying fastest
+  p: Vec<Point>,
+  w: Vec<Float>,
+  /// Segments per direction of the refined mesh
+  dice: usize,
+  /// Area of the diced mesh, computed when first asked for
+  area: OnceCell<Float>
+}
+
+impl Nurbs {
+  /// Without weights the surface is a plain, non rational B-spline.
+  /// `dice` is the number of segments per direction it is refined into,
+  /// `DEFAULT_DICE` unless a scene asks for finer.
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform, reverse_orientation: bool,
+      u: NurbsBasis, v: NurbsBasis, p: Vec<Point>, w: Option<Vec<Float>>, dice: usize) -> Result<Nurbs, RenderError> {
+    let n = u.num_control_points() * v.num_control_points();
+    if p.len() != n {
+      return Err(RenderError::InvalidParameter {
+        name: "P".to_string(),
+        message: format!("expected {} control points, found {}", n, p.len())
+      });
+    }
+
+    let w = w.unwrap_or_else(|| vec![1.0; n]);
+    if w.len() != n || w.iter().any(|&w| w.is_nan() || w <= 0.0) {
+      return Err(RenderError::InvalidParameter {
+        name: "Pw".to_string(),
+        message: format!("expected {} positive weights", n)
+      });
+    }
+
+    if dice == 0 {
+      return Err(RenderError::InvalidParameter {
+        name: "dice".to_string(),
+        message: "the surface must be diced into at least one segment".to_string()
+      });
+    }
+
+    Ok(Nurbs {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      u, v, p, w, dice,
+      area: OnceCell::new()
+    })
+  }
+
+  /// Basis along `u`
+  pub fn u(&self) -> &NurbsBasis {
+    &self.u
+  }
+
+  /// Basis along `v`
+  pub fn v(&self) -> &NurbsBasis {
+    &self.v
+  }
+
+  /// Control points, with `u` varying fastest
+  pub fn p(&self) -> &[Point] {
+    &self.p
+  }
+
+  /// Weight of each control point
+  pub fn w(&self) -> &[Float] {
+    &self.w
+  }
+
+  /// Segments per direction of the refined mesh
+  pub fn dice(&self) -> usize {
+    self.dice
+  }
+
+  /// Object space point on the surface at `(u, v)`, with its partial
+  /// derivatives
+  pub fn evaluate(&self, u: Float, v: Float) -> (Point, Vector, Vector) {
+    let (u_span, nu, dnu) = self.u.evaluate(u);
+    let (v_span, nv, dnv) = self.v.evaluate(v);
+    let (u0, v0) = (u_span + 1 - self.u.order, v_span + 1 - self.v.order);
+    let row = self.u.num_control_points();
+
+    // Homogeneous sums, and the weights on their own
+    let (mut a, mut a_u, mut a_v) = (Vector::zero(), Vector::zero(), Vector::zero());
+    let (mut w, mut w_u, mut w_v) = (0.0, 0.0, 0.0);
+
+    for (j, (&bv, &dbv)) in nv.iter().zip(&dnv).enumerate() {
+      for (i, (&bu, &dbu)) in nu.iter().zip(&dnu).enumerate() {
+        let k = (v0 + j) * row + u0 + i;
+        let (p, pw) = (self.p[k], self.w[k]);
+        let wp = Vector::new(p.x, p.y, p.z) * pw;
+
+        a = a + wp * (bu * bv);
+        a_u = a_u + wp * (dbu * bv);
+        a_v = a_v + wp * (bu * dbv);
+        w += pw * bu * bv;
+        w_u += pw * dbu * bv;
+        w_v += pw * bu * dbv;
+      }
+    }
+
+    let s = a / w;
+    let dpdu = (a_u - s * w_u) / w;
+    let dpdv = (a_v - s * w_v) / w;
+
+    (Point::new(s.x, s.y, s.z), dpdu, dpdv)
+  }
+
+  /// The surface diced into `dice` by `dice` quads, each split in two
+  pub fn mesh(&self) -> TriangleMesh {
+    let n = self.dice;
+    let mut p = Vec::with_capacity((n + 1) * (n + 1));
+    let mut partials = Vec::with_capacity(p.capacity());
+    let mut uv = Vec::with_capacity(p.capacity());
+
+    for j in 0..=n {
+      let v = lerp(j as Float / n as Float, self.v.t_min, self.v.t_max);
+      for i in 0..=n {
+        let u = lerp(i as Float / n as Float, self.u.t_min, self.u.t_max);
+        let (point, dpdu, dpdv) = self.evaluate(u, v);
+
+        p.push(point);
+        partials.push((dpdu, dpdv));
+        uv.push((u, v));
+      }
+    }
+
+    let index = |i: usize, j: usize| j * (n + 1) + i;
+    let indices: Vec<usize> = (0..n).flat_map(|j| (0..n).map(move |i| (i, j)))
+      .flat_map(|(i, j)| [index(i, j), index(i + 1, j), index(i + 1, j + 1),
+        index(i, j), index(i + 1, j + 1), index(i, j + 1)])
+      .collect();
+
+    // Area weighted normals of the faces around each vertex, wound the
+    // same way as `cross(dpdu, dpdv)`
+    let mut face_normals = vec![Vector::zero(); p.len()];
+    for t in indices.chunks(3) {
+      let face = cross(p[t[1]] - p[t[0]], p[t[2]] - p[t[0]]);
+      for &k in t {
+        face_normals[k] = face_normals[k] + face;
+      }
+    }
+
+    // At poles and collapsed edges the partials vanish or line up, so the
+    // faces stand in for the normal and `dpdv` for the tangent
+    let (normals, tangents) = partials.iter().zip(&face_normals).map(|(&(dpdu, dpdv), &faces)| {
+      let analytic = cross(dpdu, dpdv);
+      let nn = if analytic.length_squared() > Float::EPSILON * dpdu.length_squared() * dpdv.length_squared() {
+        normalize(analytic)
+      } else if faces.length_squared() > 0.0 {
+        normalize(faces)
+      } else {
+        Vector::new(0.0, 0.0, 1.0)
+      };
+
+      let ss = if dpdu.length_squared() > Float::EPSILON * dpdv.length_squared() {
+        normalize(dpdu)
+      } else if cross(dpdv, nn).length_squared() > 0.0 {
+        normalize(cross(dpdv, nn))
+      } else {
+        coordinate_system(&nn).0
+      };
+
+      (Normal::from_vector(&nn), ss)
+    }).unzip();
+
+    // The grid is consistent by construction
+    TriangleMesh::new(&self.base.object_to_world, &self.base.world_to_object,
+      self.base.reverse_orientation, indices, p, Some(normals), Some(tangents), Some(uv), None)
+      .expect("diced mesh is valid")
+  }
+}
+
+impl Shape for Nurbs {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  /// With positive weights the surface lies within the convex hull of
+  /// its control points
+  fn object_bound(&self) -> BBox {
+    self.p[1..].iter().fold(BBox::from_point(&self.p[0]), |b, p| b.union(p))
+  }
+
+  /// Area of the diced mesh rather than of the exact surface, so that it
+  /// matches what is rendered
+  fn area(&self) -> Float {
+    *self.area.get_or_init(|| self.mesh().into_shapes().iter().map(|t| t.area()).sum())
+  }
+
+  /// Never hits, there is no exact ray intersection for the surface and
+  /// rays are traced against the mesh it is diced into
+  fn intersect(&self, _ray: &Ray) -> Option<ShapeIntersection> {
+    None
+  }
+
+  fn can_intersect(&self) -> bool { false }
+
+  fn refine(&self) -> Result<Vec<Rc<dyn Shape>>, RenderError> {
+    Ok(self.mesh().into_shapes())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rbrtcore::geometry::{ PI, dot };
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  fn linear() -> NurbsBasis {
+    NurbsBasis::new(2, vec![0.0, 0.0, 1.0, 1.0], 0.0, 1.0).unwrap()
+  }
+
+  /// Quarter of a unit cylinder around z, as an exact rational quadratic
+  /// arc in `u` swept linearly along z in `v`
+  fn quarter_cylinder() -> Nurbs {
+    let u = NurbsBasis::new(3, vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0], 0.0, 1.0).unwrap();
+    let arc = [Point::new(1.0, 0.0, 0.0), Point::new(1.0, 1.0, 0.0), Point::new(0.0, 1.0, 0.0)];
+    let p = arc.iter().copied().chain(arc.iter().map(|&p| p + Vector::new(0.0, 0.0, 2.0))).collect();
+    let w = 0.5 * (2.0 as Float).sqrt();
+
+    Nurbs::new(&identity(), &identity(), false, u, linear(), p, Some(vec![1.0, w, 1.0, 1.0, w, 1.0]),
+      DEFAULT_DICE).unwrap()
+  }
+
+  #[test]
+  fn new_validates_knots_and_control_points() {
+    assert!(NurbsBasis::new(2, vec![0.0, 0.0, 1.0], 0.0, 1.0).is_err());
+    assert!(NurbsBasis::new(2, vec![0.0, 1.0, 0.5, 1.0], 0.0, 1.0).is_err());
+    assert!(NurbsBasis::new(2, vec![0.0, 0.0, 1.0, 1.0], 0.0, 2.0).is_err());
+
+    let p = vec![Point::zero(); 4];
+    assert!(Nurbs::new(&identity(), &identity(), false, linear(), linear(), p[..3].to_vec(), None,
+      DEFAULT_DICE).is_err());
+    assert!(Nurbs::new(&identity(), &identity(), false, linear(), linear(), p.clone(),
+      Some(vec![1.0, 1.0, 0.0, 1.0]), DEFAULT_DICE).is_err());
+    assert!(Nurbs::new(&identity(), &identity(), false, linear(), linear(), p.clone(), None, 0).is_err());
+    assert!(Nurbs::new(&identity(), &identity(), false, linear(), linear(), p, None, DEFAULT_DICE).is_ok());
+  }
+
+  #[test]
+  fn bilinear_surface_is_flat() {
+    let p = vec![Point::new(0.0, 0.0, 1.0), Point::new(2.0, 0.0, 1.0),
+      Point::new(0.0, 1.0, 1.0), Point::new(2.0, 1.0, 1.0)];
+    let plane = Nurbs::new(&identity(), &identity(), false, linear(), linear(), p, None, 4).unwrap();
+
+    let (p, dpdu, dpdv) = plane.evaluate(0.25, 0.5);
+    assert!((p.x - 0.5).abs() < 1e-6 && (p.y - 0.5).abs() < 1e-6);
+    assert!((dpdu.x - 2.0).abs() < 1e-6 && (dpdv.y - 1.0).abs() < 1e-6);
+
+    let mesh = plane.mesh();
+    assert_eq!(mesh.num_triangles, 32);
+    assert!(mesh.n.as_ref().unwrap().iter().all(|n| (n.z - 1.0).abs() < 1e-6));
+    assert!((plane.area() - 2.0).abs() < 1e-5);
+    assert!(!plane.can_intersect());
+  }
+
+  #[test]
+  fn collapsed_edge_keeps_normals_finite() {
+    // The v = 0 edge shrinks to a point, where dpdu vanishes
+    let p = vec![Point::zero(), Point::zero(), Point::new(0.0, 1.0, 0.0), Point::new(1.0, 1.0, 0.0)];
+    let triangle = Nurbs::new(&identity(), &identity(), false, linear(), linear(), p, None, 4).unwrap();
+
+    let mesh = triangle.mesh();
+    assert!(mesh.n.as_ref().unwrap().iter().all(|n| n.x.is_finite() && n.y.is_finite() && (n.z - 1.0).abs() < 1e-6));
+    assert!(mesh.s.as_ref().unwrap().iter().all(|s| s.x.is_finite() && s.y.is_finite() && s.z.is_finite()));
+  }
+
+  #[test]
+  fn rational_weights_give_an_exact_circle() {
+    let cylinder = quarter_cylinder();
+
+    for &u in [0.0, 0.2, 0.5, 0.9, 1.0].iter() {
+      let (p, dpdu, dpdv) = cylinder.evaluate(u, 0.3);
+      assert!(((p.x * p.x + p.y * p.y).sqrt() - 1.0).abs() < 1e-5);
+      assert!((p.z - 0.6).abs() < 1e-5);
+
+      // Analytic normals point away from the axis
+      let n = normalize(cross(dpdu, dpdv));
+      assert!((dot(n, Vector::new(p.x, p.y, 0.0)) - 1.0).abs() < 1e-4);
+    }
+
+    assert!((cylinder.area() - PI).abs() < 1e-2);
+  }
+
+  #[test]
+  fn refined_mesh_can_be_intersected() {
+    let cylinder = quarter_cylinder();
+    let d = normalize(Vector::new(-1.0, -1.0, 0.0));
+    let ray = Ray::new(&(Point::new(0.0, 0.0, 1.0) - d * 5.0), &d, 0.0, Float::INFINITY, 0.0);
+
+    let triangles = cylinder.refine().unwrap();
+    assert_eq!(triangles.len(), 2 * DEFAULT_DICE * DEFAULT_DICE);
+
+    let (t_hit, _, dg) = triangles.iter().filter_map(|t| t.intersect(&ray))
+      .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap()).unwrap();
+    assert!((t_hit - 4.0).abs() < 1e-2);
+    assert!((dg.u - 0.5).abs() < 0.05 && (dg.v - 0.5).abs() < 0.05);
+  }
+}
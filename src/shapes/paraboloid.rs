@@ -0,0 +1,138 @@
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::geometry::{ Float, BBox, Point, Ray, Vector, clamp, radians };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::transform::{ Applicable, Transform };
+
+use crate::quadric::{ azimuth, nearest_hit, ray_point_error, weingarten };
+
+/// Paraboloid `z = z_max (x^2 + y^2) / radius^2` opening up the z axis,
+/// clipped to `z_min..z_max` and swept up to `phi_max`
+pub struct Paraboloid {
+  base: ShapeBase,
+  pub radius: Float,
+  pub z_min: Float,
+  pub z_max: Float,
+  pub phi_max: Float
+}
+
+impl Paraboloid {
+  /// `phi_max` is given in degrees
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform,
+      reverse_orientation: bool, radius: Float, z0: Float, z1: Float, phi_max: Float) -> Paraboloid {
+    Paraboloid {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      radius,
+      z_min: z0.min(z1),
+      z_max: z0.max(z1),
+      phi_max: radians(clamp(phi_max, 0.0, 360.0))
+    }
+  }
+
+  /// Closest hit of the world space `r`, as the ray parameter, object space
+  /// hit point, its azimuth and error bound
+  fn hit(&self, r: &Ray) -> Option<(Float, Point, Float, Vector)> {
+    let (ray, o_error, d_error) = self.base.world_to_object.apply_ray_with_error(r);
+    let (o, d) = (ray.o, ray.d);
+
+    let k = self.z_max / (self.radius * self.radius);
+    let a = k * (d.x * d.x + d.y * d.y);
+    let b = 2.0 * k * (d.x * o.x + d.y * o.y) - d.z;
+    let c = k * (o.x * o.x + o.y * o.y) - o.z;
+
+    let (t_hit, (phit, phi)) = nearest_hit(&ray, a, b, c, |t| {
+      let phit = ray.apply(t);
+      let phi = azimuth(&phit);
+
+      if phit.z < self.z_min || phit.z > self.z_max || phi > self.phi_max {
+        None
+      } else {
+        Some((phit, phi))
+      }
+    })?;
+
+    Some((t_hit, phit, phi, ray_point_error(&ray, &o_error, &d_error, t_hit)))
+  }
+}
+
+impl Shape for Paraboloid {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    BBox::new(&Point::new(-self.radius, -self.radius, self.z_min),
+      &Point::new(self.radius, self.radius, self.z_max))
+  }
+
+  fn area(&self) -> Float {
+    let radius2 = self.radius * self.radius;
+    let k = 4.0 * self.z_max / radius2;
+
+    (radius2 * radius2 * self.phi_max / (12.0 * self.z_max * self.z_max)) *
+      ((k * self.z_max + 1.0).powf(1.5) - (k * self.z_min + 1.0).powf(1.5))
+  }
+
+  fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+    let (t_hit, phit, phi, p_error) = self.hit(r)?;
+
+    let z_range = self.z_max - self.z_min;
+    let u = phi / self.phi_max;
+    let v = (phit.z - self.z_min) / z_range;
+
+    let dpdu = Vector::new(-self.phi_max * phit.y, self.phi_max * phit.x, 0.0);
+    let dpdv = Vector::new(phit.x / (2.0 * phit.z), phit.y / (2.0 * phit.z), 1.0) * z_range;
+
+    let d2pduu = Vector::new(phit.x, phit.y, 0.0) * (-self.phi_max * self.phi_max);
+    let d2pduv = Vector::new(-phit.y / (2.0 * phit.z), phit.x / (2.0 * phit.z), 0.0) *
+      (z_range * self.phi_max);
+    let d2pdvv = Vector::new(phit.x / (4.0 * phit.z * phit.z), phit.y / (4.0 * phit.z * phit.z), 0.0) *
+      (-z_range * z_range);
+    let (dndu, dndv) = weingarten(dpdu, dpdv, d2pduu, d2pduv, d2pdvv);
+
+    let o2w = &self.base.object_to_world;
+    let (p, p_error) = o2w.apply_point_with_abs_error(&phit, &p_error);
+    let dg = DifferentialGeometry::from_shape(p, o2w.apply(dpdu), o2w.apply(dpdv),
+      o2w.apply(dndu), o2w.apply(dndv), u, v, Some(self));
+
+    Some((t_hit, p_error, dg))
+  }
+
+  fn intersect_p(&self, r: &Ray) -> bool {
+    self.hit(r).is_some()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rbrtcore::geometry::PI;
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  #[test]
+  fn ray_down_the_axis_hits_the_linear_root() {
+    let paraboloid = Paraboloid::new(&identity(), &identity(), false, 1.0, 0.5, 1.0, 360.0);
+    let outside = Ray::new(&Point::new(0.5, 0.5, 5.0), &Vector::new(0.0, 0.0, -1.0), 0.0, Float::INFINITY, 0.0);
+    let (t_hit, p_error, dg) = paraboloid.intersect(&outside).unwrap();
+
+    assert!((t_hit - 4.5).abs() < 1e-5);
+    assert!((dg.p.z - 0.5).abs() <= p_error.z.max(1e-5));
+    assert!(dg.v.abs() < 1e-5);
+
+    // Below z_min the surface is clipped away
+    let clipped = Ray::new(&Point::new(0.1, 0.0, 5.0), &Vector::new(0.0, 0.0, -1.0), 0.0, Float::INFINITY, 0.0);
+    assert!(!paraboloid.intersect_p(&clipped));
+  }
+
+  #[test]
+  fn area_matches_surface_of_revolution() {
+    let paraboloid = Paraboloid::new(&identity(), &identity(), false, 1.0, 0.0, 1.0, 360.0);
+
+    // 2pi * integral of r sqrt(1 + 4 r^2) over [0, 1]
+    let expected = PI / 6.0 * ((5.0 as Float).powf(1.5) - 1.0);
+    assert!((paraboloid.area() - expected).abs() < 1e-4);
+  }
+}
@@ -0,0 +1,61 @@
+use rbrtcore::efloat::gamma;
+use rbrtcore::geometry::{
+  Float, PI, Normal, Point, Ray, Vector,
+  cross, dot, normalize, quadratic };
+
+/// Nearest root of `a t^2 + b t + c = 0` inside the ray extent whose hit
+/// is kept by `accept`, falling back to the far root when the near one is
+/// clipped away
+pub(crate) fn nearest_hit<T, F>(ray: &Ray, a: Float, b: Float, c: Float, accept: F) -> Option<(Float, T)>
+    where F: Fn(Float) -> Option<T> {
+  // With `a == 0` the quadratic degenerates to a linear equation, which
+  // `quadratic` still handles as long as `b` is non-zero
+  if a == 0.0 && b == 0.0 {
+    return None;
+  }
+
+  let (t0, t1) = quadratic(a, b, c)?;
+  if t0 > ray.maxt || t1 <= ray.mint {
+    return None;
+  }
+
+  [t0, t1].iter()
+    .filter(|&&t| t > ray.mint && t <= ray.maxt)
+    .find_map(|&t| accept(t).map(|hit| (t, hit)))
+}
+
+/// Angle of `p` around the z axis, in `[0, 2pi)`
+pub(crate) fn azimuth(p: &Point) -> Float {
+  let phi = p.y.atan2(p.x);
+  if phi < 0.0 { phi + 2.0 * PI } else { phi }
+}
+
+/// Conservative bound on the error of the hit point `ray.apply(t)`, for
+/// shapes that cannot reproject it onto their surface
+pub(crate) fn ray_point_error(ray: &Ray, o_error: &Vector, d_error: &Vector, t: Float) -> Vector {
+  let t = t.abs();
+
+  Vector::new(ray.o.x.abs() + ray.d.x.abs() * t, ray.o.y.abs() + ray.d.y.abs() * t,
+    ray.o.z.abs() + ray.d.z.abs() * t) * gamma(7) + *o_error + *d_error * t
+}
+
+/// Normal derivatives from the first and second partial derivatives of
+/// the surface, using the Weingarten equations
+pub(crate) fn weingarten(dpdu: Vector, dpdv: Vector,
+    d2pduu: Vector, d2pduv: Vector, d2pdvv: Vector) -> (Normal, Normal) {
+  let e1 = dot(dpdu, dpdu);
+  let f1 = dot(dpdu, dpdv);
+  let g1 = dot(dpdv, dpdv);
+  let n = normalize(cross(dpdu, dpdv));
+  let e2 = dot(n, d2pduu);
+  let f2 = dot(n, d2pduv);
+  let g2 = dot(n, d2pdvv);
+
+  let inv_egf2 = 1.0 / (e1 * g1 - f1 * f1);
+  let dndu = Normal::from_vector(&(dpdu * ((f2 * f1 - e2 * g1) * inv_egf2) +
+    dpdv * ((e2 * f1 - f2 * e1) * inv_egf2)));
+  let dndv = Normal::from_vector(&(dpdu * ((g2 * f1 - f2 * g1) * inv_egf2) +
+    dpdv * ((f2 * f1 - g2 * e1) * inv_egf2)));
+
+  (dndu, dndv)
+}
@@ -0,0 +1,497 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{
+  Float, BBox, Normal, Point, Ray, Vector, Length, Union,
+  clamp, coordinate_system, distance, lerp, normalize };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::transform::{ Applicable, Transform };
+
+/// Number of sphere tracing steps before a ray is given up as a miss
+const MAX_STEPS: usize = 1000;
+
+/// Samples per axis of the grid `Sdf::area` estimates the area on
+const AREA_SAMPLES: usize = 64;
+
+/// Signed distance to an implicit surface in object space, negative inside
+pub trait DistanceFunction {
+  fn distance(&self, p: &Point) -> Float;
+
+  /// Bound of the surface, outside of which rays are never traced
+  fn bound(&self) -> BBox;
+
+  /// Bound on how fast `distance` changes, 1 for exact distances. Sphere
+  /// tracing divides its steps by it.
+  fn lipschitz(&self) -> Float { 1.0 }
+}
+
+pub struct SdfSphere {
+  pub center: Point,
+  pub radius: Float
+}
+
+impl DistanceFunction for SdfSphere {
+  fn distance(&self, p: &Point) -> Float {
+    distance(p, &self.center) - self.radius
+  }
+
+  fn bound(&self) -> BBox {
+    let r = Vector::new(self.radius, self.radius, self.radius);
+    BBox::new(&(self.center - r), &(self.center + r))
+  }
+}
+
+/// Axis aligned box
+pub struct SdfBox {
+  pub center: Point,
+  pub half_size: Vector
+}
+
+impl DistanceFunction for SdfBox {
+  fn distance(&self, p: &Point) -> Float {
+    let d = *p - self.center;
+    let q = Vector::new(d.x.abs() - self.half_size.x, d.y.abs() - self.half_size.y,
+      d.z.abs() - self.half_size.z);
+
+    let outside = Vector::new(Float::max(0.0, q.x), Float::max(0.0, q.y), Float::max(0.0, q.z));
+    outside.length() + Float::min(0.0, q.x.max(q.y).max(q.z))
+  }
+
+  fn bound(&self) -> BBox {
+    BBox::new(&(self.center - self.half_size), &(self.center + self.half_size))
+  }
+}
+
+/// Torus around the z axis, centered at the origin
+pub struct SdfTorus {
+  pub major_radius: Float,
+  pub minor_radius: Float
+}
+
+impl DistanceFunction for SdfTorus {
+  fn distance(&self, p: &Point) -> Float {
+    let ring = (p.x * p.x + p.y * p.y).sqrt() - self.major_radius;
+    (ring * ring + p.z * p.z).sqrt() - self.minor_radius
+  }
+
+  fn bound(&self) -> BBox {
+    let r = self.major_radius + self.minor_radius;
+    BBox::new(&Point::new(-r, -r, -self.minor_radius), &Point::new(r, r, self.minor_radius))
+  }
+}
+
+/// Union of two distance functions, blended over a distance of about `k`.
+/// A `k` of zero gives the plain union.
+pub struct SmoothUnion {
+  pub a: Rc<dyn DistanceFunction>,
+  pub b: Rc<dyn DistanceFunction>,
+  pub k: Float
+}
+
+impl SmoothUnion {
+  pub fn new(a: Rc<dyn DistanceFunction>, b: Rc<dyn DistanceFunction>, k: Float) -> SmoothUnion {
+    SmoothUnion { a, b, k: Float::max(0.0, k) }
+  }
+}
+
+impl DistanceFunction for SmoothUnion {
+  /// Polynomial smooth minimum, whose gradient is a convex combination of
+  /// the gradients of `a` and `b`
+  fn distance(&self, p: &Point) -> Float {
+    let (da, db) = (self.a.distance(p), self.b.distance(p));
+    if self.k == 0.0 {
+      return da.min(db);
+    }
+
+    let h = clamp(0.5 + 0.5 * (db - da) / self.k, 0.0, 1.0);
+    lerp(h, db, da) - self.k * h * (1.0 - h)
+  }
+
+  /// The blend lowers the distance by at most `k / 4`
+  fn bound(&self) -> BBox {
+    let mut bound = self.a.bound().union(&self.b.bound());
+    bound.expand(0.25 * self.k);
+    bound
+  }
+
+  fn lipschitz(&self) -> Float {
+    self.a.lipschitz().max(self.b.lipschitz())
+  }
+}
+
+fn malformed(message: &str) -> RenderError {
+  RenderError::InvalidScene(format!("sdf grid: {}", message))
+}
+
+/// Distances sampled on a regular grid over `bound` and trilinearly
+/// interpolated. Samples are stored with x varying fastest, then y.
+pub struct SdfGrid {
+  pub nx: usize,
+  pub ny: usize,
+  pub nz: usize,
+  pub bound: BBox,
+  pub values: Vec<Float>,
+  lipschitz: Float
+}
+
+impl SdfGrid {
+  pub fn new(nx: usize, ny: usize, nz: usize, bound: BBox, values: Vec<Float>) -> Result<SdfGrid, RenderError> {
+    if nx < 2 || ny < 2 || nz < 2 {
+      return Err(RenderError::InvalidParameter {
+        name: "resolution".to_string(),
+        message: format!("needs at least 2 samples per axis, found {}x{}x{}", nx, ny, nz)
+      });
+    }
+
+    if values.len() != nx * ny * nz {
+      return Err(RenderError::InvalidParameter {
+        name: "values".to_string(),
+        message: format!("expected {} samples, found {}", nx * ny * nz, values.len())
+      });
+    }
+
+    let size = bound.p_max - bound.p_min;
+    if !(size.x > 0.0 && size.y > 0.0 && size.z > 0.0) || values.iter().any(|v| !v.is_finite()) {
+      return Err(RenderError::InvalidParameter {
+        name: "bound".to_string(),
+        message: "grid bound must not be empty and samples must be finite".to_string()
+      });
+    }
+
+    // The interpolant is steepest along the largest difference between
+    // neighbouring samples on each axis
+    let index = |x: usize, y: usize, z: usize| (z * ny + y) * nx + x;
+    let (mut lx, mut ly, mut lz): (Float, Float, Float) = (0.0, 0.0, 0.0);
+    for z in 0..nz {
+      for y in 0..ny {
+        for x in 0..nx {
+          let v = values[index(x, y, z)];
+          if x + 1 < nx { lx = lx.max((values[index(x + 1, y, z)] - v).abs()); }
+          if y + 1 < ny { ly = ly.max((values[index(x, y + 1, z)] - v).abs()); }
+          if z + 1 < nz { lz = lz.max((values[index(x, y, z + 1)] - v).abs()); }
+        }
+      }
+    }
+
+    lx *= (nx - 1) as Float / size.x;
+    ly *= (ny - 1) as Float / size.y;
+    lz *= (nz - 1) as Float / size.z;
+
+    Ok(SdfGrid { nx, ny, nz, bound, values, lipschitz: (lx * lx + ly * ly + lz * lz).sqrt() })
+  }
+
+  /// Reads a text grid: the resolution `nx ny nz`, the bound
+  /// `x0 y0 z0 x1 y1 z1` and then the samples, separated by white space.
+  /// Everything after a `#` on a line is ignored.
+  pub fn read<R: Read>(mut reader: R) -> Result<SdfGrid, RenderError> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+
+    let mut tokens = text.lines().flat_map(|l| l.split('#').next().unwrap_or("").split_whitespace());
+
+    let mut resolution = [0usize; 3];
+    for n in resolution.iter_mut() {
+      let token = tokens.next().ok_or_else(|| malformed("missing resolution"))?;
+      *n = token.parse().map_err(|_| malformed(&format!("invalid resolution \"{}\"", token)))?;
+    }
+
+    let values = tokens
+      .map(|t| t.parse::<Float>().map_err(|_| malformed(&format!("invalid number \"{}\"", t))))
+      .collect::<Result<Vec<Float>, RenderError>>()?;
+    if values.len() < 6 {
+      return Err(malformed("missing bound"));
+    }
+
+    let bound = BBox::new(&Point::new(values[0], values[1], values[2]),
+      &Point::new(values[3], values[4], values[5]));
+
+    SdfGrid::new(resolution[0], resolution[1], resolution[2], bound, values[6..].to_vec())
+  }
+
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<SdfGrid, RenderError> {
+    SdfGrid::read(File::open(path)?)
+  }
+
+  fn value(&self, x: usize, y: usize, z: usize) -> Float {
+    self.values[(z * self.ny + y) * self.nx + x]
+  }
+}
+
+/// Cell of a grid axis with `n` samples over `lo..hi` and the offset in it
+fn grid_cell(v: Float, lo: Float, hi: Float, n: usize) -> (usize, Float) {
+  let x = clamp((v - lo) / (hi - lo), 0.0, 1.0) * (n - 1) as Float;
+  let i = (x.floor() as usize).min(n - 2);
+  (i, x - i as Float)
+}
+
+impl DistanceFunction for SdfGrid {
+  /// Outside the grid, the value at the closest point of the bound grows
+  /// with the distance to it
+  fn distance(&self, p: &Point) -> Float {
+    let (b0, b1) = (&self.bound.p_min, &self.bound.p_max);
+    let (x, fx) = grid_cell(p.x, b0.x, b1.x, self.nx);
+    let (y, fy) = grid_cell(p.y, b0.y, b1.y, self.ny);
+    let (z, fz) = grid_cell(p.z, b0.z, b1.z, self.nz);
+
+    let along_x = |y: usize, z: usize| lerp(fx, self.value(x, y, z), self.value(x + 1, y, z));
+    let along_y = |z: usize| lerp(fy, along_x(y, z), along_x(y + 1, z));
+    let inside = lerp(fz, along_y(z), along_y(z + 1));
+
+    let q = Point::new(clamp(p.x, b0.x, b1.x), clamp(p.y, b0.y, b1.y), clamp(p.z, b0.z, b1.z));
+    inside + distance(p, &q)
+  }
+
+  fn bound(&self) -> BBox {
+    self.bound
+  }
+
+  fn lipschitz(&self) -> Float {
+    self.lipschitz.max(1.0)
+  }
+}
+
+/// Implicit surface at the zero set of a distance function, intersected
+/// by sphere tracing
+pub struct Sdf {
+  base: ShapeBase,
+  pub function: Rc<dyn DistanceFunction>,
+  pub epsilon: Float
+}
+
+impl Sdf {
+  /// Rays stop once they are closer than `epsilon` to the surface, in
+  /// object space
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform, reverse_orientation: bool,
+      function: Rc<dyn DistanceFunction>, epsilon: Float) -> Result<Sdf, RenderError> {
+    if !(epsilon > 0.0 && epsilon.is_finite()) {
+      return Err(RenderError::InvalidParameter {
+        name: "epsilon".to_string(),
+        message: format!("must be finite and positive, found {}", epsilon)
+      });
+    }
+
+    Ok(Sdf {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      function,
+      epsilon
+    })
+  }
+
+  /// Closest hit of the world space `r`, as the ray parameter and object
+  /// space point, and the object space ray
+  fn hit(&self, r: &Ray) -> Option<(Float, Point, Ray)> {
+    let ray = self.base.world_to_object.apply_ray_with_error(r).0;
+    let (t0, t1) = self.function.bound().intersect_p(&ray)?;
+
+    // The ray parameter is not a distance when the transform scales
+    let step = 1.0 / (self.function.lipschitz() * ray.d.length());
+
+    // A ray spawned from the surface starts inside the `epsilon` shell,
+    // which has to be left before anything counts as a hit
+    let mut leaving = t0 == ray.mint && self.function.distance(&ray.apply(t0)).abs() < self.epsilon;
+    let mut t = t0;
+
+    for _ in 0..MAX_STEPS {
+      if t > t1 {
+        return None;
+      }
+
+      let p = ray.apply(t);
+      let d = self.function.distance(&p).abs();
+      if d < self.epsilon {
+        if !leaving {
+          return Some((t, p, ray));
+        }
+        t += self.epsilon * step;
+      } else {
+        leaving = false;
+        t += d * step;
+      }
+    }
+
+    None
+  }
+
+  /// Central differences over `epsilon`
+  fn gradient(&self, p: &Point) -> Vector {
+    let f = |d: Vector| self.function.distance(&(*p + d)) - self.function.distance(&(*p - d));
+    let h = self.epsilon;
+
+    Vector::new(f(Vector::new(h, 0.0, 0.0)), f(Vector::new(0.0, h, 0.0)),
+      f(Vector::new(0.0, 0.0, h))) / (2.0 * h)
+  }
+}
+
+impl Shape for Sdf {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    self.function.bound()
+  }
+
+  /// Estimated with the coarea formula, integrating the gradient over a
+  /// thin shell around the surface on a regular grid
+  fn area(&self) -> Float {
+    let mut bound = self.function.bound();
+    let size = bound.p_max - bound.p_min;
+    let h = 2.0 * size.x.max(size.y).max(size.z) / AREA_SAMPLES as Float;
+    bound.expand(h);
+
+    let cell = (bound.p_max - bound.p_min) / AREA_SAMPLES as Float;
+    let mut sum = 0.0;
+
+    for z in 0..AREA_SAMPLES {
+      for y in 0..AREA_SAMPLES {
+        for x in 0..AREA_SAMPLES {
+          let p = bound.p_min + Vector::new((x as Float + 0.5) * cell.x, (y as Float + 0.5) * cell.y,
+            (z as Float + 0.5) * cell.z);
+          if self.function.distance(&p).abs() < h {
+            sum += self.gradient(&p).length();
+          }
+        }
+      }
+    }
+
+    sum * cell.x * cell.y * cell.z / (2.0 * h)
+  }
+
+  /// The surface has no parameterization, `u` and `v` are zero and
+  /// textures have to use 3D mappings
+  fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+    let (t_hit, phit, ray) = self.hit(r)?;
+
+    let grad = self.gradient(&phit);
+    let n = if grad.length() > 0.0 { normalize(grad) } else { -normalize(ray.d) };
+    let (dpdu, dpdv) = coordinate_system(&n);
+
+    // The hit is anywhere in the shell, offsetting spawned rays by twice
+    // its width clears it
+    let e = 2.0 * self.epsilon;
+    let p_error = Vector::new(e, e, e);
+
+    let o2w = &self.base.object_to_world;
+    let (p, p_error) = o2w.apply_point_with_abs_error(&phit, &p_error);
+    let dg = DifferentialGeometry::from_shape(p, o2w.apply(dpdu), o2w.apply(dpdv),
+      Normal::zero(), Normal::zero(), 0.0, 0.0, Some(self));
+
+    Some((t_hit, p_error, dg))
+  }
+
+  fn intersect_p(&self, r: &Ray) -> bool {
+    self.hit(r).is_some()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rbrtcore::geometry::{ PI, dot };
+
+  fn identity() -> Transform {
+    Transform::translate(&Vector::new(0.0, 0.0, 0.0))
+  }
+
+  fn sdf(function: Rc<dyn DistanceFunction>) -> Sdf {
+    Sdf::new(&identity(), &identity(), false, function, 1e-4).unwrap()
+  }
+
+  fn ray(o: Point, d: Vector) -> Ray {
+    Ray::new(&o, &d, 0.0, Float::INFINITY, 0.0)
+  }
+
+  fn unit_sphere() -> Rc<dyn DistanceFunction> {
+    Rc::new(SdfSphere { center: Point::zero(), radius: 1.0 })
+  }
+
+  /// Text grid of the unit sphere distance, `n` samples per axis
+  fn sphere_grid(n: usize) -> String {
+    let mut text = format!("{} {} {}\n# bound\n-1.5 -1.5 -1.5 1.5 1.5 1.5\n", n, n, n);
+    let x = |i: usize| -1.5 + 3.0 * i as Float / (n - 1) as Float;
+    for k in 0..n {
+      for j in 0..n {
+        for i in 0..n {
+          let d = (x(i) * x(i) + x(j) * x(j) + x(k) * x(k)).sqrt() - 1.0;
+          text.push_str(&format!("{} ", d));
+        }
+      }
+    }
+    text
+  }
+
+  #[test]
+  fn sphere_tracing_matches_the_analytic_sphere() {
+    let o2w = Transform::translate(&Vector::new(0.0, 0.0, 2.0));
+    let shape = Sdf::new(&o2w, &Transform::inverse(&o2w), false, unit_sphere(), 1e-4).unwrap();
+    let (t_hit, p_error, dg) = shape.intersect(&ray(Point::zero(), Vector::new(0.0, 0.0, 2.0))).unwrap();
+
+    // The direction is not normalized, so the hit at distance 1 is t = 0.5
+    assert!((t_hit - 0.5).abs() < 1e-4);
+    assert!((dg.p.z - 1.0).abs() <= p_error.z);
+    assert!((dg.nn.z + 1.0).abs() < 1e-3);
+    assert!(!shape.intersect_p(&ray(Point::new(2.0, 0.0, 0.0), Vector::new(0.0, 0.0, 1.0))));
+  }
+
+  #[test]
+  fn rays_leave_the_surface_they_start_on() {
+    let shape = sdf(unit_sphere());
+    let (_, _, dg) = shape.intersect(&ray(Point::new(0.0, 0.0, -5.0), Vector::new(0.0, 0.0, 1.0))).unwrap();
+
+    assert!(!shape.intersect_p(&ray(dg.p, Vector::new(0.0, 0.0, -1.0))));
+
+    let (t_hit, _, inner) = shape.intersect(&ray(dg.p, Vector::new(0.0, 0.0, 1.0))).unwrap();
+    assert!((t_hit - 2.0).abs() < 1e-3);
+    assert!(dot(inner.nn, Vector::new(0.0, 0.0, 1.0)) > 0.99);
+  }
+
+  #[test]
+  fn smooth_union_fills_the_gap_between_spheres() {
+    let left: Rc<dyn DistanceFunction> = Rc::new(SdfSphere { center: Point::new(-0.6, 0.0, 0.0), radius: 0.5 });
+    let right: Rc<dyn DistanceFunction> = Rc::new(SdfSphere { center: Point::new(0.6, 0.0, 0.0), radius: 0.5 });
+    let down = ray(Point::new(0.0, 0.0, 5.0), Vector::new(0.0, 0.0, -1.0));
+
+    let union = sdf(Rc::new(SmoothUnion::new(left.clone(), right.clone(), 0.0)));
+    assert!(!union.intersect_p(&down));
+
+    let blend = SmoothUnion::new(left, right, 0.5);
+    assert!(blend.bound().p_max.x > 1.1);
+    let (_, _, dg) = sdf(Rc::new(blend)).intersect(&down).unwrap();
+    assert!((dg.nn.z - 1.0).abs() < 1e-3);
+  }
+
+  #[test]
+  fn primitives_are_exact_distances() {
+    let cube = SdfBox { center: Point::zero(), half_size: Vector::new(1.0, 2.0, 3.0) };
+    assert!((cube.distance(&Point::new(3.0, 0.0, 0.0)) - 2.0).abs() < 1e-6);
+    assert!((cube.distance(&Point::new(0.5, 0.0, 0.0)) + 0.5).abs() < 1e-6);
+    assert!((cube.distance(&Point::new(2.0, 3.0, 0.0)) - (2.0 as Float).sqrt()).abs() < 1e-6);
+
+    let torus = SdfTorus { major_radius: 2.0, minor_radius: 0.5 };
+    assert!((torus.distance(&Point::new(2.0, 0.0, 1.0)) - 0.5).abs() < 1e-6);
+    assert!(!sdf(Rc::new(torus)).intersect_p(&ray(Point::new(0.0, 0.0, 5.0), Vector::new(0.0, 0.0, -1.0))));
+
+    // A sphere of radius 1 has an area of 4 pi
+    assert!((sdf(unit_sphere()).area() - 4.0 * PI).abs() < 0.05 * 4.0 * PI);
+  }
+
+  #[test]
+  fn grid_read_from_text() {
+    let grid = SdfGrid::read(sphere_grid(16).as_bytes()).unwrap();
+    assert!(grid.lipschitz() >= 1.0);
+
+    let shape = sdf(Rc::new(grid));
+    let (t_hit, _, dg) = shape.intersect(&ray(Point::new(0.0, 0.0, -5.0), Vector::new(0.0, 0.0, 1.0))).unwrap();
+    assert!((t_hit - 4.0).abs() < 0.05);
+    assert!((dg.nn.z + 1.0).abs() < 0.05);
+
+    assert!(SdfGrid::read("2 2".as_bytes()).is_err());
+    assert!(SdfGrid::read("2 2 2 0 0 0 1 1 1 0.5".as_bytes()).is_err());
+    assert!(SdfGrid::read("2 2 x 0 0 0 1 1 1".as_bytes()).is_err());
+    assert!(matches!(SdfGrid::load("/nonexistent/grid.sdf"), Err(RenderError::Io(_))));
+  }
+}
@@ -0,0 +1,410 @@
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::efloat::{ EFloat, gamma, quadratic };
+use rbrtcore::geometry::{
+  Float, BBox, Length, Normal, Point, Ray, Vector, PI,
+  clamp, coordinate_system, cross, distance_squared, dot, lerp, normalize, radians };
+use rbrtcore::montecarlo::{ uniform_cone_pdf, uniform_sample_cone_frame };
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection, area_pdf_to_solid_angle };
+use rbrtcore::transform::{ Applicable, Transform };
+
+use crate::quadric::{ azimuth, weingarten };
+
+/// Sphere centered at the object space origin, optionally clipped to
+/// `z_min..z_max` and swept only up to `phi_max`
+pub struct Sphere {
+  base: ShapeBase,
+  pub radius: Float,
+  pub z_min: Float,
+  pub z_max: Float,
+  pub theta_min: Float,
+  pub theta_max: Float,
+  pub phi_max: Float
+}
+
+impl Sphere {
+  /// `phi_max` is given in degrees
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform,
+      reverse_orientation: bool, radius: Float, z0: Float, z1: Float, phi_max: Float) -> Sphere {
+    let z_min = clamp(z0.min(z1), -radius, radius);
+    let z_max = clamp(z0.max(z1), -radius, radius);
+
+    Sphere {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      radius,
+      z_min,
+      z_max,
+      theta_min: clamp(z_min / radius, -1.0, 1.0).acos(),
+      theta_max: clamp(z_max / radius, -1.0, 1.0).acos(),
+      phi_max: radians(clamp(phi_max, 0.0, 360.0))
+    }
+  }
+
+  /// Hit point on the full sphere at `t`, refined onto the surface, and
+  /// its azimuth
+  fn hit_point(&self, ray: &Ray, t: Float) -> (Point, Float) {
+    let mut phit = ray.apply(t);
+    phit = phit * (self.radius / (phit.x * phit.x + phit.y * phit.y + phit.z * phit.z).sqrt());
+
+    if phit.x == 0.0 && phit.y == 0.0 {
+      phit.x = 1e-5 * self.radius;
+    }
+
+    (phit, azimuth(&phit))
+  }
+
+  fn is_clipped(&self, phit: &Point, phi: Float) -> bool {
+    (self.z_min > -self.radius && phit.z < self.z_min) ||
+      (self.z_max < self.radius && phit.z > self.z_max) ||
+      phi > self.phi_max
+  }
+
+  /// World space center and radius, if the sphere is not clipped and the
+  /// transform scales it uniformly, so that it subtends a cone of directions
+  fn world_sphere(&self) -> Option<(Point, Float)> {
+    if self.z_min > -self.radius || self.z_max < self.radius || self.phi_max < 2.0 * PI {
+      return None;
+    }
+
+    let o2w = &self.base.object_to_world;
+    let rx = o2w.apply(Vector::new(self.radius, 0.0, 0.0)).length();
+    let ry = o2w.apply(Vector::new(0.0, self.radius, 0.0)).length();
+    let rz = o2w.apply(Vector::new(0.0, 0.0, self.radius)).length();
+    if (rx - ry).abs() > 1e-4 * rx || (rx - rz).abs() > 1e-4 * rx {
+      return None;
+    }
+
+    Some((o2w.apply(Point::zero()), rx))
+  }
+
+  /// World space normal at the object space point `phit`, oriented like
+  /// the geometric normal that `intersect` reports
+  fn normal_at(&self, phit: &Point) -> Normal {
+    let mut phit = *phit;
+    if phit.x == 0.0 && phit.y == 0.0 {
+      phit.x = 1e-5 * self.radius;
+    }
+
+    // Tangents along theta and phi, ordered so their cross product points out
+    let o2w = &self.base.object_to_world;
+    let z_radius2 = phit.x * phit.x + phit.y * phit.y;
+    let n = cross(o2w.apply(Vector::new(phit.x * phit.z, phit.y * phit.z, -z_radius2)),
+      o2w.apply(Vector::new(-phit.y, phit.x, 0.0)));
+
+    let n = Normal::from_vector(&normalize(n));
+    if self.base.reverse_orientation ^ self.base.transform_swaps_handedness { -n } else { n }
+  }
+
+  /// Closest hit of the world space `r` with the clipped sphere, as the
+  /// ray parameter, object space hit point and azimuth
+  fn hit(&self, r: &Ray) -> Option<(Float, Point, Float)> {
+    let (ray, o_error, d_error) = self.base.world_to_object.apply_ray_with_error(r);
+
+    let ox = EFloat::new(ray.o.x, o_error.x);
+    let oy = EFloat::new(ray.o.y, o_error.y);
+    let oz = EFloat::new(ray.o.z, o_error.z);
+    let dx = EFloat::new(ray.d.x, d_error.x);
+    let dy = EFloat::new(ray.d.y, d_error.y);
+    let dz = EFloat::new(ray.d.z, d_error.z);
+    let radius = EFloat::from(self.radius);
+
+    let a = dx * dx + dy * dy + dz * dz;
+    let b = EFloat::from(2.0) * (dx * ox + dy * oy + dz * oz);
+    let c = ox * ox + oy * oy + oz * oz - radius * radius;
+
+    let (t0, t1) = quadratic(a, b, c)?;
+
+    if t0.upper_bound() > ray.maxt || t1.lower_bound() <= ray.mint {
+      return None;
+    }
+
+    let mut t_hit = t0;
+    if t_hit.lower_bound() <= ray.mint {
+      t_hit = t1;
+      if t_hit.upper_bound() > ray.maxt {
+        return None;
+      }
+    }
+
+    let (mut phit, mut phi) = self.hit_point(&ray, t_hit.v);
+
+    // Try the far hit if the near one is clipped away
+    if self.is_clipped(&phit, phi) {
+      if t_hit == t1 || t1.upper_bound() > ray.maxt {
+        return None;
+      }
+
+      t_hit = t1;
+      (phit, phi) = self.hit_point(&ray, t_hit.v);
+
+      if self.is_clipped(&phit, phi) {
+        return None;
+      }
+    }
+
+    Some((t_hit.v, phit, phi))
+  }
+}
+
+impl Shape for Sphere {
+  fn get_base(&self) -> &ShapeBase {
+    &self.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    BBox::new(&Point::new(-self.radius, -self.radius, self.z_min),
+      &Point::new(self.radius, self.radius, self.z_max))
+  }
+
+  fn area(&self) -> Float {
+    self.phi_max * self.radius * (self.z_max - self.z_min)
+  }
+
+  fn intersect(&self, r: &Ray) -> Option<ShapeIntersection> {
+    let (t_hit, phit, phi) = self.hit(r)?;
+
+    // Parametric representation of the hit point
+    let u = phi / self.phi_max;
+    let cos_theta = clamp(phit.z / self.radius, -1.0, 1.0);
+    let theta = cos_theta.acos();
+    let v = (theta - self.theta_min) / (self.theta_max - self.theta_min);
+
+    let z_radius = (phit.x * phit.x + phit.y * phit.y).sqrt();
+    let cos_phi = phit.x / z_radius;
+    let sin_phi = phit.y / z_radius;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let theta_range = self.theta_max - self.theta_min;
+
+    let dpdu = Vector::new(-self.phi_max * phit.y, self.phi_max * phit.x, 0.0);
+    let dpdv = Vector::new(phit.z * cos_phi, phit.z * sin_phi, -self.radius * sin_theta) *
+      theta_range;
+
+    let d2pduu = Vector::new(phit.x, phit.y, 0.0) * (-self.phi_max * self.phi_max);
+    let d2pduv = Vector::new(-sin_phi, cos_phi, 0.0) * (theta_range * phit.z * self.phi_max);
+    let d2pdvv = Vector::new(phit.x, phit.y, phit.z) * (-theta_range * theta_range);
+    let (dndu, dndv) = weingarten(dpdu, dpdv, d2pduu, d2pduv, d2pdvv);
+
+    let p_error = Vector::new(phit.x.abs(), phit.y.abs(), phit.z.abs()) * gamma(5);
+
+    let o2w = &self.base.object_to_world;
+    let (p, p_error) = o2w.apply_point_with_abs_error(&phit, &p_error);
+    let dg = DifferentialGeometry::from_shape(p, o2w.apply(dpdu), o2w.apply(dpdv),
+      o2w.apply(dndu), o2w.apply(dndv), u, v, Some(self));
+
+    Some((t_hit, p_error, dg))
+  }
+
+  fn intersect_p(&self, r: &Ray) -> bool {
+    self.hit(r).is_some()
+  }
+
+  /// Samples the clipped sphere uniformly by area: z is uniform over
+  /// `z_min..z_max` and the azimuth over `0..phi_max`
+  fn sample(&self, u1: Float, u2: Float) -> Option<(Point, Normal)> {
+    let z = lerp(u1, self.z_min, self.z_max);
+    let z_radius = Float::max(0.0, self.radius * self.radius - z * z).sqrt();
+    let (sin_phi, cos_phi) = (u2 * self.phi_max).sin_cos();
+    let p = Point::new(z_radius * cos_phi, z_radius * sin_phi, z);
+
+    Some((self.base.object_to_world.apply(p), self.normal_at(&p)))
+  }
+
+  /// Samples the cone of directions from `p` that the sphere subtends,
+  /// falling back to area sampling when `p` is inside it or the sphere
+  /// does not subtend a cone
+  fn sample_from(&self, p: &Point, u1: Float, u2: Float) -> Option<(Point, Normal)> {
+    let (p_center, radius) = match self.world_sphere() {
+      Some(sphere) => sphere,
+      None => return self.sample(u1, u2)
+    };
+    let dist2 = distance_squared(p, &p_center);
+    if dist2 <= radius * radius {
+      return self.sample(u1, u2);
+    }
+
+    let wc = normalize(p_center - *p);
+    let (wc_x, wc_y) = coordinate_system(&wc);
+    let sin_theta_max2 = radius * radius / dist2;
+    let cos_theta_max = Float::max(0.0, 1.0 - sin_theta_max2).sqrt();
+
+    let r = Ray::new(p, &uniform_sample_cone_frame(u1, u2, cos_theta_max, &wc_x, &wc_y, &wc),
+      0.0, Float::INFINITY, 0.0);
+
+    // Numerically the ray may just miss, take the closest point instead
+    let t_hit = match self.intersect(&r) {
+      Some((t_hit, _, _)) => t_hit,
+      None => dot(p_center - *p, normalize(r.d))
+    };
+
+    let ps = r.apply(t_hit);
+    Some((ps, self.normal_at(&self.base.world_to_object.apply(ps))))
+  }
+
+  fn pdf(&self, p: &Point, wi: &Vector) -> Float {
+    let (p_center, radius) = match self.world_sphere() {
+      Some(sphere) => sphere,
+      None => return area_pdf_to_solid_angle(self, p, wi)
+    };
+    let dist2 = distance_squared(p, &p_center);
+    if dist2 <= radius * radius {
+      return area_pdf_to_solid_angle(self, p, wi);
+    }
+
+    let sin_theta_max2 = radius * radius / dist2;
+    uniform_cone_pdf(Float::max(0.0, 1.0 - sin_theta_max2).sqrt())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn unit_sphere(phi_max: Float, z0: Float, z1: Float) -> Sphere {
+    let t = Transform::translate(&Vector::new(0.0, 0.0, 0.0));
+    Sphere::new(&t, &t, false, 1.0, z0, z1, phi_max)
+  }
+
+  fn ray_towards(o: Point, d: Vector) -> Ray {
+    Ray::new(&o, &d, 0.0, Float::INFINITY, 0.0)
+  }
+
+  #[test]
+  fn intersect_full_sphere() {
+    let sphere = unit_sphere(360.0, -1.0, 1.0);
+    let ray = ray_towards(Point::new(0.0, 0.0, -5.0), Vector::new(0.0, 0.0, 1.0));
+    let (t_hit, p_error, dg) = sphere.intersect(&ray).unwrap();
+
+    assert!((t_hit - 4.0).abs() < 1e-5);
+    assert!((dg.p.z + 1.0).abs() <= p_error.z.max(1e-6));
+    assert!(dg.nn.z < 0.0);
+    assert!((sphere.area() - 4.0 * PI).abs() < 1e-4);
+  }
+
+  #[test]
+  fn intersect_from_inside_hits_far_side() {
+    let sphere = unit_sphere(360.0, -1.0, 1.0);
+    let ray = ray_towards(Point::new(0.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+    let (t_hit, _, dg) = sphere.intersect(&ray).unwrap();
+
+    assert!((t_hit - 1.0).abs() < 1e-5);
+    assert!((dg.p.x - 1.0).abs() < 1e-5);
+  }
+
+  #[test]
+  fn clipping_in_z_and_phi() {
+    let capped = unit_sphere(360.0, -1.0, 0.5);
+    let down = ray_towards(Point::new(0.0, 0.0, 5.0), Vector::new(0.0, 0.0, -1.0));
+    let (t_hit, _, _) = capped.intersect(&down).unwrap();
+    assert!((t_hit - 6.0).abs() < 1e-5);
+
+    let half = unit_sphere(180.0, -1.0, 1.0);
+    let from_below = ray_towards(Point::new(0.0, -5.0, 0.0), Vector::new(0.0, 1.0, 0.0));
+    let (t_hit, _, _) = half.intersect(&from_below).unwrap();
+    assert!((t_hit - 6.0).abs() < 1e-5);
+
+    let from_side = ray_towards(Point::new(0.5, -5.0, 0.0), Vector::new(0.0, 0.0, 1.0));
+    assert!(!half.intersect_p(&from_side));
+  }
+
+  #[test]
+  fn weingarten_normal_derivatives() {
+    let sphere = unit_sphere(360.0, -1.0, 1.0);
+    let ray = ray_towards(Point::new(5.0, 1.0, 2.0), Vector::new(-1.0, -0.2, -0.4));
+    let (_, _, dg) = sphere.intersect(&ray).unwrap();
+
+    // On a unit sphere n = p, so the normal changes exactly like the point
+    assert!((dg.dndu.x - dg.dpdu.x).abs() < 1e-4 && (dg.dndu.y - dg.dpdu.y).abs() < 1e-4);
+    assert!((dg.dndv.z - dg.dpdv.z).abs() < 1e-4);
+  }
+
+  #[test]
+  fn respects_object_to_world() {
+    let o2w = Transform::translate(&Vector::new(10.0, 0.0, 0.0));
+    let sphere = Sphere::new(&o2w, &Transform::inverse(&o2w), false, 2.0, -2.0, 2.0, 360.0);
+    let ray = ray_towards(Point::new(0.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0));
+    let (t_hit, _, dg) = sphere.intersect(&ray).unwrap();
+
+    assert!((t_hit - 8.0).abs() < 1e-4);
+    assert!((dg.p.x - 8.0).abs() < 1e-4);
+    assert_eq!(sphere.world_bound().p_min.x, 8.0);
+    assert!(!sphere.intersect_p(&ray_towards(Point::new(0.0, 5.0, 0.0), Vector::new(1.0, 0.0, 0.0))));
+  }
+
+  #[test]
+  fn sample_lies_on_the_surface() {
+    let o2w = Transform::translate(&Vector::new(0.0, 0.0, 3.0));
+    let sphere = Sphere::new(&o2w, &Transform::inverse(&o2w), false, 2.0, -2.0, 2.0, 360.0);
+
+    for &(u1, u2) in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.7)].iter() {
+      let (p, n) = sphere.sample(u1, u2).unwrap();
+      let r = p - Point::new(0.0, 0.0, 3.0);
+      assert!((r.length() - 2.0).abs() < 1e-4);
+      assert!((dot(r, n) - 2.0).abs() < 1e-4);
+    }
+  }
+
+  #[test]
+  fn sample_from_stays_in_the_visible_cone() {
+    let sphere = unit_sphere(360.0, -1.0, 1.0);
+    let p = Point::new(0.0, 0.0, -4.0);
+
+    for &(u1, u2) in [(0.0, 0.0), (0.3, 0.6), (0.99, 0.25)].iter() {
+      let (ps, ns) = sphere.sample_from(&p, u1, u2).unwrap();
+      assert!(((ps - Point::zero()).length() - 1.0).abs() < 1e-3);
+      assert!(dot(ns, p - ps) >= -1e-4);
+    }
+
+    // Solid angle of a sphere of radius 1 seen from distance 4
+    let cos_theta_max = (15.0 as Float).sqrt() / 4.0;
+    let pdf = sphere.pdf(&p, &Vector::new(0.0, 0.0, 1.0));
+    assert!((pdf - 1.0 / (2.0 * PI * (1.0 - cos_theta_max))).abs() < 1e-2);
+
+    // From the inside the pdf falls back to the area measure
+    let inside = sphere.pdf(&Point::zero(), &Vector::new(0.0, 0.0, 1.0));
+    assert!((inside - 1.0 / (4.0 * PI)).abs() < 1e-4);
+  }
+
+  #[test]
+  fn sample_respects_clipping() {
+    let sphere = unit_sphere(90.0, 0.0, 0.5);
+
+    for &(u1, u2) in [(0.0, 0.0), (0.4, 0.3), (1.0, 1.0)].iter() {
+      let (p, _) = sphere.sample(u1, u2).unwrap();
+      assert!(p.z >= -1e-5 && p.z <= 0.5 + 1e-5);
+      assert!(p.x >= -1e-5 && p.y >= -1e-5);
+    }
+
+    // A clipped sphere does not subtend a cone, it is sampled by area
+    let p = Point::new(0.0, 0.0, 4.0);
+    let wi = Vector::new(0.0, 0.0, -1.0);
+    assert_eq!(sphere.pdf(&p, &wi), area_pdf_to_solid_angle(&sphere, &p, &wi));
+  }
+
+  #[test]
+  fn sample_from_uses_the_world_space_radius() {
+    let o2w = Transform::scale(3.0, 3.0, 3.0);
+    let sphere = Sphere::new(&o2w, &Transform::inverse(&o2w), false, 1.0, -1.0, 1.0, 360.0);
+    let p = Point::new(0.0, 0.0, -12.0);
+
+    let (ps, _) = sphere.sample_from(&p, 0.5, 0.5).unwrap();
+    assert!(((ps - Point::zero()).length() - 3.0).abs() < 1e-3);
+
+    let cos_theta_max = (135.0 as Float).sqrt() / 12.0;
+    let pdf = sphere.pdf(&p, &Vector::new(0.0, 0.0, 1.0));
+    assert!((pdf - 1.0 / (2.0 * PI * (1.0 - cos_theta_max))).abs() < 1e-2);
+  }
+
+  #[test]
+  fn sampled_normals_match_intersect_under_mirroring() {
+    let o2w = Transform::scale(-1.0, 1.0, 1.0);
+    for &reverse in [false, true].iter() {
+      let sphere = Sphere::new(&o2w, &Transform::inverse(&o2w), reverse, 1.0, -1.0, 1.0, 360.0);
+      let ray = ray_towards(Point::new(0.0, 0.0, -5.0), Vector::new(0.0, 0.0, 1.0));
+      let (_, _, dg) = sphere.intersect(&ray).unwrap();
+      let (_, n) = sphere.sample(0.0, 0.0).unwrap();
+
+      // Both points are the south pole
+      assert!(dot(dg.nn, n) > 0.99);
+    }
+  }
+}
@@ -0,0 +1,524 @@
+use std::rc::Rc;
+
+use rbrtcore::diffgeom::DifferentialGeometry;
+use rbrtcore::efloat::gamma;
+use rbrtcore::error::RenderError;
+use rbrtcore::geometry::{
+  Float, BBox, Normal, Point, Ray, Vector, Length, Union,
+  coordinate_system, cross, dot, normalize, solve_linear_system };
+use rbrtcore::montecarlo::uniform_sample_triangle;
+use rbrtcore::shape::{ Shape, ShapeBase, ShapeIntersection };
+use rbrtcore::texture::Texture;
+use rbrtcore::transform::{ Applicable, Transform };
+
+/// Vertex data shared by all triangles of a mesh. Positions, normals
+/// and tangents are stored in world space.
+pub struct TriangleMesh {
+  pub base: ShapeBase,
+  pub num_triangles: usize,
+  pub vertex_indices: Vec<usize>,
+  pub p: Vec<Point>,
+  pub n: Option<Vec<Normal>>,
+  pub s: Option<Vec<Vector>>,
+  pub uv: Option<Vec<(Float, Float)>>,
+  pub alpha: Option<Rc<dyn Texture<Float>>>
+}
+
+pub(crate) fn check_len<T>(name: &str, data: &Option<Vec<T>>, expected: usize) -> Result<(), RenderError> {
+  match *data {
+    Some(ref d) if d.len() != expected => Err(RenderError::InvalidParameter {
+      name: name.to_string(),
+      message: format!("expected {} values, one per vertex, found {}", expected, d.len())
+    }),
+    _ => Ok(())
+  }
+}
+
+impl TriangleMesh {
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(object_to_world: &Transform, world_to_object: &Transform,
+      reverse_orientation: bool, vertex_indices: Vec<usize>, p: Vec<Point>,
+      n: Option<Vec<Normal>>, s: Option<Vec<Vector>>, uv: Option<Vec<(Float, Float)>>,
+      alpha: Option<Rc<dyn Texture<Float>>>) -> Result<TriangleMesh, RenderError> {
+    if !vertex_indices.len().is_multiple_of(3) {
+      return Err(RenderError::InvalidParameter {
+        name: "indices".to_string(),
+        message: format!("number of vertex indices {} is not a multiple of 3", vertex_indices.len())
+      });
+    }
+
+    if let Some(&i) = vertex_indices.iter().find(|&&i| i >= p.len()) {
+      return Err(RenderError::InvalidParameter {
+        name: "indices".to_string(),
+        message: format!("vertex index {} out of range for {} vertices", i, p.len())
+      });
+    }
+
+    check_len("N", &n, p.len())?;
+    check_len("S", &s, p.len())?;
+    check_len("uv", &uv, p.len())?;
+
+    Ok(TriangleMesh {
+      base: ShapeBase::new(object_to_world, world_to_object, reverse_orientation),
+      num_triangles: vertex_indices.len() / 3,
+      vertex_indices,
+      p: p.iter().map(|&x| object_to_world.apply(x)).collect(),
+      n: n.map(|n| n.iter().map(|&x| object_to_world.apply(x)).collect()),
+      s: s.map(|s| s.iter().map(|&x| object_to_world.apply(x)).collect()),
+      uv,
+      alpha
+    })
+  }
+
+  /// One `Triangle` shape per face, all sharing the mesh buffers
+  pub fn triangles(mesh: &Rc<TriangleMesh>) -> Vec<Triangle> {
+    (0..mesh.num_triangles).map(|i| Triangle::new(mesh.clone(), i)).collect()
+  }
+
+  /// The triangles of the mesh as shapes, for surfaces that refine to a
+  /// triangle mesh
+  pub fn into_shapes(self) -> Vec<Rc<dyn Shape>> {
+    TriangleMesh::triangles(&Rc::new(self)).into_iter().map(|t| Rc::new(t) as Rc<dyn Shape>).collect()
+  }
+}
+
+pub struct Triangle {
+  pub mesh: Rc<TriangleMesh>,
+  v: usize
+}
+
+impl Triangle {
+  pub fn new(mesh: Rc<TriangleMesh>, n: usize) -> Triangle {
+    Triangle { mesh, v: 3 * n }
+  }
+
+  fn indices(&self) -> [usize; 3] {
+    let vi = &self.mesh.vertex_indices;
+    [vi[self.v], vi[self.v + 1], vi[self.v + 2]]
+  }
+
+  fn vertices(&self) -> [Point; 3] {
+    let i = self.indices();
+    [self.mesh.p[i[0]], self.mesh.p[i[1]], self.mesh.p[i[2]]]
+  }
+
+  fn uvs(&self) -> [(Float, Float); 3] {
+    match self.mesh.uv {
+      Some(ref uv) => {
+        let i = self.indices();
+        [uv[i[0]], uv[i[1]], uv[i[2]]]
+      },
+      None => [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)]
+    }
+  }
+}
+
+fn permute(p: &Vector, x: usize, y: usize, z: usize) -> Vector {
+  Vector::new(p[x], p[y], p[z])
+}
+
+fn max_component(x: Float, y: Float, z: Float) -> Float {
+  x.abs().max(y.abs()).max(z.abs())
+}
+
+impl Shape for Triangle {
+  fn get_base(&self) -> &ShapeBase {
+    &self.mesh.base
+  }
+
+  fn object_bound(&self) -> BBox {
+    let w2o = &self.mesh.base.world_to_object;
+    let [p0, p1, p2] = self.vertices();
+
+    BBox::from_point(&w2o.apply(p0)).union(&w2o.apply(p1)).union(&w2o.apply(p2))
+  }
+
+  fn world_bound(&self) -> BBox {
+    let [p0, p1, p2] = self.vertices();
+    BBox::from_point(&p0).union(&p1).union(&p2)
+  }
+
+  fn area(&self) -> Float {
+    let [p0, p1, p2] = self.vertices();
+    0.5 * cross(p1 - p0, p2 - p0).length()
+  }
+
+  fn intersect(&self, ray: &Ray) -> Option<ShapeIntersection> {
+    let [p0, p1, p2] = self.vertices();
+
+    // Translate the vertices so the ray starts at the origin
+    let p0t = p0 - ray.o;
+    let p1t = p1 - ray.o;
+    let p2t = p2 - ray.o;
+
+    // Permute so that the ray direction's largest component is z
+    let kz = if ray.d.x.abs() > ray.d.y.abs() {
+      if ray.d.x.abs() > ray.d.z.abs() { 0 } else { 2 }
+    } else if ray.d.y.abs() > ray.d.z.abs() { 1 } else { 2 };
+    let kx = if kz == 2 { 0 } else { kz + 1 };
+    let ky = if kx == 2 { 0 } else { kx + 1 };
+
+    let d = permute(&ray.d, kx, ky, kz);
+    let mut p0t = permute(&p0t, kx, ky, kz);
+    let mut p1t = permute(&p1t, kx, ky, kz);
+    let mut p2t = permute(&p2t, kx, ky, kz);
+
+    // Shear so the ray points down +z
+    let sx = -d.x / d.z;
+    let sy = -d.y / d.z;
+    let sz = 1.0 / d.z;
+    for pt in [&mut p0t, &mut p1t, &mut p2t] {
+      pt.x += sx * pt.z;
+      pt.y += sy * pt.z;
+    }
+
+    // Edge functions, redone in double precision when exactly on an edge
+    let mut e0 = p1t.x * p2t.y - p1t.y * p2t.x;
+    let mut e1 = p2t.x * p0t.y - p2t.y * p0t.x;
+    let mut e2 = p0t.x * p1t.y - p0t.y * p1t.x;
+
+    if e0 == 0.0 || e1 == 0.0 || e2 == 0.0 {
+      #[allow(clippy::unnecessary_cast)]
+      let edge = |a: &Vector, b: &Vector|
+        (a.x as f64 * b.y as f64 - a.y as f64 * b.x as f64) as Float;
+      e0 = edge(&p1t, &p2t);
+      e1 = edge(&p2t, &p0t);
+      e2 = edge(&p0t, &p1t);
+    }
+
+    if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+      return None;
+    }
+
+    let det = e0 + e1 + e2;
+    if det == 0.0 {
+      return None;
+    }
+
+    p0t.z *= sz;
+    p1t.z *= sz;
+    p2t.z *= sz;
+
+    // Test the scaled distance against the ray's range without dividing
+    let t_scaled = e0 * p0t.z + e1 * p1t.z + e2 * p2t.z;
+    if det < 0.0 && (t_scaled >= 0.0 || t_scaled < ray.maxt * det) {
+      return None;
+    }
+    if det > 0.0 && (t_scaled <= 0.0 || t_scaled > ray.maxt * det) {
+      return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let b0 = e0 * inv_det;
+    let b1 = e1 * inv_det;
+    let b2 = e2 * inv_det;
+    let t = t_scaled * inv_det;
+
+    // Make sure t is conservatively greater than zero
+    let max_zt = max_component(p0t.z, p1t.z, p2t.z);
+    let delta_z = gamma(3) * max_zt;
+    let max_xt = max_component(p0t.x, p1t.x, p2t.x);
+    let max_yt = max_component(p0t.y, p1t.y, p2t.y);
+    let delta_x = gamma(5) * (max_xt + max_zt);
+    let delta_y = gamma(5) * (max_yt + max_zt);
+    let delta_e = 2.0 * (gamma(2) * max_xt * max_yt + delta_y * max_xt + delta_x * max_yt);
+    let max_e = max_component(e0, e1, e2);
+    let delta_t = 3.0 * (gamma(3) * max_e * max_zt + delta_e * max_zt + delta_z * max_e) *
+      inv_det.abs();
+
+    if t <= delta_t || t <= ray.mint {
+      return None;
+    }
+
+    // Partial derivatives from the triangle's parameterization
+    let uv = self.uvs();
+    let duv02 = (uv[0].0 - uv[2].0, uv[0].1 - uv[2].1);
+    let duv12 = (uv[1].0 - uv[2].0, uv[1].1 - uv[2].1);
+    let dp02 = p0 - p2;
+    let dp12 = p1 - p2;
+    let determinant = duv02.0 * duv12.1 - duv02.1 * duv12.0;
+
+    let (mut dpdu, mut dpdv) = (Vector::zero(), Vector::zero());
+    let degenerate = determinant.abs() < 1e-8;
+    if !degenerate {
+      let invdet = 1.0 / determinant;
+      dpdu = (dp02 * duv12.1 - dp12 * duv02.1) * invdet;
+      dpdv = (dp12 * duv02.0 - dp02 * duv12.0) * invdet;
+    }
+
+    if degenerate || cross(dpdu, dpdv).length_squared() == 0.0 {
+      let ng = cross(p2 - p0, p1 - p0);
+      if ng.length_squared() == 0.0 {
+        return None;
+      }
+      (dpdu, dpdv) = coordinate_system(&normalize(ng));
+    }
+
+    let p_hit = p0 * b0 + p1 * b1 + p2 * b2;
+    let u_hit = b0 * uv[0].0 + b1 * uv[1].0 + b2 * uv[2].0;
+    let v_hit = b0 * uv[0].1 + b1 * uv[1].1 + b2 * uv[2].1;
+
+    let mut dg = DifferentialGeometry::from_shape(p_hit, dpdu, dpdv,
+      Normal::new(0.0, 0.0, 0.0), Normal::new(0.0, 0.0, 0.0), u_hit, v_hit, Some(self));
+
+    if let Some(ref alpha) = self.mesh.alpha {
+      if alpha.evaluate(&dg) == 0.0 {
+        return None;
+      }
+    }
+
+    // Use the face normal, oriented by the shading normals if there are any
+    let mut nn = Normal::from_vector(&normalize(cross(dp02, dp12)));
+    match self.mesh.n {
+      Some(ref n) => {
+        let i = self.indices();
+        let ns = n[i[0]] * b0 + n[i[1]] * b1 + n[i[2]] * b2;
+        if dot(nn, ns) < 0.0 {
+          nn = -nn;
+        }
+      },
+      None => {
+        let base = &self.mesh.base;
+        if base.reverse_orientation ^ base.transform_swaps_handedness {
+          nn = -nn;
+        }
+      }
+    }
+    dg.nn = nn;
+
+    let x_abs_sum = (b0 * p0.x).abs() + (b1 * p1.x).abs() + (b2 * p2.x).abs();
+    let y_abs_sum = (b0 * p0.y).abs() + (b1 * p1.y).abs() + (b2 * p2.y).abs();
+    let z_abs_sum = (b0 * p0.z).abs() + (b1 * p1.z).abs() + (b2 * p2.z).abs();
+    let p_error = Vector::new(x_abs_sum, y_abs_sum, z_abs_sum) * gamma(7);
+
+    Some((t, p_error, dg))
+  }
+
+  fn sample(&self, u1: Float, u2: Float) -> Option<(Point, Normal)> {
+    let [p0, p1, p2] = self.vertices();
+    let (b0, b1) = uniform_sample_triangle(u1, u2);
+    let p = p0 * b0 + p1 * b1 + p2 * (1.0 - b0 - b1);
+
+    // Orient the face normal like in `intersect`
+    let mut n = Normal::from_vector(&normalize(cross(p1 - p0, p2 - p0)));
+    match self.mesh.n {
+      Some(ref ns) => {
+        let i = self.indices();
+        let ns = ns[i[0]] * b0 + ns[i[1]] * b1 + ns[i[2]] * (1.0 - b0 - b1);
+        if dot(n, ns) < 0.0 {
+          n = -n;
+        }
+      },
+      None => {
+        if self.mesh.base.reverse_orientation ^ self.mesh.base.transform_swaps_handedness {
+          n = -n;
+        }
+      }
+    }
+
+    Some((p, n))
+  }
+
+  fn get_shading_geometry(&self, object_to_world: &Transform,
+      dg: &DifferentialGeometry) -> DifferentialGeometry {
+    if self.mesh.n.is_none() && self.mesh.s.is_none() {
+      return dg.clone();
+    }
+
+    // Barycentrics of the hit point, recovered from its uv
+    let uv = self.uvs();
+    let du02 = uv[0].0 - uv[2].0;
+    let du12 = uv[1].0 - uv[2].0;
+    let dv02 = uv[0].1 - uv[2].1;
+    let dv12 = uv[1].1 - uv[2].1;
+    let (mut b0, mut b1) = (0.0, 0.0);
+    if !solve_linear_system([[du02, du12], [dv02, dv12]],
+        [dg.u - uv[2].0, dg.v - uv[2].1], &mut b0, &mut b1) {
+      b0 = 1.0 / 3.0;
+      b1 = 1.0 / 3.0;
+    }
+    let b2 = 1.0 - b0 - b1;
+    let i = self.indices();
+
+    // Mesh data is in the world the mesh was built in, which an instance
+    // places elsewhere
+    let instance = self.get_base().instance_to_world(object_to_world);
+
+    let ns = match self.mesh.n {
+      Some(ref n) => {
+        let n = n[i[0]] * b0 + n[i[1]] * b1 + n[i[2]] * b2;
+        normalize(instance.map_or(n, |t| t.apply(n)))
+      },
+      None => dg.nn
+    };
+
+    let mut ss = match self.mesh.s {
+      Some(ref s) => {
+        let s = s[i[0]] * b0 + s[i[1]] * b1 + s[i[2]] * b2;
+        normalize(instance.map_or(s, |t| t.apply(s)))
+      },
+      None => normalize(dg.dpdu)
+    };
+
+    let mut ts = cross(ss, ns);
+    if ts.length_squared() > 0.0 {
+      ts = normalize(ts);
+      ss = cross(ts, ns);
+    } else {
+      (ss, ts) = coordinate_system(&Vector::from_normal(&ns));
+    }
+
+    let (mut dndu, mut dndv) = (Normal::new(0.0, 0.0, 0.0), Normal::new(0.0, 0.0, 0.0));
+    if let Some(ref n) = self.mesh.n {
+      let dn1 = n[i[0]] - n[i[2]];
+      let dn2 = n[i[1]] - n[i[2]];
+      let determinant = du02 * dv12 - dv02 * du12;
+
+      if determinant != 0.0 {
+        let invdet = 1.0 / determinant;
+        dndu = (dn1 * dv12 - dn2 * dv02) * invdet;
+        dndv = (dn2 * du02 - dn1 * du12) * invdet;
+        if let Some(t) = instance {
+          dndu = t.apply(dndu);
+          dndv = t.apply(dndv);
+        }
+      }
+    }
+
+    let mut shading = DifferentialGeometry::from_shape(dg.p, ss, ts, dndu, dndv,
+      dg.u, dg.v, Some(self));
+    shading.shape = dg.shape.clone();
+    shading.dudx = dg.dudx;
+    shading.dvdx = dg.dvdx;
+    shading.dudy = dg.dudy;
+    shading.dvdy = dg.dvdy;
+    shading.dpdx = dg.dpdx;
+    shading.dpdy = dg.dpdy;
+
+    shading
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct ConstantTexture(Float);
+
+  impl Texture<Float> for ConstantTexture {
+    fn evaluate(&self, _dg: &DifferentialGeometry) -> Float {
+      self.0
+    }
+  }
+
+  fn quad(n: Option<Vec<Normal>>, alpha: Option<Rc<dyn Texture<Float>>>) -> Rc<TriangleMesh> {
+    let t = Transform::translate(&Vector::new(0.0, 0.0, 1.0));
+    let p = vec![Point::new(-1.0, -1.0, 0.0), Point::new(1.0, -1.0, 0.0),
+      Point::new(1.0, 1.0, 0.0), Point::new(-1.0, 1.0, 0.0)];
+    let uv = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+
+    Rc::new(TriangleMesh::new(&t, &Transform::inverse(&t), false, vec![0, 1, 2, 0, 2, 3],
+      p, n, None, Some(uv), alpha).unwrap())
+  }
+
+  fn down(x: Float, y: Float) -> Ray {
+    Ray::new(&Point::new(x, y, 5.0), &Vector::new(0.0, 0.0, -1.0), 0.0, Float::INFINITY, 0.0)
+  }
+
+  #[test]
+  fn new_validates_buffers() {
+    let t = Transform::translate(&Vector::new(0.0, 0.0, 0.0));
+    let p = vec![Point::new(0.0, 0.0, 0.0); 3];
+
+    assert!(TriangleMesh::new(&t, &t, false, vec![0, 1], p.clone(), None, None, None, None).is_err());
+    assert!(TriangleMesh::new(&t, &t, false, vec![0, 1, 3], p.clone(), None, None, None, None).is_err());
+    assert!(TriangleMesh::new(&t, &t, false, vec![0, 1, 2], p, None, None,
+      Some(vec![(0.0, 0.0)]), None).is_err());
+  }
+
+  #[test]
+  fn intersect_interpolates_uv() {
+    let mesh = quad(None, None);
+    let triangles = TriangleMesh::triangles(&mesh);
+    let (t_hit, p_error, dg) = triangles[0].intersect(&down(0.5, -0.5)).unwrap();
+
+    assert!((t_hit - 4.0).abs() < 1e-5);
+    assert!((dg.p.z - 1.0).abs() <= p_error.z.max(1e-6));
+    assert!((dg.u - 0.75).abs() < 1e-5 && (dg.v - 0.25).abs() < 1e-5);
+    assert!((dg.dpdu.x - 2.0).abs() < 1e-5 && (dg.dpdv.y - 2.0).abs() < 1e-5);
+    assert!(triangles[1].intersect(&down(0.5, -0.5)).is_none());
+    assert_eq!(triangles[0].area(), 2.0);
+    assert_eq!(triangles[0].get_base().shape_id, triangles[1].get_base().shape_id);
+  }
+
+  #[test]
+  fn shared_edge_is_watertight() {
+    let triangles = TriangleMesh::triangles(&quad(None, None));
+
+    for &(x, y) in [(0.0, 0.0), (0.25, 0.25), (-0.7, -0.7), (0.3, 0.3)].iter() {
+      let hits = triangles.iter().filter(|t| t.intersect_p(&down(x, y))).count();
+      assert!(hits >= 1, "ray at ({}, {}) leaked through the diagonal", x, y);
+    }
+  }
+
+  #[test]
+  fn alpha_texture_cuts_out_hits() {
+    let triangles = TriangleMesh::triangles(&quad(None, Some(Rc::new(ConstantTexture(0.0)))));
+    assert!(!triangles[0].intersect_p(&down(0.5, -0.5)));
+
+    let triangles = TriangleMesh::triangles(&quad(None, Some(Rc::new(ConstantTexture(1.0)))));
+    assert!(triangles[0].intersect_p(&down(0.5, -0.5)));
+  }
+
+  #[test]
+  fn shading_normals_are_interpolated() {
+    let n = vec![Normal::new(-1.0, 0.0, 1.0), Normal::new(1.0, 0.0, 1.0),
+      Normal::new(1.0, 0.0, 1.0), Normal::new(-1.0, 0.0, 1.0)];
+    let triangles = TriangleMesh::triangles(&quad(Some(n), None));
+    let (_, _, dg) = triangles[1].intersect(&down(0.0, 0.5)).unwrap();
+
+    assert!(dg.nn.z > 0.0);
+
+    let shading = triangles[1].get_shading_geometry(&Transform::translate(&Vector::zero()), &dg);
+    assert!(shading.nn.x.abs() < 1e-5 && (shading.nn.z - 1.0).abs() < 1e-5);
+    assert!(shading.dndu.x > 0.0);
+
+    let (_, _, dg) = triangles[0].intersect(&down(0.9, -0.5)).unwrap();
+    let shading = triangles[0].get_shading_geometry(&Transform::translate(&Vector::zero()), &dg);
+    assert!(shading.nn.x > 0.5);
+
+    // Seen through an instance turned about z, the normals turn with it
+    let instanced = Transform::rotate_z(90.0) * triangles[0].get_base().object_to_world;
+    let shading = triangles[0].get_shading_geometry(&instanced, &dg);
+    assert!(shading.nn.y > 0.5 && shading.nn.x.abs() < 1e-5);
+  }
+
+  #[test]
+  fn sample_lies_in_the_triangle() {
+    let triangles = TriangleMesh::triangles(&quad(None, None));
+
+    for &(u1, u2) in [(0.0, 0.0), (0.4, 0.7), (1.0, 1.0)].iter() {
+      let (p, n) = triangles[0].sample(u1, u2).unwrap();
+      assert!((p.z - 1.0).abs() < 1e-6);
+      assert!((-1.0 - 1e-5..=1.0 + 1e-5).contains(&p.x) && p.y <= p.x + 1e-5);
+      assert!((n.z.abs() - 1.0).abs() < 1e-6);
+    }
+
+    let n = vec![Normal::new(0.0, 0.0, -1.0); 4];
+    let (_, n) = TriangleMesh::triangles(&quad(Some(n), None))[0].sample(0.5, 0.5).unwrap();
+    assert!((n.z + 1.0).abs() < 1e-6);
+  }
+
+  #[test]
+  fn sampled_normal_matches_intersect_under_mirroring() {
+    let t = Transform::scale(-1.0, 1.0, 1.0);
+    let p = vec![Point::new(0.0, 0.0, 0.0), Point::new(1.0, 0.0, 0.0), Point::new(0.0, 1.0, 0.0)];
+    let mesh = TriangleMesh::new(&t, &Transform::inverse(&t), false, vec![0, 1, 2],
+      p, None, None, None, None).unwrap();
+    let triangles = TriangleMesh::triangles(&Rc::new(mesh));
+
+    let (_, _, dg) = triangles[0].intersect(&down(-0.25, 0.25)).unwrap();
+    let (_, n) = triangles[0].sample(0.3, 0.3).unwrap();
+    assert!(dot(dg.nn, n) > 0.99);
+  }
+}